@@ -1,4 +1,4 @@
-/// Build an external library of tests in C, to test the C API.
+//! Build an external library of tests in C, to test the C API.
 
 extern crate cc;
 
@@ -7,5 +7,7 @@ pub fn main() {
     cc::Build::new()
         .include("../include")
         .file("tests/version_test.c")
+        .file("tests/whole_test.c")
+        .file("tests/job_test.c")
         .compile("rdiff_capi_ctests");
 }
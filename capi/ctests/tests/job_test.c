@@ -0,0 +1,99 @@
+// Tests in C of the buffer-based job API in the Rust rdiff C API.
+
+#include <stdio.h>
+#include <stdlib.h>
+#include <string.h>
+
+#include <rdiff.h>
+
+#include "rdiff_capi_ctests.h"
+
+#define BASIS_LEN 50000
+#define OUT_CAP 200000
+
+// Run a job to completion, feeding it a little input and output space at a time.
+// Returns the result, and the output length in *out_len.
+static rs_result run_job(rs_job_t *job, const unsigned char *in, size_t in_len,
+                         unsigned char *out, size_t *out_len) {
+    rs_buffers_t buf;
+    size_t in_pos = 0;
+    rs_result r;
+    *out_len = 0;
+    memset(&buf, 0, sizeof buf);
+    do {
+        if (buf.avail_in == 0) {
+            size_t n = in_len - in_pos < 777 ? in_len - in_pos : 777;
+            buf.next_in = (char *) in + in_pos;
+            buf.avail_in = n;
+            in_pos += n;
+            buf.eof_in = in_pos == in_len;
+        }
+        buf.next_out = (char *) out + *out_len;
+        buf.avail_out = OUT_CAP - *out_len < 333 ? OUT_CAP - *out_len : 333;
+        size_t before = buf.avail_out;
+        r = rs_job_iter(job, &buf);
+        *out_len += before - buf.avail_out;
+    } while (r == RS_BLOCKED);
+    rs_job_free(job);
+    return r;
+}
+
+// Reads from the in-memory basis passed as opaque.
+static rs_result copy_cb(void *opaque, rs_long_t pos, size_t *len, void **buf) {
+    const unsigned char *basis = opaque;
+    if (pos >= BASIS_LEN)
+        return RS_INPUT_ENDED;
+    if (*len > (size_t) (BASIS_LEN - pos))
+        *len = BASIS_LEN - pos;
+    memcpy(*buf, basis + pos, *len);
+    return RS_DONE;
+}
+
+// Signature, load signature, delta and patch jobs give back the new file.
+bool job_test(void) {
+    unsigned char *basis = malloc(BASIS_LEN), *new = malloc(BASIS_LEN + 100);
+    unsigned char *sig_buf = malloc(OUT_CAP), *delta = malloc(OUT_CAP), *out = malloc(OUT_CAP);
+    size_t sig_len, delta_len, out_len;
+    rs_signature_t *sig = NULL;
+    bool ok = false;
+    for (size_t i = 0; i < BASIS_LEN; i++)
+        basis[i] = (i * 7 + i / 13) % 251;
+    memcpy(new, "hello", 5);
+    memcpy(new + 5, basis, BASIS_LEN);
+    size_t new_len = BASIS_LEN + 5;
+
+    if (run_job(rs_sig_begin(512, 8, RS_RK_BLAKE2_SIG_MAGIC), basis, BASIS_LEN,
+                sig_buf, &sig_len) != RS_DONE || sig_len != 12 + 98 * 12) {
+        printf("signature job failed, length %zu\n", sig_len);
+        goto out;
+    }
+    if (run_job(rs_loadsig_begin(&sig), sig_buf, sig_len, out, &out_len) != RS_DONE
+        || sig == NULL) {
+        printf("loadsig job failed\n");
+        goto out;
+    }
+    if (run_job(rs_delta_begin(sig), new, new_len, delta, &delta_len) != RS_DONE
+        || delta_len > 100) {
+        printf("delta job failed, length %zu\n", delta_len);
+        goto out;
+    }
+    if (run_job(rs_patch_begin(copy_cb, basis), delta, delta_len, out, &out_len) != RS_DONE
+        || out_len != new_len || memcmp(out, new, new_len)) {
+        printf("patch job failed, length %zu\n", out_len);
+        goto out;
+    }
+    if (rs_sig_begin(0, 99, RS_MD4_SIG_MAGIC) != NULL) {
+        printf("rs_sig_begin accepted an overlong strong sum\n");
+        goto out;
+    }
+    ok = true;
+
+  out:
+    rs_free_sumset(sig);
+    free(basis);
+    free(new);
+    free(sig_buf);
+    free(delta);
+    free(out);
+    return ok;
+}
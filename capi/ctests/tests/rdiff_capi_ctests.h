@@ -1,3 +1,5 @@
 #include <stdbool.h>
 
 bool version_test(void);
+bool whole_file_test(void);
+bool job_test(void);
@@ -1,9 +1,11 @@
-/// Call out to tests written in C, which will then exercise the rdiff-rs C API.
+//! Call out to tests written in C, which will then exercise the rdiff-rs C API.
 
 extern crate rdiff_capi;
 
 extern "C" {
     fn version_test() -> bool;
+    fn whole_file_test() -> bool;
+    fn job_test() -> bool;
 }
 
 #[test]
@@ -12,3 +14,17 @@ pub fn run_version_test() {
         assert!(version_test());
     }
 }
+
+#[test]
+pub fn run_whole_file_test() {
+    unsafe {
+        assert!(whole_file_test());
+    }
+}
+
+#[test]
+pub fn run_job_test() {
+    unsafe {
+        assert!(job_test());
+    }
+}
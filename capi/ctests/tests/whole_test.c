@@ -0,0 +1,81 @@
+// Tests in C of the whole-file functions in the Rust rdiff C API.
+
+#include <stdio.h>
+#include <stdlib.h>
+#include <string.h>
+
+#include <rdiff.h>
+
+#include "rdiff_capi_ctests.h"
+
+#define BASIS_LEN 100000
+
+static FILE *file_of(const unsigned char *buf, size_t len) {
+    FILE *f = tmpfile();
+    fwrite(buf, 1, len, f);
+    rewind(f);
+    return f;
+}
+
+// A signature, delta and patch through FILEs gives back the new file.
+bool whole_file_test(void) {
+    unsigned char *basis = malloc(BASIS_LEN), *new = malloc(BASIS_LEN), *out = malloc(BASIS_LEN);
+    for (size_t i = 0; i < BASIS_LEN; i++)
+        basis[i] = (i * 7 + i / 13) % 251;
+    size_t new_len = BASIS_LEN - 5000;
+    memcpy(new, basis + 5000, new_len);
+    memcpy(new + 1000, "changed", 7);
+
+    FILE *basis_file = file_of(basis, BASIS_LEN), *new_file = file_of(new, new_len);
+    FILE *sig_file = tmpfile(), *delta_file = tmpfile(), *out_file = tmpfile();
+    rs_stats_t stats;
+    rs_signature_t *sig = NULL;
+    bool ok = false;
+    if (rs_sig_file(basis_file, sig_file, 1024, 0, RS_BLAKE2_SIG_MAGIC, &stats) != RS_DONE) {
+        printf("rs_sig_file failed\n");
+        goto out;
+    }
+    rewind(sig_file);
+    if (rs_loadsig_file(sig_file, &sig, &stats) != RS_DONE || stats.sig_blocks != 98) {
+        printf("rs_loadsig_file failed, blocks %ld\n", (long) stats.sig_blocks);
+        goto out;
+    }
+    if (rs_build_hash_table(sig) != RS_DONE
+        || rs_delta_file(sig, new_file, delta_file, &stats) != RS_DONE) {
+        printf("rs_delta_file failed\n");
+        goto out;
+    }
+    rewind(delta_file);
+    rewind(basis_file);
+    if (rs_patch_file(basis_file, delta_file, out_file, &stats) != RS_DONE
+        || stats.lit_bytes + stats.copy_bytes != (rs_long_t) new_len
+        || strcmp(stats.op, "patch")) {
+        printf("rs_patch_file failed\n");
+        goto out;
+    }
+    rewind(out_file);
+    if (fread(out, 1, BASIS_LEN, out_file) != new_len || memcmp(out, new, new_len)) {
+        printf("patched output is wrong\n");
+        goto out;
+    }
+
+    // A signature where the delta should be is rejected.
+    rewind(sig_file);
+    if (rs_patch_file(basis_file, sig_file, out_file, &stats) != RS_BAD_MAGIC) {
+        printf("rs_patch_file accepted a signature\n");
+        goto out;
+    }
+    ok = true;
+
+  out:
+    rs_free_sumset(sig);
+    fclose(basis_file);
+    fclose(new_file);
+    fclose(sig_file);
+    fclose(delta_file);
+    fclose(out_file);
+    free(basis);
+    free(new);
+    free(out);
+    return ok;
+}
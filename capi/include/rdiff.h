@@ -2,6 +2,31 @@
  * Copyright 2018 Martin Pool.
  */
 
+#ifndef _RDIFF_H
+#define _RDIFF_H
+
+#include <stddef.h>
+#include <stdint.h>
+#include <stdio.h>
+#include <time.h>
+
+#ifdef __cplusplus
+extern "C" {
+#endif
+
+/** A long integer type that can handle the largest file offsets. */
+typedef intmax_t rs_long_t;
+
+/** A uint32 magic number, emitted in bigendian/network order at the start of
+ * librsync files. */
+typedef enum {
+    RS_DELTA_MAGIC = 0x72730236,         /**< A delta file: "rs\x026". */
+    RS_MD4_SIG_MAGIC = 0x72730136,       /**< Rollsum and MD4: "rs\x016". */
+    RS_BLAKE2_SIG_MAGIC = 0x72730137,    /**< Rollsum and BLAKE2: "rs\x017". */
+    RS_RK_MD4_SIG_MAGIC = 0x72730146,    /**< RabinKarp and MD4: "rs\x01F". */
+    RS_RK_BLAKE2_SIG_MAGIC = 0x72730147, /**< RabinKarp and BLAKE2: "rs\x01G". */
+} rs_magic_number;
+
 /**
  * \enum rs_result
  * \brief Return codes from nonblocking rsync operations.
@@ -43,3 +68,104 @@ char const *rs_strerror(rs_result r);
 
 /** Return a pointer to the library version, eg "3.0.0" */
 extern const char* rs_version();
+
+/** Performance statistics from an operation. */
+typedef struct rs_stats {
+    char const *op;             /**< Name of the operation, eg "delta". */
+    int lit_cmds;               /**< Number of literal commands. */
+    rs_long_t lit_bytes;        /**< Number of literal bytes. */
+    rs_long_t lit_cmdbytes;     /**< Bytes used in literal command headers. */
+
+    rs_long_t copy_cmds, copy_bytes, copy_cmdbytes;
+    rs_long_t sig_cmds, sig_bytes;
+    int false_matches;
+
+    rs_long_t sig_blocks;       /**< Number of blocks in the signature. */
+
+    size_t block_len;
+
+    rs_long_t in_bytes;         /**< Total bytes read from input. */
+    rs_long_t out_bytes;        /**< Total bytes written to output. */
+
+    time_t start, end;
+} rs_stats_t;
+
+/** A signature loaded into memory. */
+typedef struct rs_signature rs_signature_t;
+
+/** Free a signature from rs_loadsig_file() or rs_loadsig_begin(). */
+void rs_free_sumset(rs_signature_t *);
+
+/** Index a signature for rs_delta_begin() or rs_delta_file(). This is done
+ * automatically if it's not called. */
+rs_result rs_build_hash_table(rs_signature_t *sums);
+
+/** Input and output buffers for rs_job_iter(), which updates them to show how
+ * much was consumed and produced. */
+struct rs_buffers_s {
+    char *next_in;              /**< Next input byte. */
+    size_t avail_in;            /**< Number of bytes available at next_in. */
+    int eof_in;                 /**< True if there is no more data after this. */
+    char *next_out;             /**< Next output byte should be put there. */
+    size_t avail_out;           /**< Remaining free space at next_out. */
+};
+
+typedef struct rs_buffers_s rs_buffers_t;
+
+/** Default block length, if not overridden by the caller. */
+#define RS_DEFAULT_BLOCK_LEN 2048
+
+/** A signature, delta, patch or load-signature job. */
+typedef struct rs_job rs_job_t;
+
+/** Run a job as far as the buffers allow: returns RS_DONE when it's complete,
+ * RS_BLOCKED when it needs more input or output space, or an error. */
+rs_result rs_job_iter(rs_job_t *job, rs_buffers_t *buffers);
+
+/** Statistics for the job so far. */
+const rs_stats_t *rs_job_statistics(rs_job_t *job);
+
+/** Free a job. */
+rs_result rs_job_free(rs_job_t *);
+
+/** Start generating a signature. A block_len, strong_sum_len or sig_magic of 0
+ * chooses the default. Returns NULL if they're invalid. */
+rs_job_t *rs_sig_begin(size_t new_block_len, size_t strong_sum_len,
+                       rs_magic_number sig_magic);
+
+/** Start generating a delta against a signature, which must outlive the job. */
+rs_job_t *rs_delta_begin(rs_signature_t *);
+
+/** Start loading a signature, which is stored in *sig when the job is done. */
+rs_job_t *rs_loadsig_begin(rs_signature_t **);
+
+/** Callback used to read the basis for a patch: copy up to *len bytes from pos
+ * into *buf, or point *buf at them, and set *len to the number provided. */
+typedef rs_result rs_copy_cb(void *opaque, rs_long_t pos, size_t *len,
+                             void **buf);
+
+/** Start applying a delta to a basis read through copy_cb. */
+rs_job_t *rs_patch_begin(rs_copy_cb *copy_cb, void *copy_arg);
+
+/** Write the signature of old_file to sig_file. */
+rs_result rs_sig_file(FILE *old_file, FILE *sig_file, size_t block_len,
+                      size_t strong_len, rs_magic_number sig_magic,
+                      rs_stats_t *stats);
+
+/** Load a signature from sig_file into *sumset. */
+rs_result rs_loadsig_file(FILE *sig_file, rs_signature_t **sumset,
+                          rs_stats_t *stats);
+
+/** Write a delta from the signature to new_file into delta_file. */
+rs_result rs_delta_file(rs_signature_t *, FILE *new_file, FILE *delta_file,
+                        rs_stats_t *);
+
+/** Apply delta_file to basis_file, which must be seekable, writing new_file. */
+rs_result rs_patch_file(FILE *basis_file, FILE *delta_file, FILE *new_file,
+                        rs_stats_t *);
+
+#ifdef __cplusplus
+}                               /* extern "C" */
+#endif
+
+#endif                          /* !_RDIFF_H */
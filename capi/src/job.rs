@@ -0,0 +1,216 @@
+// rdiff-rs capi -- C api to a Rust library for network deltas.
+// Copyright 2018 Martin Pool.
+
+//! The buffer-based job API: `rs_job_t` and `rs_job_iter`.
+
+use std::io;
+use std::io::{Read, Seek, SeekFrom};
+use std::ptr;
+use std::slice;
+
+use libc::{c_char, c_int, c_void, size_t};
+
+use rdiff::index::SignatureIndex;
+use rdiff::job::{Job, JobStatus};
+use rdiff::signature::Signature;
+
+use signature::RsSignature;
+use stats::RsStats;
+use {result_of, sig_options, RsResult};
+
+/// Input and output buffers for `rs_job_iter`, laid out as in librsync.
+#[repr(C)]
+pub struct RsBuffers {
+    pub next_in: *mut c_char,
+    pub avail_in: size_t,
+    pub eof_in: c_int,
+    pub next_out: *mut c_char,
+    pub avail_out: size_t,
+}
+
+/// A callback that provides the basis for a patch job: it's asked for `*len` bytes from
+/// `pos`, and either copies them into `*buf`, or points `*buf` at its own copy. It sets
+/// `*len` to the number provided, and returns `RS_DONE` on success.
+pub type RsCopyCb = unsafe extern "C" fn(opaque: *mut c_void, pos: i64, len: *mut size_t,
+                                         buf: *mut *mut c_void) -> c_int;
+
+/// Reads the basis for a patch through a copy callback.
+struct CopyCbBasis {
+    cb: RsCopyCb,
+    opaque: *mut c_void,
+    pos: u64,
+}
+
+// Safety: C callers are responsible for using the job, and so the callback, from one
+// thread at a time, as in librsync.
+unsafe impl Send for CopyCbBasis {}
+
+impl Read for CopyCbBasis {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let mut len = buf.len();
+        let mut p = buf.as_mut_ptr() as *mut c_void;
+        let r = unsafe { (self.cb)(self.opaque, self.pos as i64, &mut len, &mut p) };
+        if r != RsResult::Done as c_int {
+            return Err(io::Error::other("basis copy callback failed"));
+        }
+        let len = len.min(buf.len());
+        if p != buf.as_mut_ptr() as *mut c_void {
+            unsafe { ptr::copy_nonoverlapping(p as *const u8, buf.as_mut_ptr(), len) };
+        }
+        self.pos += len as u64;
+        Ok(len)
+    }
+}
+
+impl Seek for CopyCbBasis {
+    /// The callback doesn't say how long the basis is, so its end is taken to be as far
+    /// as an `rs_long_t` can reach, and COPY commands beyond the real end fail when the
+    /// callback provides no data.
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        self.pos = match pos {
+            SeekFrom::Start(p) => p,
+            SeekFrom::End(_) => i64::MAX as u64,
+            SeekFrom::Current(d) => (self.pos as i64 + d) as u64,
+        };
+        Ok(self.pos)
+    }
+}
+
+enum Kind {
+    Job(Job<'static>),
+
+    /// Loading a signature, which is collected until the end of the input and then
+    /// stored in `*out`.
+    LoadSig { buf: Vec<u8>, out: *mut *mut RsSignature },
+}
+
+/// A job in progress: an `rs_job_t`.
+pub struct RsJob {
+    kind: Kind,
+    stats: RsStats,
+}
+
+fn new_job(kind: Kind, op: &'static [u8]) -> *mut RsJob {
+    Box::into_raw(Box::new(RsJob { kind, stats: RsStats::new(op) }))
+}
+
+/// Start a job that generates a signature, returning null if the options are invalid.
+#[no_mangle]
+pub extern "C" fn rs_sig_begin(block_len: size_t, strong_len: size_t, sig_magic: u32)
+    -> *mut RsJob {
+    match sig_options(block_len, strong_len, sig_magic).ok().and_then(|o| Job::signature(&o).ok())
+    {
+        Some(job) => new_job(Kind::Job(job), b"signature\0"),
+        None => ptr::null_mut(),
+    }
+}
+
+/// Start a job that loads a signature, storing it in `*sig` when the job is done.
+#[no_mangle]
+pub extern "C" fn rs_loadsig_begin(sig: *mut *mut RsSignature) -> *mut RsJob {
+    new_job(Kind::LoadSig { buf: Vec::new(), out: sig }, b"loadsig\0")
+}
+
+/// Start a job that generates a delta from the basis described by `sig`.
+///
+/// `sig` must stay alive until the job is freed.
+#[no_mangle]
+pub unsafe extern "C" fn rs_delta_begin(sig: *mut RsSignature) -> *mut RsJob {
+    let sig = match sig.as_mut() {
+        Some(sig) => sig,
+        None => return ptr::null_mut(),
+    };
+    // Safety: the caller keeps the signature, which owns the index, alive.
+    let index = &*(sig.index() as *const SignatureIndex<'static>);
+    match Job::delta(index) {
+        Ok(job) => new_job(Kind::Job(job), b"delta\0"),
+        Err(_) => ptr::null_mut(),
+    }
+}
+
+/// Start a job that applies a delta to a basis read through `copy_cb`.
+#[no_mangle]
+pub extern "C" fn rs_patch_begin(copy_cb: RsCopyCb, copy_arg: *mut c_void) -> *mut RsJob {
+    match Job::patch(CopyCbBasis { cb: copy_cb, opaque: copy_arg, pos: 0 }) {
+        Ok(job) => new_job(Kind::Job(job), b"patch\0"),
+        Err(_) => ptr::null_mut(),
+    }
+}
+
+/// Run the job as far as possible with the given buffers, updating them to show what was
+/// consumed and produced.
+///
+/// Returns `RS_DONE` when the job is complete, `RS_BLOCKED` if it needs more input or
+/// output space, or an error.
+#[no_mangle]
+pub unsafe extern "C" fn rs_job_iter(job: *mut RsJob, buffers: *mut RsBuffers) -> RsResult {
+    let (job, b) = match (job.as_mut(), buffers.as_mut()) {
+        (Some(job), Some(b)) => (job, b),
+        _ => return RsResult::ParamError,
+    };
+    let input: &[u8] = if b.avail_in == 0 {
+        &[]
+    } else {
+        slice::from_raw_parts(b.next_in as *const u8, b.avail_in)
+    };
+    let output: &mut [u8] = if b.avail_out == 0 {
+        &mut []
+    } else {
+        slice::from_raw_parts_mut(b.next_out as *mut u8, b.avail_out)
+    };
+    let eof_in = b.eof_in != 0;
+    let (r, consumed, produced) = match job.kind {
+        Kind::Job(ref mut j) => match j.iter(input, eof_in, output) {
+            Ok(p) => {
+                let r = match p.status {
+                    JobStatus::Done => RsResult::Done,
+                    JobStatus::Blocked => RsResult::Blocked,
+                };
+                (r, p.consumed, p.produced)
+            }
+            Err(e) => (result_of(&e), 0, 0),
+        },
+        Kind::LoadSig { ref mut buf, out } => {
+            buf.extend_from_slice(input);
+            if !eof_in {
+                (RsResult::Blocked, input.len(), 0)
+            } else {
+                match Signature::read_from(&mut buf.as_slice()) {
+                    Ok(sig) => {
+                        job.stats.sig_blocks = sig.block_count() as i64;
+                        job.stats.block_len = sig.block_len() as size_t;
+                        *out = Box::into_raw(Box::new(RsSignature::new(sig)));
+                        (RsResult::Done, input.len(), 0)
+                    }
+                    Err(e) => (result_of(&e), input.len(), 0),
+                }
+            }
+        }
+    };
+    b.next_in = b.next_in.wrapping_add(consumed);
+    b.avail_in -= consumed;
+    b.next_out = b.next_out.wrapping_add(produced);
+    b.avail_out -= produced;
+    job.stats.in_bytes += consumed as i64;
+    job.stats.out_bytes += produced as i64;
+    r
+}
+
+/// Return the statistics for the job so far. Only the byte counts, and for a loaded
+/// signature its size, are filled in.
+#[no_mangle]
+pub unsafe extern "C" fn rs_job_statistics(job: *mut RsJob) -> *const RsStats {
+    match job.as_ref() {
+        Some(job) => &job.stats,
+        None => ptr::null(),
+    }
+}
+
+/// Free a job, whether or not it's finished.
+#[no_mangle]
+pub unsafe extern "C" fn rs_job_free(job: *mut RsJob) -> RsResult {
+    if !job.is_null() {
+        drop(Box::from_raw(job));
+    }
+    RsResult::Done
+}
@@ -1,19 +1,37 @@
 // rdiff-rs capi -- C api to a Rust library for network deltas.
 // Copyright 2015, 2016, 2018 Martin Pool.
 
+// The exported functions take pointers from C, with the same requirements on them as in
+// librsync, so they're not repeated on each one.
+#![allow(clippy::missing_safety_doc)]
+
 extern crate libc;
 extern crate rdiff;
 
+use std::io::ErrorKind;
+
+use libc::size_t;
+
+use rdiff::magic::SignatureFormat;
+use rdiff::mksum::SignatureOptions;
+use rdiff::Error;
+
+pub mod job;
+pub mod signature;
+pub mod stats;
+pub mod whole;
+
 /// Nul-terminated version number for ease of C binding.
 // 
 // Unfortunately this can't be automatically generated from the crate
 // version, it seems.
-pub static VERSION: &'static str = "0.0.0\0";
+pub static VERSION: &str = "0.0.0\0";
 
 // NB: These should stay in sync with the C result enums.
 //
 // TODO: Maybe these should be attributes of rust-library error types?
 #[repr(C)]
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
 pub enum RsResult {
     Done = 0,
 
@@ -39,45 +57,69 @@ pub enum RsResult {
     Corrupt =        106,    //< Unbelievable value in stream. */
     InternalError = 107,    //< Probably a library bug. */
     /// Bad value passed in to library, probably an application bug.
-    ParamErorr =    108,
+    ParamError =    108,
+}
+
+/// The result code corresponding to an error.
+pub fn result_of(e: &Error) -> RsResult {
+    match *e {
+        Error::Io(ref e) if e.kind() == ErrorKind::UnexpectedEof => RsResult::InputEnded,
+        Error::Io(_) => RsResult::IoError,
+        Error::BadMagic(_) | Error::UnsupportedFormat(_) => RsResult::BadMagic,
+        Error::CorruptSignature(_) | Error::CorruptDelta(_) | Error::ChecksumMismatch
+            | Error::BasisMismatch => RsResult::Corrupt,
+        Error::InvalidOptions(_) | Error::StrongLenTooLong { .. } => RsResult::ParamError,
+        Error::MemoryLimit(_) => RsResult::MemError,
+        Error::Cancelled => RsResult::InternalError,
+    }
+}
+
+/// Signature options from the arguments to `rs_sig_begin` or `rs_sig_file`, where zero
+/// chooses the default as in librsync.
+pub fn sig_options(block_len: size_t, strong_len: size_t, sig_magic: u32)
+    -> Result<SignatureOptions, RsResult> {
+    let magic = if sig_magic == 0 {
+        SignatureFormat::RkBlake2Sig
+    } else {
+        SignatureFormat::from_magic(sig_magic).ok_or(RsResult::BadMagic)?
+    };
+    let block_len = match block_len {
+        0 => rdiff::DEFAULT_BLOCK_LEN,
+        l if l > u32::MAX as size_t => return Err(RsResult::ParamError),
+        l => l as u32,
+    };
+    let strong_len = match strong_len {
+        0 => magic.max_strong_len(),
+        l if l > magic.max_strong_len() as size_t => return Err(RsResult::ParamError),
+        l => l as u32,
+    };
+    Ok(SignatureOptions { magic, block_len, strong_len, seed: 0 })
 }
 
 #[no_mangle]
-pub extern fn rs_version() -> *const libc::c_char {
+pub extern "C" fn rs_version() -> *const libc::c_char {
     // Version from environment has nul termination (I think we can count on this?)
-    return VERSION.as_ptr() as *const libc::c_char;
+    VERSION.as_ptr() as *const libc::c_char
 }
 
 #[no_mangle]
-pub extern fn rs_strerror(r: RsResult) -> *const libc::c_char {
-    match r {
-        RsResult::Done => b"OK\0".as_ptr() as *const libc::c_char,
-
-        // case RS_DONE:
-        //     return "OK";
-        // case RS_RUNNING:
-        //     return "still running";
-        // case RS_BLOCKED:
-        //     return "blocked waiting for input or output buffers";
-        // case RS_BAD_MAGIC:
-        //     return "bad magic number at start of stream";
-        // case RS_INPUT_ENDED:
-        //     return "unexpected end of input";
-        // case RS_CORRUPT:
-        //     return "stream corrupt";
-        // case RS_UNIMPLEMENTED:
-        //     return "unimplemented case";
-        // case RS_MEM_ERROR:
-        //     return "out of memory";
-        // case RS_IO_ERROR:
-        //     return "IO error";
-        // case RS_SYNTAX_ERROR:
-        //     return "bad command line syntax";
-        // case RS_INTERNAL_ERROR:
-        //     return "library internal error";
-        //
-        _ => b"unexplained problem\0".as_ptr() as *const libc::c_char,
-    }
+pub extern "C" fn rs_strerror(r: RsResult) -> *const libc::c_char {
+    let s: &'static [u8] = match r {
+        RsResult::Done => b"OK\0",
+        RsResult::Running => b"still running\0",
+        RsResult::Blocked => b"blocked waiting for input or output buffers\0",
+        RsResult::BadMagic => b"bad magic number at start of stream\0",
+        RsResult::InputEnded => b"unexpected end of input\0",
+        RsResult::Corrupt => b"stream corrupt\0",
+        RsResult::Unimplemented => b"unimplemented case\0",
+        RsResult::MemError => b"out of memory\0",
+        RsResult::IoError => b"IO error\0",
+        RsResult::SyntaxError => b"bad command line syntax\0",
+        RsResult::InternalError => b"library internal error\0",
+        RsResult::ParamError => b"bad value passed in to library\0",
+        _ => b"unexplained problem\0",
+    };
+    s.as_ptr() as *const libc::c_char
 }
 
 #[cfg(test)]
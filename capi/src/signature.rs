@@ -0,0 +1,63 @@
+// rdiff-rs capi -- C api to a Rust library for network deltas.
+// Copyright 2018 Martin Pool.
+
+//! `rs_signature_t`: a signature loaded into memory, and its index.
+
+use rdiff::index::SignatureIndex;
+use rdiff::signature::Signature;
+
+use RsResult;
+
+/// A loaded signature, and once `rs_build_hash_table` has been called, its index.
+pub struct RsSignature {
+    // Declared first so that it's dropped before the signature it refers to.
+    index: Option<SignatureIndex<'static>>,
+    sig: Box<Signature>,
+}
+
+impl RsSignature {
+    pub fn new(sig: Signature) -> RsSignature {
+        RsSignature { index: None, sig: Box::new(sig) }
+    }
+
+    pub fn signature(&self) -> &Signature {
+        &self.sig
+    }
+
+    /// Return the index, building it if it's not been built yet.
+    ///
+    /// The index lives as long as this `RsSignature`, which C callers must keep until
+    /// they're finished with anything using it.
+    pub fn index(&mut self) -> &SignatureIndex<'static> {
+        if self.index.is_none() {
+            // Safety: the signature is boxed, so it doesn't move when this struct does,
+            // and it's never changed or dropped while the index exists.
+            let sig: &'static Signature = unsafe { &*(&*self.sig as *const Signature) };
+            self.index = Some(SignatureIndex::new(sig));
+        }
+        self.index.as_ref().unwrap()
+    }
+}
+
+/// Build the index used to look up blocks while generating a delta.
+///
+/// Calling this is optional: the index is built when it's first needed, if it's not been
+/// built already.
+#[no_mangle]
+pub unsafe extern "C" fn rs_build_hash_table(sums: *mut RsSignature) -> RsResult {
+    match sums.as_mut() {
+        Some(sums) => {
+            sums.index();
+            RsResult::Done
+        }
+        None => RsResult::ParamError,
+    }
+}
+
+/// Free a signature loaded by `rs_loadsig_file` or `rs_loadsig_begin`.
+#[no_mangle]
+pub unsafe extern "C" fn rs_free_sumset(sums: *mut RsSignature) {
+    if !sums.is_null() {
+        drop(Box::from_raw(sums));
+    }
+}
@@ -0,0 +1,94 @@
+// rdiff-rs capi -- C api to a Rust library for network deltas.
+// Copyright 2018 Martin Pool.
+
+//! `rs_stats_t`, filled in from `rdiff::stats::Statistics`.
+
+use std::ptr;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use libc::{c_char, c_int, size_t, time_t};
+
+use rdiff::stats::Statistics;
+
+/// Counts of the work done by an operation, laid out as in librsync.
+#[repr(C)]
+pub struct RsStats {
+    pub op: *const c_char,
+    pub lit_cmds: c_int,
+    pub lit_bytes: i64,
+    pub lit_cmdbytes: i64,
+    pub copy_cmds: i64,
+    pub copy_bytes: i64,
+    pub copy_cmdbytes: i64,
+    pub sig_cmds: i64,
+    pub sig_bytes: i64,
+    pub false_matches: c_int,
+    pub sig_blocks: i64,
+    pub block_len: size_t,
+    pub in_bytes: i64,
+    pub out_bytes: i64,
+    pub start: time_t,
+    pub end: time_t,
+}
+
+impl RsStats {
+    pub fn new(op: &'static [u8]) -> RsStats {
+        let now = now();
+        RsStats {
+            op: op.as_ptr() as *const c_char,
+            lit_cmds: 0,
+            lit_bytes: 0,
+            lit_cmdbytes: 0,
+            copy_cmds: 0,
+            copy_bytes: 0,
+            copy_cmdbytes: 0,
+            sig_cmds: 0,
+            sig_bytes: 0,
+            false_matches: 0,
+            sig_blocks: 0,
+            block_len: 0,
+            in_bytes: 0,
+            out_bytes: 0,
+            start: now,
+            end: now,
+        }
+    }
+
+    /// Convert `stats`, whose `op` must be one of the static names used by rdiff.
+    pub fn from_statistics(stats: &Statistics) -> RsStats {
+        let op: &'static [u8] = match stats.op {
+            "signature" => b"signature\0",
+            "delta" => b"delta\0",
+            "patch" => b"patch\0",
+            _ => b"noop\0",
+        };
+        let end = now();
+        RsStats {
+            lit_cmds: stats.literal_cmds as c_int,
+            lit_bytes: stats.literal_bytes as i64,
+            lit_cmdbytes: stats.literal_cmd_bytes as i64,
+            copy_cmds: stats.copy_cmds as i64,
+            copy_bytes: stats.copy_bytes as i64,
+            copy_cmdbytes: stats.copy_cmd_bytes as i64,
+            false_matches: stats.false_matches as c_int,
+            sig_blocks: stats.block_count as i64,
+            block_len: stats.block_len as size_t,
+            in_bytes: stats.in_bytes as i64,
+            out_bytes: stats.out_bytes as i64,
+            start: end - stats.elapsed.as_secs() as time_t,
+            end,
+            .. RsStats::new(op)
+        }
+    }
+
+    /// Store the statistics into `*out`, if it's not null.
+    pub unsafe fn store(self, out: *mut RsStats) {
+        if !out.is_null() {
+            ptr::write(out, self);
+        }
+    }
+}
+
+fn now() -> time_t {
+    SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs() as time_t).unwrap_or(0)
+}
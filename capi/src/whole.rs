@@ -0,0 +1,135 @@
+// rdiff-rs capi -- C api to a Rust library for network deltas.
+// Copyright 2018 Martin Pool.
+
+//! Whole-file operations on C `FILE`s, like librsync's `whole.c`.
+
+use std::io;
+use std::io::{Read, Seek, SeekFrom, Write};
+
+use libc::{c_void, size_t, FILE};
+
+use rdiff::mkdelta::generate_delta_with_index;
+use rdiff::mksum::generate_signature;
+use rdiff::patch::apply_patch;
+use rdiff::signature::Signature;
+use rdiff::stats::Statistics;
+
+use signature::RsSignature;
+use stats::RsStats;
+use {result_of, sig_options, RsResult};
+
+/// Reads, writes and seeks a C `FILE`.
+struct CFile(*mut FILE);
+
+impl Read for CFile {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let l = unsafe { libc::fread(buf.as_mut_ptr() as *mut c_void, 1, buf.len(), self.0) };
+        if l == 0 && unsafe { libc::ferror(self.0) } != 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(l)
+    }
+}
+
+impl Write for CFile {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let l = unsafe { libc::fwrite(buf.as_ptr() as *const c_void, 1, buf.len(), self.0) };
+        if l < buf.len() {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(l)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        if unsafe { libc::fflush(self.0) } != 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(())
+    }
+}
+
+impl Seek for CFile {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        let (offset, whence) = match pos {
+            SeekFrom::Start(p) => (p as i64, libc::SEEK_SET),
+            SeekFrom::End(d) => (d, libc::SEEK_END),
+            SeekFrom::Current(d) => (d, libc::SEEK_CUR),
+        };
+        unsafe {
+            if libc::fseeko(self.0, offset as libc::off_t, whence) != 0 {
+                return Err(io::Error::last_os_error());
+            }
+            match libc::ftello(self.0) {
+                p if p < 0 => Err(io::Error::last_os_error()),
+                p => Ok(p as u64),
+            }
+        }
+    }
+}
+
+/// Store the statistics from a finished operation, and return its result code.
+unsafe fn finish(r: rdiff::Result<Statistics>, stats: *mut RsStats) -> RsResult {
+    match r {
+        Ok(s) => {
+            RsStats::from_statistics(&s).store(stats);
+            RsResult::Done
+        }
+        Err(e) => result_of(&e),
+    }
+}
+
+/// Write the signature of `old_file` to `sig_file`.
+///
+/// As in librsync, a `block_len` or `strong_len` of 0, or a `sig_magic` of 0, chooses
+/// the default.
+#[no_mangle]
+pub unsafe extern "C" fn rs_sig_file(old_file: *mut FILE, sig_file: *mut FILE,
+                                     block_len: size_t, strong_len: size_t, sig_magic: u32,
+                                     stats: *mut RsStats) -> RsResult {
+    let options = match sig_options(block_len, strong_len, sig_magic) {
+        Ok(o) => o,
+        Err(r) => return r,
+    };
+    finish(generate_signature(&mut CFile(old_file), &options, &mut CFile(sig_file)), stats)
+}
+
+/// Read a signature from `sig_file`, returning it in `*sumset`.
+///
+/// It should be freed with `rs_free_sumset`.
+#[no_mangle]
+pub unsafe extern "C" fn rs_loadsig_file(sig_file: *mut FILE, sumset: *mut *mut RsSignature,
+                                         stats: *mut RsStats) -> RsResult {
+    let mut input = CFile(sig_file);
+    match Signature::read_from(&mut input) {
+        Ok(sig) => {
+            RsStats {
+                sig_blocks: sig.block_count() as i64,
+                block_len: sig.block_len() as size_t,
+                .. RsStats::new(b"loadsig\0")
+            }.store(stats);
+            *sumset = Box::into_raw(Box::new(RsSignature::new(sig)));
+            RsResult::Done
+        }
+        Err(e) => result_of(&e),
+    }
+}
+
+/// Write a delta from the basis described by `sig` to `new_file`.
+#[no_mangle]
+pub unsafe extern "C" fn rs_delta_file(sig: *mut RsSignature, new_file: *mut FILE,
+                                       delta_file: *mut FILE, stats: *mut RsStats)
+    -> RsResult {
+    match sig.as_mut() {
+        Some(sig) => finish(generate_delta_with_index(sig.index(), &mut CFile(new_file),
+                                                      &mut CFile(delta_file)), stats),
+        None => RsResult::ParamError,
+    }
+}
+
+/// Apply `delta_file` to `basis_file`, which must be seekable, writing `new_file`.
+#[no_mangle]
+pub unsafe extern "C" fn rs_patch_file(basis_file: *mut FILE, delta_file: *mut FILE,
+                                       new_file: *mut FILE, stats: *mut RsStats) -> RsResult {
+    finish(apply_patch(&mut CFile(basis_file), &mut CFile(delta_file), &mut CFile(new_file)),
+           stats)
+}
@@ -0,0 +1,22 @@
+//! Apply an arbitrary delta to a fixed basis, both seeking in it and from memory, and
+//! check that the two agree.
+
+#![no_main]
+
+use std::io::Cursor;
+
+use libfuzzer_sys::fuzz_target;
+
+use rdiff::patch::{apply_patch, apply_patch_from_slice};
+
+fuzz_target!(|data: &[u8]| {
+    let basis: Vec<u8> = (0..4096).map(|i| ((i * 7 + i / 13) % 251) as u8).collect();
+    let mut seek_out = Vec::new();
+    let seek = apply_patch(&mut Cursor::new(&basis), &mut &data[..], &mut seek_out);
+    let mut slice_out = Vec::new();
+    let slice = apply_patch_from_slice(&basis, &mut &data[..], &mut slice_out);
+    assert_eq!(seek.is_ok(), slice.is_ok());
+    if seek.is_ok() {
+        assert_eq!(seek_out, slice_out);
+    }
+});
@@ -0,0 +1,17 @@
+//! Decode an arbitrary delta into commands.
+
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+
+use rdiff::delta::DeltaReader;
+
+fuzz_target!(|data: &[u8]| {
+    if let Ok(reader) = DeltaReader::new(data) {
+        for command in reader {
+            if command.is_err() {
+                break;
+            }
+        }
+    }
+});
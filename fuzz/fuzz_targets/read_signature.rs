@@ -0,0 +1,15 @@
+//! Read an arbitrary signature, and if it's accepted, search for matches against it.
+
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+
+use rdiff::mkdelta::generate_delta;
+use rdiff::signature::Signature;
+
+fuzz_target!(|data: &[u8]| {
+    if let Ok(sig) = Signature::read_from(&mut &data[..]) {
+        let new: Vec<u8> = (0..1000).map(|i| (i % 7) as u8).collect();
+        let _ = generate_delta(&sig, &mut new.as_slice(), &mut Vec::new());
+    }
+});
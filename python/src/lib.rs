@@ -0,0 +1,233 @@
+// rdiff-rs python -- Python bindings to a Rust library for network deltas.
+// Copyright 2018 Martin Pool.
+
+//! Python bindings, built into an importable `rdiff` module with
+//! [maturin](https://www.maturin.rs/).
+//!
+//! The whole-file functions take and return `bytes`, like the library's `memory` module:
+//!
+//! ```python
+//! import rdiff
+//!
+//! sig = rdiff.signature(basis)
+//! d = rdiff.delta(sig, new_file)
+//! assert rdiff.patch(basis, d) == new_file
+//! ```
+//!
+//! For data that's too big to hold in memory, `rdiff.Job` is the streaming job API: it's
+//! fed input and drained of output by repeated calls to `iter`, like librsync's
+//! `rs_job_iter`.
+//!
+//! Invalid input or options raise `ValueError`, input that ends too soon raises
+//! `EOFError`, and failures reading a basis file raise `OSError`. The GIL is released
+//! while the library works.
+
+extern crate pyo3;
+extern crate rdiff;
+
+use std::io;
+use std::io::{Read, Seek, SeekFrom};
+use std::sync::Mutex;
+
+use pyo3::exceptions::{PyEOFError, PyValueError};
+use pyo3::prelude::*;
+use pyo3::types::PyBytes;
+
+use rdiff::index::SignatureIndex;
+use rdiff::memory;
+use rdiff::mksum::SignatureOptions;
+use rdiff::signature::Signature;
+use rdiff::Error;
+
+/// How much output `Job.iter` returns at most, unless told otherwise.
+const DEFAULT_OUTPUT_LEN: usize = 64 << 10;
+
+fn py_err(e: Error) -> PyErr {
+    match e {
+        Error::Io(e) if e.kind() == io::ErrorKind::UnexpectedEof => {
+            PyEOFError::new_err(e.to_string())
+        }
+        Error::Io(e) => e.into(),
+        e => PyValueError::new_err(e.to_string()),
+    }
+}
+
+fn sig_options(block_len: Option<u32>, strong_len: Option<u32>) -> PyResult<SignatureOptions> {
+    let mut builder = SignatureOptions::new();
+    if let Some(block_len) = block_len {
+        builder = builder.block_len(block_len);
+    }
+    if let Some(strong_len) = strong_len {
+        builder = builder.strong_len(strong_len);
+    }
+    builder.build().map_err(py_err)
+}
+
+/// Return the signature of `basis`.
+///
+/// `block_len` and `strong_len` default to the library's defaults if they're omitted.
+#[pyfunction]
+#[pyo3(signature = (basis, block_len=None, strong_len=None))]
+fn signature<'py>(py: Python<'py>, basis: &[u8], block_len: Option<u32>,
+                  strong_len: Option<u32>) -> PyResult<Bound<'py, PyBytes>> {
+    let options = sig_options(block_len, strong_len)?;
+    let sig = py.detach(|| memory::signature_with_options(basis, &options)).map_err(py_err)?;
+    Ok(PyBytes::new(py, &sig))
+}
+
+/// Return a delta from the basis described by the signature `sig`, to `new_file`.
+#[pyfunction]
+fn delta<'py>(py: Python<'py>, sig: &[u8], new_file: &[u8]) -> PyResult<Bound<'py, PyBytes>> {
+    let delta = py.detach(|| memory::delta_of(sig, new_file)).map_err(py_err)?;
+    Ok(PyBytes::new(py, &delta))
+}
+
+/// Apply `delta` to `basis`, returning the new file.
+#[pyfunction]
+fn patch<'py>(py: Python<'py>, basis: &[u8], delta: &[u8]) -> PyResult<Bound<'py, PyBytes>> {
+    let new = py.detach(|| memory::apply(basis, delta)).map_err(py_err)?;
+    Ok(PyBytes::new(py, &new))
+}
+
+/// A binary Python file object, read through its `read` and `seek` methods.
+struct PyFileBasis(Py<PyAny>);
+
+impl Read for PyFileBasis {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        Python::attach(|py| {
+            let data = self.0.call_method1(py, "read", (buf.len(),))?;
+            let data = data.cast_bound::<PyBytes>(py).map_err(PyErr::from)?.as_bytes();
+            let len = data.len().min(buf.len());
+            buf[..len].copy_from_slice(&data[..len]);
+            Ok(len)
+        })
+        .map_err(|e: PyErr| io::Error::other(e.to_string()))
+    }
+}
+
+impl Seek for PyFileBasis {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        let (offset, whence) = match pos {
+            SeekFrom::Start(p) => (p as i64, 0),
+            SeekFrom::Current(d) => (d, 1),
+            SeekFrom::End(d) => (d, 2),
+        };
+        Python::attach(|py| self.0.call_method1(py, "seek", (offset, whence))?.extract(py))
+            .map_err(|e: PyErr| io::Error::other(e.to_string()))
+    }
+}
+
+/// A delta job, and the signature and index it refers to.
+struct DeltaJob {
+    // Declared first so that they're dropped before what they refer to.
+    job: rdiff::job::Job<'static>,
+    _index: Box<SignatureIndex<'static>>,
+    _sig: Box<Signature>,
+}
+
+impl DeltaJob {
+    fn new(sig: Signature) -> Result<DeltaJob, Error> {
+        let sig = Box::new(sig);
+        // Safety: the signature and index are boxed, so they don't move when this struct
+        // does, and they're never changed or dropped while the job exists.
+        let index = Box::new(SignatureIndex::new(unsafe { &*(&*sig as *const Signature) }));
+        let job = rdiff::job::Job::delta(unsafe { &*(&*index as *const SignatureIndex) })?;
+        Ok(DeltaJob { job, _index: index, _sig: sig })
+    }
+}
+
+enum Kind {
+    Job(rdiff::job::Job<'static>),
+    Delta(DeltaJob),
+}
+
+impl Kind {
+    fn job(&mut self) -> &mut rdiff::job::Job<'static> {
+        match self {
+            Kind::Job(job) => job,
+            Kind::Delta(d) => &mut d.job,
+        }
+    }
+}
+
+/// A signature, delta or patch operation, fed input and drained of output by repeated
+/// calls to `iter`.
+///
+/// ```python
+/// job = rdiff.Job.signature()
+/// buf, eof, done = b"", False, False
+/// while not done:
+///     if not eof:
+///         more = f.read(65536)
+///         eof = not more
+///         buf += more
+///     done, consumed, out = job.iter(buf, eof)
+///     buf = buf[consumed:]
+///     sig.write(out)
+/// ```
+#[pyclass(module = "rdiff")]
+struct Job {
+    kind: Mutex<Kind>,
+}
+
+#[pymethods]
+impl Job {
+    /// Make a job that generates a signature of the input.
+    #[staticmethod]
+    #[pyo3(signature = (block_len=None, strong_len=None))]
+    fn signature(block_len: Option<u32>, strong_len: Option<u32>) -> PyResult<Job> {
+        let options = sig_options(block_len, strong_len)?;
+        let job = rdiff::job::Job::signature(&options).map_err(py_err)?;
+        Ok(Job { kind: Mutex::new(Kind::Job(job)) })
+    }
+
+    /// Make a job that reads a new file and generates a delta from the signature `sig`.
+    #[staticmethod]
+    fn delta(sig: &[u8]) -> PyResult<Job> {
+        let sig = Signature::read_from(&mut &sig[..]).map_err(py_err)?;
+        let job = DeltaJob::new(sig).map_err(py_err)?;
+        Ok(Job { kind: Mutex::new(Kind::Delta(job)) })
+    }
+
+    /// Make a job that reads a delta and applies it to `basis`, producing the new file.
+    ///
+    /// `basis` is either `bytes`, or a binary file opened for reading and seeking.
+    #[staticmethod]
+    fn patch(basis: &Bound<'_, PyAny>) -> PyResult<Job> {
+        let job = if let Ok(b) = basis.cast::<PyBytes>() {
+            rdiff::job::Job::patch(io::Cursor::new(b.as_bytes().to_vec()))
+        } else {
+            rdiff::job::Job::patch(PyFileBasis(basis.clone().unbind()))
+        };
+        Ok(Job { kind: Mutex::new(Kind::Job(job.map_err(py_err)?)) })
+    }
+
+    /// Run the job as far as possible, returning `(done, consumed, output)`.
+    ///
+    /// `input` is the data available now, and `eof` says whether it's the end of the
+    /// input. Input that's not consumed must be passed in again next time. At most
+    /// `max_output` bytes of output are returned: if the job has more, it's returned by
+    /// the next call.
+    #[pyo3(signature = (input, eof, max_output=DEFAULT_OUTPUT_LEN))]
+    fn iter<'py>(&self, py: Python<'py>, input: &[u8], eof: bool, max_output: usize)
+                 -> PyResult<(bool, usize, Bound<'py, PyBytes>)> {
+        let mut output = vec![0; max_output];
+        let progress = py
+            .detach(|| self.kind.lock().unwrap().job().iter(input, eof, &mut output))
+            .map_err(py_err)?;
+        Ok((progress.status == rdiff::job::JobStatus::Done,
+            progress.consumed,
+            PyBytes::new(py, &output[..progress.produced])))
+    }
+}
+
+/// Generate and apply diffs relative to file signatures, like rdiff.
+#[pymodule]
+#[pyo3(name = "rdiff")]
+fn rdiff_module(m: &Bound<'_, PyModule>) -> PyResult<()> {
+    m.add_function(wrap_pyfunction!(signature, m)?)?;
+    m.add_function(wrap_pyfunction!(delta, m)?)?;
+    m.add_function(wrap_pyfunction!(patch, m)?)?;
+    m.add_class::<Job>()?;
+    Ok(())
+}
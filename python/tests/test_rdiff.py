@@ -0,0 +1,67 @@
+# rdiff-rs python -- Python bindings to a Rust library for network deltas.
+# Copyright 2018 Martin Pool.
+
+"""Tests of the Python bindings, run after building the module into the import path."""
+
+import io
+import unittest
+
+import rdiff
+
+BASIS = b"".join(b"line %d of the basis\n" % i for i in range(2000))
+NEW = BASIS[:10000] + b"a change in the middle\n" + BASIS[12000:] + b"and an ending\n"
+
+
+def run_job(job, data, chunk=1000):
+    """Feed `data` through `job` in chunks, and return all its output."""
+    out = []
+    pos = 0
+    while True:
+        inp = data[pos:pos + chunk]
+        eof = pos + chunk >= len(data)
+        done, consumed, produced = job.iter(inp, eof, max_output=777)
+        pos += consumed
+        out.append(produced)
+        if done:
+            return b"".join(out)
+
+
+class WholeFileTest(unittest.TestCase):
+
+    def test_round_trip(self):
+        sig = rdiff.signature(BASIS)
+        delta = rdiff.delta(sig, NEW)
+        self.assertLess(len(delta), len(NEW) // 10)
+        self.assertEqual(rdiff.patch(BASIS, delta), NEW)
+
+    def test_options(self):
+        sig = rdiff.signature(BASIS, block_len=256, strong_len=8)
+        self.assertEqual(rdiff.patch(BASIS, rdiff.delta(sig, NEW)), NEW)
+        with self.assertRaises(ValueError):
+            rdiff.signature(BASIS, block_len=0)
+
+    def test_bad_input(self):
+        with self.assertRaises(ValueError):
+            rdiff.delta(b"not a signature", NEW)
+        with self.assertRaises(ValueError):
+            rdiff.patch(BASIS, b"not a delta")
+
+
+class JobTest(unittest.TestCase):
+
+    def test_jobs(self):
+        sig = run_job(rdiff.Job.signature(), BASIS)
+        self.assertEqual(sig, rdiff.signature(BASIS))
+        delta = run_job(rdiff.Job.delta(sig), NEW)
+        self.assertEqual(run_job(rdiff.Job.patch(BASIS), delta), NEW)
+        self.assertEqual(run_job(rdiff.Job.patch(io.BytesIO(BASIS)), delta), NEW)
+
+    def test_truncated_delta(self):
+        delta = rdiff.delta(rdiff.signature(BASIS), NEW)
+        job = rdiff.Job.patch(BASIS)
+        with self.assertRaises(EOFError):
+            run_job(job, delta[:-5])
+
+
+if __name__ == "__main__":
+    unittest.main()
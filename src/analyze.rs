@@ -0,0 +1,218 @@
+// rdiff(rust) -- library for network deltas
+// Copyright 2018 Martin Pool.
+
+//! Summarize a delta without applying it.
+//!
+//! A sync service can decide from the summary alone whether applying a delta is worth
+//! it, compared to sending the whole new file, and what access to the basis it needs.
+
+use std::collections::HashMap;
+use std::io;
+use std::io::{BufReader, Read};
+
+use super::delta::{BasisId, CommandHeader, DeltaReader};
+use super::error::{Error, Result};
+use super::magic::DeltaFormat;
+
+/// What a delta does, found by reading it through without the basis.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DeltaSummary {
+    /// Format of the delta.
+    pub format: DeltaFormat,
+
+    /// The identity of the basis, if the delta has one.
+    pub basis_id: Option<BasisId>,
+
+    /// Length of the delta in bytes.
+    pub delta_len: u64,
+
+    /// Length of the new file that applying the delta makes.
+    pub new_len: u64,
+
+    /// Number of COPY commands.
+    pub copy_cmds: u64,
+
+    /// Bytes of the new file copied from the basis.
+    pub copy_bytes: u64,
+
+    /// Number of LITERAL commands.
+    pub literal_cmds: u64,
+
+    /// Bytes of the new file carried as literal data in the delta. In a compressed
+    /// delta, this counts the data after decompression.
+    pub literal_bytes: u64,
+
+    /// The largest offset in the basis that any COPY reads from, or `None` if there are
+    /// no COPYs.
+    pub max_copy_offset: Option<u64>,
+
+    /// The end of the furthest range that any COPY reads: the basis, or the longest of
+    /// several, must be at least this long.
+    pub min_basis_len: u64,
+
+    /// Number of bases the delta needs, from the highest numbered basis it copies from:
+    /// more than one only for a `DeltaFormat::MultiBasisDelta`.
+    pub basis_count: u64,
+
+    /// True if some COPY reads from before the end of the last COPY from the same basis,
+    /// so that the basis must be seekable. Otherwise, the basis can be read as a stream,
+    /// from start to end, skipping over what's not copied.
+    pub needs_seek: bool,
+}
+
+impl DeltaSummary {
+    /// Number of commands, other than END.
+    pub fn commands(&self) -> u64 {
+        self.copy_cmds + self.literal_cmds
+    }
+}
+
+/// Read a delta through to its end and summarize it, without the basis and without
+/// holding its literal data in memory.
+///
+/// Deltas in any format are read, including those against several bases.
+/// `Error::CorruptDelta` is returned if the delta is malformed, and an `Io` error of kind
+/// `UnexpectedEof` if it ends before its END command.
+pub fn analyze_delta<D: Read + ?Sized>(delta: &mut D) -> Result<DeltaSummary> {
+    let mut commands = DeltaReader::new(BufReader::new(delta))?;
+    commands.set_basis_count(u64::MAX);
+    let mut summary = DeltaSummary {
+        format: commands.format(),
+        basis_id: commands.basis_id().copied(),
+        delta_len: 0,
+        new_len: 0,
+        copy_cmds: 0,
+        copy_bytes: 0,
+        literal_cmds: 0,
+        literal_bytes: 0,
+        max_copy_offset: None,
+        min_basis_len: 0,
+        basis_count: 1,
+        needs_seek: false,
+    };
+    // Where the last COPY from each basis ended.
+    let mut copy_ends: HashMap<u64, u64> = HashMap::new();
+    loop {
+        match commands.read_header()? {
+            CommandHeader::Literal { len } => {
+                commands.copy_literal(len, &mut io::sink())?;
+            }
+            CommandHeader::Copy { offset, len } => {
+                if len == 0 {
+                    continue;
+                }
+                let basis = commands.basis();
+                let end = copy_ends.entry(basis).or_insert(0);
+                summary.needs_seek |= offset < *end;
+                *end = offset.saturating_add(len);
+                summary.max_copy_offset = summary.max_copy_offset.max(Some(offset));
+                summary.min_basis_len = summary.min_basis_len.max(*end);
+                summary.basis_count = summary.basis_count.max(basis.saturating_add(1));
+            }
+            CommandHeader::End => break,
+        }
+    }
+    let stats = commands.statistics();
+    let new_len = stats.copy_bytes.checked_add(stats.literal_bytes).ok_or_else(|| {
+        Error::CorruptDelta("the new file is too long to count".to_owned())
+    })?;
+    Ok(DeltaSummary {
+        delta_len: stats.in_bytes,
+        new_len,
+        copy_cmds: stats.copy_cmds,
+        copy_bytes: stats.copy_bytes,
+        literal_cmds: stats.literal_cmds,
+        literal_bytes: stats.literal_bytes,
+        .. summary
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use super::super::delta::{DeltaWriter, OP_COPY_N8_N8, OP_END};
+    use super::super::memory;
+    use super::super::test_util::random;
+
+    #[test]
+    pub fn summarize_commands() {
+        let mut w = DeltaWriter::new(Vec::new()).unwrap();
+        w.copy(100, 50).unwrap();
+        w.literal(b"hello").unwrap();
+        w.copy(1000, 20).unwrap();
+        w.copy(0, 10).unwrap();
+        let delta = w.finish().unwrap();
+        let summary = analyze_delta(&mut delta.as_slice()).unwrap();
+        assert_eq!(summary, DeltaSummary {
+            format: DeltaFormat::Delta,
+            basis_id: None,
+            delta_len: delta.len() as u64,
+            new_len: 85,
+            copy_cmds: 3,
+            copy_bytes: 80,
+            literal_cmds: 1,
+            literal_bytes: 5,
+            max_copy_offset: Some(1000),
+            min_basis_len: 1020,
+            basis_count: 1,
+            needs_seek: true,
+        });
+        assert_eq!(summary.commands(), 4);
+    }
+
+    #[test]
+    pub fn forward_copies_need_no_seek() {
+        let old = random(100_000);
+        let mut new = old[..30_000].to_vec();
+        new.extend_from_slice(b"an insertion");
+        new.extend_from_slice(&old[50_000..]);
+        let delta = memory::delta_of(&memory::signature_of(&old), &new).unwrap();
+        let summary = analyze_delta(&mut delta.as_slice()).unwrap();
+        assert!(!summary.needs_seek);
+        assert_eq!(summary.new_len, new.len() as u64);
+        assert_eq!(summary.min_basis_len, old.len() as u64);
+
+        let mut moved = old[50_000..].to_vec();
+        moved.extend_from_slice(&old[..50_000]);
+        let delta = memory::delta_of(&memory::signature_of(&old), &moved).unwrap();
+        assert!(analyze_delta(&mut delta.as_slice()).unwrap().needs_seek);
+    }
+
+    /// Each basis of a multi-basis delta is read forwards on its own.
+    #[test]
+    pub fn multiple_bases() {
+        let mut w = DeltaWriter::with_format(Vec::new(), DeltaFormat::MultiBasisDelta).unwrap();
+        w.copy_from(0, 500, 100).unwrap();
+        w.copy_from(2, 0, 100).unwrap();
+        w.copy_from(0, 700, 100).unwrap();
+        let summary = analyze_delta(&mut w.finish().unwrap().as_slice()).unwrap();
+        assert_eq!(summary.basis_count, 3);
+        assert_eq!(summary.min_basis_len, 800);
+        assert!(!summary.needs_seek);
+    }
+
+    #[test]
+    pub fn empty_and_bad_deltas() {
+        let delta = DeltaWriter::new(Vec::new()).unwrap().finish().unwrap();
+        let summary = analyze_delta(&mut delta.as_slice()).unwrap();
+        assert_eq!((summary.new_len, summary.max_copy_offset, summary.delta_len), (0, None, 5));
+        let err = analyze_delta(&mut &delta[..4]).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::UnexpectedEof);
+        let err = analyze_delta(&mut &b"not a delta"[..]).unwrap_err();
+        assert!(matches!(err, Error::BadMagic(_)), "{:?}", err);
+    }
+
+    /// A delta whose lengths add up to more than fit in a u64 is corrupt.
+    #[test]
+    pub fn overflowing_delta() {
+        let mut delta = b"rs\x026".to_vec();
+        for _ in 0..2 {
+            delta.push(OP_COPY_N8_N8);
+            delta.extend_from_slice(&0u64.to_be_bytes());
+            delta.extend_from_slice(&(1u64 << 63).to_be_bytes());
+        }
+        delta.push(OP_END);
+        let err = analyze_delta(&mut delta.as_slice()).unwrap_err();
+        assert!(matches!(err, Error::CorruptDelta(_)), "{:?}", err);
+    }
+}
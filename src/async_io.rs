@@ -0,0 +1,296 @@
+// rdiff(rust) -- library for network deltas
+// Copyright 2018 Martin Pool.
+
+//! Async versions of the signature, delta and patch operations, over tokio's
+//! `AsyncRead` and `AsyncWrite`.
+//!
+//! Signatures and deltas are generated by driving a `Job`, so the results are identical
+//! to the blocking functions. Hashing is done on the calling task, between reads and
+//! writes, so no threads are spawned. Only available with the `tokio` feature.
+//!
+//! `delta_commands` decodes a delta as a `Stream` of commands, for programs that want to
+//! look at or rewrite them on the way through.
+
+use std::io;
+use std::io::{ErrorKind, SeekFrom};
+
+use futures_util::stream::{self, Stream};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncSeek, AsyncSeekExt, AsyncWrite, AsyncWriteExt,
+                BufReader, BufWriter};
+
+use super::delta::{check_copy, parse_header, CommandHeader, DeltaCommand};
+use super::error::{Error, Result};
+use super::index::SignatureIndex;
+use super::job::{Job, JobStatus};
+use super::magic::DeltaFormat;
+use super::mksum::SignatureOptions;
+use super::signature::Signature;
+
+/// Size of the buffers used to move data in and out of jobs.
+const BUF_LEN: usize = 64 << 10;
+
+/// Run `job` to completion, reading from `input` and writing to `output`.
+async fn run_job<R, W>(mut job: Job<'_>, input: &mut R, output: &mut W) -> Result<()>
+    where R: AsyncRead + Unpin, W: AsyncWrite + Unpin {
+    let mut inbuf = vec![0; BUF_LEN];
+    let mut outbuf = vec![0; BUF_LEN];
+    let mut in_pos = 0;
+    let mut in_len = 0;
+    let mut eof = false;
+    loop {
+        if in_pos == in_len && !eof {
+            in_pos = 0;
+            in_len = input.read(&mut inbuf).await?;
+            eof = in_len == 0;
+        }
+        let progress = job.iter(&inbuf[in_pos..in_len], eof, &mut outbuf)?;
+        in_pos += progress.consumed;
+        output.write_all(&outbuf[..progress.produced]).await?;
+        if progress.status == JobStatus::Done {
+            output.flush().await?;
+            return Ok(());
+        }
+    }
+}
+
+/// Generate a signature, reading a basis file and writing a signature file.
+///
+/// This is the async equivalent of `mksum::generate_signature`.
+pub async fn generate_signature_async<R, W>(basis: &mut R, options: &SignatureOptions,
+                                            sig: &mut W) -> Result<()>
+    where R: AsyncRead + Unpin, W: AsyncWrite + Unpin {
+    run_job(Job::signature(options)?, basis, sig).await
+}
+
+/// Generate a delta, reading a new file and writing delta commands that will rebuild it
+/// from the basis described by `sig`.
+///
+/// This is the async equivalent of `mkdelta::generate_delta`.
+pub async fn generate_delta_async<R, W>(sig: &Signature, new: &mut R, delta: &mut W)
+    -> Result<()>
+    where R: AsyncRead + Unpin, W: AsyncWrite + Unpin {
+    let index = SignatureIndex::new(sig);
+    let job = Job::delta(&index)?;
+    run_job(job, new, delta).await
+}
+
+/// Copy exactly `len` bytes from `from` to `to`, failing if `from` ends early.
+async fn copy_exactly<R, W>(from: &mut R, len: u64, to: &mut W) -> Result<()>
+    where R: AsyncRead + Unpin, W: AsyncWrite + Unpin {
+    let copied = tokio::io::copy(&mut from.take(len), to).await?;
+    if copied < len {
+        Err(io::Error::new(ErrorKind::UnexpectedEof, "input ended early").into())
+    } else {
+        Ok(())
+    }
+}
+
+/// Read and check the delta magic number.
+async fn read_delta_magic<R: AsyncRead + Unpin>(delta: &mut R) -> Result<()> {
+    let magic = delta.read_u32().await?;
+    if DeltaFormat::check_magic(magic)? != DeltaFormat::Delta {
+        return Err(Error::UnsupportedFormat(magic));
+    }
+    Ok(())
+}
+
+/// Read the opcode and parameters of the next command, one byte at a time.
+async fn read_header<R: AsyncRead + Unpin>(delta: &mut R) -> Result<CommandHeader> {
+    let mut header = Vec::with_capacity(17);
+    loop {
+        header.push(delta.read_u8().await?);
+        if let Some((command, _)) = parse_header(&header)? {
+            return Ok(command);
+        }
+    }
+}
+
+async fn read_command<R: AsyncRead + Unpin>(delta: &mut R) -> Result<DeltaCommand> {
+    Ok(match read_header(delta).await? {
+        CommandHeader::Literal { len } => {
+            // Read incrementally rather than trusting the length to preallocate.
+            let mut data = Vec::new();
+            (&mut *delta).take(len).read_to_end(&mut data).await?;
+            if (data.len() as u64) < len {
+                return Err(io::Error::new(ErrorKind::UnexpectedEof,
+                                          "delta ended within a literal").into());
+            }
+            DeltaCommand::Literal(data)
+        }
+        CommandHeader::Copy { offset, len } => DeltaCommand::Copy { offset, len },
+        CommandHeader::End => DeltaCommand::End,
+    })
+}
+
+/// Decode a delta as a stream of commands.
+///
+/// This is the async equivalent of `delta::DeltaReader`: the stream yields each command
+/// in turn, ending with `DeltaCommand::End`. If the magic number is wrong or the delta is
+/// malformed, it yields one error and then ends.
+pub fn delta_commands<R>(delta: R) -> impl Stream<Item = Result<DeltaCommand>>
+    where R: AsyncRead + Unpin {
+    let state = (BufReader::new(delta), false, false);
+    stream::unfold(state, |(mut delta, started, done)| async move {
+        if done {
+            return None;
+        }
+        let r = if started {
+            read_command(&mut delta).await
+        } else {
+            match read_delta_magic(&mut delta).await {
+                Ok(()) => read_command(&mut delta).await,
+                Err(e) => Err(e),
+            }
+        };
+        let done = matches!(r, Ok(DeltaCommand::End) | Err(_));
+        Some((r, (delta, true, done)))
+    })
+}
+
+/// Apply a delta to a basis file, writing out the new file.
+///
+/// This is the async equivalent of `patch::apply_patch`, and returns the same errors,
+/// except that only a plain `DeltaFormat::Delta` is accepted: others give
+/// `Error::UnsupportedFormat`.
+pub async fn apply_patch_async<B, R, W>(basis: &mut B, delta: &mut R, out: &mut W)
+    -> Result<()>
+    where B: AsyncRead + AsyncSeek + Unpin, R: AsyncRead + Unpin, W: AsyncWrite + Unpin {
+    let basis_len = basis.seek(SeekFrom::End(0)).await?;
+    let delta = &mut BufReader::new(delta);
+    let out = &mut BufWriter::new(out);
+    read_delta_magic(delta).await?;
+    loop {
+        match read_header(delta).await? {
+            CommandHeader::Literal { len } => copy_exactly(delta, len, out).await?,
+            CommandHeader::Copy { offset, len } => {
+                check_copy(offset, len, basis_len)?;
+                basis.seek(SeekFrom::Start(offset)).await?;
+                copy_exactly(basis, len, out).await?;
+            }
+            CommandHeader::End => break,
+        }
+    }
+    out.flush().await?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use std::future::Future;
+    use std::io::Cursor;
+
+    use futures_util::StreamExt;
+
+    use super::*;
+    use super::super::delta::DeltaReader;
+    use super::super::magic::SignatureFormat;
+    use super::super::mkdelta::generate_delta;
+    use super::super::mksum::{calculate_signature, generate_signature};
+    use super::super::patch::apply_patch;
+    use super::super::test_util::pattern;
+
+    /// Run a future to completion. Requiring `Send` checks that the futures could be
+    /// spawned onto a multi-threaded runtime.
+    fn block_on<F: Future + Send>(f: F) -> F::Output {
+        tokio::runtime::Builder::new_current_thread().build().unwrap().block_on(f)
+    }
+
+    fn options() -> SignatureOptions {
+        SignatureOptions {
+            magic: SignatureFormat::RkBlake2Sig,
+            block_len: 1000,
+            .. SignatureOptions::default()
+        }
+    }
+
+    fn files() -> (Vec<u8>, Vec<u8>) {
+        let basis = pattern(150_000);
+        let mut new = basis[4000..].to_vec();
+        new.extend_from_slice(b"something new");
+        new.extend_from_slice(&basis[..4000]);
+        (basis, new)
+    }
+
+    #[test]
+    pub fn signature() {
+        let (basis, _) = files();
+        let mut expected = Vec::new();
+        generate_signature(&mut basis.as_slice(), &options(), &mut expected).unwrap();
+        let mut sig = Vec::new();
+        block_on(generate_signature_async(&mut basis.as_slice(), &options(), &mut sig))
+            .unwrap();
+        assert_eq!(sig, expected);
+    }
+
+    #[test]
+    pub fn delta() {
+        let (basis, new) = files();
+        let sig = calculate_signature(&mut basis.as_slice(), &options()).unwrap();
+        let mut expected = Vec::new();
+        generate_delta(&sig, &mut new.as_slice(), &mut expected).unwrap();
+        let mut delta = Vec::new();
+        block_on(generate_delta_async(&sig, &mut new.as_slice(), &mut delta)).unwrap();
+        assert_eq!(delta, expected);
+    }
+
+    #[test]
+    pub fn patch() {
+        let (basis, new) = files();
+        let sig = calculate_signature(&mut basis.as_slice(), &options()).unwrap();
+        let mut delta = Vec::new();
+        generate_delta(&sig, &mut new.as_slice(), &mut delta).unwrap();
+        let mut out = Vec::new();
+        block_on(apply_patch_async(&mut Cursor::new(&basis), &mut delta.as_slice(), &mut out))
+            .unwrap();
+        assert_eq!(out, new);
+    }
+
+    #[test]
+    pub fn patch_errors() {
+        for delta in &[&[b'r', b's', 0x01, 0x36, 0][..],
+                       &[b'r', b's', 0x02, 0x36, 0x55][..],
+                       &[b'r', b's', 0x02, 0x36, 0x45, 3, 3, 0][..],
+                       &[b'r', b's', 0x02, 0x36, 4, b'a'][..],
+                       &[b'r', b's', 0x02, 0x36, 0x41][..]] {
+            let err = block_on(apply_patch_async(&mut Cursor::new(b"hello"), &mut &delta[..],
+                                                 &mut Vec::new())).unwrap_err();
+            let expected = apply_patch(&mut Cursor::new(b"hello"), &mut &delta[..],
+                                       &mut Vec::new()).unwrap_err();
+            assert_eq!(err.kind(), expected.kind(), "{:?}", delta);
+        }
+    }
+
+    fn stream_all(delta: &[u8]) -> Vec<Result<DeltaCommand>> {
+        block_on(delta_commands(delta).collect())
+    }
+
+    #[test]
+    pub fn stream_commands() {
+        let (basis, new) = files();
+        let sig = calculate_signature(&mut basis.as_slice(), &options()).unwrap();
+        let mut delta = Vec::new();
+        generate_delta(&sig, &mut new.as_slice(), &mut delta).unwrap();
+        let commands: Vec<DeltaCommand> =
+            stream_all(&delta).into_iter().map(|r| r.unwrap()).collect();
+        let expected: Vec<DeltaCommand> =
+            DeltaReader::new(delta.as_slice()).unwrap().map(|r| r.unwrap()).collect();
+        assert_eq!(commands, expected);
+        assert_eq!(commands.last(), Some(&DeltaCommand::End));
+    }
+
+    #[test]
+    pub fn stream_errors_end_the_stream() {
+        for delta in &[&[b'r', b's', 0x01, 0x36, 0][..],
+                       &[b'r', b's', 0x02, 0x36, 1, b'a', 0x55, 0][..],
+                       &[b'r', b's', 0x02, 0x36, 4, b'a'][..]] {
+            let r = stream_all(delta);
+            let expected: Vec<Result<DeltaCommand>> = match DeltaReader::new(*delta) {
+                Ok(reader) => reader.collect(),
+                Err(e) => vec![Err(e)],
+            };
+            assert_eq!(r.len(), expected.len());
+            assert_eq!(r.last().unwrap().as_ref().unwrap_err().kind(),
+                       expected.last().unwrap().as_ref().unwrap_err().kind());
+        }
+    }
+}
@@ -0,0 +1,255 @@
+// rdiff(rust) -- library for network deltas
+// Copyright 2018 Martin Pool.
+
+//! Random access to the basis of a patch.
+//!
+//! COPY commands can read any part of the basis, in any order. Anything that's `Read +
+//! Seek`, such as a `File` or a `Cursor`, can be used, as can a byte slice. Other sources,
+//! such as an object store that serves ranges of a file, can implement `BasisProvider`
+//! themselves, and `CachedBasis` keeps recently read parts of any basis in memory.
+
+use std::collections::{BTreeMap, HashMap};
+use std::convert::TryFrom;
+use std::io;
+use std::io::{Read, Seek, SeekFrom, Write};
+
+use super::error::Result;
+
+/// How much of the basis is read at a time for a COPY.
+const COPY_CHUNK: usize = 64 << 10;
+
+/// Reads ranges of the basis of a patch.
+pub trait BasisProvider {
+    /// Fill `buf` from `offset` in the basis, failing with an error of kind
+    /// `UnexpectedEof` if the basis ends first.
+    fn read_at(&mut self, offset: u64, buf: &mut [u8]) -> io::Result<()>;
+
+    /// The length of the basis.
+    fn size(&mut self) -> io::Result<u64>;
+}
+
+impl<T: Read + Seek> BasisProvider for T {
+    fn read_at(&mut self, offset: u64, buf: &mut [u8]) -> io::Result<()> {
+        self.seek(SeekFrom::Start(offset))?;
+        self.read_exact(buf)
+    }
+
+    fn size(&mut self) -> io::Result<u64> {
+        self.seek(SeekFrom::End(0))
+    }
+}
+
+impl BasisProvider for [u8] {
+    fn read_at(&mut self, offset: u64, buf: &mut [u8]) -> io::Result<()> {
+        match usize::try_from(offset).ok().and_then(|o| self.get(o..)?.get(..buf.len())) {
+            Some(data) => {
+                buf.copy_from_slice(data);
+                Ok(())
+            }
+            None => Err(io::Error::new(io::ErrorKind::UnexpectedEof, "read beyond end of basis")),
+        }
+    }
+
+    fn size(&mut self) -> io::Result<u64> {
+        Ok(self.len() as u64)
+    }
+}
+
+/// A basis that keeps the blocks it has most recently read from another in memory.
+///
+/// A read that needs several consecutive blocks that aren't cached reads them from the
+/// inner basis all at once, so that many small reads of nearby data become a few larger
+/// ones. This suits a basis where each read is slow, such as one fetched over the
+/// network, or a delta that copies the same parts of the basis many times.
+pub struct CachedBasis<B> {
+    inner: B,
+    len: u64,
+    block_len: u64,
+    cache: BlockCache,
+}
+
+impl<B: BasisProvider> CachedBasis<B> {
+    /// Cache up to `max_blocks` blocks of `block_len` bytes from `inner`.
+    ///
+    /// An error of kind `InvalidInput` is returned if either is zero.
+    pub fn new(mut inner: B, block_len: usize, max_blocks: usize) -> io::Result<CachedBasis<B>> {
+        if block_len == 0 || max_blocks == 0 {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput,
+                                      "the cache block length and count must not be zero"));
+        }
+        let len = inner.size()?;
+        Ok(CachedBasis {
+            inner,
+            len,
+            block_len: block_len as u64,
+            cache: BlockCache::new(max_blocks),
+        })
+    }
+
+    /// The basis the blocks are read from.
+    pub fn get_ref(&self) -> &B {
+        &self.inner
+    }
+
+    /// Stop caching, and return the inner basis.
+    pub fn into_inner(self) -> B {
+        self.inner
+    }
+}
+
+impl<B: BasisProvider> BasisProvider for CachedBasis<B> {
+    fn read_at(&mut self, offset: u64, buf: &mut [u8]) -> io::Result<()> {
+        let end = offset.checked_add(buf.len() as u64).filter(|&e| e <= self.len)
+            .ok_or_else(|| io::Error::new(io::ErrorKind::UnexpectedEof,
+                                          "read beyond end of basis"))?;
+        let bl = self.block_len;
+        let mut i = offset / bl;
+        while i * bl < end && !buf.is_empty() {
+            if let Some(block) = self.cache.get(i) {
+                copy_overlap(block, i * bl, offset, buf);
+                i += 1;
+                continue;
+            }
+            let mut j = i + 1;
+            while j * bl < end && !self.cache.contains(j) {
+                j += 1;
+            }
+            let start = i * bl;
+            let mut data = vec![0; ((j * bl).min(self.len) - start) as usize];
+            self.inner.read_at(start, &mut data)?;
+            copy_overlap(&data, start, offset, buf);
+            for (n, block) in data.chunks(bl as usize).enumerate() {
+                self.cache.insert(i + n as u64, block.to_vec());
+            }
+            i = j;
+        }
+        Ok(())
+    }
+
+    fn size(&mut self) -> io::Result<u64> {
+        Ok(self.len)
+    }
+}
+
+/// Copy the part of `data`, which starts at `start` in the basis, that overlaps `buf`,
+/// which starts at `offset`.
+fn copy_overlap(data: &[u8], start: u64, offset: u64, buf: &mut [u8]) {
+    let from = offset.max(start);
+    let to = (offset + buf.len() as u64).min(start + data.len() as u64);
+    if from < to {
+        buf[(from - offset) as usize..(to - offset) as usize]
+            .copy_from_slice(&data[(from - start) as usize..(to - start) as usize]);
+    }
+}
+
+/// The most recently used blocks of a basis, by their index.
+struct BlockCache {
+    max_blocks: usize,
+
+    /// Cached blocks, with the time each was last used.
+    blocks: HashMap<u64, (u64, Vec<u8>)>,
+
+    /// The index of each cached block by the time it was last used, oldest first.
+    by_use: BTreeMap<u64, u64>,
+
+    /// Incremented each time a block is used.
+    clock: u64,
+}
+
+impl BlockCache {
+    fn new(max_blocks: usize) -> BlockCache {
+        BlockCache { max_blocks, blocks: HashMap::new(), by_use: BTreeMap::new(), clock: 0 }
+    }
+
+    fn contains(&self, i: u64) -> bool {
+        self.blocks.contains_key(&i)
+    }
+
+    /// Find block `i`, marking it as the most recently used.
+    fn get(&mut self, i: u64) -> Option<&[u8]> {
+        let (used, data) = self.blocks.get_mut(&i)?;
+        self.clock += 1;
+        self.by_use.remove(used);
+        self.by_use.insert(self.clock, i);
+        *used = self.clock;
+        Some(data)
+    }
+
+    /// Add block `i`, which isn't cached, dropping the least recently used if full.
+    fn insert(&mut self, i: u64, data: Vec<u8>) {
+        if self.blocks.len() >= self.max_blocks {
+            let (_, oldest) = self.by_use.pop_first().expect("cache is empty");
+            self.blocks.remove(&oldest);
+        }
+        self.clock += 1;
+        self.by_use.insert(self.clock, i);
+        self.blocks.insert(i, (self.clock, data));
+    }
+}
+
+/// Write `len` bytes from `offset` in `basis` to `out`, a piece at a time through `buf`.
+pub(crate) fn copy_range<B: BasisProvider + ?Sized>(basis: &mut B, offset: u64, len: u64,
+                                                    buf: &mut Vec<u8>, out: &mut dyn Write)
+    -> Result<()> {
+    buf.resize(COPY_CHUNK, 0);
+    let mut done = 0;
+    while done < len {
+        let l = (len - done).min(COPY_CHUNK as u64) as usize;
+        basis.read_at(offset + done, &mut buf[..l])?;
+        out.write_all(&buf[..l])?;
+        done += l as u64;
+    }
+    Ok(())
+}
+
+/// Reads a basis from start to end.
+pub(crate) struct BasisReader<'a, B: ?Sized> {
+    basis: &'a mut B,
+    pos: u64,
+    len: u64,
+}
+
+impl<'a, B: BasisProvider + ?Sized> BasisReader<'a, B> {
+    pub(crate) fn new(basis: &'a mut B) -> io::Result<BasisReader<'a, B>> {
+        let len = basis.size()?;
+        Ok(BasisReader { basis, pos: 0, len })
+    }
+}
+
+impl<'a, B: BasisProvider + ?Sized> Read for BasisReader<'a, B> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let l = (self.len - self.pos).min(buf.len() as u64) as usize;
+        self.basis.read_at(self.pos, &mut buf[..l])?;
+        self.pos += l as u64;
+        Ok(l)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::io::Cursor;
+
+    use super::*;
+
+    fn check<B: BasisProvider + ?Sized>(basis: &mut B, data: &[u8]) {
+        assert_eq!(basis.size().unwrap(), data.len() as u64);
+        let mut buf = [0; 10];
+        basis.read_at(85, &mut buf).unwrap();
+        assert_eq!(buf, data[85..95]);
+        let err = basis.read_at(95, &mut buf).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::UnexpectedEof);
+        let mut all = Vec::new();
+        BasisReader::new(basis).unwrap().read_to_end(&mut all).unwrap();
+        assert_eq!(all, data);
+        let mut copied = Vec::new();
+        copy_range(basis, 3, 90, &mut Vec::new(), &mut copied).unwrap();
+        assert_eq!(copied, data[3..93]);
+    }
+
+    #[test]
+    pub fn slices_and_seekable() {
+        let data: Vec<u8> = (0..100).collect();
+        check(&mut data.clone()[..], &data);
+        check(&mut Cursor::new(&data), &data);
+    }
+}
@@ -1,4 +1,4 @@
-/// rdiff command-line tool
+//! rdiff command-line tool
 
 // Copyright 2018 Martin Pool.
 
@@ -7,78 +7,599 @@ extern crate clap;
 extern crate rdiff;
 
 use std::ffi::OsStr;
-use std::fs::File;
+use std::fs::{self, File};
 use std::io::prelude::*;
-use std::io::{Result, stdin, stdout};
+use std::io;
+use std::io::{BufReader, ErrorKind, stdin, stdout};
+use std::path::Path;
 
-use clap::{AppSettings, Arg, ArgMatches, SubCommand};
+use clap::{App, AppSettings, Arg, ArgMatches, SubCommand};
 
-use rdiff::mksum::{SignatureOptions, generate_signature};
+use rdiff::compress::{decompressed, Compressor};
+use rdiff::delta::{DeltaCommand, DeltaReader};
+use rdiff::error::{Error, Result};
+use rdiff::files::{cached_signature, write_atomically, write_new_atomically};
+use rdiff::io_options::IoOptions;
+use rdiff::magic::{SignatureFormat, SIGNATURE_MULTI_MAGIC};
+use rdiff::mkdelta::{generate_delta, generate_delta_multires};
+use rdiff::mksum::{SignatureOptions, calculate_signature, generate_signature};
+use rdiff::multires::{generate_multi_signature, MultiSignature};
+use rdiff::patch::{apply_patch, apply_patch_and_sign, check_patch, PatchOptions};
+use rdiff::signature::Signature;
+use rdiff::stats::Statistics;
+
+/// Exit status for command line syntax errors, `RS_SYNTAX_ERROR` in C librsync.
+const SYNTAX_ERROR: i32 = 101;
 
 pub fn main() {
     let app = app_from_crate!()
         .setting(AppSettings::SubcommandRequired)
+        .arg(Arg::with_name("statistics")
+            .short("s")
+            .long("statistics")
+            .global(true)
+            .help("Show performance statistics"))
+        .arg(Arg::with_name("force")
+            .short("f")
+            .long("force")
+            .global(true)
+            .help("Overwrite an output file that already exists"))
         .subcommand(
-            SubCommand::with_name("signature")
+            signature_args(SubCommand::with_name("signature"),
+                           "Set signature block size, in bytes; several separated by commas \
+                            make a multi-resolution signature, not readable by librsync")
             .about("Generate a signature file from a basis")
             .arg(Arg::with_name("basis")
-                .required(true)
-                .help("Basis file to read, or - for stdin"))
+                .help("Basis file to read, or - for stdin (the default)"))
             .arg(Arg::with_name("signature")
+                .help("Signature file to write, or - for stdout (the default)"))
+            .arg(Arg::with_name("cdc")
+                .long("cdc")
+                .help("Divide the basis into content-defined chunks, averaging the block size, \
+                       with blake2 and rabinkarp"))
+            .arg(Arg::with_name("seed")
+                .long("seed")
+                .takes_value(true)
+                .help("Mix a number into the sums, so that collisions can't be chosen in \
+                       advance; not readable by librsync"))
+            .arg(Arg::with_name("compress")
+                .long("compress")
+                .takes_value(true)
+                .help("Compress the signature: zstd, if built in; not readable by librsync"))
+            )
+        .subcommand(
+            SubCommand::with_name("delta")
+            .about("Generate a delta from a signature to a new file")
+            .arg(Arg::with_name("signature")
+                .required(true)
+                .help("Signature file to read, or - for stdin"))
+            .arg(Arg::with_name("new")
+                .help("New file to read, or - for stdin (the default)"))
+            .arg(Arg::with_name("delta")
+                .help("Delta file to write, or - for stdout (the default)"))
+            .arg(Arg::with_name("compress")
+                .long("compress")
+                .takes_value(true)
+                .help("Compress the delta: zstd, if built in; not readable by librsync"))
+            )
+        .subcommand(
+            signature_args(SubCommand::with_name("diff"), "Set signature block size, in bytes")
+            .about("Generate a delta from an old file to a new one, without a signature file")
+            .arg(Arg::with_name("old")
+                .required(true)
+                .help("Old file to read, or - for stdin"))
+            .arg(Arg::with_name("new")
+                .required(true)
+                .help("New file to read, or - for stdin"))
+            .arg(Arg::with_name("delta")
+                .help("Delta file to write, or - for stdout (the default)"))
+            .arg(Arg::with_name("sig_cache")
+                .long("sig-cache")
+                .takes_value(true)
+                .value_name("DIR")
+                .help("Keep the old file's signature in DIR, and reuse it while the file is \
+                       unchanged"))
+            )
+        .subcommand(
+            signature_args(SubCommand::with_name("patch"),
+                           "Set the new file's signature block size, in bytes")
+            .about("Apply a delta to a basis to recreate the new file")
+            .arg(Arg::with_name("basis")
                 .required(true)
-                .help("Signature file to write, or - for stdout"))
-            .arg(Arg::with_name("sum_size")
-                .short("S")
-                .long("sum-size")
+                .help("Basis file to read"))
+            .arg(Arg::with_name("delta")
+                .help("Delta file to read, or - for stdin (the default)"))
+            .arg(Arg::with_name("new")
+                .help("New file to write, or - for stdout (the default)"))
+            .arg(Arg::with_name("check")
+                .long("check")
+                .help("Only check that the delta applies, and matches its checksum if it has \
+                       one, without writing the new file"))
+            .arg(Arg::with_name("signature")
+                .long("signature")
                 .takes_value(true)
-                .help("Set strong sum strength, in bytes"))
+                .value_name("FILE")
+                .help("Also write the signature of the new file to FILE, made with -b, -S, -H \
+                       and -R and calculated as it's written, for the next delta"))
+            )
+        .subcommand(
+            SubCommand::with_name("dump-sig")
+            .about("Describe the contents of a signature file")
+            .arg(Arg::with_name("signature")
+                .help("Signature file to read, or - for stdin (the default)"))
+            .arg(Arg::with_name("blocks")
+                .long("blocks")
+                .help("Show the weak and strong sums of each block"))
+            )
+        .subcommand(
+            SubCommand::with_name("dump-delta")
+            .about("List the commands in a delta file")
+            .arg(Arg::with_name("delta")
+                .help("Delta file to read, or - for stdin (the default)"))
+            )
+        .subcommand(
+            signature_args(SubCommand::with_name("bench"), "Set signature block size, in bytes")
+            .about("Measure the speed of each operation, and the size of deltas, on \
+                    generated data")
+            .arg(Arg::with_name("size")
+                .long("size")
+                .takes_value(true)
+                .help("Length of the generated basis, in bytes (default 16MiB)"))
             );
 
-
-    let r = match app.get_matches().subcommand() {
-        ("signature", Some(subm)) => signature_cmd(&subm),
+    let matches = app.get_matches();
+    let r = match matches.subcommand() {
+        ("signature", Some(subm)) => signature_cmd(subm).map(Some),
+        ("delta", Some(subm)) => delta_cmd(subm).map(Some),
+        ("diff", Some(subm)) => diff_cmd(subm).map(Some),
+        ("patch", Some(subm)) => patch_cmd(subm).map(Some),
+        ("dump-sig", Some(subm)) => dump_sig_cmd(subm).map(|()| None),
+        ("dump-delta", Some(subm)) => dump_delta_cmd(subm).map(|()| None),
+        ("bench", Some(subm)) => bench_cmd(subm).map(|()| None),
         _ => unimplemented!(), // shouldn't happen
     };
-    if let Err(e) = r {
-        eprintln!("rdiff error: {:?}", e);
-        std::process::exit(1);
+    match r {
+        Ok(stats) => if let (Some(stats), true) = (stats, matches.is_present("statistics")) {
+            eprintln!("rdiff: {}", stats);
+        },
+        Err(e) => {
+            eprintln!("rdiff error: {}", e);
+            std::process::exit(1);
+        }
+    }
+}
+
+/// Add the options that choose how a signature is made to `subcommand`: `-b`, `-S`, `-H`
+/// and `-R`, read by `signature_options`.
+fn signature_args<'a, 'b>(subcommand: App<'a, 'b>, block_size_help: &'b str) -> App<'a, 'b> {
+    subcommand
+        .arg(Arg::with_name("block_size")
+            .short("b")
+            .long("block-size")
+            .takes_value(true)
+            .help(block_size_help))
+        .arg(Arg::with_name("sum_size")
+            .short("S")
+            .long("sum-size")
+            .takes_value(true)
+            .help("Set strong sum strength, in bytes"))
+        .arg(Arg::with_name("hash")
+            .short("H")
+            .long("hash")
+            .takes_value(true)
+            .help("Strong hash: blake2 (the default), md4, or blake3 if built in"))
+        .arg(Arg::with_name("rollsum")
+            .short("R")
+            .long("rollsum")
+            .takes_value(true)
+            .help("Rolling hash: rabinkarp (the default) or rollsum"))
+}
+
+fn signature_cmd(subm: &ArgMatches) -> Result<Statistics> {
+    let options = signature_options(subm)?;
+    let compress = compression(subm);
+    let mut basis = open_input(subm.value_of_os("basis"))?;
+    let sizes = block_sizes(subm).filter(|s| s.len() > 1);
+    let mut write = |sig: &mut dyn Write| match &sizes {
+        Some(sizes) => generate_multi_signature(&mut basis, &options, sizes, sig),
+        None => generate_signature(&mut basis, &options, sig),
     };
+    write_output(subm, "signature", false, |sig| {
+        if !compress {
+            return write(sig);
+        }
+        compressed(sig, &mut write)
+    })
 }
 
-fn signature_cmd(subm: &ArgMatches) -> Result<()> {
-    let mut basis = open_input(subm.value_of_os("basis").unwrap())?;
-    let mut sig = open_output(subm.value_of_os("signature").unwrap())?;
-    let mut options = SignatureOptions::default();
-    if let Some(s) = subm.value_of("sum_size") {
-        options = options.with_strong_len(s.parse::<u32>().expect("sum_size isn't an integer"));
+/// Whether `--compress` asks for zstd, exiting on any other compression.
+fn compression(subm: &ArgMatches) -> bool {
+    match subm.value_of("compress") {
+        None => false,
+        Some("zstd") if cfg!(feature = "zstd") => true,
+        Some("zstd") => usage("zstd compression isn't built in."),
+        Some(other) => usage(&format!("Unknown compression '{}'; only zstd is supported.",
+                                      other)),
     }
-    generate_signature(&mut basis, &options, &mut sig)
 }
 
-/// Open a file from a file name for input, treating `-` as stdin.
-fn open_input(n: &OsStr) -> Result<Box<Read>> {
-    match n.to_str() {
-        Some("-") => Ok(Box::new(stdin())),
-        _ => match File::open(n) {
-            Ok(f) => Ok(Box::new(f)),
-            Err(e) => {
-                eprintln!("rdiff: can't open input {:?}: {}", n, e);
-                Err(e)
+/// Call `write` with a writer that compresses to `out`, and count the compressed bytes
+/// in its statistics.
+fn compressed(out: &mut dyn Write, write: &mut dyn FnMut(&mut dyn Write) -> Result<Statistics>)
+    -> Result<Statistics> {
+    let mut compressor = Compressor::new(out, 0)?;
+    let stats = write(&mut compressor)?;
+    let (_, out_bytes) = compressor.finish()?;
+    Ok(Statistics { out_bytes, .. stats })
+}
+
+/// Parse the block sizes, separated by commas, exiting if they're not numbers.
+fn block_sizes(subm: &ArgMatches) -> Option<Vec<u32>> {
+    subm.value_of("block_size").map(|v| v.split(',').map(|s| match s.trim().parse() {
+        Ok(n) => n,
+        Err(_) => usage(&format!("Bad numeric value for --block-size: '{}'.", v)),
+    }).collect())
+}
+
+/// Choose the signature format and sizes from the command line, exiting on a syntax
+/// error.
+///
+/// Like C rdiff 2.2, this defaults to RabinKarp and BLAKE2, and to 8-byte strong sums for
+/// MD4 or otherwise the whole hash. A sum size of 0 also means the whole hash.
+fn signature_options(subm: &ArgMatches) -> Result<SignatureOptions> {
+    let hash = subm.value_of("hash").unwrap_or("blake2");
+    let rollsum = subm.value_of("rollsum").unwrap_or("rabinkarp");
+    let rabinkarp = match rollsum {
+        "rabinkarp" => true,
+        "rollsum" => false,
+        _ => usage(&format!("Unknown rollsum algorithm '{}'.", rollsum)),
+    };
+    let magic = match (hash, rabinkarp) {
+        ("blake2", false) => SignatureFormat::Blake2Sig,
+        ("blake2", true) => SignatureFormat::RkBlake2Sig,
+        ("md4", false) => SignatureFormat::Md4Sig,
+        ("md4", true) => SignatureFormat::RkMd4Sig,
+        #[cfg(feature = "blake3")]
+        ("blake3", true) => SignatureFormat::Blake3Sig,
+        #[cfg(feature = "blake3")]
+        ("blake3", false) => usage("The blake3 hash can only be used with rabinkarp."),
+        _ => usage(&format!("Unknown hash algorithm '{}'.", hash)),
+    };
+    let magic = match (subm.is_present("cdc"), magic) {
+        (false, _) => magic,
+        (true, SignatureFormat::RkBlake2Sig) => SignatureFormat::CdcBlake2Sig,
+        (true, _) => usage("Content-defined chunks can only be used with blake2 and rabinkarp."),
+    };
+    let mut builder = SignatureOptions::new().magic(magic);
+    // Of several block sizes, the longest is checked here; the others are used for the
+    // other levels of a multi-resolution signature.
+    if let Some(&block_len) = block_sizes(subm).iter().flatten().max() {
+        builder = builder.block_len(block_len);
+    }
+    if let Some(seed) = numeric_arg(subm, "seed") {
+        builder = builder.seed(seed);
+    }
+    match numeric_arg(subm, "sum_size") {
+        Some(0) => (),
+        Some(strong_len) => builder = builder.strong_len(strong_len),
+        None => if let SignatureFormat::Md4Sig | SignatureFormat::RkMd4Sig = magic {
+            builder = builder.strong_len(8);
+        },
+    }
+    builder.build()
+}
+
+/// Parse an optional numeric argument, exiting if it's not a number.
+fn numeric_arg(subm: &ArgMatches, name: &str) -> Option<u32> {
+    subm.value_of(name).map(|v| match v.parse() {
+        Ok(n) => n,
+        Err(_) => usage(&format!("Bad numeric value for --{}: '{}'.", name.replace('_', "-"), v)),
+    })
+}
+
+/// Complain about a command line syntax error, and exit with the same status as C rdiff.
+fn usage(message: &str) -> ! {
+    eprintln!("rdiff: {}\n\nTry `rdiff --help' for more information.", message);
+    std::process::exit(SYNTAX_ERROR);
+}
+
+fn delta_cmd(subm: &ArgMatches) -> Result<Statistics> {
+    let sig_name = subm.value_of_os("signature");
+    let new_name = subm.value_of_os("new");
+    if is_stdio(sig_name) && is_stdio(new_name) {
+        return Err(Error::InvalidOptions(
+            "the signature and new file can't both be read from stdin".to_owned()));
+    }
+    let compress = compression(subm);
+    let sig = read_signature(open_input(sig_name)?)?;
+    let mut new = open_input(new_name)?;
+    let mut write = |delta: &mut dyn Write| match &sig {
+        Sig::One(sig) => generate_delta(sig, &mut new, delta),
+        Sig::Multi(multi) => generate_delta_multires(multi, &mut new, delta),
+    };
+    write_output(subm, "delta", false, |delta| {
+        if !compress {
+            return write(delta);
+        }
+        compressed(delta, &mut write)
+    })
+}
+
+fn diff_cmd(subm: &ArgMatches) -> Result<Statistics> {
+    let options = signature_options(subm)?;
+    let old_name = subm.value_of_os("old");
+    let new_name = subm.value_of_os("new");
+    if is_stdio(old_name) && is_stdio(new_name) {
+        return Err(Error::InvalidOptions(
+            "the old and new files can't both be read from stdin".to_owned()));
+    }
+    // The signature is only kept in memory, or in the cache.
+    let sig = match (subm.value_of_os("sig_cache"), old_name) {
+        (Some(_), n) if is_stdio(n) => usage("--sig-cache needs the old file to be named."),
+        (Some(cache), Some(old)) => cached_signature(Path::new(old), &options, Path::new(cache))?,
+        _ => calculate_signature(&mut open_input(old_name)?, &options)?,
+    };
+    let mut new = open_input(new_name)?;
+    write_output(subm, "delta", false, |delta| generate_delta(&sig, &mut new, delta))
+}
+
+/// A signature file, which may hold signatures at several block lengths.
+enum Sig {
+    One(Signature),
+    Multi(MultiSignature),
+}
+
+/// Read a signature file of either kind, chosen by its magic number, decompressing it if
+/// it's compressed.
+fn read_signature(input: Box<dyn Read>) -> Result<Sig> {
+    let mut input = decompressed(input)?;
+    let mut magic = [0; 4];
+    input.read_exact(&mut magic)?;
+    let input = &mut (&magic[..]).chain(input);
+    if u32::from_be_bytes(magic) == SIGNATURE_MULTI_MAGIC {
+        Ok(Sig::Multi(MultiSignature::read_from(input)?))
+    } else {
+        Ok(Sig::One(Signature::read_from(input)?))
+    }
+}
+
+fn patch_cmd(subm: &ArgMatches) -> Result<Statistics> {
+    // The basis is read in random order, so it can't be a pipe.
+    let basis_name = subm.value_of_os("basis").unwrap();
+    let mut basis = match File::open(basis_name) {
+        Ok(f) => BufReader::new(f),
+        Err(e) => {
+            eprintln!("rdiff: can't open basis {:?}: {}", basis_name, e);
+            return Err(e.into());
+        }
+    };
+    // A compressed delta is recognized and decompressed by `apply_patch`.
+    let mut delta = open_input(subm.value_of_os("delta"))?;
+    if subm.is_present("check") {
+        if subm.is_present("new") || subm.is_present("signature") {
+            usage("--check doesn't write a new file.");
+        }
+        return check_patch(&mut basis, &mut delta);
+    }
+    if subm.is_present("signature") {
+        if is_stdio(subm.value_of_os("signature")) && is_stdio(subm.value_of_os("new")) {
+            usage("The new file and its signature can't both be written to stdout.");
+        }
+        let options = signature_options(subm)?;
+        // The signature is only kept if the new file is written too.
+        return write_output(subm, "signature", false, |out| {
+            let (stats, sig) = write_output(subm, "new", true, |new| {
+                apply_patch_and_sign(&mut basis, &mut delta, new, &PatchOptions::default(),
+                                     &options)
+            })?;
+            sig.write_to(out)?;
+            Ok(stats)
+        });
+    }
+    // Blocks of zeros are left as holes in a file, so that a sparse file stays sparse.
+    write_output(subm, "new", true, |new| apply_patch(&mut basis, &mut delta, new))
+}
+
+fn dump_sig_cmd(subm: &ArgMatches) -> Result<()> {
+    let out = &mut stdout();
+    match read_signature(open_input(subm.value_of_os("signature"))?)? {
+        Sig::One(sig) => dump_sig(out, &sig, subm.is_present("blocks")),
+        Sig::Multi(multi) => {
+            writeln!(out, "multi-resolution signature of {} bytes", multi.basis_len())?;
+            for (i, sig) in multi.levels().iter().enumerate() {
+                writeln!(out, "level {}:", i)?;
+                dump_sig(out, sig, subm.is_present("blocks"))?;
+            }
+            Ok(())
+        }
+    }
+}
+
+/// Describe one signature, and if `blocks` is set, list its blocks.
+fn dump_sig(out: &mut dyn Write, sig: &Signature, blocks: bool) -> Result<()> {
+    writeln!(out, "format: {:?} ({:#010x})", sig.format(), sig.format() as u32)?;
+    writeln!(out, "block length: {}", sig.block_len())?;
+    writeln!(out, "strong sum length: {}", sig.strong_len())?;
+    if sig.seed() != 0 {
+        writeln!(out, "seed: {}", sig.seed())?;
+    }
+    writeln!(out, "blocks: {}", sig.block_count())?;
+    if blocks {
+        for i in 0..sig.block_count() {
+            write!(out, "{:8} ", i)?;
+            if let Some(lens) = sig.block_lens() {
+                write!(out, "{:10} ", lens[i])?;
+            }
+            writeln!(out, "{:08x} {}", sig.weak_sum(i), hex(sig.strong_sum(i)))?;
+        }
+    }
+    Ok(())
+}
+
+/// List each command, preceded by the offset in the new file where its output starts,
+/// and then totals for each kind of command.
+fn dump_delta_cmd(subm: &ArgMatches) -> Result<()> {
+    let delta = decompressed(open_input(subm.value_of_os("delta"))?)?;
+    let mut commands = DeltaReader::new(BufReader::new(delta))?;
+    let out = &mut stdout();
+    let mut pos: u64 = 0;
+    for command in commands.by_ref() {
+        match command? {
+            DeltaCommand::Copy { offset, len } => {
+                writeln!(out, "{:12} COPY offset={} len={}", pos, offset, len)?;
+                pos += len;
+            }
+            DeltaCommand::Literal(data) => {
+                writeln!(out, "{:12} LITERAL len={}", pos, data.len())?;
+                pos += data.len() as u64;
             }
+            DeltaCommand::End => writeln!(out, "{:12} END", pos)?,
         }
     }
+    let stats = commands.statistics();
+    let percent = |bytes: u64| if pos == 0 { 0.0 } else { bytes as f64 * 100.0 / pos as f64 };
+    writeln!(out, "copy: {} cmds, {} bytes ({:.1}%)",
+             stats.copy_cmds, stats.copy_bytes, percent(stats.copy_bytes))?;
+    writeln!(out, "literal: {} cmds, {} bytes ({:.1}%)",
+             stats.literal_cmds, stats.literal_bytes, percent(stats.literal_bytes))?;
+    Ok(())
 }
 
-/// Open a file from a file name for output, treating `-` as stdout.
-fn open_output(n: &OsStr) -> Result<Box<Write>> {
-    match n.to_str() {
-        Some("-") => Ok(Box::new(stdout())),
-        _ => match File::create(n) {
+/// Default length of the basis generated by `bench`.
+const BENCH_SIZE: u32 = 16 << 20;
+
+/// Generate a basis and several changed versions of it, and report how fast each is
+/// signed, diffed and patched, and how large the deltas are.
+fn bench_cmd(subm: &ArgMatches) -> Result<()> {
+    let options = signature_options(subm)?;
+    let size = numeric_arg(subm, "size").unwrap_or(BENCH_SIZE) as usize;
+    let mut basis = bench_data(size, 1);
+    let out = &mut stdout();
+    writeln!(out, "basis: {} bytes, {:?}, block length {}, strong sum length {}",
+             size, options.magic, options.block_len, options.strong_len)?;
+    let mut sig = Vec::new();
+    let stats = generate_signature(&mut basis.as_slice(), &options, &mut sig)?;
+    writeln!(out, "{:>10}: {:8.1} MB/s, {} bytes", "signature",
+             throughput(size, &stats), sig.len())?;
+    let sig = Signature::read_from(&mut sig.as_slice())?;
+    for &(name, ref new) in &bench_mutations(&basis) {
+        let mut delta = Vec::new();
+        let delta_stats = generate_delta(&sig, &mut new.as_slice(), &mut delta)?;
+        let mut patched = Vec::with_capacity(new.len());
+        let patch_stats = apply_patch(basis.as_mut_slice(), &mut delta.as_slice(), &mut patched)?;
+        assert!(patched == *new, "patching didn't reproduce the {} file", name);
+        writeln!(out, "{:>10}: delta {:8.1} MB/s, patch {:8.1} MB/s, delta {} bytes ({:.2}%)",
+                 name, throughput(new.len(), &delta_stats), throughput(new.len(), &patch_stats),
+                 delta.len(), delta.len() as f64 * 100.0 / new.len().max(1) as f64)?;
+    }
+    Ok(())
+}
+
+/// Megabytes per second to process `len` bytes.
+fn throughput(len: usize, stats: &Statistics) -> f64 {
+    len as f64 / 1e6 / stats.elapsed.as_secs_f64().max(1e-6)
+}
+
+/// Bytes that don't repeat, generated from `seed`.
+fn bench_data(len: usize, seed: u64) -> Vec<u8> {
+    let mut x = seed;
+    (0..len).map(|_| {
+        x = x.wrapping_mul(6_364_136_223_846_793_005).wrapping_add(1_442_695_040_888_963_407);
+        (x >> 56) as u8
+    }).collect()
+}
+
+/// New files made from `basis` by typical changes, with their names.
+fn bench_mutations(basis: &[u8]) -> Vec<(&'static str, Vec<u8>)> {
+    let len = basis.len();
+    // Scattered small changes, in place and shifting what follows.
+    let mut edited = basis.to_vec();
+    for pos in (0..len).step_by(100_000) {
+        edited[pos] ^= 0xff;
+    }
+    let mut inserted = Vec::with_capacity(len + len / 1000);
+    for piece in basis.chunks(100_000) {
+        inserted.extend_from_slice(piece);
+        inserted.extend_from_slice(b"an insertion");
+    }
+    let mut appended = basis.to_vec();
+    appended.extend_from_slice(&bench_data(len / 10, 2));
+    let mut moved = basis[len / 2..].to_vec();
+    moved.extend_from_slice(&basis[..len / 2]);
+    vec![
+        ("unchanged", basis.to_vec()),
+        ("edited", edited),
+        ("inserted", inserted),
+        ("appended", appended),
+        ("moved", moved),
+        ("new", bench_data(len, 3)),
+    ]
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+/// True if the file name `n` means stdin or stdout: it's `-` or omitted.
+fn is_stdio(n: Option<&OsStr>) -> bool {
+    n.map_or(true, |n| n == "-")
+}
+
+/// Open a file from a file name for input, treating `-` or no name as stdin.
+fn open_input(n: Option<&OsStr>) -> io::Result<Box<dyn Read>> {
+    match n {
+        Some(n) if n != "-" => match File::open(n) {
             Ok(f) => Ok(Box::new(f)),
             Err(e) => {
-                eprintln!("rdiff: can't create output {:?}: {}", n, e);
+                eprintln!("rdiff: can't open input {:?}: {}", n, e);
                 Err(e)
             }
+        },
+        _ => Ok(Box::new(stdin())),
+    }
+}
+
+/// Write the output file named by argument `arg` with `write`, treating `-` or no name
+/// as stdout, and leaving holes for blocks of zeros in a file if `sparse` is set.
+///
+/// A file is written under a temporary name in the same directory, and moved into
+/// place only once it's complete, so an interrupted or failed command never leaves a
+/// truncated output. Unless `--force` was given, it's not moved over anything that
+/// appeared at its name meanwhile.
+fn write_output<T>(subm: &ArgMatches, arg: &str, sparse: bool,
+                   write: impl FnOnce(&mut dyn Write) -> Result<T>)
+    -> Result<T> {
+    check_output(subm, arg)?;
+    let n = match subm.value_of_os(arg) {
+        Some(n) if n != "-" => n,
+        _ => return write(&mut stdout()),
+    };
+    let (path, io) = (Path::new(n), IoOptions::default());
+    if subm.is_present("force") {
+        return write_atomically(path, &io, sparse, write);
+    }
+    write_new_atomically(path, &io, sparse, write).map_err(|e| {
+        if e.kind() == ErrorKind::AlreadyExists {
+            already_exists(n);
+        }
+        e
+    })
+}
+
+/// Check that the output file named by argument `arg` doesn't already exist, unless
+/// `--force` was given, to fail before doing any work. `write_output` checks again as
+/// the file is moved into place.
+fn check_output(subm: &ArgMatches, arg: &str) -> io::Result<()> {
+    match subm.value_of_os(arg) {
+        Some(n) if n != "-" && !subm.is_present("force")
+            && fs::symlink_metadata(n).is_ok() => {
+            already_exists(n);
+            Err(ErrorKind::AlreadyExists.into())
         }
+        _ => Ok(()),
     }
 }
+
+fn already_exists(n: &OsStr) {
+    eprintln!("rdiff: output {:?} already exists; use --force to overwrite it", n);
+}
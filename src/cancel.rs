@@ -0,0 +1,55 @@
+// rdiff(rust) -- library for network deltas
+// Copyright 2018 Martin Pool.
+
+//! Cooperative cancellation of long-running operations.
+//!
+//! A `CancelToken` is shared between the thread doing the work and any others that might
+//! want to stop it. The `_cancellable` variants of the signature, delta and patch
+//! functions check it before each read of their input, so they stop within one block or
+//! buffer of `cancel()` being called, and return `Error::Cancelled`.
+
+use std::io::{self, Read};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+
+use super::error::Error;
+
+/// A flag that can be set from any thread to ask an operation to stop.
+#[derive(Debug, Clone, Default)]
+pub struct CancelToken(Arc<AtomicBool>);
+
+impl CancelToken {
+    pub fn new() -> CancelToken {
+        CancelToken::default()
+    }
+
+    /// Ask operations using this token, or any clone of it, to stop.
+    pub fn cancel(&self) {
+        self.0.store(true, Ordering::Relaxed);
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.0.load(Ordering::Relaxed)
+    }
+
+    /// Wrap `inner` so that reads fail once this token is cancelled.
+    pub(crate) fn reader<R: Read>(&self, inner: R) -> CancelRead<'_, R> {
+        CancelRead { token: self, inner }
+    }
+}
+
+pub(crate) struct CancelRead<'t, R> {
+    token: &'t CancelToken,
+    inner: R,
+}
+
+impl<'t, R: Read> Read for CancelRead<'t, R> {
+    /// Fails with `Error::Cancelled` wrapped in an `io::Error`, which converts back to the
+    /// original `Error` as it's returned.
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.token.is_cancelled() {
+            return Err(Error::Cancelled.into());
+        }
+        self.inner.read(buf)
+    }
+}
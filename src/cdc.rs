@@ -0,0 +1,164 @@
+// rdiff(rust) -- library for network deltas
+// Copyright 2018 Martin Pool.
+
+//! Content-defined chunking, to divide a file at boundaries chosen by its contents.
+//!
+//! This is the Gear hash as used in FastCDC: a 64-bit hash is shifted left and has a
+//! random value for each byte added to it, so its top bits depend on only the last few
+//! dozen bytes. A chunk ends where the top bits are all zero. Since the boundaries depend
+//! only on nearby data, an insertion or deletion moves just the boundaries around it,
+//! and the chunks after them are the same as before.
+//!
+//! As in FastCDC, chunks are at least a quarter and at most four times the average
+//! length, and boundaries are harder to find before the average length and easier after
+//! it, so that chunk lengths cluster around the average.
+
+use core::cmp::min;
+
+use super::signature::MAX_BLOCK_LEN;
+
+/// The random value added to the hash for each byte.
+const GEAR: [u64; 256] = gear_table();
+
+/// Make the Gear table from the splitmix64 generator, so that every build, and every
+/// implementation of this format, chunks files the same way.
+const fn gear_table() -> [u64; 256] {
+    let mut table = [0u64; 256];
+    let mut state: u64 = 0;
+    let mut i = 0;
+    while i < 256 {
+        state = state.wrapping_add(0x9e37_79b9_7f4a_7c15);
+        let mut z = state;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        table[i] = z ^ (z >> 31);
+        i += 1;
+    }
+    table
+}
+
+/// Shortest average chunk length allowed.
+pub(crate) const MIN_AVG_LEN: u32 = 64;
+
+/// Longest average chunk length allowed, so that the longest chunks are no longer than
+/// `MAX_BLOCK_LEN`.
+pub(crate) const MAX_AVG_LEN: u32 = MAX_BLOCK_LEN / 4;
+
+/// Finds chunk boundaries for a given average chunk length.
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct Chunker {
+    min_len: usize,
+    avg_len: usize,
+    max_len: usize,
+    /// Mask of the hash bits that must be zero at a boundary before `avg_len`.
+    mask_hard: u64,
+    /// Mask used after `avg_len`, with fewer bits.
+    mask_easy: u64,
+}
+
+impl Chunker {
+    /// Make a chunker for chunks of `avg_len` bytes on average, which must be between
+    /// `MIN_AVG_LEN` and `MAX_AVG_LEN`.
+    pub(crate) fn new(avg_len: u32) -> Chunker {
+        debug_assert!((MIN_AVG_LEN..=MAX_AVG_LEN).contains(&avg_len));
+        let bits = 31 - avg_len.leading_zeros();
+        Chunker {
+            min_len: (avg_len / 4) as usize,
+            avg_len: avg_len as usize,
+            max_len: avg_len as usize * 4,
+            mask_hard: !0u64 << (64 - (bits + 1)),
+            mask_easy: !0u64 << (64 - (bits - 1)),
+        }
+    }
+
+    /// Length of the longest chunk.
+    #[cfg(feature = "std")]
+    pub(crate) fn max_len(&self) -> usize {
+        self.max_len
+    }
+
+    /// Find the length of the chunk at the start of `data`.
+    ///
+    /// If `eof` is false, more data may follow `data`, and `None` is returned if it's
+    /// needed to find the end of the chunk. If `eof` is true, the rest of the file is in
+    /// `data`, and it ends the last chunk. The result is zero only if `data` is empty.
+    pub(crate) fn next_chunk(&self, data: &[u8], eof: bool) -> Option<usize> {
+        let end = min(data.len(), self.max_len);
+        let normal = min(end, self.avg_len);
+        let mut h = 0u64;
+        let mut i = self.min_len;
+        while i < normal {
+            h = (h << 1).wrapping_add(GEAR[data[i] as usize]);
+            if h & self.mask_hard == 0 {
+                return Some(i + 1);
+            }
+            i += 1;
+        }
+        while i < end {
+            h = (h << 1).wrapping_add(GEAR[data[i] as usize]);
+            if h & self.mask_easy == 0 {
+                return Some(i + 1);
+            }
+            i += 1;
+        }
+        if end == self.max_len || eof { Some(end) } else { None }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use alloc::vec::Vec;
+
+    use super::*;
+    use super::super::test_util::random_seeded;
+
+    fn chunk_lens(chunker: &Chunker, mut data: &[u8]) -> Vec<usize> {
+        let mut lens = Vec::new();
+        while !data.is_empty() {
+            let l = chunker.next_chunk(data, true).unwrap();
+            lens.push(l);
+            data = &data[l..];
+        }
+        lens
+    }
+
+    #[test]
+    pub fn chunk_lengths() {
+        let chunker = Chunker::new(1024);
+        let data = random_seeded(1 << 20, 1);
+        let lens = chunk_lens(&chunker, &data);
+        assert_eq!(lens.iter().sum::<usize>(), data.len());
+        assert!(lens[..lens.len() - 1].iter().all(|&l| (256..=4096).contains(&l)), "{:?}", lens);
+        let avg = data.len() / lens.len();
+        assert!((700..1500).contains(&avg), "{}", avg);
+    }
+
+    /// Boundaries after an insertion are the same as before it.
+    #[test]
+    pub fn boundaries_resynchronize() {
+        let chunker = Chunker::new(256);
+        let data = random_seeded(100_000, 2);
+        let mut edited = random_seeded(77, 3);
+        edited.extend_from_slice(&data);
+        let ends = |lens: Vec<usize>| -> Vec<usize> {
+            lens.iter().scan(0, |end, l| { *end += l; Some(*end) }).collect()
+        };
+        let old_ends = ends(chunk_lens(&chunker, &data));
+        let new_ends: Vec<usize> = ends(chunk_lens(&chunker, &edited)).iter()
+            .filter(|&&e| e > 77).map(|e| e - 77).collect();
+        let shared = new_ends.iter().filter(|e| old_ends.binary_search(e).is_ok()).count();
+        assert!(shared + 2 >= old_ends.len(), "{} of {}", shared, old_ends.len());
+    }
+
+    /// Without the end of the file, a chunk isn't ended early.
+    #[test]
+    pub fn needs_more_data() {
+        let chunker = Chunker::new(1024);
+        let data = random_seeded(20_000, 4);
+        let first = chunker.next_chunk(&data, false).unwrap();
+        assert_eq!(chunker.next_chunk(&data[..first], true), Some(first));
+        assert_eq!(chunker.next_chunk(&data[..first - 1], false), None);
+        assert_eq!(chunker.next_chunk(&data[..100], true), Some(100));
+        assert_eq!(chunker.next_chunk(&[], true), Some(0));
+    }
+}
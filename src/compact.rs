@@ -0,0 +1,265 @@
+// rdiff(rust) -- library for network deltas
+// Copyright 2018 Martin Pool.
+
+//! A compact binary form of signatures, for keeping signatures that have already been
+//! calculated or read in a database or cache.
+//!
+//! A signature file interleaves the sums of each block, so reading one means decoding
+//! every block in turn. The compact form instead holds all the weak sums, then all the
+//! block lengths if the blocks vary in length, then all the strong sums, each packed
+//! together, so `Signature::from_bytes` copies each of them in one go, and
+//! `SignatureView` reads the blocks straight from the bytes without copying them at all.
+//!
+//! It starts with `magic::SIGNATURE_COMPACT_MAGIC`, followed by the magic number of the
+//! signature format, the block length, the strong sum length, the seed, 1 if the blocks
+//! vary in length or otherwise 0, and the number of blocks as a u64. Everything after the
+//! first magic number is little-endian. This is an extension of this library, not
+//! understood by librsync, and isn't read as a signature file.
+//!
+//! The signature still has to be indexed, by `index::SignatureIndex`, for each delta.
+
+use alloc::borrow::ToOwned;
+use alloc::format;
+use alloc::vec::Vec;
+use core::convert::TryInto;
+
+use super::error::{Error, Result};
+use super::magic::{SignatureFormat, SIGNATURE_COMPACT_MAGIC};
+use super::signature::{check_header, max_block_len, Signature, SignatureOptions};
+
+/// Length of the header, before the weak sums.
+const HEADER_LEN: usize = 32;
+
+/// A signature in the compact form, read in place from the bytes holding it.
+///
+/// This gives the same blocks as the `Signature` that `Signature::from_bytes` would
+/// return, without copying them, which is cheaper for looking at a few blocks, or at
+/// just the header. To generate a delta, copy it into a `Signature` with
+/// `to_signature`.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct SignatureView<'a> {
+    options: SignatureOptions,
+    variable: bool,
+    weak_sums: &'a [u8],
+    block_lens: &'a [u8],
+    strong_sums: &'a [u8],
+}
+
+impl<'a> SignatureView<'a> {
+    /// Check that `bytes` hold a signature in the compact form, and read its header.
+    ///
+    /// `Error::BadMagic` is returned if they don't start with the compact form's magic
+    /// number, and `Error::CorruptSignature` if they're not the length the header says or
+    /// anything in them is inconsistent, as for a signature file.
+    pub fn from_bytes(bytes: &'a [u8]) -> Result<SignatureView<'a>> {
+        if bytes.len() < 4 {
+            return Err(too_short(bytes.len()));
+        }
+        let magic = u32::from_be_bytes(bytes[..4].try_into().unwrap());
+        if magic != SIGNATURE_COMPACT_MAGIC {
+            return Err(Error::BadMagic(magic));
+        }
+        if bytes.len() < HEADER_LEN {
+            return Err(too_short(bytes.len()));
+        }
+        let field = |i: usize| u32::from_le_bytes(bytes[4 * i..4 * (i + 1)].try_into().unwrap());
+        let format = SignatureFormat::check_magic(field(1))?;
+        let (block_len, strong_len, seed) = (field(2), field(3), field(4));
+        check_header(format, block_len, strong_len)?;
+        let variable = match field(5) {
+            0 if format.is_content_defined() => {
+                return Err(Error::CorruptSignature(
+                    "content-defined chunks have no lengths".to_owned()));
+            }
+            0 => false,
+            1 => true,
+            flag => return Err(Error::CorruptSignature(format!("unknown flags {:#x}", flag))),
+        };
+        let count = u64::from_le_bytes(bytes[24..32].try_into().unwrap());
+        let entry_len = 4 + if variable { 4 } else { 0 } + u64::from(strong_len);
+        let body = &bytes[HEADER_LEN..];
+        if count.checked_mul(entry_len) != Some(body.len() as u64) {
+            return Err(Error::CorruptSignature(format!(
+                "{} bytes of blocks don't match {} blocks", body.len(), count)));
+        }
+        let count = count as usize;
+        let (weak_sums, rest) = body.split_at(count * 4);
+        let (block_lens, strong_sums) = rest.split_at(if variable { count * 4 } else { 0 });
+        let max = max_block_len(format, block_len);
+        if let Some(len) = block_lens.chunks_exact(4).map(le_u32)
+            .find(|&l| l == 0 || u64::from(l) > max) {
+            return Err(Error::CorruptSignature(format!("a block has length {}", len)));
+        }
+        Ok(SignatureView {
+            options: SignatureOptions { magic: format, block_len, strong_len, seed },
+            variable,
+            weak_sums,
+            block_lens,
+            strong_sums,
+        })
+    }
+
+    /// Format of the signature, determining its weak and strong hashes.
+    pub fn format(&self) -> SignatureFormat {
+        self.options.magic
+    }
+
+    /// Length of the basis blocks, as given by `Signature::block_len`.
+    pub fn block_len(&self) -> u32 {
+        self.options.block_len
+    }
+
+    /// Length of each strong sum.
+    pub fn strong_len(&self) -> u32 {
+        self.options.strong_len
+    }
+
+    /// The options the signature was made with.
+    pub fn options(&self) -> SignatureOptions {
+        self.options
+    }
+
+    /// Number of blocks in the basis.
+    pub fn block_count(&self) -> usize {
+        self.weak_sums.len() / 4
+    }
+
+    /// Length of block `i`, if the blocks vary in length.
+    pub fn chunk_len(&self, i: usize) -> Option<u32> {
+        if self.variable { Some(le_u32(&self.block_lens[i * 4..(i + 1) * 4])) } else { None }
+    }
+
+    /// Return the weak sum for block `i`.
+    pub fn weak_sum(&self, i: usize) -> u32 {
+        le_u32(&self.weak_sums[i * 4..(i + 1) * 4])
+    }
+
+    /// Return the strong sum for block `i`.
+    pub fn strong_sum(&self, i: usize) -> &'a [u8] {
+        let l = self.options.strong_len as usize;
+        &self.strong_sums[i * l..(i + 1) * l]
+    }
+
+    /// Copy the blocks into a `Signature`.
+    pub fn to_signature(&self) -> Signature {
+        Signature {
+            magic: self.options.magic,
+            block_len: self.options.block_len,
+            strong_len: self.options.strong_len,
+            seed: self.options.seed,
+            variable: self.variable,
+            block_lens: self.block_lens.chunks_exact(4).map(le_u32).collect(),
+            weak_sums: self.weak_sums.chunks_exact(4).map(le_u32).collect(),
+            strong_sums: self.strong_sums.to_vec(),
+        }
+    }
+}
+
+impl Signature {
+    /// Return the signature in the compact form described in `compact`, to be read back
+    /// by `from_bytes` or `SignatureView::from_bytes`.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let n = self.block_count() as u64;
+        let mut buf = Vec::with_capacity(
+            HEADER_LEN + 4 * (self.weak_sums.len() + self.block_lens.len())
+                + self.strong_sums.len());
+        buf.extend_from_slice(&SIGNATURE_COMPACT_MAGIC.to_be_bytes());
+        for field in &[self.magic as u32, self.block_len, self.strong_len, self.seed,
+                       self.variable as u32] {
+            buf.extend_from_slice(&field.to_le_bytes());
+        }
+        buf.extend_from_slice(&n.to_le_bytes());
+        for weak in &self.weak_sums {
+            buf.extend_from_slice(&weak.to_le_bytes());
+        }
+        for len in &self.block_lens {
+            buf.extend_from_slice(&len.to_le_bytes());
+        }
+        buf.extend_from_slice(&self.strong_sums);
+        buf
+    }
+
+    /// Read a signature in the compact form, returned by `to_bytes`.
+    ///
+    /// This gives the same errors as `SignatureView::from_bytes`.
+    pub fn from_bytes(bytes: &[u8]) -> Result<Signature> {
+        Ok(SignatureView::from_bytes(bytes)?.to_signature())
+    }
+}
+
+fn le_u32(b: &[u8]) -> u32 {
+    u32::from_le_bytes(b.try_into().unwrap())
+}
+
+fn too_short(len: usize) -> Error {
+    Error::CorruptSignature(format!("{} bytes are too short for the header", len))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use super::super::mksum::{calculate_signature, calculate_signature_variable};
+    use super::super::test_util::pattern;
+
+    fn signatures() -> Vec<Signature> {
+        let basis = pattern(5000);
+        let options = SignatureOptions { block_len: 1000, .. SignatureOptions::default() };
+        let cdc = SignatureOptions {
+            magic: SignatureFormat::CdcBlake2Sig,
+            block_len: 256,
+            seed: 7,
+            .. SignatureOptions::default()
+        };
+        vec![
+            Signature::new(&options),
+            calculate_signature(&mut basis.as_slice(), &options).unwrap(),
+            calculate_signature(&mut basis.as_slice(), &options.with_strong_len(5)).unwrap(),
+            calculate_signature(&mut basis.as_slice(), &cdc).unwrap(),
+            calculate_signature_variable(&mut basis.as_slice(), &options, &[10, 20]).unwrap(),
+        ]
+    }
+
+    #[test]
+    pub fn round_trip() {
+        for sig in signatures() {
+            let bytes = sig.to_bytes();
+            assert_eq!(&bytes[..4], b"rs\x84S");
+            assert_eq!(Signature::from_bytes(&bytes).unwrap(), sig);
+            let view = SignatureView::from_bytes(&bytes).unwrap();
+            assert_eq!(view.options(), sig.options());
+            assert_eq!(view.block_count(), sig.block_count());
+            for (i, weak, strong) in sig.blocks() {
+                assert_eq!(view.weak_sum(i), weak);
+                assert_eq!(view.strong_sum(i), strong);
+                assert_eq!(view.chunk_len(i), sig.block_lens().map(|l| l[i]));
+            }
+        }
+    }
+
+    #[test]
+    pub fn rejects_bad_bytes() {
+        let bytes = signatures()[3].to_bytes();
+        assert!(matches!(Signature::from_bytes(b"rs\x81S"), Err(Error::BadMagic(0x72738153))));
+        for len in &[0, 3, 31, bytes.len() - 1] {
+            let err = Signature::from_bytes(&bytes[..*len]).unwrap_err();
+            assert!(matches!(err, Error::CorruptSignature(_)), "{:?}", err);
+        }
+        let mut longer = bytes.clone();
+        longer.push(0);
+        let mut huge_count = bytes.clone();
+        huge_count[24..32].copy_from_slice(&u64::MAX.to_le_bytes());
+        let mut zero_len = bytes.clone();
+        let lens = HEADER_LEN + 4 * SignatureView::from_bytes(&bytes).unwrap().block_count();
+        zero_len[lens..lens + 4].copy_from_slice(&[0; 4]);
+        let mut flags = bytes.clone();
+        flags[20] = 2;
+        let mut no_lens = bytes.clone();
+        no_lens[20] = 0;
+        let mut strong_len = bytes;
+        strong_len[12] = 33;
+        for bad in &[longer, huge_count, zero_len, flags, no_lens, strong_len] {
+            let err = Signature::from_bytes(bad).unwrap_err();
+            assert!(matches!(err, Error::CorruptSignature(_)), "{:?}", err);
+        }
+    }
+}
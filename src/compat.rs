@@ -0,0 +1,50 @@
+// rdiff(rust) -- library for network deltas
+// Copyright 2018 Martin Pool.
+
+//! The output trait used by the parts of the library that build without `std`.
+//!
+//! With the `std` feature this is just `std::io::Write`. Without it, `Write` is a
+//! minimal stand-in, implemented for `Vec<u8>`, that applications can implement for
+//! their own outputs.
+
+#[cfg(feature = "std")]
+pub use std::io::Write;
+
+#[cfg(not(feature = "std"))]
+use alloc::vec::Vec;
+
+#[cfg(not(feature = "std"))]
+use super::error::Result;
+
+/// Somewhere to write bytes, when `std::io::Write` isn't available.
+#[cfg(not(feature = "std"))]
+pub trait Write {
+    /// Write all of `buf`, or fail.
+    fn write_all(&mut self, buf: &[u8]) -> Result<()>;
+
+    /// Push out any buffered data.
+    fn flush(&mut self) -> Result<()>;
+}
+
+#[cfg(not(feature = "std"))]
+impl Write for Vec<u8> {
+    fn write_all(&mut self, buf: &[u8]) -> Result<()> {
+        self.extend_from_slice(buf);
+        Ok(())
+    }
+
+    fn flush(&mut self) -> Result<()> {
+        Ok(())
+    }
+}
+
+#[cfg(not(feature = "std"))]
+impl<W: Write + ?Sized> Write for &mut W {
+    fn write_all(&mut self, buf: &[u8]) -> Result<()> {
+        (**self).write_all(buf)
+    }
+
+    fn flush(&mut self) -> Result<()> {
+        (**self).flush()
+    }
+}
@@ -0,0 +1,250 @@
+// rdiff(rust) -- library for network deltas
+// Copyright 2018 Martin Pool.
+
+//! Compose two deltas into one, without the files they apply to.
+//!
+//! A delta from A to B describes each part of B as either a COPY from A or literal data.
+//! A delta from B to C can then be rewritten in terms of A, by replacing each COPY from
+//! B with the parts of the first delta that made that range of B. Applying the result
+//! to A gives C, as applying the two deltas in turn would, but without writing out B.
+
+use std::io::{BufReader, BufWriter, Read, Write};
+
+use super::delta::{check_copy, BasisId, DeltaCommand, DeltaReader, DeltaWriter};
+use super::error::Result;
+use super::magic::DeltaFormat;
+use super::stats::{Statistics, Timer};
+
+/// Where one part of the intermediate file comes from.
+#[derive(Debug, Clone, Copy)]
+enum Source {
+    /// A COPY from this offset in the first basis.
+    Copy(u64),
+
+    /// Literal data from this offset in `Intermediate::literals`.
+    Literal(usize),
+}
+
+/// The intermediate file, as described by the first delta.
+struct Intermediate {
+    /// The offset in the intermediate file where each part starts, and its source, in
+    /// order. Each part continues up to the start of the next.
+    parts: Vec<(u64, Source)>,
+
+    /// All the literal data from the first delta.
+    literals: Vec<u8>,
+
+    /// Length of the intermediate file.
+    len: u64,
+
+    /// The identity of the first basis, if the first delta has it.
+    basis_id: Option<BasisId>,
+}
+
+impl Intermediate {
+    fn read<D: Read + ?Sized>(delta: &mut D) -> Result<(Intermediate, Statistics)> {
+        let mut reader = DeltaReader::new(BufReader::new(delta))?;
+        let mut b = Intermediate {
+            parts: Vec::new(),
+            literals: Vec::new(),
+            len: 0,
+            basis_id: reader.basis_id().copied(),
+        };
+        for command in reader.by_ref() {
+            match command? {
+                DeltaCommand::Copy { offset, len } if len > 0 => {
+                    b.parts.push((b.len, Source::Copy(offset)));
+                    b.len += len;
+                }
+                DeltaCommand::Literal(data) if !data.is_empty() => {
+                    b.parts.push((b.len, Source::Literal(b.literals.len())));
+                    b.literals.extend_from_slice(&data);
+                    b.len += data.len() as u64;
+                }
+                _ => (),
+            }
+        }
+        Ok((b, reader.statistics().clone()))
+    }
+
+    /// Write commands producing `len` bytes from `offset` in the intermediate file, which
+    /// must lie within it.
+    fn copy_to<W: Write>(&self, mut offset: u64, len: u64, out: &mut DeltaWriter<W>)
+        -> Result<()> {
+        if len == 0 || self.parts.is_empty() {
+            return Ok(());
+        }
+        let end = offset + len;
+        let mut i = self.parts.partition_point(|&(start, _)| start <= offset) - 1;
+        while offset < end {
+            let (start, source) = self.parts[i];
+            let part_end = self.parts.get(i + 1).map_or(self.len, |&(s, _)| s);
+            let n = part_end.min(end) - offset;
+            let skip = offset - start;
+            match source {
+                Source::Copy(a) => out.copy(a + skip, n)?,
+                Source::Literal(l) => {
+                    let l = l + skip as usize;
+                    out.literal(&self.literals[l..(l + n as usize)])?
+                }
+            }
+            offset += n;
+            i += 1;
+        }
+        Ok(())
+    }
+}
+
+/// Compose a delta from A to B with one from B to C, writing a delta from A to C.
+///
+/// Neither A, B nor C are needed. The first delta is read into memory, including its
+/// literal data; the second is streamed through. Adjacent COPYs in the result are
+/// merged, so a long chain of deltas can be composed one at a time into a single delta
+/// that's no bigger than the changes it carries.
+///
+/// If `delta_bc` is a `DeltaFormat::ChecksummedDelta`, so is the result, with the same
+/// checksum of C. If `delta_bc` is a `DeltaFormat::BasisCheckedDelta`, the result
+/// identifies A if `delta_ab` does, and is otherwise a plain delta.
+///
+/// `Error::CorruptDelta` is returned if `delta_bc` copies from beyond the end of B as
+/// described by `delta_ab`. The statistics count the commands written, and the bytes
+/// read from both deltas.
+pub fn compose<A: Read + ?Sized, B: Read + ?Sized, W: Write + ?Sized>(
+    delta_ab: &mut A, delta_bc: &mut B, delta_ac: &mut W) -> Result<Statistics> {
+    let start = Timer::start();
+    let (b, ab_stats) = Intermediate::read(delta_ab)?;
+    let mut reader = DeltaReader::new(BufReader::new(delta_bc))?;
+    let w = BufWriter::new(delta_ac);
+    let mut out = match (reader.format(), &b.basis_id) {
+        (DeltaFormat::BasisCheckedDelta, Some(id)) => DeltaWriter::with_basis_id(w, id)?,
+        (DeltaFormat::BasisCheckedDelta, None) => DeltaWriter::new(w)?,
+        (format, _) => DeltaWriter::with_format(w, format)?,
+    };
+    for command in reader.by_ref() {
+        match command? {
+            DeltaCommand::Copy { offset, len } => {
+                check_copy(offset, len, b.len)?;
+                b.copy_to(offset, len, &mut out)?;
+            }
+            DeltaCommand::Literal(data) => out.literal(&data)?,
+            DeltaCommand::End => (),
+        }
+    }
+    if let Some(checksum) = reader.checksum() {
+        out.set_checksum(*checksum);
+    }
+    out.write_command(&DeltaCommand::End)?;
+    Ok(Statistics {
+        op: "compose",
+        in_bytes: ab_stats.in_bytes + reader.statistics().in_bytes,
+        elapsed: start.elapsed(),
+        .. out.statistics().clone()
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use super::super::error::Error;
+    use super::super::memory;
+    use super::super::mkdelta::{generate_delta_with_options, DeltaOptions};
+    use super::super::signature::Signature;
+    use super::super::test_util::pattern_seeded;
+
+    fn delta(old: &[u8], new: &[u8]) -> Vec<u8> {
+        memory::delta_of(&memory::signature_of(old), new).unwrap()
+    }
+
+    fn compose_all(ab: &[u8], bc: &[u8]) -> Result<Vec<u8>> {
+        let mut ac = Vec::new();
+        compose(&mut &ab[..], &mut &bc[..], &mut ac)?;
+        Ok(ac)
+    }
+
+    #[test]
+    pub fn compose_edits() {
+        let a = pattern_seeded(100_000, 0);
+        let mut b = a[..30_000].to_vec();
+        b.extend(pattern_seeded(5000, 3));
+        b.extend_from_slice(&a[40_000..]);
+        let mut c = b[..20_000].to_vec();
+        c.extend_from_slice(&b[60_000..90_000]);
+        c.extend(pattern_seeded(3000, 9));
+        c.extend_from_slice(&b[28_000..36_000]);
+        let ac = compose_all(&delta(&a, &b), &delta(&b, &c)).unwrap();
+        assert_eq!(memory::apply(&a, &ac).unwrap(), c);
+        assert!(ac.len() < 10_000, "{}", ac.len());
+    }
+
+    /// A chain of deltas composes into one no bigger than a delta made directly.
+    #[test]
+    pub fn compose_chain() {
+        let mut files = vec![pattern_seeded(50_000, 0)];
+        for i in 1..6 {
+            let mut next = files[i - 1].clone();
+            let at = i * 7000;
+            next[at..(at + 100)].copy_from_slice(&pattern_seeded(100, i));
+            files.push(next);
+        }
+        let mut composed = delta(&files[0], &files[1]);
+        for i in 2..files.len() {
+            composed = compose_all(&composed, &delta(&files[i - 1], &files[i])).unwrap();
+        }
+        assert_eq!(memory::apply(&files[0], &composed).unwrap(), files[5]);
+        assert!(composed.len() < 5 * 2048 + 200, "{}", composed.len());
+    }
+
+    #[test]
+    pub fn checksum_carried_over() {
+        let a = pattern_seeded(10_000, 0);
+        let b = pattern_seeded(10_000, 1);
+        let mut bc = Vec::new();
+        let sig = Signature::read_from(&mut &memory::signature_of(&b)[..]).unwrap();
+        let options = DeltaOptions { checksum: true, .. DeltaOptions::default() };
+        generate_delta_with_options(&sig, &mut &a[..], &mut bc, &options).unwrap();
+        let ac = compose_all(&delta(&a, &b), &bc).unwrap();
+        let reader = DeltaReader::new(&ac[..]).unwrap();
+        assert_eq!(reader.format(), DeltaFormat::ChecksummedDelta);
+        assert_eq!(memory::apply(&a, &ac).unwrap(), a);
+    }
+
+    #[test]
+    pub fn basis_id_of_first_basis() {
+        let a = pattern_seeded(10_000, 0);
+        let b = pattern_seeded(10_000, 1);
+        let options = DeltaOptions { basis_id: true, .. DeltaOptions::default() };
+        let sig_a = Signature::read_from(&mut &memory::signature_of(&a)[..]).unwrap();
+        let sig_b = Signature::read_from(&mut &memory::signature_of(&b)[..]).unwrap();
+        let (mut ab, mut bc) = (Vec::new(), Vec::new());
+        generate_delta_with_options(&sig_a, &mut &b[..], &mut ab, &options).unwrap();
+        generate_delta_with_options(&sig_b, &mut &a[..], &mut bc, &options).unwrap();
+        let ac = compose_all(&ab, &bc).unwrap();
+        let reader = DeltaReader::new(&ac[..]).unwrap();
+        assert_eq!(reader.basis_id(), Some(&BasisId::of(&sig_a)));
+        assert_eq!(memory::apply(&a, &ac).unwrap(), a);
+
+        let ac = compose_all(&delta(&a, &b), &bc).unwrap();
+        assert_eq!(DeltaReader::new(&ac[..]).unwrap().format(), DeltaFormat::Delta);
+    }
+
+    #[test]
+    pub fn copy_beyond_intermediate() {
+        let a = pattern_seeded(10_000, 0);
+        let ab = delta(&a, &a[..5000]);
+        let mut w = DeltaWriter::new(Vec::new()).unwrap();
+        w.copy(4000, 2000).unwrap();
+        let bc = w.finish().unwrap();
+        let err = compose_all(&ab, &bc).unwrap_err();
+        assert!(matches!(err, Error::CorruptDelta(_)), "{:?}", err);
+    }
+
+    /// An empty COPY, even from an empty intermediate file, writes nothing.
+    #[test]
+    pub fn empty_copy() {
+        let ac = compose_all(b"rs\x026\x00", b"rs\x026\x45\x00\x00\x00").unwrap();
+        assert_eq!(ac, b"rs\x026\x00");
+        let ab = delta(&pattern_seeded(1000, 0), &pattern_seeded(1000, 0));
+        let ac = compose_all(&ab, b"rs\x026\x45\x10\x00\x00").unwrap();
+        assert_eq!(ac, b"rs\x026\x00");
+    }
+}
@@ -0,0 +1,192 @@
+// rdiff(rust) -- library for network deltas
+// Copyright 2018 Martin Pool.
+
+//! Whole signature and delta files compressed with zstd.
+//!
+//! Signatures are mostly hashes, but their weak sums and the header repeat enough that
+//! compression is still worthwhile on a slow link. The literals in a delta are often
+//! much more compressible; unlike `DeltaFormat::CompressedDelta`, which compresses only
+//! those, a compressed delta file is any delta format inside zstd.
+//!
+//! A compressed file is a zstd stream wrapping the usual format, and is recognized by
+//! the magic number of a zstd frame, so readers take either.
+//!
+//! gzip files are recognized, but not read: `Error::UnsupportedFormat` is returned.
+//! Compressed files are only read and written with the `zstd` feature.
+
+#[cfg(not(feature = "zstd"))]
+use std::convert::Infallible;
+use std::io;
+use std::io::{Read, Write};
+#[cfg(not(feature = "zstd"))]
+use std::marker::PhantomData;
+
+use super::error::{Error, Result};
+
+/// Magic number at the start of a zstd frame, read big-endian like the others.
+pub const ZSTD_MAGIC: u32 = 0x28b5_2ffd;
+
+/// The first two bytes of a gzip file, read big-endian.
+pub const GZIP_MAGIC: u16 = 0x1f8b;
+
+/// Default zstd compression level.
+#[cfg(feature = "zstd")]
+pub const DEFAULT_LEVEL: i32 = 3;
+
+/// Return a reader of the data in `input`, decompressing it if it's compressed.
+///
+/// Anything that doesn't start with a zstd frame is passed through unchanged, including
+/// inputs too short to have a magic number. `Error::UnsupportedFormat` is returned for
+/// a gzip file, or for zstd without the `zstd` feature.
+pub fn decompressed<'a, R: Read + 'a>(mut input: R) -> Result<Box<dyn Read + 'a>> {
+    let mut start = [0; 4];
+    let mut l = 0;
+    while l < start.len() {
+        match input.read(&mut start[l..]) {
+            Ok(0) => break,
+            Ok(n) => l += n,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => (),
+            Err(e) => return Err(e.into()),
+        }
+    }
+    let magic = u32::from_be_bytes(start);
+    let input = io::Cursor::new(start).take(l as u64).chain(input);
+    if l == start.len() && magic == ZSTD_MAGIC {
+        #[cfg(feature = "zstd")]
+        return Ok(Box::new(zstd::stream::read::Decoder::new(input)?));
+        #[cfg(not(feature = "zstd"))]
+        return Err(Error::UnsupportedFormat(magic));
+    }
+    if l >= 2 && (magic >> 16) as u16 == GZIP_MAGIC {
+        return Err(Error::UnsupportedFormat(magic));
+    }
+    Ok(Box::new(input))
+}
+
+/// Compresses everything written to it with zstd, and writes it to another writer.
+///
+/// `finish` must be called to end the stream. Without the `zstd` feature, `new` gives
+/// `Error::UnsupportedFormat`.
+pub struct Compressor<W: Write> {
+    #[cfg(feature = "zstd")]
+    encoder: zstd::stream::write::Encoder<'static, Counter<W>>,
+    #[cfg(not(feature = "zstd"))]
+    never: (Infallible, PhantomData<W>),
+}
+
+impl<W: Write> Compressor<W> {
+    /// Compress to `inner` at `level`: 1 is fastest, and 19 smallest; 0 means
+    /// `DEFAULT_LEVEL`.
+    #[cfg(feature = "zstd")]
+    pub fn new(inner: W, level: i32) -> Result<Compressor<W>> {
+        let level = if level == 0 { DEFAULT_LEVEL } else { level };
+        Ok(Compressor { encoder: zstd::stream::write::Encoder::new(Counter(inner, 0), level)? })
+    }
+
+    #[cfg(not(feature = "zstd"))]
+    pub fn new(_inner: W, _level: i32) -> Result<Compressor<W>> {
+        Err(Error::UnsupportedFormat(ZSTD_MAGIC))
+    }
+
+    /// End the stream, and return the inner writer and the number of compressed bytes
+    /// written to it.
+    #[cfg(feature = "zstd")]
+    pub fn finish(self) -> Result<(W, u64)> {
+        let mut counter = self.encoder.finish()?;
+        counter.flush()?;
+        Ok((counter.0, counter.1))
+    }
+
+    #[cfg(not(feature = "zstd"))]
+    pub fn finish(self) -> Result<(W, u64)> {
+        match self.never.0 {}
+    }
+}
+
+impl<W: Write> Write for Compressor<W> {
+    #[cfg(feature = "zstd")]
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.encoder.write(buf)
+    }
+
+    #[cfg(feature = "zstd")]
+    fn flush(&mut self) -> io::Result<()> {
+        self.encoder.flush()
+    }
+
+    #[cfg(not(feature = "zstd"))]
+    fn write(&mut self, _buf: &[u8]) -> io::Result<usize> {
+        match self.never.0 {}
+    }
+
+    #[cfg(not(feature = "zstd"))]
+    fn flush(&mut self) -> io::Result<()> {
+        match self.never.0 {}
+    }
+}
+
+/// Counts the bytes written through it.
+#[cfg(feature = "zstd")]
+struct Counter<W>(W, u64);
+
+#[cfg(feature = "zstd")]
+impl<W: Write> Write for Counter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let l = self.0.write(buf)?;
+        self.1 += l as u64;
+        Ok(l)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.0.flush()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    pub fn passes_through_other_data() {
+        for data in &[&b""[..], b"a", b"rs\x016", b"rs\x016 and more"] {
+            let mut out = Vec::new();
+            decompressed(*data).unwrap().read_to_end(&mut out).unwrap();
+            assert_eq!(&out[..], *data);
+        }
+    }
+
+    #[test]
+    pub fn gzip_unsupported() {
+        match decompressed(&b"\x1f\x8b\x08\x00"[..]) {
+            Err(Error::UnsupportedFormat(0x1f8b_0800)) => (),
+            r => panic!("unexpected {:?}", r.map(|_| ())),
+        }
+    }
+
+    #[cfg(feature = "zstd")]
+    #[test]
+    pub fn round_trip() {
+        let data = b"some data to compress, some data to compress".repeat(100);
+        let mut c = Compressor::new(Vec::new(), 0).unwrap();
+        c.write_all(&data).unwrap();
+        let (compressed, len) = c.finish().unwrap();
+        assert_eq!(len, compressed.len() as u64);
+        assert!(compressed.len() < 200);
+        let mut out = Vec::new();
+        decompressed(compressed.as_slice()).unwrap().read_to_end(&mut out).unwrap();
+        assert_eq!(out, data);
+    }
+
+    #[cfg(not(feature = "zstd"))]
+    #[test]
+    pub fn zstd_unsupported() {
+        match decompressed(&b"\x28\xb5\x2f\xfd\x00"[..]) {
+            Err(Error::UnsupportedFormat(ZSTD_MAGIC)) => (),
+            r => panic!("unexpected {:?}", r.map(|_| ())),
+        }
+        match Compressor::new(Vec::new(), 0) {
+            Err(Error::UnsupportedFormat(ZSTD_MAGIC)) => (),
+            r => panic!("unexpected {:?}", r.map(|_| ())),
+        }
+    }
+}
@@ -0,0 +1,1313 @@
+// rdiff(rust) -- library for network deltas
+// Copyright 2018 Martin Pool.
+
+//! The delta format: a stream of commands that rebuild a new file from a basis.
+//!
+//! A delta starts with the `rs\x026` magic, followed by commands each consisting of an
+//! opcode byte and then big-endian parameters of 1, 2, 4 or 8 bytes, as determined by
+//! the opcode. LITERAL commands are followed by their data. The last command is END.
+//!
+//! In a `DeltaFormat::CompressedDelta`, a LITERAL may instead be a ZLITERAL, whose
+//! parameter is the length of a zstd frame that follows it and decompresses to the
+//! literal data. The writer only uses one where it's shorter.
+//!
+//! A `DeltaFormat::BasisCheckedDelta` has a `BasisId` between the magic and the first
+//! command.
+//!
+//! In a `DeltaFormat::MultiBasisDelta`, a BASIS command, whose parameter is the index of
+//! a basis, selects the basis that following COPY commands refer to.
+
+#[cfg(feature = "std")]
+use std::io;
+#[cfg(feature = "std")]
+use std::io::{ErrorKind, Read};
+
+use alloc::borrow::ToOwned;
+use alloc::format;
+use alloc::vec::Vec;
+use byteorder::{BigEndian, ByteOrder};
+#[cfg(feature = "std")]
+use byteorder::ReadBytesExt;
+
+use super::compat::Write;
+use super::error::{Error, Result};
+use super::magic::DeltaFormat;
+#[cfg(feature = "std")]
+use super::magic::SignatureFormat;
+#[cfg(feature = "std")]
+use super::signature::check_header;
+use super::signature::{Signature, SignatureOptions};
+use super::stats::Statistics;
+use super::strongsum::{Blake2Hash, StrongHash};
+
+/// Opcodes from librsync's `prototab.h`.
+///
+/// Values between 1 and 64 are literals of that length, with no parameters.
+pub(crate) const OP_END: u8 = 0x00;
+pub(crate) const OP_LITERAL_N1: u8 = 0x41;
+pub(crate) const OP_LITERAL_N8: u8 = 0x44;
+pub(crate) const OP_COPY_N1_N1: u8 = 0x45;
+pub(crate) const OP_COPY_N8_N8: u8 = 0x54;
+
+/// Opcodes of compressed literals, only in a `DeltaFormat::CompressedDelta`.
+#[cfg(feature = "zstd")]
+pub(crate) const OP_ZLITERAL_N1: u8 = 0x55;
+#[cfg(feature = "zstd")]
+pub(crate) const OP_ZLITERAL_N8: u8 = 0x58;
+
+/// Opcodes selecting the basis, only in a `DeltaFormat::MultiBasisDelta`.
+pub(crate) const OP_BASIS_N1: u8 = 0x59;
+#[cfg(feature = "std")]
+pub(crate) const OP_BASIS_N8: u8 = 0x5c;
+
+/// zstd compression level for literals.
+#[cfg(feature = "zstd")]
+const ZSTD_LEVEL: i32 = 3;
+
+/// Longest literal that can be encoded entirely in the opcode.
+const MAX_IMMEDIATE_LITERAL: usize = 64;
+
+/// Length of the whole-file checksum at the end of a `DeltaFormat::ChecksummedDelta`.
+pub const CHECKSUM_LEN: usize = 32;
+
+/// Length of the hash in a `BasisId`.
+pub const BASIS_HASH_LEN: usize = 32;
+
+/// Length of a `BasisId` in a delta header.
+const BASIS_ID_LEN: usize = 12 + BASIS_HASH_LEN;
+
+/// Identifies the basis a delta was made from, in the header of a
+/// `DeltaFormat::BasisCheckedDelta`.
+///
+/// The hash is the BLAKE2b hash of the blocks of the basis's signature, each a big-endian
+/// weak sum followed by the strong sum. It can be recalculated from a basis, given the
+/// signature options, to check that it's the same file.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct BasisId {
+    /// Options of the signature, needed to recalculate it.
+    pub options: SignatureOptions,
+
+    /// Hash of the signature's blocks.
+    pub hash: [u8; BASIS_HASH_LEN],
+}
+
+impl BasisId {
+    /// The identity of the basis that `sig` was made from.
+    pub fn of(sig: &Signature) -> BasisId {
+        let mut hash = Blake2Hash::default();
+        for (_, weak, strong) in sig.blocks() {
+            hash_block(&mut hash, weak, strong);
+        }
+        BasisId {
+            options: sig.options(),
+            hash: finish_basis_hash(&mut hash),
+        }
+    }
+
+    /// Read a `BasisId` from a delta header, checking its signature options.
+    #[cfg(feature = "std")]
+    fn read_from(r: &mut dyn Read) -> Result<BasisId> {
+        let magic = r.read_u32::<BigEndian>()?;
+        let block_len = r.read_u32::<BigEndian>()?;
+        let strong_len = r.read_u32::<BigEndian>()?;
+        let mut hash = [0; BASIS_HASH_LEN];
+        r.read_exact(&mut hash)?;
+        let corrupt = |e: Error| Error::CorruptDelta(format!("bad basis identity: {}", e));
+        let magic = SignatureFormat::check_magic(magic).map_err(corrupt)?;
+        check_header(magic, block_len, strong_len).map_err(corrupt)?;
+        let options = SignatureOptions { magic, block_len, strong_len, seed: 0 };
+        Ok(BasisId { options, hash })
+    }
+
+    fn to_bytes(self) -> [u8; BASIS_ID_LEN] {
+        let mut buf = [0; BASIS_ID_LEN];
+        BigEndian::write_u32(&mut buf[0..], self.options.magic as u32);
+        BigEndian::write_u32(&mut buf[4..], self.options.block_len);
+        BigEndian::write_u32(&mut buf[8..], self.options.strong_len);
+        buf[12..].copy_from_slice(&self.hash);
+        buf
+    }
+}
+
+/// Add one signature block to the hash of a `BasisId`.
+pub(crate) fn hash_block(hash: &mut Blake2Hash, weak: u32, strong: &[u8]) {
+    hash.update(&weak.to_be_bytes());
+    hash.update(strong);
+}
+
+pub(crate) fn finish_basis_hash(hash: &mut Blake2Hash) -> [u8; BASIS_HASH_LEN] {
+    let mut out = [0; BASIS_HASH_LEN];
+    hash.finalize_truncated(&mut out);
+    out
+}
+
+/// Make a hasher for the whole-file checksum of a `DeltaFormat::ChecksummedDelta`.
+#[cfg(feature = "std")]
+pub(crate) fn checksum_hash() -> Blake2Hash {
+    Blake2Hash::default()
+}
+
+/// Finish `hash` to give a whole-file checksum.
+#[cfg(feature = "std")]
+pub(crate) fn finish_checksum(hash: &mut Blake2Hash) -> [u8; CHECKSUM_LEN] {
+    let mut checksum = [0; CHECKSUM_LEN];
+    hash.finalize_truncated(&mut checksum);
+    checksum
+}
+
+/// Check that a COPY command lies within a basis of `basis_len` bytes.
+pub(crate) fn check_copy(offset: u64, len: u64, basis_len: u64) -> Result<()> {
+    match offset.checked_add(len) {
+        Some(end) if end <= basis_len => Ok(()),
+        _ => Err(Error::CorruptDelta(format!(
+            "COPY({}, {}) is beyond the end of the {} byte basis", offset, len, basis_len))),
+    }
+}
+
+/// One command from a delta.
+///
+/// With the `serde` feature, commands can be serialized, for example to log a delta in a
+/// structured form.
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum DeltaCommand {
+    /// Copy `len` bytes starting at `offset` in the basis.
+    Copy { offset: u64, len: u64 },
+
+    /// Insert these bytes, carried in the delta.
+    Literal(#[cfg_attr(feature = "serde", serde(with = "serde_bytes"))] Vec<u8>),
+
+    /// End of the delta.
+    End,
+}
+
+/// The opcode and parameters of a command, without any literal data.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub(crate) enum CommandHeader {
+    Copy { offset: u64, len: u64 },
+    Literal { len: u64 },
+    End,
+}
+
+/// Decode the command with opcode `op`, calling `param` to read each parameter of the
+/// given length.
+fn decode_header<F: FnMut(usize) -> Result<u64>>(op: u8, mut param: F) -> Result<CommandHeader> {
+    match op {
+        OP_END => Ok(CommandHeader::End),
+        1..=0x40 => Ok(CommandHeader::Literal { len: u64::from(op) }),
+        OP_LITERAL_N1..=OP_LITERAL_N8 => {
+            Ok(CommandHeader::Literal { len: param(1 << (op - OP_LITERAL_N1))? })
+        }
+        OP_COPY_N1_N1..=OP_COPY_N8_N8 => {
+            let i = op - OP_COPY_N1_N1;
+            let offset = param(1 << (i / 4))?;
+            let len = param(1 << (i % 4))?;
+            Ok(CommandHeader::Copy { offset, len })
+        }
+        _ => Err(Error::CorruptDelta(format!("unknown command {:#04x}", op))),
+    }
+}
+
+/// Decode the command header at the start of `buf`.
+///
+/// Returns the header and its length, or `None` if `buf` doesn't yet hold all of it.
+pub(crate) fn parse_header(buf: &[u8]) -> Result<Option<(CommandHeader, usize)>> {
+    let op = match buf.first() {
+        Some(&op) => op,
+        None => return Ok(None),
+    };
+    let mut pos = 1;
+    let mut short = false;
+    let header = decode_header(op, |l| {
+        if buf.len() < pos + l {
+            short = true;
+            return Ok(0);
+        }
+        let v = BigEndian::read_uint(&buf[pos..], l);
+        pos += l;
+        Ok(v)
+    })?;
+    Ok(if short { None } else { Some((header, pos)) })
+}
+
+/// Decodes commands from a delta stream, as an iterator.
+///
+/// The iterator yields each command in turn, ending with `DeltaCommand::End`, and then
+/// returns `None`. If the delta is malformed it yields an error, and then stops:
+/// `Error::CorruptDelta` indicates an unknown command or an overlong literal, and an `Io`
+/// error of kind `UnexpectedEof` means the delta is truncated.
+///
+/// For a `DeltaFormat::BasisCheckedDelta`, the `BasisId` is read by `new`, and is then
+/// available from `basis_id`.
+///
+/// For a `DeltaFormat::ChecksummedDelta`, the checksum is read along with the END
+/// command, and is then available from `checksum`. Compressed literals in a
+/// `DeltaFormat::CompressedDelta` are decompressed, and yielded like any other.
+///
+/// BASIS commands in a `DeltaFormat::MultiBasisDelta` aren't yielded: after each COPY,
+/// `basis` tells which basis it refers to. Only the first basis may be selected unless
+/// `set_basis_count` allows more, so a caller that knows of only one basis never
+/// mistakenly copies from it.
+///
+/// The reader makes many small reads, so `R` should normally be buffered. Only available
+/// with the `std` feature.
+#[cfg(feature = "std")]
+#[derive(Debug)]
+pub struct DeltaReader<R: Read> {
+    inner: R,
+    format: DeltaFormat,
+    done: bool,
+    max_literal_len: u64,
+    basis_count: u64,
+    basis: u64,
+    basis_id: Option<BasisId>,
+    checksum: Option<[u8; CHECKSUM_LEN]>,
+    stats: Statistics,
+    /// True if the LITERAL just read is compressed.
+    #[cfg(feature = "zstd")]
+    compressed_literal: bool,
+}
+
+#[cfg(feature = "std")]
+impl<R: Read> DeltaReader<R> {
+    /// Start reading a delta, checking its magic number.
+    pub fn new(mut inner: R) -> Result<DeltaReader<R>> {
+        let magic = inner.read_u32::<BigEndian>()?;
+        let format = DeltaFormat::check_magic(magic)?;
+        let mut stats = Statistics::new("patch");
+        stats.in_bytes = 4;
+        let basis_id = if format == DeltaFormat::BasisCheckedDelta {
+            stats.in_bytes += BASIS_ID_LEN as u64;
+            Some(BasisId::read_from(&mut inner)?)
+        } else {
+            None
+        };
+        Ok(DeltaReader {
+            inner,
+            format,
+            done: false,
+            max_literal_len: u64::MAX,
+            basis_count: 1,
+            basis: 0,
+            basis_id,
+            checksum: None,
+            stats,
+            #[cfg(feature = "zstd")]
+            compressed_literal: false,
+        })
+    }
+
+    /// The format of the delta, from its magic number.
+    pub fn format(&self) -> DeltaFormat {
+        self.format
+    }
+
+    /// The identity of the basis, if the delta has one.
+    pub fn basis_id(&self) -> Option<&BasisId> {
+        self.basis_id.as_ref()
+    }
+
+    /// The whole-file checksum, once END has been read from a checksummed delta.
+    pub fn checksum(&self) -> Option<&[u8; CHECKSUM_LEN]> {
+        self.checksum.as_ref()
+    }
+
+    /// Fail with `Error::CorruptDelta` on reaching a LITERAL command longer than `max`
+    /// bytes, rather than reading it into memory. A compressed literal fails if it's
+    /// longer either compressed or decompressed.
+    ///
+    /// By default there's no limit.
+    pub fn set_max_literal_len(&mut self, max: u64) {
+        self.max_literal_len = max;
+    }
+
+    /// Allow BASIS commands to select any of `count` bases, rather than only the first.
+    ///
+    /// Selecting any other gives `Error::CorruptDelta`.
+    pub fn set_basis_count(&mut self, count: u64) {
+        self.basis_count = count;
+    }
+
+    /// The index of the basis that COPY commands read now refer to.
+    pub fn basis(&self) -> u64 {
+        self.basis
+    }
+
+    /// Counts of the commands read so far, and of the bytes they took.
+    pub fn statistics(&self) -> &Statistics {
+        &self.stats
+    }
+
+    /// Return the underlying reader.
+    pub fn into_inner(self) -> R {
+        self.inner
+    }
+
+    fn read_literal(&mut self, len: u64) -> Result<DeltaCommand> {
+        // Read incrementally rather than trusting the length to preallocate.
+        let mut data = Vec::new();
+        self.copy_literal(len, &mut data)?;
+        Ok(DeltaCommand::Literal(data))
+    }
+
+    /// Copy the `len` bytes of literal data following a LITERAL header to `out`, without
+    /// holding them all in memory.
+    pub(crate) fn copy_literal(&mut self, len: u64, out: &mut dyn Write) -> Result<()> {
+        #[cfg(feature = "zstd")]
+        {
+            if self.compressed_literal {
+                self.compressed_literal = false;
+                return self.copy_compressed_literal(len, out);
+            }
+        }
+        let copied = io::copy(&mut (&mut self.inner).take(len), out)?;
+        if copied < len {
+            return Err(io::Error::new(ErrorKind::UnexpectedEof, "delta ended within a literal")
+                       .into());
+        }
+        self.stats.literal_bytes += len;
+        self.stats.in_bytes += len;
+        Ok(())
+    }
+
+    /// Decompress the `len` byte zstd frame following a ZLITERAL header to `out`.
+    ///
+    /// The frame is read into memory, but not the decompressed data.
+    #[cfg(feature = "zstd")]
+    fn copy_compressed_literal(&mut self, len: u64, out: &mut dyn Write) -> Result<()> {
+        let mut frame = Vec::new();
+        if (&mut self.inner).take(len).read_to_end(&mut frame)? as u64 != len {
+            return Err(io::Error::new(ErrorKind::UnexpectedEof, "delta ended within a literal")
+                       .into());
+        }
+        self.stats.in_bytes += len;
+        let corrupt = |e: io::Error| Error::CorruptDelta(format!("bad compressed literal: {}", e));
+        let mut decoder = zstd::stream::read::Decoder::with_buffer(&frame[..])
+            .map_err(corrupt)?
+            .single_frame();
+        let mut buf = [0; 8192];
+        let mut decompressed = 0;
+        loop {
+            let l = decoder.read(&mut buf).map_err(corrupt)?;
+            if l == 0 {
+                break;
+            }
+            decompressed += l as u64;
+            if decompressed > self.max_literal_len {
+                return Err(Error::CorruptDelta(format!(
+                    "compressed LITERAL is longer than the limit of {} bytes",
+                    self.max_literal_len)));
+            }
+            out.write_all(&buf[..l])?;
+        }
+        if !decoder.finish().is_empty() {
+            return Err(Error::CorruptDelta(
+                "compressed LITERAL has data after its zstd frame".to_owned()));
+        }
+        self.stats.literal_bytes += decompressed;
+        Ok(())
+    }
+
+    /// Read the opcode and parameters of the next command, counting it in the statistics.
+    ///
+    /// After a LITERAL header, the caller must read the data with `copy_literal`.
+    pub(crate) fn read_header(&mut self) -> Result<CommandHeader> {
+        let mut op = self.inner.read_u8()?;
+        while self.format == DeltaFormat::MultiBasisDelta
+            && (OP_BASIS_N1..=OP_BASIS_N8).contains(&op) {
+            self.select_basis(op)?;
+            op = self.inner.read_u8()?;
+        }
+        #[cfg(feature = "zstd")]
+        {
+            // A ZLITERAL has the same parameters as a LITERAL; only its data differs.
+            self.compressed_literal = self.format == DeltaFormat::CompressedDelta
+                && (OP_ZLITERAL_N1..=OP_ZLITERAL_N8).contains(&op);
+            if self.compressed_literal {
+                op = op - OP_ZLITERAL_N1 + OP_LITERAL_N1;
+            }
+        }
+        let inner = &mut self.inner;
+        let mut cmd_bytes = 1;
+        let header = decode_header(op, |l| {
+            cmd_bytes += l as u64;
+            Ok(inner.read_uint::<BigEndian>(l)?)
+        })?;
+        self.stats.in_bytes += cmd_bytes;
+        match header {
+            CommandHeader::End => {
+                if self.format == DeltaFormat::ChecksummedDelta {
+                    let mut checksum = [0; CHECKSUM_LEN];
+                    self.inner.read_exact(&mut checksum)?;
+                    self.stats.in_bytes += CHECKSUM_LEN as u64;
+                    self.checksum = Some(checksum);
+                }
+            }
+            CommandHeader::Literal { len } => {
+                if len > self.max_literal_len {
+                    return Err(Error::CorruptDelta(format!(
+                        "LITERAL of {} bytes is longer than the limit of {}",
+                        len, self.max_literal_len)));
+                }
+                self.check_new_len(len)?;
+                self.stats.literal_cmds += 1;
+                self.stats.literal_cmd_bytes += cmd_bytes;
+            }
+            CommandHeader::Copy { offset, len } => {
+                if offset.checked_add(len).is_none() {
+                    return Err(Error::CorruptDelta(format!(
+                        "COPY({}, {}) ends beyond the largest offset", offset, len)));
+                }
+                self.check_new_len(len)?;
+                self.stats.copy_cmds += 1;
+                self.stats.copy_cmd_bytes += cmd_bytes;
+                self.stats.copy_bytes += len;
+            }
+        }
+        Ok(header)
+    }
+
+    /// Check that `len` more bytes of the new file, on top of those from the commands so
+    /// far, don't make it too long to count, so that its length and the statistics can
+    /// be added up without overflowing.
+    fn check_new_len(&self, len: u64) -> Result<()> {
+        match self.stats.copy_bytes.checked_add(self.stats.literal_bytes)
+            .and_then(|l| l.checked_add(len)) {
+            Some(_) => Ok(()),
+            None => Err(Error::CorruptDelta(format!(
+                "a command of {} bytes makes the new file too long", len))),
+        }
+    }
+
+    /// Read the parameter of a BASIS command with opcode `op`, and select that basis.
+    fn select_basis(&mut self, op: u8) -> Result<()> {
+        let l = 1 << (op - OP_BASIS_N1);
+        let basis = self.inner.read_uint::<BigEndian>(l)?;
+        if basis >= self.basis_count {
+            return Err(Error::CorruptDelta(format!(
+                "BASIS {} is beyond the {} bases given", basis, self.basis_count)));
+        }
+        self.basis = basis;
+        self.stats.in_bytes += 1 + l as u64;
+        self.stats.copy_cmd_bytes += 1 + l as u64;
+        Ok(())
+    }
+
+    fn read_command(&mut self) -> Result<DeltaCommand> {
+        match self.read_header()? {
+            CommandHeader::End => Ok(DeltaCommand::End),
+            CommandHeader::Literal { len } => self.read_literal(len),
+            CommandHeader::Copy { offset, len } => Ok(DeltaCommand::Copy { offset, len }),
+        }
+    }
+}
+
+#[cfg(feature = "std")]
+impl<R: Read> Iterator for DeltaReader<R> {
+    type Item = Result<DeltaCommand>;
+
+    fn next(&mut self) -> Option<Result<DeltaCommand>> {
+        if self.done {
+            return None;
+        }
+        let r = self.read_command();
+        match r {
+            Ok(DeltaCommand::End) | Err(_) => self.done = true,
+            _ => (),
+        }
+        Some(r)
+    }
+}
+
+/// Number of bytes needed to encode `v` as a librsync variable-length integer.
+fn int_len(v: u64) -> usize {
+    if v <= 0xff {
+        1
+    } else if v <= 0xffff {
+        2
+    } else if v <= 0xffff_ffff {
+        4
+    } else {
+        8
+    }
+}
+
+/// Index of an integer length within the 1/2/4/8 opcode families.
+fn int_len_code(l: usize) -> u8 {
+    match l {
+        1 => 0,
+        2 => 1,
+        4 => 2,
+        _ => 3,
+    }
+}
+
+/// Encodes commands into a delta stream.
+///
+/// Each command is written with the shortest encoding that can represent its
+/// parameters. The delta magic is written when the writer is created, and the END
+/// command by `finish`, or for a checksummed delta, `finish_with_checksum`.
+///
+/// A COPY that continues on from the previous one in the basis is merged into it, as
+/// librsync does, so a run of unchanged blocks takes a single command. The COPY is held
+/// back until some other command is written, so it's not yet in the underlying writer or
+/// the statistics.
+///
+/// In a `DeltaFormat::MultiBasisDelta`, `copy_from` copies from any basis, writing a
+/// BASIS command first whenever it's different from the last.
+///
+/// In a `DeltaFormat::CompressedDelta`, each literal longer than can be encoded in the
+/// opcode is compressed, and written as a ZLITERAL if that makes it shorter. The
+/// statistics count the uncompressed `literal_bytes`, and the compressed `out_bytes`.
+///
+/// The writer makes many small writes, so `W` should normally be buffered.
+#[derive(Debug)]
+pub struct DeltaWriter<W: Write> {
+    inner: W,
+    format: DeltaFormat,
+    checksum: Option<[u8; CHECKSUM_LEN]>,
+    ended: bool,
+    stats: Statistics,
+    /// Offset and length of a COPY that's not yet written, in case the next extends it.
+    pending_copy: Option<(u64, u64)>,
+    /// No COPY is longer than this.
+    max_copy_len: u64,
+    /// The basis that COPY commands refer to.
+    basis: u64,
+}
+
+impl<W: Write> DeltaWriter<W> {
+    /// Start writing a delta, by writing its magic number.
+    pub fn new(inner: W) -> Result<DeltaWriter<W>> {
+        DeltaWriter::with_format(inner, DeltaFormat::Delta)
+    }
+
+    /// Start writing a delta in the given format.
+    ///
+    /// A `DeltaFormat::BasisCheckedDelta` must be started by `with_basis_id` instead:
+    /// here, it gives `Error::InvalidOptions`. A `DeltaFormat::CompressedDelta` gives
+    /// `Error::UnsupportedFormat` without the `zstd` feature.
+    pub fn with_format(mut inner: W, format: DeltaFormat) -> Result<DeltaWriter<W>> {
+        if format == DeltaFormat::BasisCheckedDelta {
+            return Err(Error::InvalidOptions(
+                "a basis-checked delta needs the identity of its basis".to_owned()));
+        }
+        format.check_built_in()?;
+        inner.write_all(&(format as u32).to_be_bytes())?;
+        let mut stats = Statistics::new("delta");
+        stats.out_bytes = 4;
+        Ok(DeltaWriter {
+            inner,
+            format,
+            checksum: None,
+            ended: false,
+            stats,
+            pending_copy: None,
+            max_copy_len: u64::MAX,
+            basis: 0,
+        })
+    }
+
+    /// Start writing a `DeltaFormat::BasisCheckedDelta` identifying its basis by `id`.
+    ///
+    /// The delta header has no room for a seed, so an `id` of a seeded signature gives
+    /// `Error::InvalidOptions`.
+    pub fn with_basis_id(mut inner: W, id: &BasisId) -> Result<DeltaWriter<W>> {
+        if id.options.seed != 0 {
+            return Err(Error::InvalidOptions(
+                "a basis can't be identified by a seeded signature".to_owned()));
+        }
+        inner.write_all(&(DeltaFormat::BasisCheckedDelta as u32).to_be_bytes())?;
+        inner.write_all(&id.to_bytes())?;
+        let mut stats = Statistics::new("delta");
+        stats.out_bytes = 4 + BASIS_ID_LEN as u64;
+        Ok(DeltaWriter {
+            inner,
+            format: DeltaFormat::BasisCheckedDelta,
+            checksum: None,
+            ended: false,
+            stats,
+            pending_copy: None,
+            max_copy_len: u64::MAX,
+            basis: 0,
+        })
+    }
+
+    /// Write commands that will follow some already written, without a magic number.
+    #[cfg(feature = "parallel")]
+    pub(crate) fn without_magic(inner: W) -> DeltaWriter<W> {
+        DeltaWriter {
+            inner,
+            format: DeltaFormat::Delta,
+            checksum: None,
+            ended: false,
+            stats: Statistics::new("delta"),
+            pending_copy: None,
+            max_copy_len: u64::MAX,
+            basis: 0,
+        }
+    }
+
+    /// Continue a delta of which `out_bytes` have already been written, apart from
+    /// `pending_copy`, as saved by `pending_copy()`.
+    #[cfg(feature = "std")]
+    pub(crate) fn resumed(inner: W, out_bytes: u64, pending_copy: Option<(u64, u64)>)
+        -> DeltaWriter<W> {
+        let mut stats = Statistics::new("delta");
+        stats.out_bytes = out_bytes;
+        DeltaWriter {
+            inner,
+            format: DeltaFormat::Delta,
+            checksum: None,
+            ended: false,
+            stats,
+            pending_copy,
+            max_copy_len: u64::MAX,
+            basis: 0,
+        }
+    }
+
+    /// The offset and length of a COPY that's not yet written, in case the next extends
+    /// it.
+    #[cfg(feature = "std")]
+    pub(crate) fn pending_copy(&self) -> Option<(u64, u64)> {
+        self.pending_copy
+    }
+
+    /// Return the underlying writer and the statistics, after writing any pending COPY
+    /// but not END.
+    #[cfg(feature = "parallel")]
+    pub(crate) fn into_parts(mut self) -> Result<(W, Statistics)> {
+        self.flush_copy()?;
+        Ok((self.inner, self.stats))
+    }
+
+    /// Counts of the commands written so far, and of the bytes they took.
+    pub fn statistics(&self) -> &Statistics {
+        &self.stats
+    }
+
+    /// Return a mutable reference to the underlying writer.
+    pub fn get_mut(&mut self) -> &mut W {
+        &mut self.inner
+    }
+
+    fn write_netint(&mut self, v: u64, len: usize) -> Result<()> {
+        self.inner.write_all(&v.to_be_bytes()[(8 - len)..])?;
+        Ok(())
+    }
+
+    /// Write a LITERAL command carrying `data`.
+    ///
+    /// Nothing is written if `data` is empty.
+    pub fn literal(&mut self, data: &[u8]) -> Result<()> {
+        if data.is_empty() {
+            return Ok(());
+        }
+        self.flush_copy()?;
+        #[cfg(feature = "zstd")]
+        {
+            if self.format == DeltaFormat::CompressedDelta && data.len() > MAX_IMMEDIATE_LITERAL {
+                let frame = zstd::bulk::compress(data, ZSTD_LEVEL)?;
+                if frame.len() < data.len() {
+                    let cmd_bytes = self.write_literal_header(OP_ZLITERAL_N1, frame.len())?;
+                    self.inner.write_all(&frame)?;
+                    trace_event!(len = data.len(), compressed_len = frame.len(), "ZLITERAL");
+                    self.count_literal(cmd_bytes, data.len(), frame.len());
+                    return Ok(());
+                }
+            }
+        }
+        let cmd_bytes = if data.len() <= MAX_IMMEDIATE_LITERAL {
+            self.inner.write_all(&[data.len() as u8])?;
+            1
+        } else {
+            self.write_literal_header(OP_LITERAL_N1, data.len())?
+        };
+        self.inner.write_all(data)?;
+        trace_event!(len = data.len(), "LITERAL");
+        self.count_literal(cmd_bytes, data.len(), data.len());
+        Ok(())
+    }
+
+    /// Write the opcode from the family starting at `op_n1`, and the length, of a literal
+    /// whose data takes `len` bytes. Returns the length of the header.
+    fn write_literal_header(&mut self, op_n1: u8, len: usize) -> Result<u64> {
+        let l = int_len(len as u64);
+        self.inner.write_all(&[op_n1 + int_len_code(l)])?;
+        self.write_netint(len as u64, l)?;
+        Ok(1 + l as u64)
+    }
+
+    fn count_literal(&mut self, cmd_bytes: u64, len: usize, written_len: usize) {
+        self.stats.literal_cmds += 1;
+        self.stats.literal_cmd_bytes += cmd_bytes;
+        self.stats.literal_bytes += len as u64;
+        self.stats.out_bytes += cmd_bytes + written_len as u64;
+    }
+
+    /// Split COPYs of more than `max` bytes into several, and don't extend one past it,
+    /// so that no COPY is longer. `max` must not be zero.
+    ///
+    /// By default there's no limit.
+    pub fn set_max_copy_len(&mut self, max: u64) {
+        assert!(max > 0, "max_copy_len is zero");
+        self.max_copy_len = max;
+    }
+
+    /// Write a COPY command for `len` bytes from `offset` in the basis last selected by
+    /// `copy_from`, or the first.
+    ///
+    /// Nothing is written if `len` is zero. If the previous command was a COPY ending at
+    /// `offset`, this extends it, unless that would make it too long.
+    pub fn copy(&mut self, mut offset: u64, mut len: u64) -> Result<()> {
+        if len == 0 {
+            return Ok(());
+        }
+        if let Some((pending_offset, pending_len)) = self.pending_copy {
+            if pending_offset.checked_add(pending_len) == Some(offset) {
+                match pending_len.checked_add(len) {
+                    Some(merged) if merged <= self.max_copy_len => {
+                        self.pending_copy = Some((pending_offset, merged));
+                        return Ok(());
+                    }
+                    _ => (),
+                }
+            }
+        }
+        self.flush_copy()?;
+        while len > self.max_copy_len {
+            self.pending_copy = Some((offset, self.max_copy_len));
+            self.flush_copy()?;
+            offset = offset.checked_add(self.max_copy_len).ok_or_else(|| {
+                Error::InvalidOptions("a COPY can't extend beyond 2^64 bytes".to_owned())
+            })?;
+            len -= self.max_copy_len;
+        }
+        self.pending_copy = Some((offset, len));
+        Ok(())
+    }
+
+    /// Write a COPY command for `len` bytes from `offset` in basis number `basis`.
+    ///
+    /// Only a `DeltaFormat::MultiBasisDelta` can refer to any basis but the first:
+    /// otherwise, that gives `Error::InvalidOptions`.
+    pub fn copy_from(&mut self, basis: u64, offset: u64, len: u64) -> Result<()> {
+        if len > 0 && basis != self.basis {
+            if self.format != DeltaFormat::MultiBasisDelta {
+                return Err(Error::InvalidOptions(format!(
+                    "a {:?} can't copy from basis {}", self.format, basis)));
+            }
+            self.flush_copy()?;
+            let l = int_len(basis);
+            self.inner.write_all(&[OP_BASIS_N1 + int_len_code(l)])?;
+            self.write_netint(basis, l)?;
+            trace_event!(basis, "BASIS");
+            self.stats.copy_cmd_bytes += 1 + l as u64;
+            self.stats.out_bytes += 1 + l as u64;
+            self.basis = basis;
+        }
+        self.copy(offset, len)
+    }
+
+    /// Write out the pending COPY, if there is one.
+    fn flush_copy(&mut self) -> Result<()> {
+        let (offset, len) = match self.pending_copy.take() {
+            Some(c) => c,
+            None => return Ok(()),
+        };
+        let offset_len = int_len(offset);
+        let len_len = int_len(len);
+        self.inner.write_all(&[OP_COPY_N1_N1 + 4 * int_len_code(offset_len)
+                               + int_len_code(len_len)])?;
+        self.write_netint(offset, offset_len)?;
+        self.write_netint(len, len_len)?;
+        trace_event!(offset, len, "COPY");
+        let cmd_bytes = (1 + offset_len + len_len) as u64;
+        self.stats.copy_cmds += 1;
+        self.stats.copy_cmd_bytes += cmd_bytes;
+        self.stats.copy_bytes += len;
+        self.stats.out_bytes += cmd_bytes;
+        Ok(())
+    }
+
+    /// Write any command. Writing `End` is the same as calling `finish`, except that the
+    /// writer is not consumed.
+    pub fn write_command(&mut self, command: &DeltaCommand) -> Result<()> {
+        match *command {
+            DeltaCommand::Copy { offset, len } => self.copy(offset, len),
+            DeltaCommand::Literal(ref data) => self.literal(data),
+            DeltaCommand::End => self.end(),
+        }
+    }
+
+    /// Set the whole-file checksum of a checksummed delta, to be written after END.
+    ///
+    /// This must be called before END is written, or writing END fails with
+    /// `Error::InvalidOptions`.
+    pub fn set_checksum(&mut self, checksum: [u8; CHECKSUM_LEN]) {
+        self.checksum = Some(checksum);
+    }
+
+    fn end(&mut self) -> Result<()> {
+        self.flush_copy()?;
+        if !self.ended {
+            let checksum = match (self.format, self.checksum) {
+                (DeltaFormat::Delta, _) | (DeltaFormat::MultiBasisDelta, _)
+                    | (DeltaFormat::BasisCheckedDelta, _)
+                    | (DeltaFormat::CompressedDelta, _) => None,
+                (DeltaFormat::ChecksummedDelta, Some(c)) => Some(c),
+                (DeltaFormat::ChecksummedDelta, None) => return Err(Error::InvalidOptions(
+                    "the checksum wasn't set before ending a checksummed delta".to_owned())),
+            };
+            self.inner.write_all(&[OP_END])?;
+            self.stats.out_bytes += 1;
+            if let Some(checksum) = checksum {
+                self.inner.write_all(&checksum)?;
+                self.stats.out_bytes += CHECKSUM_LEN as u64;
+            }
+            self.ended = true;
+        }
+        self.inner.flush()?;
+        Ok(())
+    }
+
+    /// Write the END command, if it's not already been written, and flush.
+    ///
+    /// Returns the underlying writer.
+    pub fn finish(mut self) -> Result<W> {
+        self.end()?;
+        Ok(self.inner)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn read_all(delta: &[u8]) -> Vec<Result<DeltaCommand>> {
+        DeltaReader::new(delta).unwrap().collect()
+    }
+
+    #[test]
+    pub fn all_opcodes() {
+        let mut delta = vec![b'r', b's', 0x02, 0x36];
+        let mut expected = Vec::new();
+        for l in 1..=64u8 {
+            delta.push(l);
+            delta.extend(vec![l; l as usize]);
+            expected.push(DeltaCommand::Literal(vec![l; l as usize]));
+        }
+        for (i, width) in [1usize, 2, 4, 8].iter().enumerate() {
+            delta.push(OP_LITERAL_N1 + i as u8);
+            delta.extend(vec![0; width - 1]);
+            delta.push(3);
+            delta.extend(b"abc");
+            expected.push(DeltaCommand::Literal(b"abc".to_vec()));
+        }
+        for (i, pos_width) in [1usize, 2, 4, 8].iter().enumerate() {
+            for (j, len_width) in [1usize, 2, 4, 8].iter().enumerate() {
+                delta.push(OP_COPY_N1_N1 + 4 * i as u8 + j as u8);
+                delta.extend(vec![0; pos_width - 1]);
+                delta.push(i as u8 + 1);
+                delta.extend(vec![0; len_width - 1]);
+                delta.push(j as u8 + 10);
+                expected.push(DeltaCommand::Copy { offset: i as u64 + 1, len: j as u64 + 10 });
+            }
+        }
+        delta.push(OP_END);
+        expected.push(DeltaCommand::End);
+        let commands: Vec<DeltaCommand> =
+            read_all(&delta).into_iter().map(|r| r.unwrap()).collect();
+        assert_eq!(commands, expected);
+    }
+
+    #[test]
+    pub fn wide_parameters() {
+        let delta = [b'r', b's', 0x02, 0x36,
+            OP_COPY_N8_N8, 1, 2, 3, 4, 5, 6, 7, 8, 0, 0, 0, 1, 0, 0, 0, 0,
+            OP_END];
+        assert_eq!(read_all(&delta)[0].as_ref().unwrap(),
+                   &DeltaCommand::Copy { offset: 0x0102030405060708, len: 1 << 32 });
+    }
+
+    /// Commands whose lengths add up to more than fit in a u64 are corrupt, rather than
+    /// overflowing the statistics.
+    #[test]
+    pub fn overflowing_lengths() {
+        let mut delta = vec![b'r', b's', 0x02, 0x36];
+        for _ in 0..2 {
+            delta.push(OP_COPY_N8_N8);
+            delta.extend_from_slice(&0u64.to_be_bytes());
+            delta.extend_from_slice(&(1u64 << 63).to_be_bytes());
+        }
+        delta.push(OP_END);
+        let commands = read_all(&delta);
+        assert_eq!(commands[0].as_ref().unwrap(), &DeltaCommand::Copy { offset: 0, len: 1 << 63 });
+        assert!(matches!(commands[1], Err(Error::CorruptDelta(_))), "{:?}", commands[1]);
+
+        let mut delta = vec![b'r', b's', 0x02, 0x36, OP_COPY_N8_N8];
+        delta.extend_from_slice(&u64::MAX.to_be_bytes());
+        delta.extend_from_slice(&1u64.to_be_bytes());
+        delta.push(OP_END);
+        assert!(matches!(read_all(&delta)[0], Err(Error::CorruptDelta(_))));
+    }
+
+    #[test]
+    pub fn max_literal_len() {
+        let delta = [b'r', b's', 0x02, 0x36, 2, b'a', b'b', 3, b'c', b'd', b'e', OP_END];
+        let mut reader = DeltaReader::new(&delta[..]).unwrap();
+        reader.set_max_literal_len(2);
+        assert_eq!(reader.next().unwrap().unwrap(), DeltaCommand::Literal(b"ab".to_vec()));
+        let err = reader.next().unwrap().unwrap_err();
+        assert!(matches!(err, Error::CorruptDelta(_)), "{:?}", err);
+        assert!(reader.next().is_none());
+    }
+
+    #[test]
+    pub fn checksummed() {
+        let mut w = DeltaWriter::with_format(Vec::new(), DeltaFormat::ChecksummedDelta).unwrap();
+        w.literal(b"abc").unwrap();
+        assert!(matches!(w.end(), Err(Error::InvalidOptions(_))));
+        w.set_checksum([7; CHECKSUM_LEN]);
+        let buf = w.finish().unwrap();
+        assert_eq!(&buf[..4], b"rs\x826");
+        assert_eq!(buf.len(), 4 + 4 + 1 + CHECKSUM_LEN);
+        let mut reader = DeltaReader::new(buf.as_slice()).unwrap();
+        assert_eq!(reader.format(), DeltaFormat::ChecksummedDelta);
+        assert_eq!(reader.next().unwrap().unwrap(), DeltaCommand::Literal(b"abc".to_vec()));
+        assert_eq!(reader.checksum(), None);
+        assert_eq!(reader.next().unwrap().unwrap(), DeltaCommand::End);
+        assert_eq!(reader.checksum(), Some(&[7; CHECKSUM_LEN]));
+        assert_eq!(reader.statistics().in_bytes, buf.len() as u64);
+        let err = DeltaReader::new(&buf[..buf.len() - 1]).unwrap().nth(1).unwrap().unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::UnexpectedEof);
+    }
+
+    #[test]
+    pub fn bad_magic() {
+        let err = DeltaReader::new(&[b'r', b's', 0x01, 0x36][..]).unwrap_err();
+        assert!(matches!(err, Error::BadMagic(0x72730136)), "{:?}", err);
+    }
+
+    #[test]
+    #[cfg(not(feature = "zstd"))]
+    pub fn compressed_delta_unsupported() {
+        let err = DeltaReader::new(&b"rs\x836"[..]).unwrap_err();
+        assert!(matches!(err, Error::UnsupportedFormat(0x72738336)), "{:?}", err);
+        let err = DeltaWriter::with_format(Vec::new(), DeltaFormat::CompressedDelta)
+            .err().unwrap();
+        assert!(matches!(err, Error::UnsupportedFormat(0x72738336)), "{:?}", err);
+    }
+
+    /// Long compressible literals are compressed; short or incompressible ones aren't.
+    #[test]
+    #[cfg(feature = "zstd")]
+    pub fn compressed_literals() {
+        let text: Vec<u8> = (0..500).flat_map(|i| format!("line {} of some text\n", i)
+                                                  .into_bytes()).collect();
+        let noise: Vec<u8> = (0..1000u32).map(|i| (i.wrapping_mul(2654435761) >> 13) as u8)
+            .collect();
+        let commands = vec![
+            DeltaCommand::Literal(text.clone()),
+            DeltaCommand::Copy { offset: 10, len: 20 },
+            DeltaCommand::Literal(b"short".to_vec()),
+            DeltaCommand::Literal(noise.clone()),
+            DeltaCommand::End,
+        ];
+        let mut w = DeltaWriter::with_format(Vec::new(), DeltaFormat::CompressedDelta).unwrap();
+        for c in &commands {
+            w.write_command(c).unwrap();
+        }
+        let written = w.statistics().clone();
+        let buf = w.finish().unwrap();
+        assert_eq!(&buf[..4], b"rs\x836");
+        assert_eq!(written.out_bytes, buf.len() as u64);
+        assert_eq!(written.literal_bytes, (text.len() + 5 + noise.len()) as u64);
+        assert!(buf.len() < text.len() / 3 + noise.len() + 30, "{}", buf.len());
+        assert!((OP_ZLITERAL_N1..=OP_ZLITERAL_N8).contains(&buf[4]), "{:#04x}", buf[4]);
+
+        let mut reader = DeltaReader::new(buf.as_slice()).unwrap();
+        assert_eq!(reader.format(), DeltaFormat::CompressedDelta);
+        let read: Vec<DeltaCommand> = reader.by_ref().map(|r| r.unwrap()).collect();
+        assert_eq!(read, commands);
+        let stats = reader.statistics();
+        assert_eq!((stats.in_bytes, stats.literal_bytes), (written.out_bytes, written.literal_bytes));
+        assert_eq!(stats.literal_cmd_bytes, written.literal_cmd_bytes);
+    }
+
+    /// The literal length limit applies to the decompressed data.
+    #[test]
+    #[cfg(feature = "zstd")]
+    pub fn compressed_literal_limit() {
+        let mut w = DeltaWriter::with_format(Vec::new(), DeltaFormat::CompressedDelta).unwrap();
+        w.literal(&[7; 100_000]).unwrap();
+        let buf = w.finish().unwrap();
+        assert!(buf.len() < 1000, "{}", buf.len());
+        let mut reader = DeltaReader::new(buf.as_slice()).unwrap();
+        reader.set_max_literal_len(50_000);
+        let err = reader.next().unwrap().unwrap_err();
+        assert!(matches!(err, Error::CorruptDelta(_)), "{:?}", err);
+    }
+
+    #[test]
+    #[cfg(feature = "zstd")]
+    pub fn bad_compressed_literals() {
+        let frame = zstd::bulk::compress(&[1; 1000], ZSTD_LEVEL).unwrap();
+        let mut delta = b"rs\x836".to_vec();
+        delta.extend(&[OP_ZLITERAL_N1, frame.len() as u8 + 1]);
+        delta.extend(&frame);
+        delta.extend(&[0, OP_END]);
+        let r = read_all(&delta);
+        assert!(matches!(r[0], Err(Error::CorruptDelta(_))), "{:?}", r[0]);
+
+        let mut delta = b"rs\x836".to_vec();
+        delta.extend(&[OP_ZLITERAL_N1, 10]);
+        delta.extend(&[0xee; 10]);
+        delta.push(OP_END);
+        let r = read_all(&delta);
+        assert!(matches!(r[0], Err(Error::CorruptDelta(_))), "{:?}", r[0]);
+
+        let mut delta = b"rs\x836".to_vec();
+        delta.extend(&[OP_ZLITERAL_N1, frame.len() as u8]);
+        delta.extend(&frame[..frame.len() - 2]);
+        let r = read_all(&delta);
+        assert_eq!(r[0].as_ref().unwrap_err().kind(), ErrorKind::UnexpectedEof);
+    }
+
+    #[test]
+    pub fn basis_id() {
+        let options = SignatureOptions { block_len: 100, .. SignatureOptions::default() };
+        let mut sig = Signature::new(&options);
+        sig.push_block(1234, &[5; 32]);
+        let id = BasisId::of(&sig);
+        assert_ne!(id, BasisId::of(&Signature::new(&options)));
+        let mut w = DeltaWriter::with_basis_id(Vec::new(), &id).unwrap();
+        w.literal(b"abc").unwrap();
+        let buf = w.finish().unwrap();
+        assert_eq!(&buf[..4], b"rs\x856");
+        assert_eq!(&buf[4..16], [b'r', b's', 0x01, 0x37, 0, 0, 0, 100, 0, 0, 0, 32]);
+        let mut reader = DeltaReader::new(buf.as_slice()).unwrap();
+        assert_eq!(reader.format(), DeltaFormat::BasisCheckedDelta);
+        assert_eq!(reader.basis_id(), Some(&id));
+        assert_eq!(reader.nth(1).unwrap().unwrap(), DeltaCommand::End);
+        assert_eq!(reader.statistics().in_bytes, buf.len() as u64);
+
+        let mut bad = buf.clone();
+        bad[15] = 33;
+        let err = DeltaReader::new(bad.as_slice()).unwrap_err();
+        assert!(matches!(err, Error::CorruptDelta(_)), "{:?}", err);
+        let err = DeltaReader::new(&buf[..30]).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::UnexpectedEof);
+        let r = DeltaWriter::with_format(Vec::new(), DeltaFormat::BasisCheckedDelta);
+        assert!(matches!(r, Err(Error::InvalidOptions(_))));
+    }
+
+    #[test]
+    pub fn multiple_bases() {
+        let mut w = DeltaWriter::with_format(Vec::new(), DeltaFormat::MultiBasisDelta).unwrap();
+        w.copy(0, 10).unwrap();
+        w.copy_from(0, 10, 10).unwrap();
+        w.copy_from(300, 20, 5).unwrap();
+        w.copy_from(300, 25, 5).unwrap();
+        w.copy_from(0, 0, 1).unwrap();
+        w.write_command(&DeltaCommand::End).unwrap();
+        let stats = w.statistics().clone();
+        let buf = w.finish().unwrap();
+        assert_eq!(buf, [b'r', b's', 0x84, 0x36,
+                         OP_COPY_N1_N1, 0, 20,
+                         OP_BASIS_N1 + 1, 1, 44, OP_COPY_N1_N1, 20, 10,
+                         OP_BASIS_N1, 0, OP_COPY_N1_N1, 0, 1,
+                         OP_END]);
+        assert_eq!(stats.copy_cmd_bytes, 14);
+
+        let mut reader = DeltaReader::new(buf.as_slice()).unwrap();
+        reader.set_basis_count(301);
+        let mut copies = Vec::new();
+        while let DeltaCommand::Copy { offset, len } = reader.next().unwrap().unwrap() {
+            copies.push((reader.basis(), offset, len));
+        }
+        assert_eq!(copies, [(0, 0, 20), (300, 20, 10), (0, 0, 1)]);
+        assert_eq!(reader.statistics().copy_cmd_bytes, 14);
+        assert_eq!(reader.statistics().in_bytes, buf.len() as u64);
+
+        // Without being told of the other bases, the reader doesn't accept them.
+        let r = read_all(&buf);
+        assert!(matches!(r[1], Err(Error::CorruptDelta(_))), "{:?}", r[1]);
+
+        let mut w = DeltaWriter::new(Vec::new()).unwrap();
+        assert!(matches!(w.copy_from(1, 0, 10), Err(Error::InvalidOptions(_))));
+    }
+
+    #[test]
+    pub fn unknown_command_stops() {
+        let r = read_all(&[b'r', b's', 0x02, 0x36, 1, b'a', 0x55, 0]);
+        assert_eq!(r.len(), 2);
+        assert_eq!(r[1].as_ref().unwrap_err().kind(), ErrorKind::InvalidData);
+        assert!(matches!(r[1], Err(Error::CorruptDelta(_))));
+    }
+
+    #[test]
+    pub fn truncated() {
+        for delta in &[&[b'r', b's', 0x02, 0x36][..],
+                       &[b'r', b's', 0x02, 0x36, 3, b'a'][..],
+                       &[b'r', b's', 0x02, 0x36, OP_COPY_N1_N1 + 1, 0, 1][..],
+                       &[b'r', b's', 0x02, 0x36, OP_LITERAL_N1 + 3, 0, 0, 0, 0, 0, 0, 1][..]] {
+            let r = read_all(delta);
+            assert_eq!(r.last().unwrap().as_ref().unwrap_err().kind(), ErrorKind::UnexpectedEof);
+        }
+    }
+
+    /// Nothing is read after the END command.
+    #[test]
+    pub fn stops_after_end() {
+        let mut reader = DeltaReader::new(&[b'r', b's', 0x02, 0x36, 0, 0xff][..]).unwrap();
+        assert_eq!(reader.next().unwrap().unwrap(), DeltaCommand::End);
+        assert!(reader.next().is_none());
+        assert_eq!(reader.into_inner(), &[0xff]);
+    }
+
+    #[test]
+    pub fn parse_partial_headers() {
+        let copy = [OP_COPY_N1_N1 + 1, 7, 1, 0];
+        for l in 0..copy.len() {
+            assert_eq!(parse_header(&copy[..l]).unwrap(), None);
+        }
+        assert_eq!(parse_header(&copy).unwrap(),
+                   Some((CommandHeader::Copy { offset: 7, len: 256 }, 4)));
+        assert_eq!(parse_header(&[3, b'a']).unwrap(),
+                   Some((CommandHeader::Literal { len: 3 }, 1)));
+        assert_eq!(parse_header(&[OP_END, 0xff]).unwrap(), Some((CommandHeader::End, 1)));
+        assert_eq!(parse_header(&[0x55]).unwrap_err().kind(), ErrorKind::InvalidData);
+    }
+
+    fn encode(command: DeltaCommand) -> Vec<u8> {
+        let mut w = DeltaWriter::new(Vec::new()).unwrap();
+        w.write_command(&command).unwrap();
+        let buf = w.finish().unwrap();
+        assert_eq!(DeltaReader::new(buf.as_slice()).unwrap().next().unwrap().unwrap(),
+                   command);
+        assert_eq!(buf.last(), Some(&OP_END));
+        buf[4..(buf.len() - 1)].to_vec()
+    }
+
+    #[test]
+    pub fn shortest_literal_encodings() {
+        assert_eq!(encode(DeltaCommand::Literal(vec![7])), [1, 7]);
+        assert_eq!(&encode(DeltaCommand::Literal(vec![7; 64]))[..1], [64]);
+        assert_eq!(&encode(DeltaCommand::Literal(vec![7; 65]))[..2], [OP_LITERAL_N1, 65]);
+        assert_eq!(&encode(DeltaCommand::Literal(vec![7; 256]))[..3], [OP_LITERAL_N1 + 1, 1, 0]);
+        assert_eq!(&encode(DeltaCommand::Literal(vec![7; 65536]))[..5],
+                   [OP_LITERAL_N1 + 2, 0, 1, 0, 0]);
+    }
+
+    #[test]
+    pub fn shortest_copy_encodings() {
+        assert_eq!(encode(DeltaCommand::Copy { offset: 0, len: 1 }), [OP_COPY_N1_N1, 0, 1]);
+        assert_eq!(encode(DeltaCommand::Copy { offset: 255, len: 256 }),
+                   [OP_COPY_N1_N1 + 1, 255, 1, 0]);
+        assert_eq!(encode(DeltaCommand::Copy { offset: 256, len: 65536 }),
+                   [OP_COPY_N1_N1 + 4 + 2, 1, 0, 0, 1, 0, 0]);
+        assert_eq!(encode(DeltaCommand::Copy { offset: 1 << 32, len: 1 }),
+                   [OP_COPY_N1_N1 + 12, 0, 0, 0, 1, 0, 0, 0, 0, 1]);
+        assert_eq!(encode(DeltaCommand::Copy { offset: 0, len: 1 << 40 }),
+                   [OP_COPY_N8_N8 - 12, 0, 0, 0, 1, 0, 0, 0, 0, 0]);
+    }
+
+    #[test]
+    pub fn empty_commands_are_skipped() {
+        let mut w = DeltaWriter::new(Vec::new()).unwrap();
+        w.literal(b"").unwrap();
+        w.copy(10, 0).unwrap();
+        w.write_command(&DeltaCommand::End).unwrap();
+        assert_eq!(w.finish().unwrap(), [b'r', b's', 0x02, 0x36, OP_END]);
+    }
+
+    #[test]
+    pub fn adjacent_copies_are_merged() {
+        let mut w = DeltaWriter::new(Vec::new()).unwrap();
+        w.copy(0, 10).unwrap();
+        w.copy(10, 20).unwrap();
+        w.copy(30, 5).unwrap();
+        w.copy(100, 5).unwrap(); // Not adjacent.
+        w.literal(b"x").unwrap();
+        w.copy(105, 5).unwrap(); // Adjacent, but after a literal.
+        w.copy(u64::MAX - 2, 1).unwrap();
+        w.copy(u64::MAX - 1, 1).unwrap();
+        // The last COPY is still pending.
+        assert_eq!(w.statistics().copy_cmds, 3);
+        let buf = w.finish().unwrap();
+        let commands: Vec<DeltaCommand> = DeltaReader::new(buf.as_slice()).unwrap()
+            .map(|c| c.unwrap())
+            .collect();
+        assert_eq!(commands, [
+            DeltaCommand::Copy { offset: 0, len: 35 },
+            DeltaCommand::Copy { offset: 100, len: 5 },
+            DeltaCommand::Literal(b"x".to_vec()),
+            DeltaCommand::Copy { offset: 105, len: 5 },
+            DeltaCommand::Copy { offset: u64::MAX - 2, len: 2 },
+            DeltaCommand::End,
+        ]);
+    }
+
+    #[test]
+    pub fn max_copy_len() {
+        let mut w = DeltaWriter::new(Vec::new()).unwrap();
+        w.set_max_copy_len(10);
+        w.copy(0, 4).unwrap();
+        w.copy(4, 6).unwrap();
+        w.copy(10, 1).unwrap(); // Would be too long if merged.
+        w.copy(11, 25).unwrap();
+        let commands: Vec<DeltaCommand> = DeltaReader::new(w.finish().unwrap().as_slice())
+            .unwrap()
+            .map(|c| c.unwrap())
+            .collect();
+        assert_eq!(commands, [
+            DeltaCommand::Copy { offset: 0, len: 10 },
+            DeltaCommand::Copy { offset: 10, len: 1 },
+            DeltaCommand::Copy { offset: 11, len: 10 },
+            DeltaCommand::Copy { offset: 21, len: 10 },
+            DeltaCommand::Copy { offset: 31, len: 5 },
+            DeltaCommand::End,
+        ]);
+    }
+
+    /// The reader and writer count the same commands and bytes.
+    #[test]
+    pub fn statistics() {
+        let mut w = DeltaWriter::new(Vec::new()).unwrap();
+        w.literal(b"hello").unwrap();
+        w.copy(1000, 300).unwrap();
+        w.literal(&[7; 100]).unwrap();
+        w.write_command(&DeltaCommand::End).unwrap();
+        let written = w.statistics().clone();
+        assert_eq!((written.literal_cmds, written.literal_bytes, written.literal_cmd_bytes),
+                   (2, 105, 3));
+        assert_eq!((written.copy_cmds, written.copy_bytes, written.copy_cmd_bytes), (1, 300, 5));
+        let buf = w.finish().unwrap();
+        assert_eq!(written.out_bytes, buf.len() as u64);
+
+        let mut reader = DeltaReader::new(buf.as_slice()).unwrap();
+        for command in reader.by_ref() {
+            command.unwrap();
+        }
+        let read = reader.statistics();
+        assert_eq!(read.in_bytes, buf.len() as u64);
+        assert_eq!(Statistics { op: "delta", in_bytes: 0, out_bytes: read.in_bytes, .. read.clone() },
+                   written);
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    pub fn serde_commands() {
+        let commands = vec![
+            DeltaCommand::Literal(b"hello".to_vec()),
+            DeltaCommand::Copy { offset: 1000, len: 300 },
+            DeltaCommand::End,
+        ];
+        let json = serde_json::to_string(&commands).unwrap();
+        assert_eq!(json,
+                   r#"[{"Literal":[104,101,108,108,111]},{"Copy":{"offset":1000,"len":300}},"End"]"#);
+        assert_eq!(serde_json::from_str::<Vec<DeltaCommand>>(&json).unwrap(), commands);
+    }
+}
@@ -0,0 +1,171 @@
+// rdiff(rust) -- library for network deltas
+// Copyright 2018 Martin Pool.
+
+//! Errors from this crate.
+
+use core::error;
+use core::fmt;
+use core::result;
+#[cfg(feature = "std")]
+use std::io;
+
+use alloc::string::String;
+
+/// Everything that can go wrong in reading, writing or interpreting signatures and
+/// deltas.
+#[derive(Debug)]
+pub enum Error {
+    /// Reading or writing failed. This includes inputs that end sooner than their contents
+    /// say they should, which give an error of kind `UnexpectedEof`.
+    #[cfg(feature = "std")]
+    Io(io::Error),
+
+    /// Without the `std` feature, an input ended sooner than its contents say it should.
+    #[cfg(not(feature = "std"))]
+    UnexpectedEof(&'static str),
+
+    /// The input doesn't start with the magic number of any format.
+    BadMagic(u32),
+
+    /// The magic number identifies a format that's not supported by this build, such as
+    /// a BLAKE3 signature without the `blake3` feature.
+    UnsupportedFormat(u32),
+
+    /// A signature has an impossible header.
+    CorruptSignature(String),
+
+    /// A delta contains an unknown command, or refers to data outside the basis.
+    CorruptDelta(String),
+
+    /// The options or arguments passed in can't be used.
+    InvalidOptions(String),
+
+    /// Strong sums of `strong_len` bytes were asked for, but the strong hash gives only
+    /// `max`.
+    StrongLenTooLong { strong_len: u32, max: u32 },
+
+    /// The output of a checked patch doesn't match the delta's whole-file checksum, so the
+    /// basis isn't the one the delta was made from, or something is corrupt. The output
+    /// should be discarded.
+    ChecksumMismatch,
+
+    /// The basis given to a patch isn't the one the delta was made from, as identified in
+    /// the delta. Nothing has been written.
+    BasisMismatch,
+
+    /// The operation would need more than this many bytes of memory, beyond the limit it
+    /// was given.
+    MemoryLimit(u64),
+
+    /// The operation was stopped through a `CancelToken`.
+    Cancelled,
+}
+
+/// A `Result` whose error is this crate's `Error`.
+pub type Result<T> = result::Result<T, Error>;
+
+/// The error for an input that ends too soon, described by `message`.
+pub(crate) fn unexpected_eof(message: &'static str) -> Error {
+    #[cfg(feature = "std")]
+    return Error::Io(io::Error::new(io::ErrorKind::UnexpectedEof, message));
+    #[cfg(not(feature = "std"))]
+    return Error::UnexpectedEof(message);
+}
+
+#[cfg(feature = "std")]
+impl Error {
+    /// The `io::ErrorKind` that best describes this error: malformed inputs are
+    /// `InvalidData`, bad options `InvalidInput`, going over a memory limit `OutOfMemory`,
+    /// and cancellation `Other`.
+    pub fn kind(&self) -> io::ErrorKind {
+        match *self {
+            Error::Io(ref e) => e.kind(),
+            Error::InvalidOptions(_) | Error::StrongLenTooLong { .. } => {
+                io::ErrorKind::InvalidInput
+            }
+            Error::MemoryLimit(_) => io::ErrorKind::OutOfMemory,
+            Error::Cancelled => io::ErrorKind::Other,
+            _ => io::ErrorKind::InvalidData,
+        }
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            #[cfg(feature = "std")]
+            Error::Io(ref e) => write!(f, "IO error: {}", e),
+            #[cfg(not(feature = "std"))]
+            Error::UnexpectedEof(s) => write!(f, "{}", s),
+            Error::BadMagic(m) => write!(f, "unknown magic number {:#010x}", m),
+            Error::UnsupportedFormat(m) => write!(f, "unsupported format {:#010x}", m),
+            Error::CorruptSignature(ref s) => write!(f, "corrupt signature: {}", s),
+            Error::CorruptDelta(ref s) => write!(f, "corrupt delta: {}", s),
+            Error::InvalidOptions(ref s) => write!(f, "invalid options: {}", s),
+            Error::StrongLenTooLong { strong_len, max } => {
+                write!(f, "strong_len {} is longer than the {} byte strong hash", strong_len, max)
+            }
+            Error::ChecksumMismatch => write!(f, "output doesn't match the delta's checksum"),
+            Error::BasisMismatch => write!(f, "the basis isn't the one the delta was made from"),
+            Error::MemoryLimit(l) => write!(f, "more than the {} byte memory limit is needed", l),
+            Error::Cancelled => write!(f, "cancelled"),
+        }
+    }
+}
+
+impl error::Error for Error {
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+        match *self {
+            #[cfg(feature = "std")]
+            Error::Io(ref e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+/// Wrap an `io::Error`, unless it's itself a wrapped `Error`, which is unwrapped.
+#[cfg(feature = "std")]
+impl From<io::Error> for Error {
+    fn from(e: io::Error) -> Error {
+        if e.get_ref().is_some_and(|r| r.is::<Error>()) {
+            return *e.into_inner().unwrap().downcast::<Error>().unwrap();
+        }
+        Error::Io(e)
+    }
+}
+
+/// Convert back to an `io::Error`, for callers that work in terms of `io::Result`.
+///
+/// Errors other than `Io` are wrapped in an `io::Error` of the same `kind()`.
+#[cfg(feature = "std")]
+impl From<Error> for io::Error {
+    fn from(e: Error) -> io::Error {
+        match e {
+            Error::Io(e) => e,
+            e => io::Error::new(e.kind(), e),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    pub fn into_io_error() {
+        let e = io::Error::from(Error::BadMagic(0x72730236));
+        assert_eq!(e.kind(), io::ErrorKind::InvalidData);
+        assert_eq!(e.to_string(), "unknown magic number 0x72730236");
+        let e = io::Error::from(Error::InvalidOptions("block_len is zero".to_owned()));
+        assert_eq!(e.kind(), io::ErrorKind::InvalidInput);
+        let e = io::Error::from(Error::Io(io::Error::new(io::ErrorKind::UnexpectedEof, "eof")));
+        assert_eq!(e.kind(), io::ErrorKind::UnexpectedEof);
+    }
+
+    /// Errors passed up through code that uses `io::Result` come back out unchanged.
+    #[test]
+    pub fn round_trip_through_io_error() {
+        assert!(matches!(Error::from(io::Error::from(Error::Cancelled)), Error::Cancelled));
+        assert!(matches!(Error::from(io::Error::from(Error::BadMagic(7))), Error::BadMagic(7)));
+    }
+}
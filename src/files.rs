@@ -0,0 +1,535 @@
+// rdiff(rust) -- library for network deltas
+// Copyright 2018 Martin Pool.
+
+//! Whole-file operations, taking paths.
+//!
+//! These open the files, buffer them, and write the output atomically: it goes first to
+//! a temporary file in the same directory, which is renamed over the destination only
+//! once it's complete. If anything fails, the destination is untouched.
+//!
+//! With the `io-uring` feature, on Linux, the output is written through io_uring, with
+//! several writes in flight, and a patch's reads of the basis are submitted ahead from
+//! the delta. If the kernel doesn't support io_uring or doesn't allow it, the files are
+//! read and written as usual.
+
+use std::fs::{self, File, Metadata, OpenOptions};
+use std::io::{self, BufReader, BufWriter, ErrorKind, Seek, Write};
+use std::path::{Path, PathBuf};
+use std::process;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use super::error::Result;
+use super::io_options::IoOptions;
+use super::mkdelta::{generate_delta_metered, generate_delta_with_io};
+use super::mksum::{calculate_signature, generate_signature_metered, SignatureOptions};
+use super::patch::apply_patch_metered;
+#[cfg(feature = "mmap")]
+use super::patch::apply_patch_mmap_metered;
+use super::progress::{Meter, Progress};
+use super::signature::{check_options, Signature};
+use super::sparse::SparseWriter;
+use super::stats::Statistics;
+#[cfg(all(feature = "io-uring", target_os = "linux"))]
+use super::uring::{Copies, Ring, UringBasis, UringWriter};
+
+/// Buffer size for reading input files, unless the caller sets it.
+const READ_BUF_LEN: usize = 256 << 10;
+
+/// Distinguishes temporary files made by different threads of one process.
+static TEMP_COUNTER: AtomicUsize = AtomicUsize::new(0);
+
+/// Files modified more recently than this aren't cached by `cached_signature`, as
+/// another change within the resolution of their modification time couldn't be noticed.
+const CACHE_SETTLE_TIME: Duration = Duration::from_secs(2);
+
+pub(crate) fn default_io() -> IoOptions {
+    IoOptions { read_buf: READ_BUF_LEN, .. IoOptions::default() }
+}
+
+pub(crate) fn open_input(path: &Path, io: &IoOptions) -> io::Result<BufReader<File>> {
+    Ok(BufReader::with_capacity(io.read_buf, File::open(path)?))
+}
+
+/// Make a new temporary file alongside `path`.
+fn create_temp(path: &Path) -> io::Result<(PathBuf, File)> {
+    let name = path.file_name().map(|n| n.to_string_lossy().into_owned()).unwrap_or_default();
+    loop {
+        let temp_path = path.with_file_name(format!(
+            ".{}.{}-{}.tmp", name, process::id(), TEMP_COUNTER.fetch_add(1, Ordering::Relaxed)));
+        match OpenOptions::new().write(true).create_new(true).open(&temp_path) {
+            Ok(f) => return Ok((temp_path, f)),
+            Err(ref e) if e.kind() == ErrorKind::AlreadyExists => continue,
+            Err(e) => return Err(e),
+        }
+    }
+}
+
+/// Write `path` atomically, with the contents written by `f`, leaving holes for blocks
+/// of zeros if `sparse` is set.
+///
+/// As for the other operations here, the contents go to a temporary file in the same
+/// directory, which replaces `path` only once `f` has succeeded and it's been synced. If
+/// anything fails, the temporary file is removed and `path` is untouched.
+pub fn write_atomically<F, T>(path: &Path, io: &IoOptions, sparse: bool, f: F)
+    -> Result<T>
+    where F: FnOnce(&mut dyn Write) -> Result<T> {
+    write_temp(path, io, sparse, f, |temp_path| fs::rename(temp_path, path))
+}
+
+/// Write a new file at `path` atomically, like `write_atomically`, but fail with an error
+/// of kind `AlreadyExists`, leaving it untouched, if anything is at `path` when the
+/// contents are complete.
+///
+/// The temporary file is hard-linked to `path`, which fails if it exists, even as a
+/// dangling symlink, so this needs a filesystem with hard links.
+pub fn write_new_atomically<F, T>(path: &Path, io: &IoOptions, sparse: bool, f: F)
+    -> Result<T>
+    where F: FnOnce(&mut dyn Write) -> Result<T> {
+    write_temp(path, io, sparse, f, |temp_path| {
+        fs::hard_link(temp_path, path)?;
+        // `path` is complete, so failing to tidy up the other name isn't an error.
+        let _ = fs::remove_file(temp_path);
+        Ok(())
+    })
+}
+
+/// Write a temporary file alongside `path` with `f`, sync it, and move it into place with
+/// `commit`, removing it if anything fails.
+fn write_temp<F, T>(path: &Path, io: &IoOptions, sparse: bool, f: F,
+                    commit: impl FnOnce(&Path) -> io::Result<()>) -> Result<T>
+    where F: FnOnce(&mut dyn Write) -> Result<T> {
+    let (temp_path, file) = create_temp(path)?;
+    let r = (|| {
+        let t = write_through(&file, io, sparse, f)?;
+        file.sync_all()?;
+        commit(&temp_path)?;
+        Ok(t)
+    })();
+    if r.is_err() {
+        let _ = fs::remove_file(&temp_path);
+    }
+    r
+}
+
+/// Write `file` with `f`, buffered, and flush it.
+fn write_through<F, T>(file: &File, io: &IoOptions, sparse: bool, f: F) -> Result<T>
+    where F: FnOnce(&mut dyn Write) -> Result<T> {
+    #[cfg(all(feature = "io-uring", target_os = "linux"))]
+    {
+        if let Ok(w) = UringWriter::new(file, io.write_buf) {
+            return write_with(w, io, sparse, f);
+        }
+    }
+    write_with(BufWriter::with_capacity(io.write_buf, file), io, sparse, f)
+}
+
+fn write_with<W: Write + Seek, F, T>(mut w: W, io: &IoOptions, sparse: bool, f: F)
+    -> Result<T>
+    where F: FnOnce(&mut dyn Write) -> Result<T> {
+    let t = if sparse {
+        let mut sparse = SparseWriter::with_capacity(io.write_buf, &mut w);
+        let t = f(&mut sparse)?;
+        sparse.flush()?;
+        t
+    } else {
+        f(&mut w)?
+    };
+    w.flush()?;
+    Ok(t)
+}
+
+/// The length of the file being read, for progress reports.
+fn input_len(f: &BufReader<File>) -> Option<u64> {
+    f.get_ref().metadata().ok().map(|m| m.len())
+}
+
+/// Generate the signature of the file at `basis`, writing it to `sig`.
+pub fn signature_file(basis: &Path, sig: &Path, options: &SignatureOptions)
+    -> Result<Statistics> {
+    signature_file_with_progress(basis, sig, options, &mut |_| ())
+}
+
+/// Generate a signature file, calling `progress` as the basis is read.
+///
+/// The progress reports include the length of the basis, so give a percentage.
+pub fn signature_file_with_progress(basis: &Path, sig: &Path, options: &SignatureOptions,
+                                    progress: &mut dyn FnMut(Progress)) -> Result<Statistics> {
+    signature_file_with_io(basis, sig, options, &default_io(), progress)
+}
+
+/// Generate a signature file, with buffers of the sizes set by `io`, and calling
+/// `progress`.
+pub fn signature_file_with_io(basis: &Path, sig: &Path, options: &SignatureOptions,
+                              io: &IoOptions, progress: &mut dyn FnMut(Progress))
+    -> Result<Statistics> {
+    let mut basis = open_input(basis, io)?;
+    let meter = Meter::new(input_len(&basis), progress);
+    write_atomically(sig, io, false,
+                     |out| generate_signature_metered(&mut basis, options, out, io, &meter))
+}
+
+/// Generate a delta from the signature file at `sig` to the file at `new`, writing it to
+/// `delta`.
+pub fn delta_file(sig: &Path, new: &Path, delta: &Path) -> Result<Statistics> {
+    delta_file_with_progress(sig, new, delta, &mut |_| ())
+}
+
+/// Generate a delta file, calling `progress` as the new file is read.
+pub fn delta_file_with_progress(sig: &Path, new: &Path, delta: &Path,
+                                progress: &mut dyn FnMut(Progress)) -> Result<Statistics> {
+    delta_file_with_io(sig, new, delta, &default_io(), progress)
+}
+
+/// Generate a delta file, with buffers of the sizes set by `io`, and calling `progress`.
+pub fn delta_file_with_io(sig: &Path, new: &Path, delta: &Path, io: &IoOptions,
+                          progress: &mut dyn FnMut(Progress)) -> Result<Statistics> {
+    let sig = Signature::read_from_with_io(&mut File::open(sig)?, io)?;
+    let mut new = open_input(new, io)?;
+    let meter = Meter::new(input_len(&new), progress);
+    write_atomically(delta, io, false,
+                     |out| generate_delta_metered(&sig, &mut new, out, io, &meter))
+}
+
+/// Generate a delta from the file at `old` to the file at `new`, writing it to `delta`,
+/// without writing the signature of `old` anywhere.
+///
+/// This does what `signature_file` followed by `delta_file` would, with the signature
+/// held in memory in between.
+pub fn diff_file(old: &Path, new: &Path, delta: &Path, options: &SignatureOptions)
+    -> Result<Statistics> {
+    let io = &default_io();
+    let sig = calculate_signature(&mut open_input(old, io)?, options)?;
+    let mut new = open_input(new, io)?;
+    write_atomically(delta, io, false, |out| generate_delta_with_io(&sig, &mut new, out, io))
+}
+
+/// Calculate the signature of the file at `basis`, or reuse the one cached in the
+/// directory `cache` by an earlier call, if the file hasn't changed since.
+///
+/// Signatures are cached in the compact form described in `compact`, named by the
+/// identity of the file (on Unix, its device and inode numbers), its length and
+/// modification time, and `options`, so a cached signature is only used if all of them
+/// are the same. A file modified during hashing or in the last couple of seconds isn't
+/// cached, as a further change could go unnoticed.
+///
+/// The cache is only an optimization: if it can't be read or written, the signature is
+/// calculated as usual. Nothing is removed from it.
+pub fn cached_signature(basis: &Path, options: &SignatureOptions, cache: &Path)
+    -> Result<Signature> {
+    let options = &check_options(options, &*options.magic.strong_hash()?)?;
+    let mut file = open_input(basis, &default_io())?;
+    let before = file.get_ref().metadata()?;
+    let cache_path = cache_name(basis, &before, options).map(|name| cache.join(name));
+    if let Some(cache_path) = &cache_path {
+        if let Ok(sig) = fs::read(cache_path).map_err(Into::into)
+            .and_then(|bytes| Signature::from_bytes(&bytes)) {
+            if sig.options() == *options {
+                return Ok(sig);
+            }
+        }
+    }
+    let sig = calculate_signature(&mut file, options)?;
+    let after = file.get_ref().metadata()?;
+    let unchanged = (before.len(), before.modified().ok()) == (after.len(), after.modified().ok());
+    let settled = after.modified().ok()
+        .and_then(|t| SystemTime::now().duration_since(t).ok())
+        .is_some_and(|age| age >= CACHE_SETTLE_TIME);
+    if let (Some(cache_path), true, true) = (cache_path, unchanged, settled) {
+        let _ = fs::create_dir_all(cache).map_err(Into::into).and_then(|()| {
+            write_atomically(&cache_path, &default_io(), false,
+                             |out| Ok(out.write_all(&sig.to_bytes())?))
+        });
+    }
+    Ok(sig)
+}
+
+/// The name under which `cached_signature` keeps the signature of the file at `path`,
+/// with metadata `m`, or None if it can't be identified.
+fn cache_name(path: &Path, m: &Metadata, options: &SignatureOptions) -> Option<String> {
+    let mtime = m.modified().ok()?.duration_since(UNIX_EPOCH).ok()?;
+    Some(format!("{}-{}-{}.{:09}-{:08x}-{}-{}-{}", file_identity(path, m)?, m.len(),
+                 mtime.as_secs(), mtime.subsec_nanos(), options.magic as u32,
+                 options.block_len, options.strong_len, options.seed))
+}
+
+/// Identifies the file at `path`, which has metadata `m`: the same file has the same
+/// identity even if renamed, and a file replacing it has another.
+#[cfg(unix)]
+fn file_identity(_path: &Path, m: &Metadata) -> Option<String> {
+    use std::os::unix::fs::MetadataExt;
+    Some(format!("{:x}-{:x}", m.dev(), m.ino()))
+}
+
+/// Without inode numbers, a file is identified by a hash of its full path.
+#[cfg(not(unix))]
+fn file_identity(path: &Path, _m: &Metadata) -> Option<String> {
+    use super::strongsum::{strong_sum, Blake2Hash};
+    let path = fs::canonicalize(path).ok()?;
+    let mut sum = [0; 16];
+    strong_sum(&mut Blake2Hash::default(), path.to_string_lossy().as_bytes(), &mut sum);
+    Some(sum.iter().map(|b| format!("{:02x}", b)).collect())
+}
+
+/// Apply the delta at `delta` to the file at `basis`, writing the new file to `out`.
+pub fn patch_file(basis: &Path, delta: &Path, out: &Path) -> Result<Statistics> {
+    patch_file_with_progress(basis, delta, out, &mut |_| ())
+}
+
+/// Apply a delta file, calling `progress` as the delta is read.
+///
+/// Blocks of zeros in the new file are left as holes, so that a sparse file stays
+/// sparse. With the `io-uring` feature, where io_uring is available, the basis is read
+/// through it, with the reads for the next COPY commands in flight together.
+pub fn patch_file_with_progress(basis: &Path, delta: &Path, out: &Path,
+                                progress: &mut dyn FnMut(Progress)) -> Result<Statistics> {
+    patch_file_with_io(basis, delta, out, &default_io(), progress)
+}
+
+/// Apply a delta file, with buffers of the sizes set by `io`, and calling `progress`.
+pub fn patch_file_with_io(basis: &Path, delta: &Path, out: &Path, io: &IoOptions,
+                          progress: &mut dyn FnMut(Progress)) -> Result<Statistics> {
+    #[cfg(all(feature = "io-uring", target_os = "linux"))]
+    {
+        if let Ok(ring) = Ring::new() {
+            // The COPY commands are read from the delta a second time, ahead of the patch.
+            let plan = Copies::new(open_input(delta, io)?);
+            let mut basis = UringBasis::new(ring, File::open(basis)?, plan, io.read_buf);
+            let mut delta = open_input(delta, io)?;
+            let meter = Meter::new(input_len(&delta), progress);
+            return write_atomically(out, io, true, |out| {
+                apply_patch_metered(&mut basis, &mut delta, out, io, &meter)
+            });
+        }
+    }
+    let mut basis = open_input(basis, io)?;
+    let mut delta = open_input(delta, io)?;
+    let meter = Meter::new(input_len(&delta), progress);
+    write_atomically(out, io, true, |out| {
+        apply_patch_metered(&mut basis, &mut delta, out, io, &meter)
+    })
+}
+
+/// Apply a delta file like `patch_file_with_io`, but with the basis mapped into memory,
+/// so that each COPY command is a memory copy rather than a seek and a read.
+///
+/// # Safety
+///
+/// As for `patch::apply_patch_mmap`, nothing may change or truncate the basis file until
+/// the patch is done.
+#[cfg(feature = "mmap")]
+pub unsafe fn patch_file_mmap(basis: &Path, delta: &Path, out: &Path, io: &IoOptions,
+                              progress: &mut dyn FnMut(Progress)) -> Result<Statistics> {
+    let basis = File::open(basis)?;
+    let mut delta = open_input(delta, io)?;
+    let meter = Meter::new(input_len(&delta), progress);
+    write_atomically(out, io, true, |out| {
+        // Safety: the caller keeps the basis unchanged.
+        unsafe { apply_patch_mmap_metered(&basis, &mut delta, out, io, &meter) }
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use tempfile::TempDir;
+
+    use super::*;
+    use super::super::test_util::pattern;
+
+    /// The names in a directory, sorted.
+    fn names(dir: &Path) -> Vec<String> {
+        let mut names: Vec<String> = fs::read_dir(dir).unwrap()
+            .map(|e| e.unwrap().file_name().to_string_lossy().into_owned())
+            .collect();
+        names.sort();
+        names
+    }
+
+    #[test]
+    pub fn round_trip() {
+        let tmp = TempDir::new().unwrap();
+        let dir = tmp.path();
+        let basis = pattern(100_000);
+        let mut new = basis[5000..].to_vec();
+        new.extend_from_slice(&basis[..5000]);
+        fs::write(dir.join("basis"), &basis).unwrap();
+        fs::write(dir.join("new"), &new).unwrap();
+        signature_file(&dir.join("basis"), &dir.join("sig"), &SignatureOptions::default())
+            .unwrap();
+        delta_file(&dir.join("sig"), &dir.join("new"), &dir.join("delta")).unwrap();
+        patch_file(&dir.join("basis"), &dir.join("delta"), &dir.join("out")).unwrap();
+        assert_eq!(fs::read(dir.join("out")).unwrap(), new);
+        assert_eq!(names(dir), ["basis", "delta", "new", "out", "sig"]);
+
+        diff_file(&dir.join("basis"), &dir.join("new"), &dir.join("diff"),
+                  &SignatureOptions::default()).unwrap();
+        assert_eq!(fs::read(dir.join("diff")).unwrap(), fs::read(dir.join("delta")).unwrap());
+    }
+
+    /// A new file isn't written over anything that appears at its path while it's being
+    /// written, nor over a dangling symlink.
+    #[test]
+    pub fn write_new_doesnt_replace() {
+        let tmp = TempDir::new().unwrap();
+        let dir = tmp.path();
+        let path = dir.join("out");
+        let err = write_new_atomically(&path, &default_io(), false, |out| {
+            out.write_all(b"new")?;
+            fs::write(&path, b"appeared").unwrap();
+            Ok(())
+        }).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::AlreadyExists);
+        assert_eq!(fs::read(&path).unwrap(), b"appeared");
+        assert_eq!(names(dir), ["out"]);
+
+        #[cfg(unix)]
+        {
+            let link = dir.join("link");
+            std::os::unix::fs::symlink(dir.join("missing"), &link).unwrap();
+            let err = write_new_atomically(&link, &default_io(), false, |out| {
+                Ok(out.write_all(b"new")?)
+            }).unwrap_err();
+            assert_eq!(err.kind(), ErrorKind::AlreadyExists);
+            assert!(fs::symlink_metadata(&link).unwrap().file_type().is_symlink());
+        }
+
+        fs::remove_file(&path).unwrap();
+        write_new_atomically(&path, &default_io(), false, |out| Ok(out.write_all(b"new")?))
+            .unwrap();
+        assert_eq!(fs::read(&path).unwrap(), b"new");
+    }
+
+    /// A signature is cached until the file changes, and only once it's settled.
+    #[test]
+    pub fn signature_cache() {
+        let tmp = TempDir::new().unwrap();
+        let dir = tmp.path();
+        let (basis, cache) = (dir.join("basis"), dir.join("cache"));
+        let options = SignatureOptions { block_len: 1000, .. SignatureOptions::default() };
+        let set_age = |secs| File::options().write(true).open(&basis).unwrap()
+            .set_modified(SystemTime::now() - Duration::from_secs(secs)).unwrap();
+        fs::write(&basis, pattern(10_000)).unwrap();
+        let expected = calculate_signature(&mut pattern(10_000).as_slice(), &options).unwrap();
+        assert_eq!(cached_signature(&basis, &options, &cache).unwrap(), expected);
+        assert!(!cache.exists(), "a file just modified was cached");
+
+        set_age(60);
+        assert_eq!(cached_signature(&basis, &options, &cache).unwrap(), expected);
+        let names: Vec<PathBuf> = fs::read_dir(&cache).unwrap().map(|e| e.unwrap().path())
+            .collect();
+        assert_eq!(names.len(), 1);
+        // The cached signature is used as long as the file seems unchanged.
+        let other = calculate_signature(&mut pattern(5000).as_slice(), &options).unwrap();
+        fs::write(&names[0], other.to_bytes()).unwrap();
+        assert_eq!(cached_signature(&basis, &options, &cache).unwrap(), other);
+        let strong = options.with_strong_len(8);
+        assert_eq!(cached_signature(&basis, &strong, &cache).unwrap().strong_len(), 8);
+        fs::write(&names[0], b"corrupt").unwrap();
+        assert_eq!(cached_signature(&basis, &options, &cache).unwrap(), expected);
+
+        fs::write(&basis, pattern(20_000)).unwrap();
+        set_age(30);
+        let changed = cached_signature(&basis, &options, &cache).unwrap();
+        assert_eq!(changed.block_count(), 20);
+        assert_eq!(fs::read_dir(&cache).unwrap().count(), 3);
+    }
+
+    #[test]
+    pub fn small_buffers() {
+        let tmp = TempDir::new().unwrap();
+        let dir = tmp.path();
+        let basis = pattern(50_000);
+        let new = basis[1000..].to_vec();
+        fs::write(dir.join("basis"), &basis).unwrap();
+        fs::write(dir.join("new"), &new).unwrap();
+        let io = IoOptions { read_buf: 16, write_buf: 16 };
+        signature_file_with_io(&dir.join("basis"), &dir.join("sig"),
+                               &SignatureOptions::default(), &io, &mut |_| ()).unwrap();
+        delta_file_with_io(&dir.join("sig"), &dir.join("new"), &dir.join("delta"), &io,
+                           &mut |_| ()).unwrap();
+        patch_file_with_io(&dir.join("basis"), &dir.join("delta"), &dir.join("out"), &io,
+                           &mut |_| ()).unwrap();
+        assert_eq!(fs::read(dir.join("out")).unwrap(), new);
+    }
+
+    #[cfg(feature = "mmap")]
+    #[test]
+    pub fn mapped_basis() {
+        let tmp = TempDir::new().unwrap();
+        let dir = tmp.path();
+        let basis = pattern(50_000);
+        let new = basis[1000..].to_vec();
+        fs::write(dir.join("basis"), &basis).unwrap();
+        fs::write(dir.join("new"), &new).unwrap();
+        diff_file(&dir.join("basis"), &dir.join("new"), &dir.join("delta"),
+                  &SignatureOptions::default()).unwrap();
+        // Safety: nothing else changes the basis.
+        unsafe {
+            patch_file_mmap(&dir.join("basis"), &dir.join("delta"), &dir.join("out"),
+                            &default_io(), &mut |_| ()).unwrap();
+        }
+        assert_eq!(fs::read(dir.join("out")).unwrap(), new);
+    }
+
+    #[test]
+    pub fn empty_basis() {
+        let tmp = TempDir::new().unwrap();
+        let dir = tmp.path();
+        fs::write(dir.join("basis"), b"").unwrap();
+        fs::write(dir.join("delta"), b"rs\x02\x36\x02hi\x00").unwrap();
+        patch_file(&dir.join("basis"), &dir.join("delta"), &dir.join("out")).unwrap();
+        assert_eq!(fs::read(dir.join("out")).unwrap(), b"hi");
+    }
+
+    /// If the operation fails, the destination is left alone and no temporary file
+    /// remains.
+    #[test]
+    pub fn failure_leaves_output_untouched() {
+        let tmp = TempDir::new().unwrap();
+        let dir = tmp.path();
+        fs::write(dir.join("basis"), b"hello").unwrap();
+        fs::write(dir.join("delta"), b"rs\x02\x36\x45\x03\x03\x00").unwrap();
+        fs::write(dir.join("out"), b"old").unwrap();
+        let err = patch_file(&dir.join("basis"), &dir.join("delta"), &dir.join("out"))
+            .unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::InvalidData);
+        assert_eq!(fs::read(dir.join("out")).unwrap(), b"old");
+        assert_eq!(names(dir), ["basis", "delta", "out"]);
+    }
+
+    #[test]
+    pub fn progress() {
+        let tmp = TempDir::new().unwrap();
+        let dir = tmp.path();
+        let basis = pattern(3 << 20);
+        fs::write(dir.join("basis"), &basis).unwrap();
+        let mut reports = Vec::new();
+        signature_file_with_progress(&dir.join("basis"), &dir.join("sig"),
+                                     &SignatureOptions::default(), &mut |p| reports.push(p))
+            .unwrap();
+        assert!(reports.len() >= 3);
+        assert!(reports.iter().all(|p| p.total == Some(basis.len() as u64)));
+        let last = reports.last().unwrap();
+        assert_eq!(last.percent(), Some(100.0));
+        assert_eq!(last.written, fs::metadata(dir.join("sig")).unwrap().len());
+
+        let mut reports = Vec::new();
+        delta_file_with_progress(&dir.join("sig"), &dir.join("basis"), &dir.join("delta"),
+                                 &mut |p| reports.push(p)).unwrap();
+        assert_eq!(reports.last().unwrap().percent(), Some(100.0));
+        let mut reports = Vec::new();
+        patch_file_with_progress(&dir.join("basis"), &dir.join("delta"), &dir.join("out"),
+                                 &mut |p| reports.push(p)).unwrap();
+        assert_eq!(reports.last().unwrap().written, basis.len() as u64);
+    }
+
+    #[test]
+    pub fn missing_input() {
+        let tmp = TempDir::new().unwrap();
+        let dir = tmp.path();
+        let err = signature_file(&dir.join("basis"), &dir.join("sig"),
+                                 &SignatureOptions::default()).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::NotFound);
+        assert!(names(dir).is_empty());
+    }
+}
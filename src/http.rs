@@ -0,0 +1,410 @@
+// rdiff(rust) -- library for network deltas
+// Copyright 2018 Martin Pool.
+
+//! Patch against a basis on a web server, fetching the ranges COPY commands need with
+//! HTTP Range requests.
+//!
+//! This is a small HTTP/1.1 client with no dependencies: it speaks plain `http://` only,
+//! opening a connection for each request, so it suits an artifact store inside a trusted
+//! network, or one behind a local proxy that adds TLS. Other clients can fetch ranges by
+//! implementing `basis::BasisProvider`.
+//!
+//! `HttpBasis::open_cached` wraps the basis in a `basis::CachedBasis`, so that COPYs of
+//! nearby ranges are served from a few larger requests.
+
+use std::io;
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::TcpStream;
+
+use super::basis::{BasisProvider, CachedBasis};
+
+/// Length of the blocks `HttpBasis::open_cached` requests and caches.
+pub const DEFAULT_HTTP_BLOCK_LEN: usize = 256 << 10;
+
+/// Number of blocks `HttpBasis::open_cached` keeps in memory.
+pub const DEFAULT_HTTP_CACHE_BLOCKS: usize = 64;
+
+/// A basis read from a URL, with a request for each read.
+#[derive(Debug)]
+pub struct HttpBasis {
+    /// Host and port to connect to.
+    addr: String,
+    /// The host and port as sent in the `Host` header.
+    host: String,
+    path: String,
+    len: u64,
+    requests: u64,
+}
+
+impl HttpBasis {
+    /// Open a basis at an `http://` URL, asking the server its length.
+    ///
+    /// An error of kind `InvalidInput` is returned for other URLs.
+    pub fn open(url: &str) -> io::Result<HttpBasis> {
+        let rest = url.strip_prefix("http://").ok_or_else(|| {
+            io::Error::new(io::ErrorKind::InvalidInput, "only http:// URLs are supported")
+        })?;
+        let (host, path) = match rest.find('/') {
+            Some(i) => (&rest[..i], &rest[i..]),
+            None => (rest, "/"),
+        };
+        if host.is_empty() {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "the URL has no host"));
+        }
+        let addr = if host.contains(':') { host.to_owned() } else { format!("{}:80", host) };
+        let mut basis = HttpBasis {
+            addr,
+            host: host.to_owned(),
+            path: path.to_owned(),
+            len: 0,
+            requests: 0,
+        };
+        basis.len = basis.fetch_len()?;
+        Ok(basis)
+    }
+
+    /// Open a basis at an `http://` URL, caching `DEFAULT_HTTP_CACHE_BLOCKS` blocks of
+    /// `DEFAULT_HTTP_BLOCK_LEN`.
+    pub fn open_cached(url: &str) -> io::Result<CachedBasis<HttpBasis>> {
+        CachedBasis::new(HttpBasis::open(url)?, DEFAULT_HTTP_BLOCK_LEN,
+                         DEFAULT_HTTP_CACHE_BLOCKS)
+    }
+
+    /// Number of requests made to the server, including the one that found the length.
+    pub fn requests(&self) -> u64 {
+        self.requests
+    }
+
+    /// Ask for the first byte, to learn the length from the `Content-Range` header.
+    fn fetch_len(&mut self) -> io::Result<u64> {
+        let response = self.get("bytes=0-0")?;
+        let len = match (response.status, response.content_range()) {
+            // An empty file has no first byte.
+            (206, Some((_, total))) | (416, Some((_, total))) => total,
+            // The server doesn't do ranges, and sent the whole file.
+            (200, _) => response.content_length
+                .ok_or_else(|| bad_response("no Content-Length in the response"))?,
+            _ => return Err(response.error()),
+        };
+        Ok(len)
+    }
+
+    fn get(&mut self, range: &str) -> io::Result<Response> {
+        self.requests += 1;
+        let mut stream = TcpStream::connect(&self.addr)?;
+        write!(stream, "GET {} HTTP/1.1\r\nHost: {}\r\nRange: {}\r\nUser-Agent: rdiff\r\n\
+                        Connection: close\r\n\r\n", self.path, self.host, range)?;
+        stream.flush()?;
+        Response::read(BufReader::new(stream))
+    }
+}
+
+impl BasisProvider for HttpBasis {
+    fn read_at(&mut self, offset: u64, buf: &mut [u8]) -> io::Result<()> {
+        if buf.is_empty() {
+            return Ok(());
+        }
+        let end = offset.checked_add(buf.len() as u64)
+            .filter(|&end| end <= self.len)
+            .ok_or_else(|| io::Error::new(io::ErrorKind::UnexpectedEof,
+                                          "read beyond end of basis"))?;
+        let mut response = self.get(&format!("bytes={}-{}", offset, end - 1))?;
+        match (response.status, response.content_range()) {
+            (206, Some((start, _))) if start == offset => (),
+            (206, _) => return Err(bad_response("the response isn't for the range requested")),
+            (200, _) => {
+                io::copy(&mut (&mut response.body).take(offset), &mut io::sink())?;
+            }
+            _ => return Err(response.error()),
+        }
+        response.body.read_exact(buf)
+    }
+
+    fn size(&mut self) -> io::Result<u64> {
+        Ok(self.len)
+    }
+}
+
+/// The status and headers of a response, and a reader for its body.
+struct Response {
+    status: u32,
+    reason: String,
+    content_length: Option<u64>,
+    content_range: Option<String>,
+    body: Box<dyn Read>,
+}
+
+impl Response {
+    fn read(mut stream: BufReader<TcpStream>) -> io::Result<Response> {
+        let status_line = read_line(&mut stream)?;
+        let mut words = status_line.splitn(3, ' ');
+        let status = match (words.next(), words.next()) {
+            (Some(v), Some(s)) if v.starts_with("HTTP/") => s.parse().ok(),
+            _ => None,
+        }.ok_or_else(|| bad_response("the status line is malformed"))?;
+        let reason = words.next().unwrap_or("").to_owned();
+        let (mut content_length, mut content_range, mut chunked) = (None, None, false);
+        loop {
+            let line = read_line(&mut stream)?;
+            if line.is_empty() {
+                break;
+            }
+            let (name, value) = match line.find(':') {
+                Some(i) => (line[..i].trim().to_ascii_lowercase(), line[(i + 1)..].trim()),
+                None => return Err(bad_response("a header is malformed")),
+            };
+            match name.as_str() {
+                "content-length" => content_length = Some(value.parse()
+                    .map_err(|_| bad_response("Content-Length is malformed"))?),
+                "content-range" => content_range = Some(value.to_owned()),
+                "transfer-encoding" => chunked = value.eq_ignore_ascii_case("chunked"),
+                _ => (),
+            }
+        }
+        let body: Box<dyn Read> = match (chunked, content_length) {
+            (true, _) => Box::new(Chunked { inner: stream, left: 0, done: false }),
+            (false, Some(l)) => Box::new(stream.take(l)),
+            (false, None) => Box::new(stream),
+        };
+        Ok(Response { status, reason, content_length, content_range, body })
+    }
+
+    /// The start and total length from a `Content-Range` header, like
+    /// `bytes 100-199/1000`, or `bytes */1000` in a 416 response.
+    fn content_range(&self) -> Option<(u64, u64)> {
+        let value = self.content_range.as_ref()?.strip_prefix("bytes ")?;
+        let (range, total) = value.split_at(value.find('/')?);
+        let total = total[1..].parse().ok()?;
+        let start = if range == "*" { 0 } else { range.split('-').next()?.parse().ok()? };
+        Some((start, total))
+    }
+
+    /// The error for an unexpected status.
+    fn error(&self) -> io::Error {
+        let kind = if self.status == 404 {
+            io::ErrorKind::NotFound
+        } else {
+            io::ErrorKind::Other
+        };
+        io::Error::new(kind, format!("HTTP status {} {}", self.status, self.reason))
+    }
+}
+
+/// The error for a response that can't be understood.
+fn bad_response(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, format!("bad HTTP response: {}", message))
+}
+
+/// The error for a response that ends sooner than its headers say it should.
+fn ended_early() -> io::Error {
+    io::Error::new(io::ErrorKind::UnexpectedEof, "the HTTP response ended early")
+}
+
+/// Read a line, without its line ending, failing if the stream ends first.
+fn read_line(stream: &mut dyn BufRead) -> io::Result<String> {
+    let mut line = String::new();
+    if stream.read_line(&mut line)? == 0 {
+        return Err(ended_early());
+    }
+    Ok(line.trim_end_matches(&['\r', '\n'][..]).to_owned())
+}
+
+/// Decodes a body sent with `Transfer-Encoding: chunked`.
+struct Chunked<R> {
+    inner: R,
+    /// Bytes left in the current chunk.
+    left: u64,
+    done: bool,
+}
+
+impl<R: BufRead> Read for Chunked<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.left == 0 && !self.done {
+            let line = read_line(&mut self.inner)?;
+            let size = line.split(';').next().unwrap_or("").trim();
+            self.left = u64::from_str_radix(size, 16)
+                .map_err(|_| bad_response("a chunk size is malformed"))?;
+            self.done = self.left == 0;
+        }
+        if self.done || buf.is_empty() {
+            return Ok(0);
+        }
+        let l = (&mut self.inner).take(self.left.min(buf.len() as u64)).read(buf)?;
+        if l == 0 {
+            return Err(ended_early());
+        }
+        self.left -= l as u64;
+        if self.left == 0 {
+            read_line(&mut self.inner)?;
+        }
+        Ok(l)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::net::TcpListener;
+    use std::thread;
+
+    use super::*;
+    use super::super::delta::DeltaWriter;
+    use super::super::memory;
+    use super::super::patch::apply_patch;
+    use super::super::test_util::pattern;
+
+    /// How a test server answers.
+    #[derive(Clone, Copy)]
+    enum Serve {
+        Ranges,
+        /// Ignore the Range header and send the whole file.
+        Whole,
+        /// Send ranges in chunks.
+        Chunked,
+    }
+
+    /// Serve `data` on a local port, for as long as the test runs, returning its URL.
+    fn serve(data: Vec<u8>, how: Serve) -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}/basis", listener.local_addr().unwrap());
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                let mut stream = stream.unwrap();
+                let mut reader = BufReader::new(stream.try_clone().unwrap());
+                let mut range = None;
+                let mut path = String::new();
+                loop {
+                    let line = read_line(&mut reader).unwrap();
+                    if line.is_empty() {
+                        break;
+                    } else if let Some(r) = line.strip_prefix("Range: bytes=") {
+                        let (a, b) = r.split_at(r.find('-').unwrap());
+                        range = Some((a.parse::<usize>().unwrap(),
+                                      b[1..].parse::<usize>().unwrap()));
+                    } else if let Some(p) = line.strip_prefix("GET ") {
+                        path = p.split(' ').next().unwrap().to_owned();
+                    }
+                }
+                // The client may hang up once it has what it wants, so the rest of the
+                // response may not be sent.
+                let mut response = Vec::new();
+                match (how, range) {
+                    _ if path != "/basis" => {
+                        write!(response, "HTTP/1.1 404 Not Found\r\nContent-Length: 0\r\n\r\n")
+                            .unwrap();
+                    }
+                    (Serve::Ranges, Some((a, _))) | (Serve::Chunked, Some((a, _)))
+                        if a >= data.len() => {
+                        write!(response, "HTTP/1.1 416 Range Not Satisfiable\r\n\
+                                          Content-Range: bytes */{}\r\n\r\n", data.len())
+                            .unwrap();
+                    }
+                    (Serve::Ranges, Some((a, b))) => {
+                        let b = b.min(data.len() - 1);
+                        write!(response, "HTTP/1.1 206 Partial Content\r\nContent-Length: {}\r\n\
+                                          Content-Range: bytes {}-{}/{}\r\n\r\n",
+                               b + 1 - a, a, b, data.len()).unwrap();
+                        response.extend_from_slice(&data[a..=b]);
+                    }
+                    (Serve::Chunked, Some((a, b))) => {
+                        let b = b.min(data.len() - 1);
+                        write!(response, "HTTP/1.1 206 Partial Content\r\n\
+                                          Transfer-Encoding: chunked\r\n\
+                                          Content-Range: bytes {}-{}/{}\r\n\r\n",
+                               a, b, data.len()).unwrap();
+                        for chunk in data[a..=b].chunks(1000) {
+                            write!(response, "{:x}\r\n", chunk.len()).unwrap();
+                            response.extend_from_slice(chunk);
+                            write!(response, "\r\n").unwrap();
+                        }
+                        write!(response, "0\r\n\r\n").unwrap();
+                    }
+                    _ => {
+                        write!(response, "HTTP/1.1 200 OK\r\nContent-Length: {}\r\n\r\n",
+                               data.len()).unwrap();
+                        response.extend_from_slice(&data);
+                    }
+                }
+                let _ = stream.write_all(&response);
+            }
+        });
+        url
+    }
+
+    /// A delta of many small COPYs, some of them from the same places.
+    fn scattered_delta(basis: &[u8]) -> Vec<u8> {
+        let mut w = DeltaWriter::new(Vec::new()).unwrap();
+        for i in 0..100u64 {
+            w.copy((i * 7919) % (basis.len() as u64 - 100), 100).unwrap();
+            w.literal(b"/").unwrap();
+        }
+        w.finish().unwrap()
+    }
+
+    #[test]
+    pub fn patch_from_server() {
+        let basis = pattern(1 << 20);
+        let delta = scattered_delta(&basis);
+        let expected = memory::apply(&basis, &delta).unwrap();
+        for &how in &[Serve::Ranges, Serve::Whole, Serve::Chunked] {
+            let url = serve(basis.clone(), how);
+            let mut remote = HttpBasis::open(&url).unwrap();
+            assert_eq!(remote.size().unwrap(), basis.len() as u64);
+            let mut out = Vec::new();
+            apply_patch(&mut remote, &mut delta.as_slice(), &mut out).unwrap();
+            assert_eq!(out, expected);
+            assert_eq!(remote.requests(), 101);
+        }
+    }
+
+    /// With a cache, COPYs of nearby ranges share requests.
+    #[test]
+    pub fn cached_requests() {
+        let basis = pattern(1 << 20);
+        let delta = scattered_delta(&basis);
+        let url = serve(basis.clone(), Serve::Ranges);
+        let mut remote = CachedBasis::new(HttpBasis::open(&url).unwrap(), 64 << 10, 16)
+            .unwrap();
+        let mut out = Vec::new();
+        apply_patch(&mut remote, &mut delta.as_slice(), &mut out).unwrap();
+        assert_eq!(out, memory::apply(&basis, &delta).unwrap());
+        assert!(remote.get_ref().requests() <= 17, "{}", remote.get_ref().requests());
+
+        let mut remote = HttpBasis::open_cached(&url).unwrap();
+        let mut all = vec![0; basis.len()];
+        remote.read_at(0, &mut all).unwrap();
+        assert_eq!(all, basis);
+        assert_eq!(remote.get_ref().requests(), 2);
+    }
+
+    #[test]
+    pub fn empty_basis() {
+        let url = serve(Vec::new(), Serve::Ranges);
+        let mut remote = HttpBasis::open(&url).unwrap();
+        assert_eq!(remote.size().unwrap(), 0);
+        let err = remote.read_at(0, &mut [0; 1]).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::UnexpectedEof);
+    }
+
+    /// A read whose end would overflow is beyond the end of the basis, and makes no
+    /// request.
+    #[test]
+    pub fn overflowing_read() {
+        let url = serve(pattern(100), Serve::Ranges);
+        let mut remote = HttpBasis::open(&url).unwrap();
+        let err = remote.read_at(u64::MAX, &mut [0; 2]).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::UnexpectedEof);
+        assert_eq!(remote.requests(), 1);
+    }
+
+    #[test]
+    pub fn bad_urls() {
+        let url = serve(pattern(100), Serve::Ranges);
+        let err = HttpBasis::open(&url.replace("/basis", "/missing")).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::NotFound);
+        assert_eq!(err.to_string(), "HTTP status 404 Not Found");
+        for url in &["https://example.com/basis", "http:///basis", "/basis"] {
+            let err = HttpBasis::open(url).unwrap_err();
+            assert_eq!(err.kind(), io::ErrorKind::InvalidInput, "{}", url);
+        }
+    }
+}
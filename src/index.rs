@@ -0,0 +1,334 @@
+// rdiff(rust) -- library for network deltas
+// Copyright 2018 Martin Pool.
+
+//! Index the blocks of a signature by weak sum, to find matches quickly.
+//!
+//! This is the equivalent of librsync's `rs_build_hash_table`. Building an index takes
+//! time proportional to the number of blocks, and then each lookup is O(1), so one
+//! index can be reused to generate many deltas against the same signature.
+//!
+//! Most windows of a new file match no block, so before probing the map of weak sums,
+//! each lookup checks a compact table of tags, one bit for a bucket of weak sums, and
+//! most misses stop there. Like librsync's hash table hints, the table is small enough to
+//! stay in cache where the map isn't.
+//!
+//! The index of a huge signature can be too big for the machine making the delta. Given
+//! a memory budget, it holds only every Nth block: the others are found only by
+//! following on from a match of the block before, so unchanged runs of the basis are
+//! still matched, but after a change the delta has literal data until the next indexed
+//! block. Deltas are bigger, but still made.
+
+#[cfg(not(feature = "std"))]
+use alloc::collections::BTreeMap;
+use alloc::vec;
+use alloc::vec::Vec;
+#[cfg(feature = "std")]
+use std::collections::HashMap;
+
+use super::signature::{seed_weak, seeded_strong_sum, Signature, RS_MAX_STRONG_SUM_LENGTH};
+use super::strongsum::StrongHash;
+
+/// Map from weak sums to the first block having each. Without `std` there's no
+/// `HashMap`, so lookups take logarithmic time.
+#[cfg(feature = "std")]
+type Heads = HashMap<u32, usize>;
+#[cfg(not(feature = "std"))]
+type Heads = BTreeMap<u32, usize>;
+
+/// Marks the end of a chain of blocks having the same weak sum.
+const NO_BLOCK: usize = usize::MAX;
+
+/// Bits in the tag table for each block, so that at most about one in eight weak sums
+/// that match nothing gets past it.
+const TAG_BITS_PER_BLOCK: u64 = 8;
+
+/// Approximate bytes of memory used for each indexed block: a slot in the map of weak
+/// sums with its spare capacity, a link in a chain, its tags, and maybe an offset.
+pub const INDEX_BYTES_PER_BLOCK: usize = 48;
+
+/// The outcome of looking for a block to match some data.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub(crate) enum Lookup {
+    /// No block has the weak sum.
+    Miss,
+    /// Some blocks have the weak sum, but none the strong sum.
+    FalseMatch,
+    /// This block matches.
+    Match(usize),
+}
+
+/// An index from weak sums to the blocks of a signature.
+#[derive(Debug, Clone)]
+pub struct SignatureIndex<'s> {
+    sig: &'s Signature,
+
+    /// The first block having each weak sum.
+    heads: Heads,
+
+    /// For each indexed block, the next block with the same weak sum, or `NO_BLOCK`.
+    next: Vec<usize>,
+
+    /// Only blocks whose index is a multiple of this are indexed.
+    stride: usize,
+
+    /// A bit for each bucket of weak sums, set if some block's weak sum is in it.
+    tags: Vec<u64>,
+
+    /// How far to shift a scrambled weak sum to give its bucket.
+    tag_shift: u32,
+
+    /// For a signature whose blocks vary in length, the offset in the basis where each
+    /// indexed block starts; otherwise empty.
+    offsets: Vec<u64>,
+}
+
+impl<'s> SignatureIndex<'s> {
+    /// Build an index of all the blocks in `sig`.
+    pub fn new(sig: &'s Signature) -> SignatureIndex<'s> {
+        SignatureIndex::with_stride(sig, 1)
+    }
+
+    /// Build an index of `sig` using about `limit` bytes of memory, or less.
+    ///
+    /// If indexing every block would take more, at `INDEX_BYTES_PER_BLOCK` each, only
+    /// every Nth block is indexed, and deltas made with the index are bigger. The
+    /// signature itself isn't counted.
+    pub fn with_memory_limit(sig: &'s Signature, limit: usize) -> SignatureIndex<'s> {
+        let needed = sig.block_count().saturating_mul(INDEX_BYTES_PER_BLOCK);
+        SignatureIndex::with_stride(sig, needed.div_ceil(limit.max(1)).max(1))
+    }
+
+    /// Index every `stride`th block of `sig`.
+    fn with_stride(sig: &'s Signature, stride: usize) -> SignatureIndex<'s> {
+        let n = sig.block_count().div_ceil(stride);
+        #[cfg(feature = "std")]
+        let mut heads = Heads::with_capacity(n);
+        #[cfg(not(feature = "std"))]
+        let mut heads = Heads::new();
+        let mut next = vec![NO_BLOCK; n];
+        let buckets = (n as u64 * TAG_BITS_PER_BLOCK).next_power_of_two().clamp(64, 1 << 32);
+        let tag_shift = 32 - buckets.trailing_zeros();
+        let mut tags = vec![0u64; (buckets / 64) as usize];
+        // Walk backwards so that each chain lists blocks in ascending order.
+        for j in (0..n).rev() {
+            let i = j * stride;
+            let weak = sig.weak_sum(i);
+            let bucket = tag_bucket(weak, tag_shift);
+            tags[bucket / 64] |= 1 << (bucket % 64);
+            if let Some(old) = heads.insert(weak, i) {
+                next[j] = old;
+            }
+        }
+        let offsets = match sig.block_lens() {
+            Some(lens) => lens.iter().scan(0, |offset, &l| {
+                let start = *offset;
+                *offset += u64::from(l);
+                Some(start)
+            }).step_by(stride).collect(),
+            None => Vec::new(),
+        };
+        SignatureIndex { sig, heads, next, stride, tags, tag_shift, offsets }
+    }
+
+    /// The signature that's indexed.
+    pub fn signature(&self) -> &'s Signature {
+        self.sig
+    }
+
+    /// Only blocks whose index is a multiple of this are indexed: 1 if they all are.
+    pub fn stride(&self) -> usize {
+        self.stride
+    }
+
+    /// Offset in the basis where block `i` starts.
+    pub fn block_offset(&self, i: usize) -> u64 {
+        match (self.offsets.get(i / self.stride), self.sig.block_lens()) {
+            (Some(&offset), Some(lens)) => {
+                let first = i - i % self.stride;
+                offset + lens[first..i].iter().map(|&l| u64::from(l)).sum::<u64>()
+            }
+            _ => i as u64 * u64::from(self.sig.block_len()),
+        }
+    }
+
+    /// Iterate, in ascending order, the indexes of blocks with weak sum `weak`, as stored
+    /// in the signature.
+    pub fn candidates(&self, weak: u32) -> Candidates<'_, 's> {
+        let bucket = tag_bucket(weak, self.tag_shift);
+        let next = if self.tags[bucket / 64] & (1 << (bucket % 64)) == 0 {
+            NO_BLOCK
+        } else {
+            self.heads.get(&weak).cloned().unwrap_or(NO_BLOCK)
+        };
+        Candidates { index: self, next }
+    }
+
+    /// Find a block whose sums match `data`, whose weak sum is `weak`.
+    ///
+    /// If the signature has a seed, it's mixed into both sums of `data` before they're
+    /// compared. The strong sum of `data` is only calculated if there's a candidate with the same
+    /// weak sum. If several blocks match, the first is returned. Nothing matches if the
+    /// format's strong hash isn't built in.
+    pub fn find_match(&self, weak: u32, data: &[u8]) -> Option<usize> {
+        let mut hash = self.sig.format().strong_hash().ok()?;
+        self.find_match_with_hash(weak, data, &mut *hash)
+    }
+
+    /// Find a matching block, checking strong sums with `hash` rather than the hash for
+    /// the signature's format.
+    ///
+    /// Reusing one hasher avoids making a new one for each lookup.
+    pub fn find_match_with_hash(&self, weak: u32, data: &[u8], hash: &mut dyn StrongHash)
+        -> Option<usize> {
+        match self.lookup(weak, data, hash) {
+            Lookup::Match(i) => Some(i),
+            _ => None,
+        }
+    }
+
+    /// Like `find_match_with_hash`, but distinguishing weak sums that matched only
+    /// falsely.
+    pub(crate) fn lookup(&self, weak: u32, data: &[u8], hash: &mut dyn StrongHash) -> Lookup {
+        let seed = self.sig.seed();
+        let mut candidates = self.candidates(seed_weak(weak, seed)).peekable();
+        if candidates.peek().is_none() {
+            return Lookup::Miss;
+        }
+        let mut strong = [0u8; RS_MAX_STRONG_SUM_LENGTH];
+        let strong = &mut strong[..(self.sig.strong_len() as usize)];
+        seeded_strong_sum(hash, seed, data, strong);
+        match candidates.find(|&i| self.sig.strong_sum(i) == &strong[..]) {
+            Some(i) => Lookup::Match(i),
+            None => Lookup::FalseMatch,
+        }
+    }
+
+    /// Like `lookup`, but when some blocks aren't indexed, first try the block after
+    /// `prev`, which matched the data just before this.
+    pub(crate) fn lookup_after(&self, prev: Option<usize>, weak: u32, data: &[u8],
+                               hash: &mut dyn StrongHash) -> Lookup {
+        let seed = self.sig.seed();
+        match prev.map(|p| p + 1) {
+            Some(i) if self.stride > 1 && i % self.stride != 0 && i < self.sig.block_count()
+                && self.sig.weak_sum(i) == seed_weak(weak, seed) => {
+                let mut strong = [0u8; RS_MAX_STRONG_SUM_LENGTH];
+                let strong = &mut strong[..(self.sig.strong_len() as usize)];
+                seeded_strong_sum(hash, seed, data, strong);
+                if self.sig.strong_sum(i) == &strong[..] {
+                    return Lookup::Match(i);
+                }
+                self.lookup(weak, data, hash)
+            }
+            _ => self.lookup(weak, data, hash),
+        }
+    }
+}
+
+/// The bucket of the tag table for `weak`: its top bits after scrambling, so that weak
+/// sums differing only in their high or low bits are spread out.
+fn tag_bucket(weak: u32, tag_shift: u32) -> usize {
+    (u64::from(weak.wrapping_mul(0x9e37_79b9)) >> tag_shift) as usize
+}
+
+/// Iterator over blocks having a particular weak sum.
+#[derive(Debug)]
+pub struct Candidates<'i, 's: 'i> {
+    index: &'i SignatureIndex<'s>,
+    next: usize,
+}
+
+impl<'i, 's> Iterator for Candidates<'i, 's> {
+    type Item = usize;
+
+    fn next(&mut self) -> Option<usize> {
+        if self.next == NO_BLOCK {
+            None
+        } else {
+            let i = self.next;
+            self.next = self.index.next[i / self.index.stride];
+            Some(i)
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use super::super::mksum::{calculate_signature, SignatureOptions};
+
+    fn options() -> SignatureOptions {
+        SignatureOptions {
+            block_len: 4,
+            .. SignatureOptions::default()
+        }.with_strong_len(8)
+    }
+
+    #[test]
+    pub fn find_blocks() {
+        let basis = b"abcdefghabcdijklabc";
+        let sig = calculate_signature(&mut &basis[..], &options()).unwrap();
+        let index = SignatureIndex::new(&sig);
+        let weak_abcd = sig.weak_sum(0);
+        assert_eq!(index.candidates(weak_abcd).collect::<Vec<_>>(), vec![0, 2]);
+        assert_eq!(index.find_match(weak_abcd, b"abcd"), Some(0));
+        assert_eq!(index.find_match(sig.weak_sum(3), b"ijkl"), Some(3));
+        assert_eq!(index.find_match(sig.weak_sum(4), b"abc"), Some(4));
+        // Right weak sum, wrong data.
+        assert_eq!(index.find_match(weak_abcd, b"abce"), None);
+        assert_eq!(index.candidates(0x1234_5678).count(), 0);
+    }
+
+    /// Most weak sums that match no block are rejected by the tag table alone.
+    #[test]
+    pub fn tags_reject_misses() {
+        let basis: Vec<u8> = (0..40_000u32).map(|i| (i.wrapping_mul(2_654_435_761) >> 24) as u8)
+            .collect();
+        let sig = calculate_signature(&mut basis.as_slice(), &options()).unwrap();
+        let index = SignatureIndex::new(&sig);
+        assert_eq!(index.tags.len(), 80_000usize.next_power_of_two() / 64);
+        for i in 0..sig.block_count() {
+            assert!(index.candidates(sig.weak_sum(i)).any(|j| j == i));
+        }
+        let mut passed = 0;
+        for weak in (0..100_000u32).map(|w| w.wrapping_mul(0x0101_0101)) {
+            let bucket = tag_bucket(weak, index.tag_shift);
+            if index.tags[bucket / 64] & (1 << (bucket % 64)) != 0 {
+                passed += 1;
+            }
+        }
+        assert!(passed < 20_000, "{} of 100000 passed", passed);
+    }
+
+    /// Within a memory limit, only some blocks are indexed, but offsets are still known.
+    #[test]
+    pub fn memory_limit() {
+        use super::super::mksum::calculate_signature_variable;
+
+        let basis: Vec<u8> = (0..160u32).map(|i| (i.wrapping_mul(2_654_435_761) >> 24) as u8)
+            .collect();
+        let sig = calculate_signature(&mut basis.as_slice(), &options()).unwrap();
+        assert_eq!(SignatureIndex::with_memory_limit(&sig, usize::MAX).stride(), 1);
+        assert_eq!(SignatureIndex::with_memory_limit(&sig, 0).stride(), 40 * INDEX_BYTES_PER_BLOCK);
+        let index = SignatureIndex::with_memory_limit(&sig, 10 * INDEX_BYTES_PER_BLOCK);
+        assert_eq!((index.stride(), index.next.len()), (4, 10));
+        for i in 0..sig.block_count() {
+            let found = index.candidates(sig.weak_sum(i)).any(|j| j == i);
+            assert_eq!(found, i % 4 == 0, "{}", i);
+        }
+
+        let sig = calculate_signature_variable(&mut basis.as_slice(), &options(), &[3, 5, 9])
+            .unwrap();
+        let index = SignatureIndex::with_memory_limit(&sig, 5 * INDEX_BYTES_PER_BLOCK);
+        assert!(index.stride() > 1);
+        for i in 0..sig.block_count() {
+            assert_eq!(index.block_offset(i), sig.block_offset(i));
+        }
+    }
+
+    #[test]
+    pub fn empty_signature() {
+        let sig = calculate_signature(&mut &b""[..], &options()).unwrap();
+        let index = SignatureIndex::new(&sig);
+        assert_eq!(index.find_match(0, b""), None);
+    }
+}
@@ -0,0 +1,284 @@
+// rdiff(rust) -- library for network deltas
+// Copyright 2018 Martin Pool.
+
+//! Apply a delta to a file in place, without a second copy of the file.
+//!
+//! A COPY command reads from the basis, but in place the basis is also the output, so a
+//! COPY must run before anything else overwrites the range it reads. The COPYs are
+//! ordered so that each runs before the commands that write over its source. Where the
+//! dependencies form a cycle, one COPY's source is read into memory first, and written
+//! out at the end along with the LITERALs, which are written last since they read
+//! nothing from the file.
+
+use std::fs::File;
+use std::io;
+use std::io::{BufReader, Read, Seek, SeekFrom, Write};
+
+use super::compress::decompressed;
+use super::delta::{check_copy, checksum_hash, finish_checksum, DeltaCommand, DeltaReader};
+use super::error::{Error, Result};
+use super::stats::{Statistics, Timer};
+use super::strongsum::StrongHash;
+
+/// How much is moved at a time by a COPY within the file.
+const COPY_CHUNK: usize = 64 << 10;
+
+/// A file that can be truncated or extended.
+trait SetLen {
+    fn set_len(&mut self, len: u64) -> io::Result<()>;
+}
+
+impl SetLen for File {
+    fn set_len(&mut self, len: u64) -> io::Result<()> {
+        File::set_len(self, len)
+    }
+}
+
+/// A COPY that moves data within the file.
+#[derive(Debug, Clone, Copy)]
+struct Move {
+    src: u64,
+    dest: u64,
+    len: u64,
+}
+
+/// Apply `delta` to `file`, which is both the basis and, afterwards, the new file.
+///
+/// The file must be open for reading and writing. The LITERAL data in the delta, and the
+/// source of any COPYs that have to be staged to break a cycle, are held in memory; only
+/// one copy of the file is ever on disk.
+///
+/// If this fails, or is interrupted, the file is left partly patched and is neither the
+/// basis nor the new file. `Error::CorruptDelta` is returned, before the file is changed,
+/// if the delta copies from beyond the end of the basis. A checksummed delta is checked
+/// against the file after it's patched, giving `Error::ChecksumMismatch` if it's wrong.
+pub fn apply_patch_in_place<D: Read + ?Sized>(file: &mut File, delta: &mut D)
+    -> Result<Statistics> {
+    patch_in_place(file, delta)
+}
+
+fn patch_in_place<F: Read + Write + Seek + SetLen, D: Read + ?Sized>(file: &mut F, delta: &mut D)
+    -> Result<Statistics> {
+    let start = Timer::start();
+    let basis_len = file.seek(SeekFrom::End(0))?;
+    let mut commands = DeltaReader::new(BufReader::new(decompressed(delta)?))?;
+    let mut copies = Vec::new();
+    let mut literals: Vec<(u64, Vec<u8>)> = Vec::new();
+    let mut new_len = 0;
+    for command in commands.by_ref() {
+        match command? {
+            DeltaCommand::Copy { offset, len } => {
+                check_copy(offset, len, basis_len)?;
+                if len > 0 && offset != new_len {
+                    copies.push(Move { src: offset, dest: new_len, len });
+                }
+                new_len += len;
+            }
+            DeltaCommand::Literal(data) => {
+                let l = data.len() as u64;
+                if l > 0 {
+                    literals.push((new_len, data));
+                }
+                new_len += l;
+            }
+            DeltaCommand::End => (),
+        }
+    }
+
+    // `copies` is in order of `dest`, and their destinations don't overlap. Each COPY must
+    // run before those whose destination overlaps its source.
+    let mut after: Vec<Vec<usize>> = vec![Vec::new(); copies.len()];
+    let mut waiting_for = vec![0usize; copies.len()];
+    for (i, c) in copies.iter().enumerate() {
+        let first = copies.partition_point(|d| d.dest + d.len <= c.src);
+        for (j, d) in copies.iter().enumerate().skip(first) {
+            if d.dest >= c.src + c.len {
+                break;
+            }
+            if j != i {
+                after[i].push(j);
+                waiting_for[j] += 1;
+            }
+        }
+    }
+    let mut ready: Vec<usize> = (0..copies.len()).filter(|&i| waiting_for[i] == 0).collect();
+    let mut done = vec![false; copies.len()];
+    let mut next_unfinished = 0;
+    let mut buf = vec![0; COPY_CHUNK];
+    loop {
+        let i = match ready.pop() {
+            Some(i) => {
+                move_within(file, copies[i], &mut buf)?;
+                i
+            }
+            None => {
+                // Everything left is in a cycle, or waiting on one: stage the source of
+                // one COPY in memory, which nothing has yet overwritten, to break it.
+                while next_unfinished < copies.len() && done[next_unfinished] {
+                    next_unfinished += 1;
+                }
+                if next_unfinished == copies.len() {
+                    break;
+                }
+                let c = copies[next_unfinished];
+                let mut data = vec![0; c.len as usize];
+                file.seek(SeekFrom::Start(c.src))?;
+                file.read_exact(&mut data)?;
+                literals.push((c.dest, data));
+                next_unfinished
+            }
+        };
+        done[i] = true;
+        for &j in &after[i] {
+            waiting_for[j] -= 1;
+            if waiting_for[j] == 0 && !done[j] {
+                ready.push(j);
+            }
+        }
+    }
+    for (dest, data) in &literals {
+        file.seek(SeekFrom::Start(*dest))?;
+        file.write_all(data)?;
+    }
+    file.set_len(new_len)?;
+    file.flush()?;
+
+    if let Some(expected) = commands.checksum() {
+        let mut hash = checksum_hash();
+        file.seek(SeekFrom::Start(0))?;
+        let mut reader = (&mut *file).take(new_len);
+        loop {
+            match reader.read(&mut buf)? {
+                0 => break,
+                l => hash.update(&buf[..l]),
+            }
+        }
+        if &finish_checksum(&mut hash) != expected {
+            return Err(Error::ChecksumMismatch);
+        }
+    }
+    let stats = commands.statistics();
+    Ok(Statistics {
+        out_bytes: stats.literal_bytes + stats.copy_bytes,
+        elapsed: start.elapsed(),
+        .. stats.clone()
+    })
+}
+
+/// Copy a range within the file, working from the end backwards if the destination is
+/// after an overlapping source, like `memmove`.
+fn move_within<F: Read + Write + Seek>(file: &mut F, c: Move, buf: &mut [u8]) -> Result<()> {
+    let backwards = c.dest > c.src && c.dest < c.src + c.len;
+    let mut done = 0;
+    while done < c.len {
+        let l = (c.len - done).min(buf.len() as u64);
+        let at = if backwards { c.len - done - l } else { done };
+        let chunk = &mut buf[..l as usize];
+        file.seek(SeekFrom::Start(c.src + at))?;
+        file.read_exact(chunk)?;
+        file.seek(SeekFrom::Start(c.dest + at))?;
+        file.write_all(chunk)?;
+        done += l;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use std::fs;
+    use std::fs::OpenOptions;
+    use std::io::Cursor;
+
+    use tempfile::TempDir;
+
+    use super::*;
+    use super::super::delta::DeltaWriter;
+    use super::super::memory;
+    use super::super::test_util::pattern_seeded;
+
+    impl SetLen for Cursor<Vec<u8>> {
+        fn set_len(&mut self, len: u64) -> io::Result<()> {
+            self.get_mut().resize(len as usize, 0);
+            Ok(())
+        }
+    }
+
+    fn patch_cursor(basis: &[u8], delta: &[u8]) -> Result<Vec<u8>> {
+        let mut file = Cursor::new(basis.to_vec());
+        patch_in_place(&mut file, &mut &delta[..])?;
+        Ok(file.into_inner())
+    }
+
+    fn check(basis: &[u8], delta: &[u8]) {
+        let expected = memory::apply(basis, delta).unwrap();
+        assert_eq!(patch_cursor(basis, delta).unwrap(), expected);
+    }
+
+    #[test]
+    pub fn moves_and_edits() {
+        let old = pattern_seeded(300_000, 0);
+        let mut new = old[100_000..200_000].to_vec();
+        new.extend(pattern_seeded(5000, 7));
+        new.extend_from_slice(&old[..100_000]);
+        new.extend_from_slice(&old[150_000..]);
+        check(&old, &memory::delta_of(&memory::signature_of(&old), &new).unwrap());
+    }
+
+    /// Two ranges that swap places depend on each other, and one has to be staged.
+    #[test]
+    pub fn swap_cycle() {
+        let old = pattern_seeded(200_000, 0);
+        let mut w = DeltaWriter::new(Vec::new()).unwrap();
+        w.copy(100_000, 100_000).unwrap();
+        w.literal(b"between").unwrap();
+        w.copy(0, 100_000).unwrap();
+        check(&old, &w.finish().unwrap());
+    }
+
+    /// Copies whose source and destination overlap are moved like `memmove`.
+    #[test]
+    pub fn overlapping_shifts() {
+        let old = pattern_seeded(300_000, 0);
+        let mut w = DeltaWriter::new(Vec::new()).unwrap();
+        w.literal(&[1; 1000]).unwrap();
+        w.copy(0, 150_000).unwrap();
+        w.copy(160_000, 140_000).unwrap();
+        check(&old, &w.finish().unwrap());
+    }
+
+    #[test]
+    pub fn shrink_and_grow() {
+        let old = pattern_seeded(50_000, 0);
+        check(&old, &memory::delta_of(&memory::signature_of(&old), &old[20_000..]).unwrap());
+        let mut new = old.clone();
+        new.extend_from_slice(&old);
+        check(&old, &memory::delta_of(&memory::signature_of(&old), &new).unwrap());
+    }
+
+    #[test]
+    pub fn bad_copy_leaves_file_unchanged() {
+        let old = pattern_seeded(1000, 0);
+        let mut w = DeltaWriter::new(Vec::new()).unwrap();
+        w.literal(b"hello").unwrap();
+        w.copy(900, 200).unwrap();
+        let mut file = Cursor::new(old.clone());
+        let err = patch_in_place(&mut file, &mut &w.finish().unwrap()[..]).unwrap_err();
+        assert!(matches!(err, Error::CorruptDelta(_)), "{:?}", err);
+        assert_eq!(file.into_inner(), old);
+    }
+
+    #[test]
+    pub fn patch_file() {
+        let tmp = TempDir::new().unwrap();
+        let path = tmp.path().join("file");
+        let old = pattern_seeded(100_000, 0);
+        let mut new = old[60_000..].to_vec();
+        new.extend_from_slice(&old[..30_000]);
+        fs::write(&path, &old).unwrap();
+        let delta = memory::delta_of(&memory::signature_of(&old), &new).unwrap();
+        let mut file = OpenOptions::new().read(true).write(true).open(&path).unwrap();
+        apply_patch_in_place(&mut file, &mut &delta[..]).unwrap();
+        drop(file);
+        assert_eq!(fs::read(&path).unwrap(), new);
+    }
+}
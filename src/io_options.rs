@@ -0,0 +1,32 @@
+// rdiff(rust) -- library for network deltas
+// Copyright 2018 Martin Pool.
+
+//! Sizes of the buffers used for reading and writing.
+
+/// Sizes of the buffers that operations put around their inputs and outputs.
+///
+/// The defaults suit most uses. Small buffers keep memory use down and fixed on
+/// constrained systems; buffers of a megabyte or more mean fewer system calls on large
+/// files.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct IoOptions {
+    /// Size of the buffer for the main input: the new file when generating a delta,
+    /// which is read this much at a time, the delta when patching, and a signature
+    /// being read into memory.
+    ///
+    /// Signature generation reads the basis a block at a time, so doesn't use this. Zero
+    /// means no buffering, and for the new file, reading a byte at a time.
+    pub read_buf: usize,
+
+    /// Size of the buffer for the output: the signature, delta, or new file.
+    pub write_buf: usize,
+}
+
+impl Default for IoOptions {
+    fn default() -> IoOptions {
+        IoOptions {
+            read_buf: 64 << 10,
+            write_buf: 8 << 10,
+        }
+    }
+}
@@ -0,0 +1,560 @@
+// rdiff(rust) -- library for network deltas
+// Copyright 2018 Martin Pool.
+
+//! Jobs that are fed input and drained of output by the caller, like librsync's
+//! `rs_job_t`.
+//!
+//! The functions in `mksum`, `mkdelta` and `patch` pull from a `Read` and push to a
+//! `Write`, blocking as they go. A `Job` instead never does any IO on the input or output
+//! itself: each call to `iter` passes in whatever input is available and a buffer for
+//! output, and the job returns how much of each it used. This suits event-driven
+//! programs that can't block on a stream.
+//!
+//! The basis for a patch is still read directly, since it's normally a local file, or
+//! without `std`, a slice in memory. Jobs are the way to use the library without `std`.
+
+use core::cmp::min;
+use alloc::boxed::Box;
+use alloc::vec::Vec;
+
+#[cfg(feature = "std")]
+use super::basis::BasisProvider;
+use super::cdc::Chunker;
+use super::delta::{check_copy, parse_header, CommandHeader, DeltaCommand, DeltaWriter};
+use super::error::{unexpected_eof, Error, Result};
+use super::index::SignatureIndex;
+use super::magic::{DeltaFormat, SIGNATURE_SEEDED_MAGIC};
+use super::rabinkarp::RabinKarp;
+use super::rollsum::{block_sum, RollingHash, Rollsum1};
+use super::search::{Search, DEFAULT_MAX_LITERAL_LEN};
+use super::signature::{check_options, seed_weak, seeded_strong_sum, SignatureOptions};
+use super::strongsum::StrongHash;
+
+/// At most this much is read from the basis for one step of a patch job.
+const PATCH_CHUNK: usize = 64 << 10;
+
+/// Whether a job has finished.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum JobStatus {
+    /// The job can't go further until it's given more input or more output space.
+    Blocked,
+
+    /// The job is complete, and all its output has been returned.
+    Done,
+}
+
+/// The result of one call to `Job::iter`.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct Progress {
+    pub status: JobStatus,
+
+    /// Number of bytes used from the start of the input.
+    ///
+    /// Input that's not consumed must be passed in again next time.
+    pub consumed: usize,
+
+    /// Number of bytes written to the start of the output buffer.
+    pub produced: usize,
+}
+
+/// One kind of job, consuming input and appending output to a buffer.
+trait Work {
+    /// Consume some of `input`, appending to `out`, and return how much was consumed and
+    /// whether the work is finished.
+    ///
+    /// `eof` is true if `input` holds all the remaining input.
+    fn work(&mut self, input: &[u8], eof: bool, out: &mut Vec<u8>) -> Result<(usize, bool)>;
+}
+
+/// A signature, delta or patch operation, driven by repeated calls to `iter`.
+pub struct Job<'a> {
+    work: Box<dyn Work + Send + 'a>,
+
+    /// Output that's been produced but not yet returned to the caller.
+    out: Vec<u8>,
+
+    /// How much of `out` has already been returned.
+    out_pos: usize,
+
+    finished: bool,
+}
+
+impl<'a> Job<'a> {
+    fn new(work: Box<dyn Work + Send + 'a>) -> Job<'a> {
+        Job { work, out: Vec::new(), out_pos: 0, finished: false }
+    }
+
+    /// Make a job that generates a signature of the input.
+    ///
+    /// `Error::InvalidOptions` is returned if the options are invalid.
+    pub fn signature(options: &SignatureOptions) -> Result<Job<'static>> {
+        let strong = options.magic.strong_hash()?;
+        let options = &check_options(options, &*strong)?;
+        let weak = if options.magic.is_rabinkarp() {
+            block_sum::<RabinKarp>
+        } else {
+            block_sum::<Rollsum1>
+        };
+        let chunker = if options.magic.is_content_defined() {
+            Some(Chunker::new(options.block_len))
+        } else {
+            None
+        };
+        let mut job = Job::new(Box::new(SignatureJob {
+            options: *options,
+            weak,
+            strong,
+            chunker,
+            block: Vec::with_capacity(options.block_len as usize),
+        }));
+        if options.seed != 0 {
+            job.out.extend_from_slice(&SIGNATURE_SEEDED_MAGIC.to_be_bytes());
+            job.out.extend_from_slice(&options.seed.to_be_bytes());
+        }
+        job.out.extend_from_slice(&(options.magic as u32).to_be_bytes());
+        job.out.extend_from_slice(&options.block_len.to_be_bytes());
+        job.out.extend_from_slice(&options.strong_len.to_be_bytes());
+        Ok(job)
+    }
+
+    /// Make a job that reads a new file and generates a delta from an indexed signature.
+    pub fn delta<'s>(index: &'a SignatureIndex<'s>) -> Result<Job<'a>> where 's: 'a {
+        let strong = index.signature().format().strong_hash()?;
+        Ok(if index.signature().format().is_rabinkarp() {
+            Job::new(Box::new(DeltaJob::<RabinKarp>::new(index, strong)?))
+        } else {
+            Job::new(Box::new(DeltaJob::<Rollsum1>::new(index, strong)?))
+        })
+    }
+
+    /// Make a job that reads a delta and applies it to `basis`, producing the new file.
+    ///
+    /// Only a plain `DeltaFormat::Delta` can be applied by a job: other formats give
+    /// `Error::UnsupportedFormat`.
+    #[cfg(feature = "std")]
+    pub fn patch<B: BasisProvider + Send + 'a>(mut basis: B) -> Result<Job<'a>> {
+        let basis_len = basis.size()?;
+        Ok(Job::new(Box::new(PatchJob::new(ProvidedBasis(basis), basis_len))))
+    }
+
+    /// Make a job that reads a delta and applies it to a basis held in memory.
+    pub fn patch_from_slice(basis: &'a [u8]) -> Job<'a> {
+        Job::new(Box::new(PatchJob::new(basis, basis.len() as u64)))
+    }
+
+    /// Run the job as far as possible, like librsync's `rs_job_iter`.
+    ///
+    /// `input` is the data available now, and `eof_in` says whether it's the end of the
+    /// input. Output is written into `output`. The job stops when it's done, when it
+    /// needs more input, or when `output` is full: in the last case it may hold some of
+    /// the input internally, and there will be more output on the next call.
+    ///
+    /// Errors are returned for invalid input, as they would be by the corresponding
+    /// blocking function. After an error the job should not be used again.
+    pub fn iter(&mut self, input: &[u8], eof_in: bool, output: &mut [u8]) -> Result<Progress> {
+        let mut consumed = 0;
+        let mut produced = 0;
+        loop {
+            let n = min(output.len() - produced, self.out.len() - self.out_pos);
+            output[produced..(produced + n)]
+                .copy_from_slice(&self.out[self.out_pos..(self.out_pos + n)]);
+            produced += n;
+            self.out_pos += n;
+            if self.out_pos < self.out.len() {
+                return Ok(Progress { status: JobStatus::Blocked, consumed, produced });
+            }
+            self.out.clear();
+            self.out_pos = 0;
+            if self.finished {
+                return Ok(Progress { status: JobStatus::Done, consumed, produced });
+            }
+            let rest = &input[consumed..];
+            let (c, finished) = self.work.work(rest, eof_in, &mut self.out)?;
+            consumed += c;
+            self.finished = finished;
+            if c == 0 && !finished && self.out.is_empty() {
+                if eof_in {
+                    return Err(unexpected_eof("input ended before the job was complete"));
+                }
+                return Ok(Progress { status: JobStatus::Blocked, consumed, produced });
+            }
+        }
+    }
+}
+
+struct SignatureJob {
+    options: SignatureOptions,
+    weak: fn(&[u8]) -> u32,
+    strong: Box<dyn StrongHash + Send>,
+
+    /// For a content-defined format, finds where each chunk ends.
+    chunker: Option<Chunker>,
+
+    /// Data for the current, incomplete, block; or for a content-defined format, data
+    /// not yet divided into chunks.
+    block: Vec<u8>,
+}
+
+impl SignatureJob {
+    /// Write the sums of the first `len` bytes of `block`, and remove them.
+    fn write_block(&mut self, len: usize, out: &mut Vec<u8>) -> Result<()> {
+        let block = &self.block[..len];
+        if self.chunker.is_some() {
+            out.extend_from_slice(&(len as u32).to_be_bytes());
+        }
+        let seed = self.options.seed;
+        out.extend_from_slice(&seed_weak((self.weak)(block), seed).to_be_bytes());
+        let l = out.len();
+        out.resize(l + self.options.strong_len as usize, 0);
+        seeded_strong_sum(&mut *self.strong, seed, block, &mut out[l..]);
+        self.block.drain(..len);
+        Ok(())
+    }
+}
+
+impl Work for SignatureJob {
+    fn work(&mut self, mut input: &[u8], eof: bool, out: &mut Vec<u8>) -> Result<(usize, bool)> {
+        let consumed = input.len();
+        if let Some(chunker) = self.chunker {
+            self.block.extend_from_slice(input);
+            while let Some(len) = chunker.next_chunk(&self.block, eof) {
+                if len == 0 {
+                    break;
+                }
+                self.write_block(len, out)?;
+            }
+            return Ok((consumed, eof));
+        }
+        let block_len = self.options.block_len as usize;
+        while !input.is_empty() {
+            let n = min(block_len - self.block.len(), input.len());
+            self.block.extend_from_slice(&input[..n]);
+            input = &input[n..];
+            if self.block.len() == block_len {
+                self.write_block(block_len, out)?;
+            }
+        }
+        if eof && !self.block.is_empty() {
+            self.write_block(self.block.len(), out)?;
+        }
+        Ok((consumed, eof))
+    }
+}
+
+struct DeltaJob<'i, 's: 'i, R> {
+    search: Search<'i, 's, R>,
+    strong: Box<dyn StrongHash + Send>,
+    writer: DeltaWriter<Vec<u8>>,
+}
+
+impl<'i, 's, R: RollingHash + Default> DeltaJob<'i, 's, R> {
+    fn new(index: &'i SignatureIndex<'s>, strong: Box<dyn StrongHash + Send>)
+        -> Result<DeltaJob<'i, 's, R>> {
+        Ok(DeltaJob {
+            search: Search::new(index, DEFAULT_MAX_LITERAL_LEN),
+            strong,
+            writer: DeltaWriter::new(Vec::new())?,
+        })
+    }
+}
+
+impl<'i, 's, R: RollingHash + Default> Work for DeltaJob<'i, 's, R> {
+    fn work(&mut self, input: &[u8], eof: bool, out: &mut Vec<u8>) -> Result<(usize, bool)> {
+        self.search.buf.extend_from_slice(input);
+        self.search.process(&mut *self.strong, eof, &mut self.writer)?;
+        if eof {
+            self.writer.write_command(&DeltaCommand::End)?;
+        }
+        out.append(self.writer.get_mut());
+        Ok((input.len(), eof))
+    }
+}
+
+enum PatchState {
+    /// Waiting for the delta magic number.
+    Magic,
+    /// Waiting for the next command.
+    Command,
+    /// Passing through this many more bytes of literal data.
+    Literal(u64),
+    /// Copying from the basis.
+    Copy { offset: u64, len: u64 },
+    Done,
+}
+
+/// Where a patch job gets the data for COPY commands.
+trait Basis {
+    /// Fill `buf` from `offset`, which has been checked to lie within the basis.
+    fn read_exact_at(&mut self, offset: u64, buf: &mut [u8]) -> Result<()>;
+}
+
+impl Basis for &[u8] {
+    fn read_exact_at(&mut self, offset: u64, buf: &mut [u8]) -> Result<()> {
+        let offset = offset as usize;
+        buf.copy_from_slice(&self[offset..(offset + buf.len())]);
+        Ok(())
+    }
+}
+
+/// A basis that's read through a `BasisProvider`.
+#[cfg(feature = "std")]
+struct ProvidedBasis<B>(B);
+
+#[cfg(feature = "std")]
+impl<B: BasisProvider> Basis for ProvidedBasis<B> {
+    fn read_exact_at(&mut self, offset: u64, buf: &mut [u8]) -> Result<()> {
+        Ok(self.0.read_at(offset, buf)?)
+    }
+}
+
+struct PatchJob<B> {
+    basis: B,
+    basis_len: u64,
+
+    /// Delta data that's been consumed but not yet decoded.
+    inbuf: Vec<u8>,
+
+    state: PatchState,
+}
+
+impl<B: Basis> PatchJob<B> {
+    fn new(basis: B, basis_len: u64) -> PatchJob<B> {
+        PatchJob { basis, basis_len, inbuf: Vec::new(), state: PatchState::Magic }
+    }
+
+    /// Take one step, returning false if more input is needed.
+    fn step(&mut self, out: &mut Vec<u8>) -> Result<bool> {
+        match self.state {
+            PatchState::Magic => {
+                if self.inbuf.len() < 4 {
+                    return Ok(false);
+                }
+                let magic = u32::from(self.inbuf[0]) << 24 | u32::from(self.inbuf[1]) << 16
+                    | u32::from(self.inbuf[2]) << 8 | u32::from(self.inbuf[3]);
+                if DeltaFormat::check_magic(magic)? != DeltaFormat::Delta {
+                    return Err(Error::UnsupportedFormat(magic));
+                }
+                self.inbuf.drain(..4);
+                self.state = PatchState::Command;
+            }
+            PatchState::Command => {
+                let (header, l) = match parse_header(&self.inbuf)? {
+                    Some(h) => h,
+                    None => return Ok(false),
+                };
+                self.inbuf.drain(..l);
+                self.state = match header {
+                    CommandHeader::End => PatchState::Done,
+                    CommandHeader::Literal { len } => PatchState::Literal(len),
+                    CommandHeader::Copy { offset, len } => {
+                        check_copy(offset, len, self.basis_len)?;
+                        PatchState::Copy { offset, len }
+                    }
+                };
+            }
+            PatchState::Literal(0) | PatchState::Copy { len: 0, .. } => {
+                self.state = PatchState::Command;
+            }
+            PatchState::Literal(len) => {
+                if self.inbuf.is_empty() {
+                    return Ok(false);
+                }
+                let n = min(len, self.inbuf.len() as u64) as usize;
+                out.extend(self.inbuf.drain(..n));
+                self.state = PatchState::Literal(len - n as u64);
+            }
+            PatchState::Copy { offset, len } => {
+                let n = min(len, PATCH_CHUNK as u64) as usize;
+                let l = out.len();
+                out.resize(l + n, 0);
+                self.basis.read_exact_at(offset, &mut out[l..])?;
+                self.state = PatchState::Copy { offset: offset + n as u64, len: len - n as u64 };
+            }
+            PatchState::Done => return Ok(false),
+        }
+        Ok(true)
+    }
+}
+
+impl<B: Basis> Work for PatchJob<B> {
+    fn work(&mut self, input: &[u8], _eof: bool, out: &mut Vec<u8>) -> Result<(usize, bool)> {
+        if let PatchState::Done = self.state {
+            return Ok((0, true));
+        }
+        self.inbuf.extend_from_slice(input);
+        while out.len() < PATCH_CHUNK && self.step(out)? {}
+        Ok((input.len(), matches!(self.state, PatchState::Done)))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::io::{Cursor, ErrorKind};
+    use std::mem;
+
+    use super::*;
+    use super::super::magic::SignatureFormat;
+    use super::super::mkdelta::generate_delta;
+    use super::super::mksum::{calculate_signature, generate_signature};
+    use super::super::patch::apply_patch;
+    use super::super::test_util::pattern;
+
+    /// Run a job to completion, feeding it `in_chunk` bytes and draining `out_chunk`
+    /// bytes at a time.
+    fn run(job: &mut Job, input: &[u8], in_chunk: usize, out_chunk: usize) -> Result<Vec<u8>> {
+        let mut output = Vec::new();
+        let mut pos = 0;
+        let mut buf = vec![0; out_chunk];
+        loop {
+            let end = min(input.len(), pos + in_chunk);
+            let p = job.iter(&input[pos..end], end == input.len(), &mut buf)?;
+            pos += p.consumed;
+            output.extend_from_slice(&buf[..p.produced]);
+            if p.status == JobStatus::Done {
+                return Ok(output);
+            }
+        }
+    }
+
+    fn options() -> SignatureOptions {
+        SignatureOptions {
+            magic: SignatureFormat::RkBlake2Sig,
+            block_len: 1000,
+            .. SignatureOptions::default()
+        }.with_strong_len(16)
+    }
+
+    #[test]
+    pub fn signature_job() {
+        let basis = pattern(10_500);
+        let mut expected = Vec::new();
+        generate_signature(&mut basis.as_slice(), &options(), &mut expected).unwrap();
+        for &(in_chunk, out_chunk) in &[(1, 1), (7, 3), (999, 100), (100_000, 100_000)] {
+            let mut job = Job::signature(&options()).unwrap();
+            assert_eq!(run(&mut job, &basis, in_chunk, out_chunk).unwrap(), expected);
+        }
+        let mut job = Job::signature(&options()).unwrap();
+        assert_eq!(run(&mut job, b"", 1, 1).unwrap(), &expected[..12]);
+    }
+
+    #[test]
+    pub fn seeded_signature_job() {
+        let options = SignatureOptions { seed: 99, .. options() };
+        let basis = pattern(10_500);
+        let mut expected = Vec::new();
+        generate_signature(&mut basis.as_slice(), &options, &mut expected).unwrap();
+        let mut job = Job::signature(&options).unwrap();
+        assert_eq!(run(&mut job, &basis, 999, 100).unwrap(), expected);
+    }
+
+    /// Content-defined chunks are found the same way however the input is fed in.
+    #[test]
+    pub fn content_defined_jobs() {
+        let options = SignatureOptions {
+            magic: SignatureFormat::CdcBlake2Sig,
+            block_len: 256,
+            .. SignatureOptions::default()
+        };
+        let basis = pattern(10_500);
+        let mut expected = Vec::new();
+        generate_signature(&mut basis.as_slice(), &options, &mut expected).unwrap();
+        for &(in_chunk, out_chunk) in &[(1, 1), (7, 3), (999, 100), (100_000, 100_000)] {
+            let mut job = Job::signature(&options).unwrap();
+            assert_eq!(run(&mut job, &basis, in_chunk, out_chunk).unwrap(), expected);
+        }
+
+        let mut new = basis[4000..].to_vec();
+        new.extend_from_slice(b"something new");
+        new.extend_from_slice(&basis[..4000]);
+        let sig = calculate_signature(&mut basis.as_slice(), &options).unwrap();
+        let mut expected = Vec::new();
+        generate_delta(&sig, &mut new.as_slice(), &mut expected).unwrap();
+        let index = SignatureIndex::new(&sig);
+        for &(in_chunk, out_chunk) in &[(1, 1), (100, 7), (100_000, 100_000)] {
+            let mut job = Job::delta(&index).unwrap();
+            assert_eq!(run(&mut job, &new, in_chunk, out_chunk).unwrap(), expected);
+        }
+    }
+
+    #[test]
+    pub fn signature_job_bad_options() {
+        let options = SignatureOptions { magic: SignatureFormat::Md4Sig, .. options() }
+            .with_strong_len(32);
+        assert_eq!(Job::signature(&options).err().unwrap().kind(), ErrorKind::InvalidInput);
+        let options = SignatureOptions { block_len: 0, .. options.with_strong_len(8) };
+        assert_eq!(Job::signature(&options).err().unwrap().kind(), ErrorKind::InvalidInput);
+    }
+
+    #[test]
+    pub fn delta_job() {
+        let basis = pattern(10_500);
+        let mut new = basis[4000..].to_vec();
+        new.extend_from_slice(b"something new");
+        new.extend_from_slice(&basis[..4000]);
+        let sig = calculate_signature(&mut basis.as_slice(), &options()).unwrap();
+        let mut expected = Vec::new();
+        generate_delta(&sig, &mut new.as_slice(), &mut expected).unwrap();
+        let index = SignatureIndex::new(&sig);
+        for &(in_chunk, out_chunk) in &[(1, 1), (100, 7), (5000, 5000), (100_000, 100_000)] {
+            let mut job = Job::delta(&index).unwrap();
+            assert_eq!(run(&mut job, &new, in_chunk, out_chunk).unwrap(), expected);
+        }
+    }
+
+    #[test]
+    pub fn patch_job() {
+        let basis = pattern(200_000);
+        let mut new = basis[4000..].to_vec();
+        new.extend_from_slice(b"something new");
+        new.extend_from_slice(&basis[..4000]);
+        let sig = calculate_signature(&mut basis.as_slice(), &options()).unwrap();
+        let mut delta = Vec::new();
+        generate_delta(&sig, &mut new.as_slice(), &mut delta).unwrap();
+        for &(in_chunk, out_chunk) in &[(1, 1000), (3, 77), (100_000, 100_000)] {
+            let mut job = Job::patch(Cursor::new(&basis)).unwrap();
+            assert_eq!(run(&mut job, &delta, in_chunk, out_chunk).unwrap(), new);
+            let mut job = Job::patch_from_slice(&basis);
+            assert_eq!(run(&mut job, &delta, in_chunk, out_chunk).unwrap(), new);
+        }
+    }
+
+    /// Errors are the same as from `apply_patch`.
+    #[test]
+    pub fn patch_job_errors() {
+        for delta in &[&[b'r', b's', 0x01, 0x36, 0][..],
+                       &[b'r', b's', 0x02, 0x36, 0x55][..],
+                       &[b'r', b's', 0x02, 0x36, 0x45, 3, 3, 0][..],
+                       &[b'r', b's', 0x02, 0x36, 4, b'a'][..],
+                       &[b'r', b's', 0x02][..]] {
+            let mut job = Job::patch(Cursor::new(b"hello")).unwrap();
+            let err = run(&mut job, delta, 1, 10).unwrap_err();
+            let expected = apply_patch(&mut Cursor::new(b"hello"), &mut &delta[..],
+                                       &mut Vec::new()).unwrap_err();
+            assert_eq!(err.kind(), expected.kind(), "{:?}", delta);
+            assert_eq!(mem::discriminant(&err), mem::discriminant(&expected), "{:?}", delta);
+        }
+        let mut job = Job::patch(Cursor::new(b"hello")).unwrap();
+        let err = run(&mut job, b"rs\x836\x00", 1, 10).unwrap_err();
+        assert!(matches!(err, Error::UnsupportedFormat(0x72738336)), "{:?}", err);
+    }
+
+    /// Once the END command has been seen, no more input is consumed.
+    #[test]
+    pub fn patch_job_stops_at_end() {
+        let mut job = Job::patch(Cursor::new(b"")).unwrap();
+        let mut out = [0u8; 10];
+        let p = job.iter(&[b'r', b's', 0x02, 0x36, 1, b'a', 0], false, &mut out).unwrap();
+        assert_eq!(p, Progress { status: JobStatus::Done, consumed: 7, produced: 1 });
+        let p = job.iter(b"more", false, &mut out).unwrap();
+        assert_eq!(p, Progress { status: JobStatus::Done, consumed: 0, produced: 0 });
+    }
+
+    /// A job with no input available, and not at eof, is blocked.
+    #[test]
+    pub fn blocked_without_input() {
+        let mut job = Job::patch(Cursor::new(b"")).unwrap();
+        let mut out = [0u8; 10];
+        let p = job.iter(b"", false, &mut out).unwrap();
+        assert_eq!(p, Progress { status: JobStatus::Blocked, consumed: 0, produced: 0 });
+    }
+}
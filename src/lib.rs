@@ -7,16 +7,105 @@
 //! and the same algorithm and format as `rdiff`.
 //!
 //! Homepage: <https://github.com/sourcefrog/rdiff-rs>.
+//!
+//! Without the default `std` feature, the crate is `no_std` and needs only `alloc`. The
+//! weak and strong hashes, in-memory signatures and their indexes, the delta encoder,
+//! and `Job`s for generating signatures and deltas and applying them to a basis in
+//! memory are all available; the functions that read and write `std::io` streams and
+//! files are not.
+
+#![cfg_attr(not(feature = "std"), no_std)]
 
+extern crate alloc;
 extern crate blake2;
+#[cfg(feature = "blake3")]
+extern crate blake3;
 extern crate byteorder;
 extern crate cast;
+#[cfg(feature = "tokio")]
+extern crate futures_util;
+extern crate md4;
+#[cfg(feature = "mmap")]
+extern crate memmap2;
+#[cfg(feature = "parallel")]
+extern crate rayon;
+#[cfg(feature = "tokio")]
+extern crate tokio;
+#[cfg(feature = "tracing")]
+extern crate tracing;
+#[cfg(feature = "wasm")]
+extern crate wasm_bindgen;
 
+#[macro_use]
+mod trace;
+
+#[cfg(feature = "std")]
+pub mod analyze;
+#[cfg(feature = "tokio")]
+pub mod async_io;
+#[cfg(feature = "std")]
+pub mod basis;
+#[cfg(feature = "std")]
+pub mod cancel;
+mod cdc;
+pub mod compat;
+pub mod compact;
+#[cfg(feature = "std")]
+pub mod compose;
+#[cfg(feature = "std")]
+pub mod compress;
+pub mod delta;
+pub mod error;
+// There's no filesystem on `wasm32-unknown-unknown`.
+#[cfg(all(feature = "std", not(all(target_arch = "wasm32", target_os = "unknown"))))]
+pub mod files;
+#[cfg(feature = "http")]
+pub mod http;
+pub mod index;
+#[cfg(all(feature = "std", not(all(target_arch = "wasm32", target_os = "unknown"))))]
+pub mod inplace;
+pub mod io_options;
+pub mod job;
 pub mod magic;
+#[cfg(feature = "std")]
+pub mod memory;
+#[cfg(feature = "std")]
+pub mod mkdelta;
+#[cfg(feature = "std")]
 pub mod mksum;
+#[cfg(feature = "std")]
+pub mod multires;
+#[cfg(feature = "std")]
+pub mod patch;
+#[cfg(all(feature = "std", not(all(target_arch = "wasm32", target_os = "unknown"))))]
+mod pipeline;
+#[cfg(feature = "std")]
+pub mod progress;
+pub mod rabinkarp;
+#[cfg(feature = "std")]
+pub mod reverse;
 pub mod rollsum;
+mod search;
+pub mod signature;
+mod simd;
+#[cfg(feature = "std")]
+pub mod sparse;
+pub mod stats;
+pub mod strongsum;
+#[cfg(feature = "std")]
+pub mod text;
+#[cfg(all(feature = "std", not(all(target_arch = "wasm32", target_os = "unknown"))))]
+pub mod tree;
+#[cfg(test)]
+mod test_util;
+#[cfg(all(feature = "io-uring", target_os = "linux"))]
+mod uring;
+#[cfg(feature = "wasm")]
+pub mod wasm;
+
+pub use error::{Error, Result};
 
 /// Semver string for this library.
-pub static VERSION: &'static str = env!("CARGO_PKG_VERSION");
+pub static VERSION: &str = env!("CARGO_PKG_VERSION");
 
 pub const DEFAULT_BLOCK_LEN: u32 = 2048;
\ No newline at end of file
@@ -7,21 +7,237 @@
  
 #![allow(dead_code)]
 
+use alloc::boxed::Box;
+
+#[cfg(feature = "blake3")]
+use super::strongsum::Blake3Hash;
+use super::error::{Error, Result};
+use super::strongsum::{Blake2Hash, Md4Hash, StrongHash};
+
+/// Magic number of a v2 signature file, in which each block has its own length.
+///
+/// It's followed by the magic number of the `SignatureFormat` giving the weak and strong
+/// hashes, then the length of the longest block and the strong sum length, and then for
+/// each block its length, weak sum and strong sum.
+///
+/// This is an extension of this library, not understood by librsync.
+pub const SIGNATURE_V2_MAGIC: u32 = 0x72738153;  // "rs\x81S"
+
+/// Magic number of a signature file whose sums are mixed with a seed.
+///
+/// It's followed by the seed, and then by a signature file as usual, starting with its
+/// own magic number, which may be `SIGNATURE_V2_MAGIC`.
+///
+/// This is an extension of this library, not understood by librsync.
+pub const SIGNATURE_SEEDED_MAGIC: u32 = 0x72738253;  // "rs\x82S"
+
+/// Magic number of a multi-resolution signature file, holding signatures of one basis at
+/// several block lengths, written by `multires::generate_multi_signature`.
+///
+/// It's followed by the length of the basis as a u64 and the number of levels, and then
+/// for each level, longest blocks first, its length in bytes as a u64 and a signature
+/// file as usual.
+///
+/// This is an extension of this library, not understood by librsync.
+pub const SIGNATURE_MULTI_MAGIC: u32 = 0x72738353;  // "rs\x83S"
+
+/// Magic number of a signature in the compact form for storing in memory or a database,
+/// written by `Signature::to_bytes`: see `compact`.
+///
+/// This is an extension of this library, not understood by librsync.
+pub const SIGNATURE_COMPACT_MAGIC: u32 = 0x72738453;  // "rs\x84S"
+
+/// Magic number of a manifest of a directory tree, written by `tree::signature_dir`.
+///
+/// This is an extension of this library, not understood by librsync.
+pub const TREE_MANIFEST_MAGIC: u32 = 0x72738154;  // "rs\x81T"
+
+/// Magic number of a delta archive for a directory tree, written by `tree::delta_dir`.
+///
+/// This is an extension of this library, not understood by librsync.
+pub const TREE_DELTA_MAGIC: u32 = 0x72738254;  // "rs\x82T"
+
 /// Delta file formats.
-#[derive(Debug, Copy, Clone)]
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
 pub enum DeltaFormat {
-    /// A delta file. 
-    ///
-    /// There's only one format so far.
+    /// A delta file, as written by librsync.
     Delta = 0x72730236,    // "rs\x026"
+
+    /// A delta followed, after its END command, by the 32-byte BLAKE2b hash of the whole
+    /// new file, so that the result of patching can be checked.
+    ///
+    /// This is an extension of this library, not understood by librsync.
+    ChecksummedDelta = 0x72738236,  // "rs\x826"
+
+    /// A delta whose longer LITERAL commands may carry their data compressed with zstd,
+    /// using opcodes that librsync doesn't have. Each compressed literal is a separate
+    /// zstd frame, so the delta can still be applied as it streams in.
+    ///
+    /// This is an extension of this library, not understood by librsync. It can only be
+    /// read or written with the `zstd` feature.
+    CompressedDelta = 0x72738336,  // "rs\x836"
+
+    /// A delta against several bases, whose COPY commands refer to whichever basis was
+    /// most recently selected by a BASIS command, another opcode that librsync doesn't
+    /// have. Until then, they refer to the first.
+    ///
+    /// This is an extension of this library, not understood by librsync.
+    MultiBasisDelta = 0x72738436,  // "rs\x846"
+
+    /// A delta whose header, after the magic, identifies the basis it was made from by a
+    /// `delta::BasisId`, so that patching can check it's given the right basis.
+    ///
+    /// This is an extension of this library, not understood by librsync.
+    BasisCheckedDelta = 0x72738536,  // "rs\x856"
+}
+
+impl DeltaFormat {
+    /// Find the delta format with the given magic number, if there is one.
+    pub fn from_magic(magic: u32) -> Option<DeltaFormat> {
+        match magic {
+            0x72730236 => Some(DeltaFormat::Delta),
+            0x72738236 => Some(DeltaFormat::ChecksummedDelta),
+            0x72738336 => Some(DeltaFormat::CompressedDelta),
+            0x72738436 => Some(DeltaFormat::MultiBasisDelta),
+            0x72738536 => Some(DeltaFormat::BasisCheckedDelta),
+            _ => None,
+        }
+    }
+
+    /// Find the delta format with the given magic number.
+    ///
+    /// A compressed delta, when the `zstd` feature is off, gives `UnsupportedFormat`.
+    /// Anything else that's not a known delta gives `BadMagic`.
+    pub(crate) fn check_magic(magic: u32) -> Result<DeltaFormat> {
+        let format = DeltaFormat::from_magic(magic).ok_or(Error::BadMagic(magic))?;
+        format.check_built_in()?;
+        Ok(format)
+    }
+
+    /// `Error::UnsupportedFormat` if deltas in this format can't be read or written,
+    /// because their compression isn't built in.
+    pub(crate) fn check_built_in(self) -> Result<()> {
+        match self {
+            #[cfg(not(feature = "zstd"))]
+            DeltaFormat::CompressedDelta => Err(Error::UnsupportedFormat(self as u32)),
+            _ => Ok(()),
+        }
+    }
 }
 
 /// Signature file formats.
-#[derive(Debug, Copy, Clone)]
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub enum SignatureFormat {
-    /// A signature file with MD4 magic. (Deprecated because insecure).
+    /// A signature file with rollsum weak sums and MD4 strong sums.
+    ///
+    /// Compatible with librsync before 1.0, but deprecated because MD4 collisions can be
+    /// constructed, which is a risk for files containing partly untrusted data.
     Md4Sig = 0x72730136,   // "rs\x016"
 
-    /// A signature file with BLAKE2 strong signatures. (Current.)
+    /// A signature file with RabinKarp weak sums and MD4 strong sums.
+    ///
+    /// Produced by librsync 2.2 and later when MD4 is requested.
+    RkMd4Sig = 0x72730146,  // "rs\x01F"
+
+    /// A signature file with BLAKE2 strong signatures.
     Blake2Sig = 0x72730137,  // "rs\x017"
-}
\ No newline at end of file
+
+    /// A signature file with RabinKarp weak sums and BLAKE2 strong sums.
+    ///
+    /// The default for librsync 2.2 and later.
+    RkBlake2Sig = 0x72730147,  // "rs\x01G"
+
+    /// A signature file with RabinKarp weak sums and BLAKE3 strong sums.
+    ///
+    /// This is an extension of this library, not understood by librsync. The high bit of
+    /// the third byte marks it as such. Signatures in this format can only be made or used
+    /// with the `blake3` feature: without it, they give `Error::UnsupportedFormat`.
+    Blake3Sig = 0x72738147,  // "rs\x81G"
+
+    /// A signature of content-defined chunks of the basis, with RabinKarp weak sums and
+    /// BLAKE2 strong sums.
+    ///
+    /// Chunk boundaries are chosen by a Gear hash of the data, so an insertion or
+    /// deletion moves only the boundaries near it, rather than shifting every later
+    /// block. The block length is the average length of a chunk, and each chunk's length
+    /// is stored before its weak sum.
+    ///
+    /// This is an extension of this library, not understood by librsync.
+    CdcBlake2Sig = 0x72738247,  // "rs\x82G"
+}
+
+impl SignatureFormat {
+    /// Length of the untruncated strong sums for this format.
+    pub fn max_strong_len(self) -> u32 {
+        match self {
+            SignatureFormat::Md4Sig | SignatureFormat::RkMd4Sig => 16,
+            SignatureFormat::Blake2Sig | SignatureFormat::RkBlake2Sig
+                | SignatureFormat::CdcBlake2Sig => 32,
+            SignatureFormat::Blake3Sig => 32,
+        }
+    }
+
+    /// Make a new hasher for this format's strong sums.
+    ///
+    /// `Error::UnsupportedFormat` is returned if the hash isn't built in.
+    pub fn strong_hash(self) -> Result<Box<dyn StrongHash + Send>> {
+        Ok(match self {
+            SignatureFormat::Md4Sig | SignatureFormat::RkMd4Sig => Box::new(Md4Hash::default()),
+            SignatureFormat::Blake2Sig | SignatureFormat::RkBlake2Sig
+                | SignatureFormat::CdcBlake2Sig => Box::new(Blake2Hash::default()),
+            #[cfg(feature = "blake3")]
+            SignatureFormat::Blake3Sig => Box::new(Blake3Hash::default()),
+            #[cfg(not(feature = "blake3"))]
+            SignatureFormat::Blake3Sig => return Err(Error::UnsupportedFormat(self as u32)),
+        })
+    }
+
+    /// `Error::UnsupportedFormat` if signatures in this format can't be made or used,
+    /// because its hash isn't built in.
+    pub(crate) fn check_built_in(self) -> Result<()> {
+        match self {
+            #[cfg(not(feature = "blake3"))]
+            SignatureFormat::Blake3Sig => Err(Error::UnsupportedFormat(self as u32)),
+            _ => Ok(()),
+        }
+    }
+
+    /// True if this format uses RabinKarp weak sums, rather than the original rollsum.
+    pub fn is_rabinkarp(self) -> bool {
+        match self {
+            SignatureFormat::RkMd4Sig | SignatureFormat::RkBlake2Sig
+                | SignatureFormat::CdcBlake2Sig | SignatureFormat::Blake3Sig => true,
+            SignatureFormat::Md4Sig | SignatureFormat::Blake2Sig => false,
+        }
+    }
+
+    /// True if this format divides the basis into content-defined chunks, rather than
+    /// blocks of a fixed length.
+    pub fn is_content_defined(self) -> bool {
+        self == SignatureFormat::CdcBlake2Sig
+    }
+
+    /// Find the signature format with the given magic number, if there is one.
+    pub fn from_magic(magic: u32) -> Option<SignatureFormat> {
+        match magic {
+            0x72730136 => Some(SignatureFormat::Md4Sig),
+            0x72730146 => Some(SignatureFormat::RkMd4Sig),
+            0x72730137 => Some(SignatureFormat::Blake2Sig),
+            0x72730147 => Some(SignatureFormat::RkBlake2Sig),
+            0x72738247 => Some(SignatureFormat::CdcBlake2Sig),
+            0x72738147 => Some(SignatureFormat::Blake3Sig),
+            _ => None,
+        }
+    }
+
+    /// Find the signature format with the given magic number.
+    ///
+    /// A BLAKE3 signature, when that feature is off, gives `UnsupportedFormat`. Anything
+    /// else that's not a known signature gives `BadMagic`.
+    pub(crate) fn check_magic(magic: u32) -> Result<SignatureFormat> {
+        let format = SignatureFormat::from_magic(magic).ok_or(Error::BadMagic(magic))?;
+        format.check_built_in()?;
+        Ok(format)
+    }
+}
@@ -0,0 +1,100 @@
+// rdiff(rust) -- library for network deltas
+// Copyright 2018 Martin Pool.
+
+//! Operations on data that's already in memory, taking and returning byte vectors.
+
+use super::error::Result;
+use super::mkdelta::generate_delta;
+use super::mksum::{generate_signature, SignatureOptions};
+use super::patch::apply_patch_from_slice;
+use super::signature::Signature;
+use super::stats::Statistics;
+
+/// Return the signature of `basis`, generated with the default options.
+pub fn signature_of(basis: &[u8]) -> Vec<u8> {
+    signature_with_options(basis, &SignatureOptions::default())
+        .expect("default options are valid")
+}
+
+/// Return the signature of `basis`.
+///
+/// This fails only if the options are invalid.
+pub fn signature_with_options(basis: &[u8], options: &SignatureOptions) -> Result<Vec<u8>> {
+    let mut sig = Vec::new();
+    generate_signature(&mut &basis[..], options, &mut sig)?;
+    Ok(sig)
+}
+
+/// Return a delta from the basis described by the signature file contents `sig`, to
+/// `new`.
+pub fn delta_of(sig: &[u8], new: &[u8]) -> Result<Vec<u8>> {
+    let sig = Signature::read_from(&mut &sig[..])?;
+    let mut delta = Vec::new();
+    generate_delta(&sig, &mut &new[..], &mut delta)?;
+    Ok(delta)
+}
+
+/// Apply `delta` to `basis`, returning the new file.
+pub fn apply(basis: &[u8], delta: &[u8]) -> Result<Vec<u8>> {
+    let mut out = Vec::new();
+    apply_into(basis, delta, &mut out)?;
+    Ok(out)
+}
+
+/// Apply `delta` to `basis`, returning the new file, without any file or stream in
+/// between: the same as `apply`. `apply_into` writes into a buffer the caller keeps.
+pub fn apply_in_memory(basis: &[u8], delta: &[u8]) -> Result<Vec<u8>> {
+    apply(basis, delta)
+}
+
+/// Apply `delta` to `basis`, replacing the contents of `out` with the new file.
+///
+/// `out` keeps its allocation, so applying many small deltas through the same `Vec`
+/// doesn't allocate for each. If this fails, `out` holds whatever was written before the
+/// error.
+pub fn apply_into(basis: &[u8], delta: &[u8], out: &mut Vec<u8>) -> Result<Statistics> {
+    out.clear();
+    apply_patch_from_slice(basis, &mut &delta[..], out)
+}
+
+#[cfg(test)]
+mod test {
+    use std::io::ErrorKind;
+
+    use super::*;
+    use super::super::test_util::pattern;
+
+    #[test]
+    pub fn round_trip() {
+        let basis = pattern(20_480);
+        let mut new = basis[4096..].to_vec();
+        new.extend_from_slice(b"a little extra");
+        let delta = delta_of(&signature_of(&basis), &new).unwrap();
+        assert!(delta.len() < 200);
+        assert_eq!(apply(&basis, &delta).unwrap(), new);
+        assert_eq!(apply_in_memory(&basis, &delta).unwrap(), new);
+    }
+
+    #[test]
+    pub fn apply_into_reuses_buffer() {
+        let basis = pattern(20_480);
+        let mut out = b"left over from before".repeat(2000);
+        let capacity = out.capacity();
+        for new in &[basis[100..].to_vec(), b"short".to_vec()] {
+            let delta = delta_of(&signature_of(&basis), new).unwrap();
+            let stats = apply_into(&basis, &delta, &mut out).unwrap();
+            assert_eq!(&out, new);
+            assert_eq!(stats.out_bytes, new.len() as u64);
+            assert_eq!(out.capacity(), capacity);
+        }
+    }
+
+    #[test]
+    pub fn errors() {
+        assert_eq!(delta_of(b"rs\x02\x36", b"").unwrap_err().kind(), ErrorKind::InvalidData);
+        assert_eq!(apply(b"", b"rs\x01\x37").unwrap_err().kind(), ErrorKind::InvalidData);
+        let options = SignatureOptions::default().with_strong_len(33);
+        assert_eq!(signature_with_options(b"", &options).unwrap_err().kind(),
+                   ErrorKind::InvalidInput);
+    }
+}
@@ -0,0 +1,1502 @@
+// rdiff(rust) -- library for network deltas
+// Copyright 2018 Martin Pool.
+
+//! Generate deltas from a signature and a new file.
+//!
+//! The weak checksum is rolled one byte at a time across the new file. When it matches
+//! the weak sum of some basis block, the strong sum of the window is checked too, and if
+//! that also matches a COPY command is emitted referring to the basis. Bytes that don't
+//! fall within any matched block are sent as LITERAL commands.
+//!
+//! Against a content-defined signature, the new file is instead divided into chunks in
+//! the same way as the basis, and each chunk is looked up whole.
+
+use std::io;
+use std::io::{BufWriter, Read, Seek, SeekFrom, Write};
+
+#[cfg(feature = "parallel")]
+use rayon::prelude::*;
+
+use super::cancel::CancelToken;
+#[cfg(feature = "zstd")]
+use super::compress::Compressor;
+use super::delta::{checksum_hash, finish_checksum, BasisId, DeltaCommand, DeltaWriter};
+use super::error::{Error, Result};
+use super::index::SignatureIndex;
+use super::io_options::IoOptions;
+use super::magic::DeltaFormat;
+#[cfg(not(all(target_arch = "wasm32", target_os = "unknown")))]
+use super::pipeline::pipelined;
+#[cfg(feature = "parallel")]
+use super::mksum::fill_buffer;
+use super::multires::MultiSignature;
+use super::progress::{Meter, Progress};
+use super::rabinkarp::RabinKarp;
+use super::rollsum::{RollingHash, Rollsum1};
+use super::search::{Parts, Search, DEFAULT_MAX_LITERAL_LEN};
+use super::signature::Signature;
+use super::stats::{Statistics, Timed, Timer};
+use super::strongsum::{Blake2Hash, StrongHash};
+
+/// Options for delta generation.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct DeltaOptions {
+    /// Unmatched data is accumulated and sent as one LITERAL command once this much is
+    /// waiting, or when a match or the end of the file is found. No literal is longer.
+    ///
+    /// This bounds the memory held for the new file. It must not be zero.
+    pub max_literal_len: usize,
+
+    /// No COPY command is longer than this: longer matches are sent as several COPYs.
+    ///
+    /// With `max_literal_len`, this bounds how much a receiver has to handle for any
+    /// one command. It must not be zero.
+    pub max_copy_len: u64,
+
+    /// Write a `DeltaFormat::ChecksummedDelta`, ending with a hash of the whole new file,
+    /// so that the patched output can be checked. librsync can't read these.
+    pub checksum: bool,
+
+    /// Write a `DeltaFormat::CompressedDelta`, with literals compressed by zstd. librsync
+    /// can't read these, and they can't also be checksummed.
+    ///
+    /// This needs the `zstd` feature.
+    pub compress: bool,
+
+    /// Write a `DeltaFormat::BasisCheckedDelta`, identifying the basis by a hash of its
+    /// signature, so that patching can check it's given the right one. librsync can't
+    /// read these, and they can't also be checksummed or compressed.
+    pub basis_id: bool,
+
+    /// Index the signature in about this many bytes of memory, or less, by indexing only
+    /// some of its blocks if need be, as `SignatureIndex::with_memory_limit` does. The
+    /// delta is then bigger, but a huge basis doesn't need a huge machine.
+    pub index_memory_limit: Option<usize>,
+
+    /// Make exactly the delta that `generate_delta_with_options` makes, even from
+    /// `generate_delta_parallel_with_options`, by searching the new file in one piece
+    /// rather than in segments.
+    ///
+    /// Every other way of generating a delta already does, and the delta never depends
+    /// on the buffer sizes, the number of threads, or the platform: only on the
+    /// signature, the new file and these options. This is for when deltas are stored by
+    /// their content, so that the same change always gives the same bytes.
+    pub deterministic: bool,
+}
+
+impl Default for DeltaOptions {
+    /// Literals of up to 32kB, as in librsync, and COPYs of any length.
+    fn default() -> DeltaOptions {
+        DeltaOptions {
+            max_literal_len: DEFAULT_MAX_LITERAL_LEN,
+            max_copy_len: u64::MAX,
+            checksum: false,
+            compress: false,
+            basis_id: false,
+            index_memory_limit: None,
+            deterministic: false,
+        }
+    }
+}
+
+/// A reasonable segment length for `generate_delta_parallel`.
+#[cfg(feature = "parallel")]
+pub const DEFAULT_SEGMENT_LEN: usize = 8 << 20;
+
+/// Generate a delta, reading a new file and writing delta commands that will rebuild it
+/// from the basis described by `sig`.
+///
+/// If the signature has no blocks, such as one of an empty or missing old file, the new
+/// file is sent as LITERAL commands without searching it.
+///
+/// Returns statistics about the commands generated.
+pub fn generate_delta<R: Read + ?Sized, W: Write + ?Sized>(sig: &Signature, new: &mut R,
+                                                          delta: &mut W) -> Result<Statistics> {
+    generate_delta_with_index(&SignatureIndex::new(sig), new, delta)
+}
+
+/// Generate a delta, reading the new file and buffering the delta as set by `io`.
+pub fn generate_delta_with_io<R: Read + ?Sized, W: Write + ?Sized>(
+    sig: &Signature, new: &mut R, delta: &mut W, io: &IoOptions) -> Result<Statistics> {
+    let index = SignatureIndex::new(sig);
+    delta_with(&index, &mut *sig.format().strong_hash()?, new, delta, &DeltaOptions::default(),
+               io)
+}
+
+/// Generate a delta with non-default `options`.
+///
+/// `Error::InvalidOptions` is returned, before anything is written, if they can't be
+/// used.
+pub fn generate_delta_with_options<R: Read + ?Sized, W: Write + ?Sized>(
+    sig: &Signature, new: &mut R, delta: &mut W, options: &DeltaOptions)
+    -> Result<Statistics> {
+    check_delta_options(options)?;
+    let index = index_with(sig, options);
+    delta_with(&index, &mut *sig.format().strong_hash()?, new, delta, options,
+               &IoOptions::default())
+}
+
+/// Generate a delta with `options`, and compress the whole delta file with zstd at
+/// `level`, where 0 means `compress::DEFAULT_LEVEL`.
+///
+/// This is a container around any delta format, unlike `DeltaOptions::compress`, which
+/// compresses only the literals inside the delta. The patch functions recognize and
+/// decompress it, but librsync can't. The statistics give the compressed length as
+/// `out_bytes`.
+#[cfg(feature = "zstd")]
+pub fn generate_delta_compressed<R: Read + ?Sized, W: Write + ?Sized>(
+    sig: &Signature, new: &mut R, delta: &mut W, options: &DeltaOptions, level: i32)
+    -> Result<Statistics> {
+    check_delta_options(options)?;
+    let mut compressor = Compressor::new(delta, level)?;
+    let stats = generate_delta_with_options(sig, new, &mut compressor, options)?;
+    let (_, out_bytes) = compressor.finish()?;
+    Ok(Statistics { out_bytes, .. stats })
+}
+
+/// Generate a delta like `generate_delta_with_options`, but with the new file read on
+/// one thread, the search on this one, and the delta written on another, so that reading
+/// and writing overlap the search.
+///
+/// The delta is the same as `generate_delta_with_options` makes. This is quicker when
+/// the new file is read from fast storage, where the single-threaded loop leaves the
+/// disk idle while it searches.
+#[cfg(not(all(target_arch = "wasm32", target_os = "unknown")))]
+pub fn generate_delta_pipelined<R: Read + Send + ?Sized, W: Write + Send + ?Sized>(
+    sig: &Signature, new: &mut R, delta: &mut W, options: &DeltaOptions)
+    -> Result<Statistics> {
+    check_delta_options(options)?;
+    let index = index_with(sig, options);
+    let io = IoOptions::default();
+    pipelined(new, delta, &io, |new, delta| {
+        delta_with(&index, &mut *sig.format().strong_hash()?, new, delta, options, &io)
+    })
+}
+
+/// Index `sig` within the memory limit in `options`, if any.
+fn index_with<'s>(sig: &'s Signature, options: &DeltaOptions) -> SignatureIndex<'s> {
+    match options.index_memory_limit {
+        Some(limit) => SignatureIndex::with_memory_limit(sig, limit),
+        None => SignatureIndex::new(sig),
+    }
+}
+
+/// Check that `options` can be used, before anything is written.
+fn check_delta_options(options: &DeltaOptions) -> Result<()> {
+    if options.max_literal_len == 0 {
+        return Err(Error::InvalidOptions("max_literal_len is zero".to_owned()));
+    }
+    if options.max_copy_len == 0 {
+        return Err(Error::InvalidOptions("max_copy_len is zero".to_owned()));
+    }
+    if options.compress && !cfg!(feature = "zstd") {
+        return Err(Error::InvalidOptions("compression needs the zstd feature".to_owned()));
+    }
+    if [options.checksum, options.compress, options.basis_id].iter().filter(|&&b| b).count() > 1 {
+        return Err(Error::InvalidOptions(
+            "only one of checksum, compress and basis_id can be set".to_owned()));
+    }
+    Ok(())
+}
+
+/// Search the new file just as `generate_delta_with_options` would, but discard the delta
+/// rather than writing it, and return only its statistics. `out_bytes` is then the length
+/// the delta would have.
+///
+/// This tells whether a delta is worth sending, rather than the whole new file, without
+/// making the delta twice or holding it in the meantime.
+pub fn estimate_delta<R: Read + ?Sized>(sig: &Signature, new: &mut R, options: &DeltaOptions)
+    -> Result<Statistics> {
+    generate_delta_with_options(sig, new, &mut io::sink(), options)
+}
+
+/// Generate a delta, calling `progress` as the new file is read.
+pub fn generate_delta_with_progress<R: Read + ?Sized, W: Write + ?Sized>(
+    sig: &Signature, new: &mut R, delta: &mut W, progress: &mut dyn FnMut(Progress))
+    -> Result<Statistics> {
+    generate_delta_metered(sig, new, delta, &IoOptions::default(), &Meter::new(None, progress))
+}
+
+/// Generate a delta, stopping with `Error::Cancelled` soon after `cancel` is cancelled.
+/// The delta written so far is incomplete.
+pub fn generate_delta_cancellable<R: Read + ?Sized, W: Write + ?Sized>(
+    sig: &Signature, new: &mut R, delta: &mut W, cancel: &CancelToken) -> Result<Statistics> {
+    generate_delta(sig, &mut cancel.reader(new), delta)
+}
+
+pub(crate) fn generate_delta_metered<R: Read + ?Sized, W: Write + ?Sized>(
+    sig: &Signature, new: &mut R, delta: &mut W, io: &IoOptions, meter: &Meter)
+    -> Result<Statistics> {
+    let stats = generate_delta_with_io(sig, &mut meter.reader(new), &mut meter.writer(delta),
+                                       io)?;
+    meter.finish();
+    Ok(stats)
+}
+
+/// Generate a delta against a signature that's already been indexed.
+///
+/// This avoids rebuilding the index when many deltas are made against one signature.
+pub fn generate_delta_with_index<R: Read + ?Sized, W: Write + ?Sized>(
+    index: &SignatureIndex, new: &mut R, delta: &mut W) -> Result<Statistics> {
+    let mut hash = index.signature().format().strong_hash()?;
+    generate_delta_with_hash(index, &mut *hash, new, delta)
+}
+
+/// Generate a delta against a signature whose strong sums were made by `hash`.
+///
+/// This is the counterpart of `generate_signature_with_hash`.
+pub fn generate_delta_with_hash<R: Read + ?Sized, W: Write + ?Sized>(
+    index: &SignatureIndex, hash: &mut dyn StrongHash, new: &mut R, delta: &mut W)
+    -> Result<Statistics> {
+    delta_with(index, hash, new, delta, &DeltaOptions::default(), &IoOptions::default())
+}
+
+fn delta_with<R: Read + ?Sized, W: Write + ?Sized>(index: &SignatureIndex,
+                                                   hash: &mut dyn StrongHash, new: &mut R,
+                                                   delta: &mut W, options: &DeltaOptions,
+                                                   io: &IoOptions)
+    -> Result<Statistics> {
+    if index.signature().format().is_rabinkarp() {
+        search_new_file::<RabinKarp>(index, hash, new, delta, options, io, Parts::Whole)
+    } else {
+        search_new_file::<Rollsum1>(index, hash, new, delta, options, io, Parts::Whole)
+    }
+}
+
+/// Generate a delta using rolling hash `R` and strong hash `hash`, which must be those
+/// used to make the signature.
+///
+/// This is the counterpart of `generate_signature_with_hashes`.
+pub fn generate_delta_with_hashes<R: RollingHash + Default>(
+    index: &SignatureIndex, hash: &mut dyn StrongHash, new: &mut (impl Read + ?Sized),
+    delta: &mut (impl Write + ?Sized)) -> Result<Statistics> {
+    search_new_file::<R>(index, hash, new, delta, &DeltaOptions::default(), &IoOptions::default(),
+                         Parts::Whole)
+}
+
+/// Generate a delta against several bases at once, such as the last few versions of a
+/// file, from their signatures.
+///
+/// Each COPY in the delta refers to whichever basis has the matching block, so the
+/// delta is often smaller than one against any single basis. It's written as a
+/// `DeltaFormat::MultiBasisDelta`, which is applied by `patch::apply_patch_multi` given
+/// the bases in the same order.
+///
+/// The signatures must all have the same format, block length, strong sum length and
+/// seed, and either all or none of them have blocks of varying length; otherwise, or if
+/// there are none, `Error::InvalidOptions` is returned.
+pub fn generate_delta_multi<R: Read + ?Sized, W: Write + ?Sized>(
+    sigs: &[&Signature], new: &mut R, delta: &mut W) -> Result<Statistics> {
+    let first = match sigs.first() {
+        Some(s) => s,
+        None => return Err(Error::InvalidOptions("no signatures were given".to_owned())),
+    };
+    let sig_options = first.options();
+    let variable = first.block_lens().is_some();
+    let mut joined = if variable {
+        Signature::new_variable(&sig_options)
+    } else {
+        Signature::new(&sig_options)
+    };
+    let mut basis_starts = Vec::with_capacity(sigs.len());
+    for sig in sigs {
+        if (sig.options(), sig.block_lens().is_some()) != (sig_options, variable) {
+            return Err(Error::InvalidOptions(
+                "the signatures have different formats or block lengths".to_owned()));
+        }
+        basis_starts.push(joined.block_count());
+        let lens = sig.block_lens().unwrap_or(&[]);
+        for (i, weak, strong) in sig.blocks() {
+            joined.push_sums(lens.get(i).cloned().unwrap_or(0), weak, strong);
+        }
+    }
+    search_joined(&joined, new, delta, Parts::Bases(&basis_starts))
+}
+
+/// Generate a delta from a multi-resolution signature.
+///
+/// At each point in the new file, a block of the coarsest level is looked for first, so
+/// that long unchanged regions are matched by few, long blocks, and then blocks of the
+/// finer levels, so that data near a change is still matched. The delta is an ordinary
+/// delta, which librsync can apply.
+pub fn generate_delta_multires<R: Read + ?Sized, W: Write + ?Sized>(
+    multi: &MultiSignature, new: &mut R, delta: &mut W) -> Result<Statistics> {
+    let levels = multi.levels();
+    if levels.len() == 1 {
+        return generate_delta(&levels[0], new, delta);
+    }
+    // Each level becomes part of one signature whose blocks vary in length, which is
+    // searched with a window of each length, the longest first.
+    let mut joined = Signature::new_variable(&levels[0].options());
+    let mut level_starts = Vec::with_capacity(levels.len());
+    for level in levels {
+        level_starts.push(joined.block_count());
+        let block_len = u64::from(level.block_len());
+        for (i, weak, strong) in level.blocks() {
+            let len = block_len.min(multi.basis_len() - i as u64 * block_len);
+            joined.push_sums(len as u32, weak, strong);
+        }
+    }
+    search_joined(&joined, new, delta, Parts::Levels(&level_starts))
+}
+
+/// Search for the blocks of a signature joined from `parts`, with the default options.
+fn search_joined<R: Read + ?Sized, W: Write + ?Sized>(joined: &Signature, new: &mut R,
+                                                      delta: &mut W, parts: Parts)
+    -> Result<Statistics> {
+    let index = SignatureIndex::new(joined);
+    let mut hash = joined.format().strong_hash()?;
+    let (options, io) = (&DeltaOptions::default(), &IoOptions::default());
+    if joined.format().is_rabinkarp() {
+        search_new_file::<RabinKarp>(&index, &mut *hash, new, delta, options, io, parts)
+    } else {
+        search_new_file::<Rollsum1>(&index, &mut *hash, new, delta, options, io, parts)
+    }
+}
+
+/// The format to write with `options`, which have been checked.
+fn delta_format(options: &DeltaOptions) -> DeltaFormat {
+    if options.compress {
+        return DeltaFormat::CompressedDelta;
+    }
+    if options.checksum { DeltaFormat::ChecksummedDelta } else { DeltaFormat::Delta }
+}
+
+#[cfg_attr(feature = "tracing", tracing::instrument(level = "debug", skip_all,
+    fields(block_len = index.signature().block_len(),
+           blocks = index.signature().block_count())))]
+fn search_new_file<R: RollingHash + Default>(
+    index: &SignatureIndex, hash: &mut dyn StrongHash, new: &mut (impl Read + ?Sized),
+    delta: &mut (impl Write + ?Sized), options: &DeltaOptions, io: &IoOptions,
+    parts: Parts)
+    -> Result<Statistics> {
+    let start = Timer::start();
+    let mut in_bytes = 0;
+    let read_len = io.read_buf.max(1);
+    let format = match parts {
+        Parts::Bases(_) => DeltaFormat::MultiBasisDelta,
+        _ => delta_format(options),
+    };
+    let new = &mut Timed::new(new);
+    let hash = &mut Timed::new(hash);
+    let buf = BufWriter::with_capacity(io.write_buf, Timed::new(delta));
+    let mut out = if options.basis_id && matches!(parts, Parts::Whole) {
+        DeltaWriter::with_basis_id(buf, &BasisId::of(index.signature()))?
+    } else {
+        DeltaWriter::with_format(buf, format)?
+    };
+    out.set_max_copy_len(options.max_copy_len);
+    let mut checksum = if options.checksum { Some(checksum_hash()) } else { None };
+    let mut search = Search::<R>::new(index, options.max_literal_len).with_parts(parts);
+    if index.signature().block_count() == 0 {
+        // Nothing can match, as when there's no old file: send the new file as it is.
+        in_bytes = send_literals(new, &mut out, read_len, options.max_literal_len,
+                                 &mut checksum)?;
+    } else {
+        loop {
+            let old_len = search.buf.len();
+            search.buf.resize(old_len + read_len, 0);
+            let l = new.read(&mut search.buf[old_len..])?;
+            search.buf.truncate(old_len + l);
+            in_bytes += l as u64;
+            if let Some(h) = checksum.as_mut() {
+                h.update(&search.buf[old_len..]);
+            }
+            let eof = l == 0;
+            search.process(hash, eof, &mut out)?;
+            if eof {
+                break;
+            }
+        }
+    }
+    if let Some(h) = checksum.as_mut() {
+        out.set_checksum(finish_checksum(h));
+    }
+    out.write_command(&DeltaCommand::End)?;
+    debug_event!(in_bytes, windows = search.windows, weak_hits = search.weak_hits,
+                 false_matches = search.false_matches,
+                 weak_hit_rate = search.weak_hits as f64 / search.windows.max(1) as f64,
+                 "delta search finished");
+    let sig = index.signature();
+    let mut stats = Statistics {
+        in_bytes,
+        false_matches: search.false_matches,
+        block_count: sig.block_count() as u64,
+        block_len: sig.block_len(),
+        elapsed: start.elapsed(),
+        write_time: out.get_mut().get_ref().time,
+        .. out.statistics().clone()
+    };
+    stats.set_phases(new.time, hash.time);
+    Ok(stats)
+}
+
+/// Send all of `new` as LITERAL commands of `max_literal_len` bytes, and a shorter last
+/// one, just as a search with no blocks to match would, and return its length.
+fn send_literals(new: &mut (impl Read + ?Sized), out: &mut DeltaWriter<impl Write>,
+                 read_len: usize, max_literal_len: usize, checksum: &mut Option<Blake2Hash>)
+    -> Result<u64> {
+    let mut buf = Vec::new();
+    let mut in_bytes = 0;
+    loop {
+        let old_len = buf.len();
+        buf.resize(old_len + read_len.min(max_literal_len - old_len), 0);
+        let l = new.read(&mut buf[old_len..])?;
+        buf.truncate(old_len + l);
+        in_bytes += l as u64;
+        if let Some(h) = checksum.as_mut() {
+            h.update(&buf[old_len..]);
+        }
+        if l == 0 || buf.len() == max_literal_len {
+            out.literal(&buf)?;
+            buf.clear();
+        }
+        if l == 0 {
+            return Ok(in_bytes);
+        }
+    }
+}
+
+/// How far a delta has been generated, from which it can be resumed.
+///
+/// This holds how much of the new file has been read and of the delta written, and the
+/// data read but not yet sent. With the `serde` feature it can be serialized, so that a
+/// delta of a very large file can carry on where it left off after the process is
+/// restarted.
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct DeltaCheckpoint {
+    new_offset: u64,
+    delta_offset: u64,
+    /// Data from the new file not yet covered by any command.
+    #[cfg_attr(feature = "serde", serde(with = "serde_bytes"))]
+    pending: Vec<u8>,
+    /// How far into `pending` the search has got.
+    search_pos: u64,
+    /// Offset and length of a COPY that's not yet written, in case the next extends it.
+    pending_copy: Option<(u64, u64)>,
+    max_literal_len: usize,
+    #[cfg_attr(feature = "serde", serde(default = "no_copy_limit"))]
+    max_copy_len: u64,
+    basis_id: bool,
+}
+
+/// The `max_copy_len` of checkpoints saved before there was one.
+#[cfg(feature = "serde")]
+fn no_copy_limit() -> u64 {
+    u64::MAX
+}
+
+impl DeltaCheckpoint {
+    /// Start a delta with `options`, before any of the new file is read.
+    ///
+    /// `Error::InvalidOptions` is returned if the options are invalid, or if they ask for
+    /// a checksummed or compressed delta, which can't be resumed.
+    pub fn new(options: &DeltaOptions) -> Result<DeltaCheckpoint> {
+        if options.max_literal_len == 0 {
+            return Err(Error::InvalidOptions("max_literal_len is zero".to_owned()));
+        }
+        if options.max_copy_len == 0 {
+            return Err(Error::InvalidOptions("max_copy_len is zero".to_owned()));
+        }
+        if options.checksum || options.compress {
+            return Err(Error::InvalidOptions(
+                "a checksummed or compressed delta can't be resumed".to_owned()));
+        }
+        Ok(DeltaCheckpoint {
+            new_offset: 0,
+            delta_offset: 0,
+            pending: Vec::new(),
+            search_pos: 0,
+            pending_copy: None,
+            max_literal_len: options.max_literal_len,
+            max_copy_len: options.max_copy_len,
+            basis_id: options.basis_id,
+        })
+    }
+
+    /// How much of the new file has been read, and so where reading it resumes.
+    pub fn new_offset(&self) -> u64 {
+        self.new_offset
+    }
+
+    /// How much of the delta has been written. The delta must be truncated to this length
+    /// before resuming, and the rest appended.
+    pub fn delta_offset(&self) -> u64 {
+        self.delta_offset
+    }
+}
+
+/// Generate a delta, starting or resuming from `checkpoint`, and passing the checkpoint
+/// to `save` each time another `interval` bytes of the new file are read.
+///
+/// `new` is read from `checkpoint.new_offset()` to its end, and the rest of the delta is
+/// written to `delta`, which must hold the first `checkpoint.delta_offset()` bytes
+/// already written. `delta` is flushed before each checkpoint is saved. The index must
+/// be of the same signature each time. If this is interrupted, resuming from the last
+/// checkpoint saved gives the same delta as if it had run straight through. An error
+/// from `save` stops the delta and is returned.
+///
+/// The statistics count the commands written since resuming, but `in_bytes` and
+/// `out_bytes` are the whole length of the new file and the delta.
+///
+/// `Error::InvalidOptions` is returned if `interval` is zero or the checkpoint is
+/// inconsistent.
+pub fn resume_delta<R: Read + Seek + ?Sized, W: Write + ?Sized>(
+    index: &SignatureIndex, checkpoint: DeltaCheckpoint, new: &mut R, delta: &mut W,
+    interval: u64, save: &mut dyn FnMut(&DeltaCheckpoint) -> Result<()>)
+    -> Result<Statistics> {
+    if interval == 0 {
+        return Err(Error::InvalidOptions("interval is zero".to_owned()));
+    }
+    if checkpoint.search_pos > checkpoint.pending.len() as u64 {
+        return Err(Error::InvalidOptions("the checkpoint is inconsistent".to_owned()));
+    }
+    if index.signature().format().is_rabinkarp() {
+        resume_search::<RabinKarp>(index, checkpoint, new, delta, interval, save)
+    } else {
+        resume_search::<Rollsum1>(index, checkpoint, new, delta, interval, save)
+    }
+}
+
+fn resume_search<R: RollingHash + Default>(
+    index: &SignatureIndex, mut checkpoint: DeltaCheckpoint,
+    new: &mut (impl Read + Seek + ?Sized), delta: &mut (impl Write + ?Sized), interval: u64,
+    save: &mut dyn FnMut(&DeltaCheckpoint) -> Result<()>)
+    -> Result<Statistics> {
+    let start = Timer::start();
+    let sig = index.signature();
+    let mut hash = sig.format().strong_hash()?;
+    let read_len = IoOptions::default().read_buf;
+    new.seek(SeekFrom::Start(checkpoint.new_offset))?;
+    let buf = BufWriter::new(delta);
+    let mut out = if checkpoint.delta_offset > 0 {
+        DeltaWriter::resumed(buf, checkpoint.delta_offset, checkpoint.pending_copy)
+    } else if checkpoint.basis_id {
+        DeltaWriter::with_basis_id(buf, &BasisId::of(sig))?
+    } else {
+        DeltaWriter::new(buf)?
+    };
+    out.set_max_copy_len(checkpoint.max_copy_len);
+    let pending = std::mem::take(&mut checkpoint.pending);
+    let mut search = Search::<R>::new(index, checkpoint.max_literal_len)
+        .resumed(pending, checkpoint.search_pos as usize);
+    let mut next_save = checkpoint.new_offset.saturating_add(interval);
+    loop {
+        let old_len = search.buf.len();
+        search.buf.resize(old_len + read_len, 0);
+        let l = new.read(&mut search.buf[old_len..])?;
+        search.buf.truncate(old_len + l);
+        checkpoint.new_offset += l as u64;
+        let eof = l == 0;
+        search.process(&mut *hash, eof, &mut out)?;
+        if eof {
+            break;
+        }
+        if checkpoint.new_offset >= next_save {
+            next_save = checkpoint.new_offset.saturating_add(interval);
+            out.get_mut().flush()?;
+            let (pending, pos) = search.pending();
+            checkpoint.pending = pending.to_vec();
+            checkpoint.search_pos = pos as u64;
+            checkpoint.delta_offset = out.statistics().out_bytes;
+            checkpoint.pending_copy = out.pending_copy();
+            save(&checkpoint)?;
+        }
+    }
+    out.write_command(&DeltaCommand::End)?;
+    Ok(Statistics {
+        in_bytes: checkpoint.new_offset,
+        false_matches: search.false_matches,
+        block_count: sig.block_count() as u64,
+        block_len: sig.block_len(),
+        elapsed: start.elapsed(),
+        .. out.statistics().clone()
+    })
+}
+
+/// Generate a delta by searching segments of the new file on the rayon thread pool.
+///
+/// The new file is read a batch of `segment_len` segments at a time, one for each thread,
+/// and each segment is searched against the signature independently. Their commands are
+/// written in order, so the delta applies like any other, but it may be slightly larger
+/// than `generate_delta` would make, because blocks that straddle two segments aren't
+/// matched. That's negligible if segments are many blocks long, as with
+/// `DEFAULT_SEGMENT_LEN`. The segments fall in the same places however many threads
+/// there are, so the delta is the same on any machine.
+///
+/// `Error::InvalidOptions` is returned if `segment_len` is zero.
+#[cfg(feature = "parallel")]
+pub fn generate_delta_parallel<R: Read + ?Sized, W: Write + ?Sized>(
+    sig: &Signature, new: &mut R, delta: &mut W, segment_len: usize) -> Result<Statistics> {
+    generate_delta_parallel_with_options(sig, new, delta, segment_len, &DeltaOptions::default())
+}
+
+/// Generate a delta like `generate_delta_parallel`, with non-default `options`.
+///
+/// If `options.deterministic` is set, the new file is searched in one piece on this
+/// thread, so the delta is the same as `generate_delta_with_options` makes. Otherwise,
+/// segments can't be checksummed,
+/// compressed or identify their basis, and `Error::InvalidOptions` is returned if
+/// `options` ask for that.
+#[cfg(feature = "parallel")]
+pub fn generate_delta_parallel_with_options<R: Read + ?Sized, W: Write + ?Sized>(
+    sig: &Signature, new: &mut R, delta: &mut W, segment_len: usize, options: &DeltaOptions)
+    -> Result<Statistics> {
+    if segment_len == 0 {
+        return Err(Error::InvalidOptions("segment_len is zero".to_owned()));
+    }
+    check_delta_options(options)?;
+    if options.deterministic {
+        return generate_delta_with_options(sig, new, delta, options);
+    }
+    if options.checksum || options.compress || options.basis_id {
+        return Err(Error::InvalidOptions(
+            "segments can't be checksummed, compressed or identify their basis".to_owned()));
+    }
+    let index = index_with(sig, options);
+    if sig.format().is_rabinkarp() {
+        generate_delta_segments::<RabinKarp>(&index, new, delta, segment_len, options)
+    } else {
+        generate_delta_segments::<Rollsum1>(&index, new, delta, segment_len, options)
+    }
+}
+
+#[cfg(feature = "parallel")]
+#[cfg_attr(feature = "tracing", tracing::instrument(level = "debug", skip_all,
+    fields(block_len = index.signature().block_len(), segment_len)))]
+fn generate_delta_segments<R: RollingHash + Default>(
+    index: &SignatureIndex, new: &mut (impl Read + ?Sized), delta: &mut (impl Write + ?Sized),
+    segment_len: usize, options: &DeltaOptions)
+    -> Result<Statistics> {
+    let start = Timer::start();
+    let new = &mut Timed::new(new);
+    let mut out = DeltaWriter::new(BufWriter::new(Timed::new(delta)))?;
+    let mut stats = Statistics::new("delta");
+    let mut buf = vec![0; segment_len * rayon::current_num_threads()];
+    loop {
+        let l = fill_buffer(new, &mut buf)?;
+        if l == 0 { break; }
+        stats.in_bytes += l as u64;
+        let segments = buf[..l]
+            .par_chunks(segment_len)
+            .map_init(|| index.signature().format().strong_hash(), |hash, segment| {
+                let format = index.signature().format();
+                let hash = hash.as_mut().map_err(|_| Error::UnsupportedFormat(format as u32))?;
+                search_segment::<R>(index, &mut **hash, segment, options)
+            })
+            .collect::<Result<Vec<_>>>()?;
+        for (commands, segment_stats) in &segments {
+            out.get_mut().write_all(commands)?;
+            stats.add(segment_stats);
+        }
+        if l < buf.len() { break; }
+    }
+    out.write_command(&DeltaCommand::End)?;
+    stats.add(out.statistics());
+    debug_event!(in_bytes = stats.in_bytes, false_matches = stats.false_matches,
+                 "segmented delta search finished");
+    let sig = index.signature();
+    let mut stats = Statistics {
+        block_count: sig.block_count() as u64,
+        block_len: sig.block_len(),
+        elapsed: start.elapsed(),
+        write_time: out.get_mut().get_ref().time,
+        .. stats
+    };
+    stats.set_phases(new.time, stats.strong_time);
+    Ok(stats)
+}
+
+/// Search one segment of the new file, returning the encoded commands, without a magic
+/// number or END, and their statistics.
+#[cfg(feature = "parallel")]
+fn search_segment<R: RollingHash + Default>(index: &SignatureIndex, hash: &mut dyn StrongHash,
+                                            segment: &[u8], options: &DeltaOptions)
+    -> Result<(Vec<u8>, Statistics)> {
+    let mut out = DeltaWriter::without_magic(Vec::new());
+    out.set_max_copy_len(options.max_copy_len);
+    let mut search = Search::<R>::new(index, options.max_literal_len);
+    search.buf.extend_from_slice(segment);
+    let hash = &mut Timed::new(hash);
+    search.process(hash, true, &mut out)?;
+    let (commands, mut stats) = out.into_parts()?;
+    stats.false_matches = search.false_matches;
+    stats.strong_time = hash.time;
+    Ok((commands, stats))
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::Duration;
+
+    use super::*;
+    use super::super::delta::{OP_COPY_N8_N8, OP_END, OP_LITERAL_N1};
+    use super::super::magic::SignatureFormat;
+    use super::super::mksum::{calculate_signature, calculate_signature_with_hash,
+                              calculate_signature_with_hashes, SignatureOptions};
+    use super::super::test_util::pattern;
+
+    fn delta_of(basis: &[u8], new: &[u8], options: &SignatureOptions) -> Vec<u8> {
+        let sig = calculate_signature(&mut &basis[..], options).unwrap();
+        let mut delta = Vec::new();
+        generate_delta(&sig, &mut &new[..], &mut delta).unwrap();
+        delta
+    }
+
+    fn small_blocks() -> SignatureOptions {
+        SignatureOptions {
+            block_len: 1024,
+            .. SignatureOptions::default()
+        }
+    }
+
+    /// An endless delta computation stops when it's cancelled from another thread.
+    #[test]
+    pub fn cancel_from_another_thread() {
+        use std::io;
+        use std::thread;
+        use std::time::Duration;
+        use super::super::error::Error;
+
+        let sig = calculate_signature(&mut pattern(10_000).as_slice(), &small_blocks()).unwrap();
+        let token = CancelToken::new();
+        let canceller = token.clone();
+        let t = thread::spawn(move || {
+            thread::sleep(Duration::from_millis(20));
+            canceller.cancel();
+        });
+        let err = generate_delta_cancellable(&sig, &mut io::repeat(7), &mut io::sink(), &token)
+            .unwrap_err();
+        t.join().unwrap();
+        assert!(matches!(err, Error::Cancelled), "{:?}", err);
+    }
+
+    #[test]
+    pub fn empty_new_file() {
+        let delta = delta_of(b"", b"", &SignatureOptions::default());
+        assert_eq!(delta, [b'r', b's', 0x02, 0x36, OP_END]);
+    }
+
+    #[test]
+    pub fn statistics() {
+        let basis = pattern(10_240);
+        let mut new = basis[2048..].to_vec();
+        new.extend_from_slice(b"hello");
+        let sig = calculate_signature(&mut basis.as_slice(), &small_blocks()).unwrap();
+        let mut delta = Vec::new();
+        let stats = generate_delta(&sig, &mut new.as_slice(), &mut delta).unwrap();
+        assert_eq!(stats.op, "delta");
+        assert_eq!(stats.in_bytes, new.len() as u64);
+        assert_eq!(stats.out_bytes, delta.len() as u64);
+        assert_eq!(stats.copy_bytes, 8192);
+        assert_eq!((stats.literal_cmds, stats.literal_bytes), (1, 5));
+        assert_eq!((stats.block_count, stats.block_len), (10, 1024));
+        assert_eq!(stats.false_matches, 0);
+        assert!(stats.strong_time > Duration::default(), "{:?}", stats);
+        assert_eq!(stats.read_time + stats.rolling_time + stats.strong_time + stats.write_time,
+                   stats.elapsed);
+    }
+
+    /// A block whose weak sum is found but whose strong sum differs is counted as a false
+    /// match, and sent as a literal.
+    #[test]
+    pub fn false_matches() {
+        let basis = pattern(1024);
+        let real = calculate_signature(&mut basis.as_slice(), &small_blocks()).unwrap();
+        let mut sig = Signature::new(&small_blocks());
+        let mut strong = real.strong_sum(0).to_vec();
+        strong[0] ^= 0xff;
+        sig.push_block(real.weak_sum(0), &strong);
+        let mut delta = Vec::new();
+        let stats = generate_delta(&sig, &mut basis.as_slice(), &mut delta).unwrap();
+        assert_eq!(stats.false_matches, 1);
+        assert_eq!((stats.copy_cmds, stats.literal_bytes), (0, 1024));
+    }
+
+    /// Searching in segments gives a delta that rebuilds the new file, matching everything
+    /// except blocks that straddle segments.
+    #[cfg(feature = "parallel")]
+    #[test]
+    pub fn parallel_segments() {
+        use std::io::Cursor;
+        use super::super::patch::apply_patch;
+
+        let basis = pattern(100_000);
+        let mut new = basis[1000..].to_vec();
+        new.extend_from_slice(b"hello");
+        let sig = calculate_signature(&mut basis.as_slice(), &small_blocks()).unwrap();
+        for &segment_len in &[10_000, 1 << 20] {
+            let mut delta = Vec::new();
+            let stats = generate_delta_parallel(&sig, &mut new.as_slice(), &mut delta,
+                                                segment_len).unwrap();
+            assert_eq!(stats.in_bytes, new.len() as u64);
+            assert_eq!(stats.out_bytes, delta.len() as u64);
+            assert_eq!(stats.copy_bytes + stats.literal_bytes, new.len() as u64);
+            let straddling = (new.len() / segment_len) as u64;
+            assert!(stats.copy_bytes >= (96 - straddling) * 1024, "{:?}", stats);
+            let mut out = Vec::new();
+            apply_patch(&mut Cursor::new(&basis), &mut delta.as_slice(), &mut out).unwrap();
+            assert_eq!(out, new);
+        }
+        let err = generate_delta_parallel(&sig, &mut new.as_slice(), &mut Vec::new(), 0)
+            .unwrap_err();
+        assert!(matches!(err, super::super::error::Error::InvalidOptions(_)), "{:?}", err);
+    }
+
+    /// The parallel search makes the same delta however many threads there are, and with
+    /// `deterministic` the same as the single-threaded search, as does the pipelined one.
+    #[cfg(feature = "parallel")]
+    #[test]
+    pub fn deterministic() {
+        use rayon::ThreadPoolBuilder;
+        use super::super::error::Error;
+
+        let basis = pattern(100_000);
+        let mut new = basis[1000..60_000].to_vec();
+        new.extend_from_slice(&b"hello".repeat(300));
+        new.extend_from_slice(&basis[50_000..]);
+        let sig = calculate_signature(&mut basis.as_slice(), &small_blocks()).unwrap();
+        let options = DeltaOptions {
+            max_literal_len: 1000,
+            max_copy_len: 5000,
+            .. DeltaOptions::default()
+        };
+        let mut expected = Vec::new();
+        generate_delta_with_options(&sig, &mut new.as_slice(), &mut expected, &options)
+            .unwrap();
+        let mut pipelined = Vec::new();
+        generate_delta_pipelined(&sig, &mut new.as_slice(), &mut pipelined, &options).unwrap();
+        assert_eq!(pipelined, expected);
+        let mut segmented = Vec::new();
+        for &threads in &[1, 3, 8] {
+            let pool = ThreadPoolBuilder::new().num_threads(threads).build().unwrap();
+            let parallel = |options: &DeltaOptions| pool.install(|| {
+                let mut delta = Vec::new();
+                generate_delta_parallel_with_options(&sig, &mut new.as_slice(), &mut delta,
+                                                     10_000, options).unwrap();
+                delta
+            });
+            let delta = parallel(&options);
+            assert_ne!(delta, expected);
+            if segmented.is_empty() {
+                segmented = delta;
+            } else {
+                assert_eq!(delta, segmented, "{} threads", threads);
+            }
+            let options = DeltaOptions { deterministic: true, .. options };
+            assert_eq!(parallel(&options), expected, "{} threads", threads);
+        }
+        let checksum = DeltaOptions { checksum: true, .. DeltaOptions::default() };
+        let err = generate_delta_parallel_with_options(&sig, &mut new.as_slice(),
+                                                       &mut Vec::new(), 10_000, &checksum)
+            .unwrap_err();
+        assert!(matches!(err, Error::InvalidOptions(_)), "{:?}", err);
+    }
+
+    /// A compressed delta of any format is patched like the uncompressed one.
+    #[cfg(feature = "zstd")]
+    #[test]
+    pub fn compressed_container() {
+        use std::io::Cursor;
+        use super::super::patch::{apply_patch_with_options, PatchOptions};
+
+        let basis = pattern(100_000);
+        let mut new = b"a new start ".repeat(1000);
+        new.extend_from_slice(&basis);
+        let sig = calculate_signature(&mut basis.as_slice(), &small_blocks()).unwrap();
+        let options = DeltaOptions { checksum: true, .. DeltaOptions::default() };
+        let mut plain = Vec::new();
+        generate_delta_with_options(&sig, &mut new.as_slice(), &mut plain, &options).unwrap();
+        let mut delta = Vec::new();
+        let stats = generate_delta_compressed(&sig, &mut new.as_slice(), &mut delta, &options,
+                                              0).unwrap();
+        assert_eq!(stats.out_bytes, delta.len() as u64);
+        assert!(delta.len() * 10 < plain.len(), "{} {}", delta.len(), plain.len());
+        let mut out = Vec::new();
+        let patch_options = PatchOptions { checked: true, .. PatchOptions::default() };
+        apply_patch_with_options(&mut Cursor::new(&basis), &mut delta.as_slice(), &mut out,
+                                 &patch_options).unwrap();
+        assert_eq!(out, new);
+    }
+
+    /// The buffer sizes make no difference to the delta.
+    #[test]
+    pub fn buffer_sizes() {
+        let basis = pattern(10_240);
+        let mut new = basis[100..].to_vec();
+        new.extend_from_slice(&basis[..3000]);
+        let sig = calculate_signature(&mut basis.as_slice(), &small_blocks()).unwrap();
+        let mut expected = Vec::new();
+        generate_delta(&sig, &mut new.as_slice(), &mut expected).unwrap();
+        for &(read_buf, write_buf) in &[(0, 0), (1, 1), (1000, 7), (1 << 20, 1 << 20)] {
+            let mut delta = Vec::new();
+            generate_delta_with_io(&sig, &mut new.as_slice(), &mut delta,
+                                   &IoOptions { read_buf, write_buf }).unwrap();
+            assert_eq!(delta, expected, "{} {}", read_buf, write_buf);
+        }
+    }
+
+    #[test]
+    pub fn literal_only() {
+        let delta = delta_of(b"", b"hello", &SignatureOptions::default());
+        assert_eq!(delta, [b'r', b's', 0x02, 0x36, 5, b'h', b'e', b'l', b'l', b'o', OP_END]);
+    }
+
+    #[test]
+    pub fn long_literal() {
+        let new = pattern(300);
+        let delta = delta_of(b"", &new, &SignatureOptions::default());
+        assert_eq!(&delta[4..7], &[OP_LITERAL_N1 + 1, 0x01, 0x2c]);
+        assert_eq!(&delta[7..307], new.as_slice());
+        assert_eq!(&delta[307..], &[OP_END]);
+    }
+
+    #[test]
+    pub fn identical_files() {
+        let basis = pattern(3000);
+        let delta = delta_of(&basis, &basis, &small_blocks());
+        assert_eq!(delta, [
+            b'r', b's', 0x02, 0x36,
+            0x46, 0x00, 0x0b, 0xb8, // COPY_N1_N2(0, 3000), merged from all three blocks
+            OP_END]);
+    }
+
+    #[test]
+    pub fn insertion_at_start() {
+        let basis = pattern(3000);
+        let mut new = b"xyz".to_vec();
+        new.extend_from_slice(&basis);
+        let delta = delta_of(&basis, &new, &small_blocks());
+        assert_eq!(&delta[4..8], &[3, b'x', b'y', b'z']);
+        assert_eq!(&delta[8..12], &[0x46, 0x00, 0x0b, 0xb8]);
+        assert_eq!(delta.len(), 4 + 4 + 4 + 1);
+    }
+
+    #[test]
+    pub fn rabinkarp_signature() {
+        let basis = pattern(3000);
+        let mut new = basis.clone();
+        new[1500] ^= 0xff;
+        let options = SignatureOptions {
+            magic: SignatureFormat::RkBlake2Sig,
+            .. small_blocks()
+        };
+        let delta = delta_of(&basis, &new, &options);
+        // COPY of the first block, a literal for the damaged one, and COPY of the last.
+        assert_eq!(&delta[4..8], &[0x46, 0x00, 0x04, 0x00]);
+        assert_eq!(&delta[8..11], &[OP_LITERAL_N1 + 1, 0x04, 0x00]);
+        assert_eq!(&delta[11..(11 + 1024)], &new[1024..2048]);
+        assert_eq!(&delta[(11 + 1024)..], &[0x4a, 0x08, 0x00, 0x03, 0xb8, OP_END]);
+    }
+
+    /// BLAKE2 with a secret key mixed in before each block.
+    #[derive(Default)]
+    struct KeyedHash {
+        inner: Blake2Hash,
+        started: bool,
+    }
+
+    impl StrongHash for KeyedHash {
+        fn digest_len(&self) -> usize { self.inner.digest_len() }
+        fn update(&mut self, buf: &[u8]) {
+            if !self.started {
+                self.inner.update(b"secret");
+                self.started = true;
+            }
+            self.inner.update(buf);
+        }
+        fn finalize_truncated(&mut self, out: &mut [u8]) {
+            self.update(b"");
+            self.inner.finalize_truncated(out);
+            self.started = false;
+        }
+    }
+
+    #[test]
+    pub fn custom_strong_hash() {
+        let basis = pattern(3000);
+        let sig = calculate_signature_with_hash(&mut basis.as_slice(), &small_blocks(),
+                                                &mut KeyedHash::default()).unwrap();
+        let index = SignatureIndex::new(&sig);
+        let mut delta = Vec::new();
+        generate_delta_with_hash(&index, &mut KeyedHash::default(), &mut basis.as_slice(),
+                                 &mut delta).unwrap();
+        assert_eq!(&delta[4..8], &[0x46, 0x00, 0x0b, 0xb8]);
+        assert_eq!(delta.len(), 4 + 4 + 1);
+        // With the standard hash, nothing matches.
+        let mut delta = Vec::new();
+        generate_delta(&sig, &mut basis.as_slice(), &mut delta).unwrap();
+        assert_eq!(delta.len(), 4 + 3 + 3000 + 1);
+    }
+
+    /// A deliberately simple rolling hash: the sum of the bytes in the window.
+    #[derive(Default)]
+    struct ByteSum(u32);
+
+    impl RollingHash for ByteSum {
+        fn digest(&self) -> u32 { self.0 }
+        fn roll_in(&mut self, c_in: u8) { self.0 += u32::from(c_in); }
+        fn roll_out(&mut self, c_out: u8) { self.0 -= u32::from(c_out); }
+        fn rotate(&mut self, c_out: u8, c_in: u8) {
+            self.roll_out(c_out);
+            self.roll_in(c_in);
+        }
+        fn update(&mut self, buf: &[u8]) {
+            for &c in buf { self.roll_in(c); }
+        }
+    }
+
+    #[test]
+    pub fn custom_rolling_hash() {
+        let basis = pattern(3000);
+        let mut new = b"xyz".to_vec();
+        new.extend_from_slice(&basis);
+        let sig = calculate_signature_with_hashes::<ByteSum>(
+            &mut basis.as_slice(), &small_blocks(), &mut Blake2Hash::default()).unwrap();
+        let mut delta = Vec::new();
+        generate_delta_with_hashes::<ByteSum>(&SignatureIndex::new(&sig),
+                                              &mut Blake2Hash::default(),
+                                              &mut new.as_slice(), &mut delta).unwrap();
+        assert_eq!(&delta[4..12], &[3, b'x', b'y', b'z', 0x46, 0x00, 0x0b, 0xb8]);
+        assert_eq!(delta.len(), 4 + 4 + 4 + 1);
+    }
+
+    /// Every window has the same weak sum.
+    #[derive(Default)]
+    struct ConstantSum;
+
+    impl RollingHash for ConstantSum {
+        fn digest(&self) -> u32 { 0 }
+        fn roll_in(&mut self, _c_in: u8) {}
+        fn roll_out(&mut self, _c_out: u8) {}
+        fn rotate(&mut self, _c_out: u8, _c_in: u8) {}
+        fn update(&mut self, _buf: &[u8]) {}
+    }
+
+    /// A weak sum matching at every position never gives a COPY unless the strong sum
+    /// matches too, and each miss is counted.
+    #[test]
+    pub fn weak_hits_are_confirmed() {
+        let basis = pattern(3000);
+        let sig = calculate_signature_with_hashes::<ConstantSum>(
+            &mut basis.as_slice(), &small_blocks(), &mut Blake2Hash::default()).unwrap();
+        let mut new: Vec<u8> = basis.iter().map(|&c| c ^ 0x55).collect();
+        new.extend_from_slice(&basis[1024..2048]);
+        let mut delta = Vec::new();
+        let stats = generate_delta_with_hashes::<ConstantSum>(&SignatureIndex::new(&sig),
+                                                              &mut Blake2Hash::default(),
+                                                              &mut new.as_slice(), &mut delta)
+            .unwrap();
+        assert_eq!((stats.copy_cmds, stats.copy_bytes), (1, 1024));
+        assert_eq!(stats.literal_bytes, 3000);
+        assert_eq!(stats.false_matches, 3000);
+    }
+
+    /// Literals are flushed periodically so that memory use is bounded.
+    #[test]
+    pub fn very_long_literal() {
+        let new = pattern(100_000);
+        let delta = delta_of(b"", &new, &SignatureOptions::default());
+        assert_eq!(&delta[4..7], &[OP_LITERAL_N1 + 1, 0x80, 0x00]);
+        assert_eq!(delta.len(), 4 + 4 * 3 + 100_000 + 1);
+    }
+
+    /// Unmatched data is sent in as few literals as `max_literal_len` allows.
+    #[test]
+    pub fn max_literal_len() {
+        let sig = calculate_signature(&mut &b""[..], &SignatureOptions::default()).unwrap();
+        let new = pattern(1000);
+        let options = DeltaOptions { max_literal_len: 100, .. DeltaOptions::default() };
+        let stats = generate_delta_with_options(&sig, &mut new.as_slice(), &mut Vec::new(),
+                                                &options).unwrap();
+        assert_eq!((stats.literal_cmds, stats.literal_bytes), (10, 1000));
+        let stats = generate_delta_with_options(&sig, &mut new.as_slice(), &mut Vec::new(),
+                                                &DeltaOptions::default()).unwrap();
+        assert_eq!(stats.literal_cmds, 1);
+        let zero = DeltaOptions { max_literal_len: 0, .. DeltaOptions::default() };
+        match generate_delta_with_options(&sig, &mut new.as_slice(), &mut Vec::new(), &zero) {
+            Err(Error::InvalidOptions(_)) => (),
+            r => panic!("unexpected {:?}", r),
+        }
+    }
+
+    /// No COPY is longer than `max_copy_len`, even where the blocks they match run on.
+    #[test]
+    pub fn max_copy_len() {
+        use std::io::Cursor;
+        use super::super::delta::DeltaReader;
+        use super::super::patch::apply_patch;
+
+        let basis = pattern(100_000);
+        let mut new = basis[..50_000].to_vec();
+        new.extend_from_slice(b"an edit");
+        new.extend_from_slice(&basis[50_000..]);
+        let sig = calculate_signature(&mut basis.as_slice(), &small_blocks()).unwrap();
+        let options = DeltaOptions { max_copy_len: 5000, .. DeltaOptions::default() };
+        let mut delta = Vec::new();
+        let stats = generate_delta_with_options(&sig, &mut new.as_slice(), &mut delta, &options)
+            .unwrap();
+        let mut reader = DeltaReader::new(delta.as_slice()).unwrap();
+        for command in reader.by_ref() {
+            if let DeltaCommand::Copy { len, .. } = command.unwrap() {
+                assert!(len <= 5000, "{}", len);
+            }
+        }
+        assert_eq!(reader.statistics().copy_cmds, stats.copy_cmds);
+        assert!(stats.copy_cmds >= 20, "{:?}", stats);
+        let mut out = Vec::new();
+        apply_patch(&mut Cursor::new(&basis), &mut delta.as_slice(), &mut out).unwrap();
+        assert_eq!(out, new);
+        let zero = DeltaOptions { max_copy_len: 0, .. DeltaOptions::default() };
+        let err = generate_delta_with_options(&sig, &mut new.as_slice(), &mut Vec::new(), &zero)
+            .unwrap_err();
+        assert!(matches!(err, Error::InvalidOptions(_)), "{:?}", err);
+    }
+
+    /// Without searching, a delta against an empty signature is just what a search that
+    /// never matches would make.
+    #[test]
+    pub fn empty_signature_sends_literals() {
+        let empty = calculate_signature(&mut &b""[..], &small_blocks()).unwrap();
+        let unrelated = calculate_signature(&mut &[0xaa; 10_000][..], &small_blocks()).unwrap();
+        let new = pattern(250_000);
+        for options in &[DeltaOptions { max_literal_len: 30_000, .. DeltaOptions::default() },
+                         DeltaOptions { checksum: true, .. DeltaOptions::default() }] {
+            let mut literal = Vec::new();
+            let stats = generate_delta_with_options(&empty, &mut new.as_slice(), &mut literal,
+                                                    options).unwrap();
+            assert_eq!((stats.in_bytes, stats.literal_bytes), (250_000, 250_000));
+            let mut searched = Vec::new();
+            generate_delta_with_options(&unrelated, &mut new.as_slice(), &mut searched, options)
+                .unwrap();
+            assert!(literal == searched);
+        }
+    }
+
+    /// An index within a memory limit still finds unchanged runs of blocks, and only
+    /// the delta around a change grows.
+    #[test]
+    pub fn index_memory_limit() {
+        use std::io::Cursor;
+        use super::super::patch::apply_patch;
+
+        let basis: Vec<u8> = (0..200_000u64).map(|i| {
+            let x = i.wrapping_mul(0x9e37_79b9_7f4a_7c15);
+            ((x ^ (x >> 29)).wrapping_mul(0xbf58_476d_1ce4_e5b9) >> 56) as u8
+        }).collect();
+        let mut new = basis[..50_000].to_vec();
+        new.extend_from_slice(b"a change");
+        new.extend_from_slice(&basis[50_100..]);
+        let cdc = SignatureOptions {
+            magic: SignatureFormat::CdcBlake2Sig,
+            block_len: 512,
+            .. SignatureOptions::default()
+        };
+        let limited = DeltaOptions { index_memory_limit: Some(2000), .. DeltaOptions::default() };
+        for sig_options in &[small_blocks(), cdc] {
+            let sig = calculate_signature(&mut basis.as_slice(), sig_options).unwrap();
+            assert!(SignatureIndex::with_memory_limit(&sig, 2000).stride() > 1);
+            let mut deltas = Vec::new();
+            for (file, options) in &[(&basis, DeltaOptions::default()), (&basis, limited),
+                                     (&new, DeltaOptions::default()), (&new, limited)] {
+                let mut delta = Vec::new();
+                generate_delta_with_options(&sig, &mut file.as_slice(), &mut delta, options)
+                    .unwrap();
+                let mut patched = Vec::new();
+                apply_patch(&mut Cursor::new(&basis), &mut delta.as_slice(), &mut patched)
+                    .unwrap();
+                assert!(&patched == *file);
+                deltas.push(delta.len());
+            }
+            assert_eq!(deltas[0], deltas[1]);
+            assert!(deltas[3] > deltas[2] && deltas[3] < 20_000, "{:?}", deltas);
+        }
+    }
+
+    #[test]
+    pub fn estimate() {
+        let old = pattern(100_000);
+        let mut new = old[..40_000].to_vec();
+        new.extend_from_slice(b"a change");
+        new.extend_from_slice(&old[45_000..]);
+        let sig = calculate_signature(&mut old.as_slice(), &SignatureOptions::default())
+            .unwrap();
+        for options in &[DeltaOptions::default(),
+                         DeltaOptions { checksum: true, .. DeltaOptions::default() }] {
+            let mut delta = Vec::new();
+            let made = generate_delta_with_options(&sig, &mut new.as_slice(), &mut delta,
+                                                   options).unwrap();
+            let estimate = estimate_delta(&sig, &mut new.as_slice(), options).unwrap();
+            assert_eq!(estimate.out_bytes, delta.len() as u64);
+            let timed_as_made = Statistics {
+                elapsed: made.elapsed,
+                read_time: made.read_time,
+                rolling_time: made.rolling_time,
+                strong_time: made.strong_time,
+                write_time: made.write_time,
+                .. estimate
+            };
+            assert_eq!(timed_as_made, made);
+        }
+    }
+
+    #[test]
+    #[cfg(feature = "zstd")]
+    pub fn compressed_delta() {
+        use super::super::memory;
+
+        let basis: Vec<u8> = (0..3000).flat_map(|i| format!("line {} of the basis\n", i)
+                                                    .into_bytes()).collect();
+        let mut new = basis[..20_000].to_vec();
+        new.extend((0..2000).flat_map(|i| format!("inserted line {}\n", i).into_bytes()));
+        new.extend_from_slice(&basis[30_000..]);
+        let sig = calculate_signature(&mut basis.as_slice(), &small_blocks()).unwrap();
+        let mut plain = Vec::new();
+        generate_delta(&sig, &mut new.as_slice(), &mut plain).unwrap();
+        let mut compressed = Vec::new();
+        let options = DeltaOptions { compress: true, .. DeltaOptions::default() };
+        let stats = generate_delta_with_options(&sig, &mut new.as_slice(), &mut compressed,
+                                                &options).unwrap();
+        assert_eq!(&compressed[..4], b"rs\x836");
+        assert_eq!(stats.out_bytes, compressed.len() as u64);
+        assert!(compressed.len() * 3 < plain.len(), "{} {}", compressed.len(), plain.len());
+        assert_eq!(memory::apply(&basis, &compressed).unwrap(), new);
+    }
+
+    #[test]
+    pub fn compress_options() {
+        let sig = calculate_signature(&mut &b""[..], &SignatureOptions::default()).unwrap();
+        let both = DeltaOptions { compress: true, checksum: true, .. DeltaOptions::default() };
+        match generate_delta_with_options(&sig, &mut &b"hello"[..], &mut Vec::new(), &both) {
+            Err(Error::InvalidOptions(_)) => (),
+            r => panic!("unexpected {:?}", r),
+        }
+        let compress = DeltaOptions { compress: true, .. DeltaOptions::default() };
+        let r = generate_delta_with_options(&sig, &mut &b"hello"[..], &mut Vec::new(), &compress);
+        assert_eq!(r.is_ok(), cfg!(feature = "zstd"), "{:?}", r);
+    }
+
+    #[test]
+    pub fn multi_basis_options() {
+        let sig = calculate_signature(&mut pattern(5000).as_slice(), &small_blocks()).unwrap();
+        let other = calculate_signature(&mut pattern(5000).as_slice(),
+                                        &SignatureOptions::default()).unwrap();
+        for sigs in &[&[][..], &[&sig, &other][..]] {
+            match generate_delta_multi(sigs, &mut &b"hello"[..], &mut Vec::new()) {
+                Err(Error::InvalidOptions(_)) => (),
+                r => panic!("unexpected {:?}", r),
+            }
+        }
+    }
+
+    /// Against a content-defined signature, chunks after an edit still match, including
+    /// from several bases.
+    #[test]
+    pub fn content_defined_chunks() {
+        use std::io::Cursor;
+        use super::super::patch::{apply_patch, apply_patch_multi};
+
+        let options = SignatureOptions {
+            magic: SignatureFormat::CdcBlake2Sig,
+            block_len: 512,
+            .. SignatureOptions::default()
+        };
+        let basis = pattern(100_000);
+        let mut new = b"inserted at the start".to_vec();
+        new.extend_from_slice(&basis[..60_000]);
+        new.extend_from_slice(&[7; 1000]);
+        new.extend_from_slice(&basis[61_000..]);
+        let delta = delta_of(&basis, &new, &options);
+        assert!(delta.len() < 5000, "{}", delta.len());
+        let mut patched = Vec::new();
+        apply_patch(&mut Cursor::new(&basis), &mut delta.as_slice(), &mut patched).unwrap();
+        assert_eq!(patched, new);
+
+        let other = pattern(3000).iter().map(|b| b ^ 0x55).collect::<Vec<u8>>();
+        let sigs = [calculate_signature(&mut &other[..], &options).unwrap(),
+                    calculate_signature(&mut &basis[..], &options).unwrap()];
+        let mut both = other.clone();
+        both.extend_from_slice(&new);
+        let mut delta = Vec::new();
+        generate_delta_multi(&[&sigs[0], &sigs[1]], &mut both.as_slice(), &mut delta).unwrap();
+        assert!(delta.len() < 6000, "{}", delta.len());
+        let mut patched = Vec::new();
+        let mut bases = [Cursor::new(&other), Cursor::new(&basis)];
+        apply_patch_multi(&mut bases, &mut delta.as_slice(), &mut patched).unwrap();
+        assert_eq!(patched, both);
+    }
+
+    /// Blocks of several lengths are all found in the new file.
+    #[test]
+    pub fn variable_blocks() {
+        use std::io::Cursor;
+        use super::super::mksum::calculate_signature_variable;
+        use super::super::patch::apply_patch;
+
+        let basis = pattern(50_000);
+        let options = SignatureOptions { block_len: 4096, .. SignatureOptions::default() };
+        let sig = calculate_signature_variable(&mut basis.as_slice(), &options, &[256; 8])
+            .unwrap();
+        let mut new = basis[..1000].to_vec();
+        new.extend_from_slice(b"a small change near the start");
+        new.extend_from_slice(&basis[1100..]);
+        let mut delta = Vec::new();
+        generate_delta(&sig, &mut new.as_slice(), &mut delta).unwrap();
+        // Only the small blocks that changed, and the new text, are sent as literals.
+        assert!(delta.len() < 600, "{}", delta.len());
+        let mut patched = Vec::new();
+        apply_patch(&mut Cursor::new(&basis), &mut delta.as_slice(), &mut patched).unwrap();
+        assert_eq!(patched, new);
+
+        let fixed = calculate_signature(&mut basis.as_slice(), &options).unwrap();
+        let mut fixed_delta = Vec::new();
+        generate_delta(&fixed, &mut new.as_slice(), &mut fixed_delta).unwrap();
+        assert!(fixed_delta.len() > 4000, "{}", fixed_delta.len());
+        match generate_delta_multi(&[&sig, &fixed], &mut &b"hello"[..], &mut Vec::new()) {
+            Err(Error::InvalidOptions(_)) => (),
+            r => panic!("unexpected {:?}", r),
+        }
+    }
+
+    /// A match far into a multi-gigabyte basis is sent with an 8-byte offset.
+    #[test]
+    pub fn copy_beyond_4gib() {
+        let options = SignatureOptions {
+            block_len: 1 << 20,
+            .. SignatureOptions::default()
+        };
+        let new = pattern(1 << 20);
+        let block = calculate_signature(&mut new.as_slice(), &options).unwrap();
+        let mut sig = Signature::new(&options);
+        let other = vec![0; sig.strong_len() as usize];
+        for _ in 0..5000 {
+            sig.push_block(block.weak_sum(0).wrapping_add(1), &other);
+        }
+        sig.push_block(block.weak_sum(0), block.strong_sum(0));
+        let mut delta = Vec::new();
+        generate_delta(&sig, &mut new.as_slice(), &mut delta).unwrap();
+        let mut expected = vec![b'r', b's', 0x02, 0x36, OP_COPY_N8_N8 - 1];
+        expected.extend(&(5000u64 << 20).to_be_bytes());
+        expected.extend(&(1u32 << 20).to_be_bytes());
+        expected.push(OP_END);
+        assert_eq!(delta, expected);
+    }
+
+    /// Generate a delta until the `stop_at`th checkpoint, and return that checkpoint and
+    /// the delta written up to it.
+    fn delta_interrupted(index: &SignatureIndex, new: &[u8], options: &DeltaOptions,
+                         stop_at: usize) -> (DeltaCheckpoint, Vec<u8>) {
+        use std::io::Cursor;
+        use super::super::error::Error;
+
+        let mut saved = Vec::new();
+        let mut delta = Vec::new();
+        let checkpoint = DeltaCheckpoint::new(options).unwrap();
+        let err = resume_delta(index, checkpoint, &mut Cursor::new(new), &mut delta, 100_000,
+                               &mut |c| {
+            saved.push(c.clone());
+            if saved.len() == stop_at { Err(Error::Cancelled) } else { Ok(()) }
+        }).unwrap_err();
+        assert!(matches!(err, Error::Cancelled), "{:?}", err);
+        let checkpoint = saved.pop().unwrap();
+        assert!(delta.len() as u64 >= checkpoint.delta_offset());
+        delta.truncate(checkpoint.delta_offset() as usize);
+        (checkpoint, delta)
+    }
+
+    /// A delta resumed from a checkpoint is the same as one made straight through, even
+    /// when a literal or a COPY spans the checkpoint.
+    #[test]
+    pub fn resume_from_checkpoint() {
+        use std::io::Cursor;
+
+        let basis = pattern(500_000);
+        let mut x: u32 = 1;
+        let noise: Vec<u8> = (0..20_000).map(|_| {
+            x = x.wrapping_mul(1_103_515_245).wrapping_add(12_345);
+            (x >> 24) as u8
+        }).collect();
+        let mut new = basis[..250_000].to_vec();
+        new.extend_from_slice(&noise);
+        new.extend_from_slice(&basis[310_000..]);
+        let sig = calculate_signature(&mut basis.as_slice(), &small_blocks()).unwrap();
+        let index = SignatureIndex::new(&sig);
+        for &basis_id in &[false, true] {
+            let options = DeltaOptions { basis_id, .. DeltaOptions::default() };
+            let mut whole = Vec::new();
+            generate_delta_with_options(&sig, &mut new.as_slice(), &mut whole, &options)
+                .unwrap();
+            for stop_at in 1..=3 {
+                let (checkpoint, mut delta) = delta_interrupted(&index, &new, &options,
+                                                                stop_at);
+                assert!(checkpoint.new_offset() >= stop_at as u64 * 100_000);
+                let stats = resume_delta(&index, checkpoint, &mut Cursor::new(&new),
+                                         &mut delta, 100_000, &mut |_| Ok(())).unwrap();
+                assert_eq!(delta, whole);
+                assert_eq!(stats.in_bytes, new.len() as u64);
+                assert_eq!(stats.out_bytes, whole.len() as u64);
+            }
+        }
+    }
+
+    #[test]
+    pub fn pipelined_delta() {
+        let basis = pattern(500_000);
+        let mut new = basis[100_000..].to_vec();
+        new.extend_from_slice(b"and something else");
+        new.extend_from_slice(&basis[..50_000]);
+        let sig = calculate_signature(&mut basis.as_slice(), &small_blocks()).unwrap();
+        for options in &[DeltaOptions::default(),
+                         DeltaOptions { checksum: true, .. DeltaOptions::default() }] {
+            let mut expected = Vec::new();
+            let expected_stats = generate_delta_with_options(&sig, &mut new.as_slice(),
+                                                             &mut expected, options).unwrap();
+            let mut delta = Vec::new();
+            let stats = generate_delta_pipelined(&sig, &mut new.as_slice(), &mut delta, options)
+                .unwrap();
+            assert_eq!(delta, expected);
+            assert_eq!((stats.in_bytes, stats.out_bytes, stats.copy_bytes),
+                       (expected_stats.in_bytes, expected_stats.out_bytes,
+                        expected_stats.copy_bytes));
+        }
+    }
+
+    #[test]
+    pub fn delta_checkpoint_errors() {
+        use std::io::Cursor;
+        use super::super::error::Error;
+
+        for options in &[DeltaOptions { checksum: true, .. DeltaOptions::default() },
+                         DeltaOptions { max_literal_len: 0, .. DeltaOptions::default() }] {
+            let err = DeltaCheckpoint::new(options).unwrap_err();
+            assert!(matches!(err, Error::InvalidOptions(_)), "{:?}", err);
+        }
+        let sig = Signature::new(&SignatureOptions::default());
+        let checkpoint = DeltaCheckpoint::new(&DeltaOptions::default()).unwrap();
+        let err = resume_delta(&SignatureIndex::new(&sig), checkpoint, &mut Cursor::new(&[]),
+                               &mut Vec::new(), 0, &mut |_| Ok(())).unwrap_err();
+        assert!(matches!(err, Error::InvalidOptions(_)), "{:?}", err);
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    pub fn serialize_delta_checkpoint() {
+        use std::io::Cursor;
+
+        let basis = pattern(300_000);
+        let mut new = b"a new start".to_vec();
+        new.extend_from_slice(&basis);
+        let sig = calculate_signature(&mut basis.as_slice(), &small_blocks()).unwrap();
+        let index = SignatureIndex::new(&sig);
+        let (checkpoint, mut delta) = delta_interrupted(&index, &new, &DeltaOptions::default(),
+                                                        2);
+        let json = serde_json::to_string(&checkpoint).unwrap();
+        let checkpoint: DeltaCheckpoint = serde_json::from_str(&json).unwrap();
+        resume_delta(&index, checkpoint, &mut Cursor::new(&new), &mut delta, 100_000,
+                     &mut |_| Ok(())).unwrap();
+        let mut whole = Vec::new();
+        generate_delta(&sig, &mut new.as_slice(), &mut whole).unwrap();
+        assert_eq!(delta, whole);
+    }
+}
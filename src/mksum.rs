@@ -6,61 +6,45 @@
 //! Signatures describe a 'base' or 'old' file, and allow deltas to be generated without
 //! access to the old file.
 
-use std::io::{BufWriter, Read, Write, Result};
+use std::io;
+use std::io::{BufWriter, Read, Seek, SeekFrom, Write};
+use std::ops::Range;
+use std::time::Duration;
 
-use blake2::{Blake2b};
-use blake2::digest::{Input, VariableOutput};
 use byteorder::{BigEndian, WriteBytesExt};
 use cast::usize;
+#[cfg(feature = "parallel")]
+use rayon::prelude::*;
 
-use super::magic::SignatureFormat;
-use super::rollsum::{Rollsum, Rollsum1};
+use super::cancel::CancelToken;
+use super::cdc::Chunker;
+#[cfg(feature = "zstd")]
+use super::compress::Compressor;
+use super::delta::{finish_basis_hash, hash_block, BasisId};
+use super::error::{Error, Result};
+use super::io_options::IoOptions;
+#[cfg(not(all(target_arch = "wasm32", target_os = "unknown")))]
+use super::pipeline::pipelined;
+use super::magic::{SIGNATURE_SEEDED_MAGIC, SIGNATURE_V2_MAGIC};
+use super::progress::{Meter, Progress};
+use super::rabinkarp::RabinKarp;
+use super::rollsum::{block_sum, RollingHash, Rollsum1};
+pub use super::signature::{SignatureOptions, SignatureOptionsBuilder};
+use super::signature::{check_options, seed_weak, seeded_strong_sum, Signature,
+                       RS_MAX_STRONG_SUM_LENGTH};
+use super::stats::{Statistics, Timed, Timer};
+use super::strongsum::{Blake2Hash, StrongHash};
 
-// Must match that in rdiff.
-const RS_MAX_STRONG_SUM_LENGTH: usize = 32;
+/// Roughly how much of the basis to read at a time to hash in parallel: enough to keep
+/// the threads busy, while still reporting progress often.
+#[cfg(feature = "parallel")]
+const PARALLEL_CHUNK_LEN: usize = 1 << 20;
 
-/// Configuration options for a generated signature file.
-///
-/// The values from `SignatureOptions::default()` are usually good, but applications
-/// might want to set the `block_len`.
-#[derive(Debug, Copy, Clone)]
-pub struct SignatureOptions {
-    /// Format of the signature, identified by its magic number.
-    pub magic: SignatureFormat,
-
-    /// Length of a block in bytes.
-    ///
-    /// Smaller blocks produce larger signatures because there are more blocks, but allow matching
-    /// smaller common regions between files.
-    pub block_len: u32,
+/// Receives the length, weak sum and truncated strong sum of each block in turn.
+type BlockFn<'a> = dyn FnMut(u32, u32, &[u8]) -> Result<()> + 'a;
 
-    /// Length of strong signatures.
-    ///
-    /// This is normally best left at the default, which is the strong hash, but
-    /// they may be truncated to get smaller signatures although with a risk of exploitable
-    /// collisions.
-    pub strong_len: u32,
-}
-
-impl SignatureOptions {
-    pub fn default() -> SignatureOptions {
-        SignatureOptions {
-            magic: SignatureFormat::Blake2Sig,
-            block_len: super::DEFAULT_BLOCK_LEN,
-            strong_len: RS_MAX_STRONG_SUM_LENGTH as u32,
-        }
-    }
-
-    pub fn with_strong_len(self, s: u32) -> SignatureOptions {
-        SignatureOptions {
-            strong_len: s,
-            .. self
-        }
-    }
-}
-
-fn write_u32be(f: &mut Write, a: u32) -> Result<()> {
-    f.write_u32::<BigEndian>(a)
+fn write_u32be<W: Write + ?Sized>(f: &mut W, a: u32) -> Result<()> {
+    Ok(f.write_u32::<BigEndian>(a)?)
 }
 
 /// Fill a block buffer with data from the input file, retrying if necessary.
@@ -72,8 +56,8 @@ fn write_u32be(f: &mut Write, a: u32) -> Result<()> {
 /// 2. There's a regular full size block.
 ///
 /// 3. There is less than a full block, and then the end of the file. In this case we
-/// return the contents, but we don't want to try again next time, as that could generate
-/// two short blocks.
+///    return the contents, but we don't want to try again next time, as that could
+///    generate two short blocks.
 ///
 /// We need to distinguish these even though any particular read from the file might
 /// return short. There might be following blocks iff this block is full sized.
@@ -81,7 +65,7 @@ fn write_u32be(f: &mut Write, a: u32) -> Result<()> {
 /// `buf.len()` is the block length.
 ///
 /// Returns Ok(bytes_read).
-fn fill_buffer(inf: &mut Read, buf: &mut [u8]) -> Result<usize> {
+pub(crate) fn fill_buffer<R: Read + ?Sized>(inf: &mut R, buf: &mut [u8]) -> Result<usize> {
     let mut bytes_read: usize = 0;
     while bytes_read < buf.len() {
         let l = inf.read(&mut buf[bytes_read..])?;
@@ -91,59 +75,864 @@ fn fill_buffer(inf: &mut Read, buf: &mut [u8]) -> Result<usize> {
             bytes_read += l;
         }
     }
-    return Ok(bytes_read);
-
+    Ok(bytes_read)
 }
 
-/// Generate a signature, reading a basis file and writing a signature file.
-pub fn generate_signature(basis: &mut Read, options: &SignatureOptions, sig: &mut Write) -> Result<()> {
-    // TODO: Use hashers selected by the options.
+/// Pass the length, weak sum and truncated strong sum of block `b` to `f`, with `seed`
+/// mixed into both sums.
+fn sum_block<R: RollingHash + Default>(b: &[u8], seed: u32, hash: &mut dyn StrongHash,
+                                       strong: &mut [u8], f: &mut BlockFn)
+    -> Result<()> {
+    seeded_strong_sum(hash, seed, b, strong);
+    f(b.len() as u32, seed_weak(block_sum::<R>(b), seed), strong)
+}
 
+/// Read the basis in blocks, and pass the weak and truncated strong sum of each to `f`.
+///
+/// For a content-defined format, the blocks are chunks found by `hash_chunks`.
+///
+/// Returns the length of the basis.
+#[cfg_attr(feature = "tracing", tracing::instrument(level = "debug", skip_all,
+    fields(block_len = options.block_len, strong_len = options.strong_len)))]
+fn hash_blocks<R: RollingHash + Default>(basis: &mut (impl Read + ?Sized),
+                                         options: &SignatureOptions,
+                                         hash: &mut dyn StrongHash, f: &mut BlockFn)
+    -> Result<u64> {
+    if options.magic.is_content_defined() {
+        return hash_chunks::<R>(basis, options, hash, f);
+    }
     // This cast should be always be safe on 32-bit platforms and will work on platforms
     // with 16-bit pointers (I think) as long as the blocks are <64k. And blocks that are
     // too large to fit in memory aren't likely to work well anyhow...
     let mut buf = vec![0; usize(options.block_len)];
+    let mut strong = vec![0; options.strong_len as usize];
+    let mut basis_len = 0;
+    loop {
+        let l = fill_buffer(basis, &mut buf)?;
+        if l == 0 { break; }
+        basis_len += l as u64;
+        sum_block::<R>(&buf[..l], options.seed, hash, &mut strong, f)?;
+        if l < buf.len() { break; } // Short block must be the last.
+    }
+    debug_event!(blocks = basis_len.div_ceil(options.block_len as u64), basis_len, "hashed basis");
+    Ok(basis_len)
+}
 
-    let sig = &mut BufWriter::new(sig);
-    write_u32be(sig, options.magic as u32)?;
-    write_u32be(sig, options.block_len)?;
-    write_u32be(sig, options.strong_len)?;
+/// Read the basis in content-defined chunks, and pass the length, weak and truncated
+/// strong sum of each to `f`.
+///
+/// A buffer of the longest chunk's length is kept full, so that the end of each chunk
+/// can be found in it. Returns the length of the basis.
+fn hash_chunks<R: RollingHash + Default>(basis: &mut (impl Read + ?Sized),
+                                         options: &SignatureOptions,
+                                         hash: &mut dyn StrongHash, f: &mut BlockFn)
+    -> Result<u64> {
+    hash_chunks_until::<R>(basis, options, hash, f, &mut |_| false)
+}
 
+/// Like `hash_chunks`, but stopping after a chunk if `stop` returns true when passed how
+/// far through the basis it ends.
+///
+/// Returns how much of the basis was read, which may be more than was divided into
+/// chunks.
+fn hash_chunks_until<R: RollingHash + Default>(basis: &mut (impl Read + ?Sized),
+                                               options: &SignatureOptions,
+                                               hash: &mut dyn StrongHash, f: &mut BlockFn,
+                                               stop: &mut dyn FnMut(u64) -> bool)
+    -> Result<u64> {
+    let chunker = Chunker::new(options.block_len);
+    let mut buf = vec![0; chunker.max_len()];
+    let mut strong = vec![0; options.strong_len as usize];
+    let (mut start, mut end, mut eof) = (0, 0, false);
+    let (mut basis_len, mut chunked) = (0, 0);
     loop {
+        if !eof && end - start < buf.len() {
+            buf.copy_within(start..end, 0);
+            end -= start;
+            start = 0;
+            let l = fill_buffer(basis, &mut buf[end..])?;
+            eof = end + l < buf.len();
+            end += l;
+            basis_len += l as u64;
+        }
+        // With a whole chunk or the rest of the basis in the buffer, there's always an
+        // answer.
+        let l = match chunker.next_chunk(&buf[start..end], eof) {
+            Some(0) | None => break,
+            Some(l) => l,
+        };
+        sum_block::<R>(&buf[start..(start + l)], options.seed, hash, &mut strong, f)?;
+        start += l;
+        chunked += l as u64;
+        if stop(chunked) { break; }
+    }
+    debug_event!(basis_len, "hashed basis in chunks");
+    Ok(basis_len)
+}
+
+/// Like `hash_blocks`, but the first blocks have the lengths in `head_lens`, and the rest
+/// `options.block_len`.
+fn hash_variable_blocks<R: RollingHash + Default>(basis: &mut (impl Read + ?Sized),
+                                                  options: &SignatureOptions,
+                                                  head_lens: &[u32],
+                                                  hash: &mut dyn StrongHash, f: &mut BlockFn)
+    -> Result<u64> {
+    let longest = head_lens.iter().cloned().fold(options.block_len, u32::max);
+    let mut buf = vec![0; usize(longest)];
+    let mut strong = vec![0; options.strong_len as usize];
+    let mut basis_len = 0;
+    for i in 0.. {
+        let block_len = usize(*head_lens.get(i).unwrap_or(&options.block_len));
+        let l = fill_buffer(basis, &mut buf[..block_len])?;
+        if l == 0 { break; }
+        basis_len += l as u64;
+        sum_block::<R>(&buf[..l], options.seed, hash, &mut strong, f)?;
+        if l < block_len { break; }
+    }
+    debug_event!(basis_len, "hashed basis in blocks of varying length");
+    Ok(basis_len)
+}
+
+/// Like `hash_blocks`, but reading many blocks at a time and hashing them on the rayon
+/// thread pool, each thread with its own hasher for the format's strong sum.
+///
+/// The sums are still passed to `f` in order.
+#[cfg(feature = "parallel")]
+#[cfg_attr(feature = "tracing", tracing::instrument(level = "debug", skip_all,
+    fields(block_len = options.block_len, strong_len = options.strong_len)))]
+fn hash_blocks_parallel<R: RollingHash + Default>(basis: &mut (impl Read + ?Sized),
+                                                  options: &SignatureOptions,
+                                                  f: &mut BlockFn, strong_time: &mut Duration)
+    -> Result<u64> {
+    let block_len = usize(options.block_len);
+    let strong_len = options.strong_len as usize;
+    let mut buf = vec![0; block_len * (PARALLEL_CHUNK_LEN / block_len).max(1)];
+    let mut basis_len = 0;
+    loop {
+        // As with single blocks, a short chunk must be the last.
         let l = fill_buffer(basis, &mut buf)?;
         if l == 0 { break; }
-        let b = &buf[..l];
-        {
-            let mut rs = Rollsum1::new();
-            rs.update(b);
-            write_u32be(sig, rs.digest())?;
-        }
-        {
-            // C rdiff uses the slightly odd thing of specifying the 'max' (32) as the
-            // hasher output length, then throwing away some of the digest. OK.
-            let mut hasher = Blake2b::new(RS_MAX_STRONG_SUM_LENGTH as usize).unwrap();
-            hasher.process(b);
-            let mut d = [0u8; RS_MAX_STRONG_SUM_LENGTH];
-            hasher.variable_result(&mut d).unwrap();
-            sig.write(&d[..(options.strong_len as usize)])?;
+        basis_len += l as u64;
+        let sums: Vec<(u32, u32, [u8; RS_MAX_STRONG_SUM_LENGTH], Duration)> = buf[..l]
+            .par_chunks(block_len)
+            .map_init(|| options.magic.strong_hash(), |hash, b| {
+                let magic = options.magic;
+                let hash = hash.as_mut().map_err(|_| Error::UnsupportedFormat(magic as u32))?;
+                let mut strong = [0; RS_MAX_STRONG_SUM_LENGTH];
+                let hash = &mut Timed::new(&mut **hash);
+                seeded_strong_sum(hash, options.seed, b, &mut strong[..strong_len]);
+                Ok((b.len() as u32, seed_weak(block_sum::<R>(b), options.seed), strong, hash.time))
+            })
+            .collect::<Result<_>>()?;
+        for (len, weak, strong, time) in &sums {
+            *strong_time += *time;
+            f(*len, *weak, &strong[..strong_len])?;
         }
-        if l < buf.len() { break; } // Short block must be the last.
+        if l < buf.len() { break; }
+    }
+    debug_event!(blocks = basis_len.div_ceil(options.block_len as u64), basis_len, "hashed basis");
+    Ok(basis_len)
+}
+
+/// Hash the basis with the format's own weak and strong hashes, on several threads if
+/// the `parallel` feature is on.
+///
+/// Chunk boundaries are found one after another, so a content-defined format is always
+/// hashed on one thread.
+fn hash_blocks_standard<B: Read + ?Sized>(basis: &mut B, options: &SignatureOptions,
+                                          f: &mut BlockFn)
+    -> Result<u64> {
+    hash_blocks_timed(basis, options, f, &mut Duration::default())
+}
+
+/// Like `hash_blocks_standard`, adding the time spent calculating strong sums to
+/// `strong_time`.
+fn hash_blocks_timed<B: Read + ?Sized>(basis: &mut B, options: &SignatureOptions,
+                                       f: &mut BlockFn, strong_time: &mut Duration)
+    -> Result<u64> {
+    #[cfg(feature = "parallel")]
+    {
+        if !options.magic.is_content_defined() {
+            return if options.magic.is_rabinkarp() {
+                hash_blocks_parallel::<RabinKarp>(basis, options, f, strong_time)
+            } else {
+                hash_blocks_parallel::<Rollsum1>(basis, options, f, strong_time)
+            };
+        }
+    }
+    let mut hash = options.magic.strong_hash()?;
+    let hash = &mut Timed::new(&mut *hash);
+    let basis_len = if options.magic.is_rabinkarp() {
+        hash_blocks::<RabinKarp>(basis, options, hash, f)
+    } else {
+        hash_blocks::<Rollsum1>(basis, options, hash, f)
+    }?;
+    *strong_time += hash.time;
+    Ok(basis_len)
+}
+
+/// Write a signature header for `options`, then the sums produced by `hash_blocks`.
+///
+/// If `v2` is true, the signature is written in the v2 format, with each block's length,
+/// and `options.block_len` is the longest.
+pub(crate) fn write_signature<W: Write + ?Sized>(options: &SignatureOptions, v2: bool, sig: &mut W,
+                                      io: &IoOptions,
+                                      hash_blocks: &mut dyn FnMut(&mut BlockFn) -> Result<u64>)
+    -> Result<Statistics> {
+    let start = Timer::start();
+    let mut stats = Statistics::new("signature");
+    let sig = &mut BufWriter::with_capacity(io.write_buf, Timed::new(sig));
+    if options.seed != 0 {
+        write_u32be(sig, SIGNATURE_SEEDED_MAGIC)?;
+        write_u32be(sig, options.seed)?;
+        stats.out_bytes += 8;
+    }
+    if v2 {
+        write_u32be(sig, SIGNATURE_V2_MAGIC)?;
+        stats.out_bytes += 4;
+    }
+    write_u32be(sig, options.magic as u32)?;
+    write_u32be(sig, options.block_len)?;
+    write_u32be(sig, options.strong_len)?;
+    stats.out_bytes += 12;
+    stats.block_len = options.block_len;
+    let with_lens = v2 || options.magic.is_content_defined();
+    stats.in_bytes = hash_blocks(&mut |len, weak, strong| {
+        stats.block_count += 1;
+        stats.out_bytes += 4 + strong.len() as u64;
+        if with_lens {
+            stats.out_bytes += 4;
+            write_u32be(sig, len)?;
+        }
+        write_u32be(sig, weak)?;
+        Ok(sig.write_all(strong)?)
+    })?;
+    sig.flush()?;
+    stats.elapsed = start.elapsed();
+    stats.write_time = sig.get_ref().time;
+    Ok(stats)
+}
+
+/// Generate a signature, reading a basis file and writing a signature file.
+///
+/// The basis is read in `block_len` chunks. For each chunk, the signature gets a 4-byte
+/// weak rolling checksum (rollsum or RabinKarp, depending on the format), followed by
+/// the strong sum (BLAKE2 or MD4) truncated to `strong_len`. The last block may be shorter than
+/// `block_len`, and is hashed at its real length.
+///
+/// A content-defined format such as `SignatureFormat::CdcBlake2Sig` instead divides the
+/// basis into chunks that average `block_len`, and gives each chunk's length before
+/// its sums.
+///
+/// A `strong_len` of zero keeps the whole strong sum. `Error::InvalidOptions` is
+/// returned, before anything is written, if `block_len` is zero, and
+/// `Error::StrongLenTooLong` if `strong_len` is longer than the format's strong hash. On
+/// success, the statistics give the number of bytes read and written.
+///
+/// With the `parallel` feature, blocks are read about a megabyte at a time and hashed
+/// on the rayon thread pool. The signature is the same either way.
+pub fn generate_signature<R: Read + ?Sized, W: Write + ?Sized>(
+    basis: &mut R, options: &SignatureOptions, sig: &mut W) -> Result<Statistics> {
+    generate_signature_with_io(basis, options, sig, &IoOptions::default())
+}
+
+/// Generate a signature, buffering its output as set by `io`.
+pub fn generate_signature_with_io<R: Read + ?Sized, W: Write + ?Sized>(
+    basis: &mut R, options: &SignatureOptions, sig: &mut W, io: &IoOptions)
+    -> Result<Statistics> {
+    let options = &check_options(options, &*options.magic.strong_hash()?)?;
+    timed_signature(basis, options, sig, io)
+}
+
+/// Write the signature of `basis`, timing the reading and hashing.
+fn timed_signature<R: Read + ?Sized, W: Write + ?Sized>(
+    basis: &mut R, options: &SignatureOptions, sig: &mut W, io: &IoOptions)
+    -> Result<Statistics> {
+    let basis = &mut Timed::new(basis);
+    let mut strong_time = Duration::default();
+    let mut stats = write_signature(options, false, sig, io, &mut |f| {
+        hash_blocks_timed(basis, options, f, &mut strong_time)
+    })?;
+    stats.set_phases(basis.time, strong_time);
+    Ok(stats)
+}
+
+/// Generate a signature like `generate_signature`, but with the basis read on one
+/// thread, hashed on this one, and the signature written on another, so that reading
+/// overlaps hashing.
+#[cfg(not(all(target_arch = "wasm32", target_os = "unknown")))]
+pub fn generate_signature_pipelined<R: Read + Send + ?Sized, W: Write + Send + ?Sized>(
+    basis: &mut R, options: &SignatureOptions, sig: &mut W) -> Result<Statistics> {
+    let options = &check_options(options, &*options.magic.strong_hash()?)?;
+    let io = IoOptions::default();
+    pipelined(basis, sig, &io, |basis, sig| timed_signature(basis, options, sig, &io))
+}
+
+/// Generate a signature file compressed with zstd at `level`, or at
+/// `compress::DEFAULT_LEVEL` if it's zero.
+///
+/// `Signature::read_from` and `verify_signature` read it like any other, but librsync
+/// can't. The statistics give the compressed length as `out_bytes`.
+#[cfg(feature = "zstd")]
+pub fn generate_signature_compressed<R: Read + ?Sized, W: Write + ?Sized>(
+    basis: &mut R, options: &SignatureOptions, sig: &mut W, level: i32) -> Result<Statistics> {
+    let mut compressor = Compressor::new(sig, level)?;
+    let stats = generate_signature(basis, options, &mut compressor)?;
+    let (_, out_bytes) = compressor.finish()?;
+    Ok(Statistics { out_bytes, .. stats })
+}
+
+/// Generate a signature, calling `progress` as the basis is read.
+pub fn generate_signature_with_progress<R: Read + ?Sized, W: Write + ?Sized>(
+    basis: &mut R, options: &SignatureOptions, sig: &mut W,
+    progress: &mut dyn FnMut(Progress))
+    -> Result<Statistics> {
+    generate_signature_metered(basis, options, sig, &IoOptions::default(),
+                               &Meter::new(None, progress))
+}
+
+/// Generate a signature, stopping with `Error::Cancelled` soon after `cancel` is
+/// cancelled. The signature written so far is incomplete.
+pub fn generate_signature_cancellable<R: Read + ?Sized, W: Write + ?Sized>(
+    basis: &mut R, options: &SignatureOptions, sig: &mut W, cancel: &CancelToken)
+    -> Result<Statistics> {
+    generate_signature(&mut cancel.reader(basis), options, sig)
+}
+
+pub(crate) fn generate_signature_metered<R: Read + ?Sized, W: Write + ?Sized>(
+    basis: &mut R, options: &SignatureOptions, sig: &mut W, io: &IoOptions, meter: &Meter)
+    -> Result<Statistics> {
+    let stats = generate_signature_with_io(&mut meter.reader(basis), options,
+                                           &mut meter.writer(sig), io)?;
+    meter.finish();
+    Ok(stats)
+}
+
+/// Generate a signature using a caller-supplied strong hash.
+///
+/// The header and weak sums are written according to `options.magic` as usual, but the
+/// strong sums come from `hash`. This lets applications use their own hash, such as a
+/// keyed one, without otherwise changing the format. The same kind of hash must be used
+/// to generate deltas from the signature.
+pub fn generate_signature_with_hash<R: Read + ?Sized, W: Write + ?Sized>(
+    basis: &mut R, options: &SignatureOptions, hash: &mut dyn StrongHash, sig: &mut W)
+    -> Result<Statistics> {
+    if options.magic.is_rabinkarp() {
+        generate_signature_with_hashes::<RabinKarp>(basis, options, hash, sig)
+    } else {
+        generate_signature_with_hashes::<Rollsum1>(basis, options, hash, sig)
+    }
+}
+
+/// Generate a signature using caller-supplied weak and strong hashes.
+///
+/// Only the magic number is taken from `options.magic`; the weak sums come from `R`.
+/// Deltas must be generated with `generate_delta_with_hashes` using the same hashes.
+pub fn generate_signature_with_hashes<R: RollingHash + Default>(
+    basis: &mut (impl Read + ?Sized), options: &SignatureOptions, hash: &mut dyn StrongHash,
+    sig: &mut (impl Write + ?Sized)) -> Result<Statistics> {
+    let options = &check_options(options, hash)?;
+    let basis = &mut Timed::new(basis);
+    let hash = &mut Timed::new(hash);
+    let mut stats = write_signature(options, false, sig, &IoOptions::default(),
+                                    &mut |f| hash_blocks::<R>(basis, options, hash, f))?;
+    stats.set_phases(basis.time, hash.time);
+    Ok(stats)
+}
+
+/// Calculate the signature of a basis file into memory, without serializing it.
+///
+/// Like `generate_signature`, this hashes in parallel if the `parallel` feature is on.
+pub fn calculate_signature<R: Read + ?Sized>(basis: &mut R, options: &SignatureOptions)
+    -> Result<Signature> {
+    let options = &check_options(options, &*options.magic.strong_hash()?)?;
+    let mut signature = Signature::new(options);
+    hash_blocks_standard(basis, options, &mut |len, weak, strong| {
+        signature.push_sums(len, weak, strong);
+        Ok(())
+    })?;
+    Ok(signature)
+}
+
+/// Calculate a signature into memory, using a caller-supplied strong hash.
+pub fn calculate_signature_with_hash<R: Read + ?Sized>(basis: &mut R, options: &SignatureOptions,
+                                                      hash: &mut dyn StrongHash)
+    -> Result<Signature> {
+    if options.magic.is_rabinkarp() {
+        calculate_signature_with_hashes::<RabinKarp>(basis, options, hash)
+    } else {
+        calculate_signature_with_hashes::<Rollsum1>(basis, options, hash)
+    }
+}
+
+/// Calculate a signature into memory, using caller-supplied weak and strong hashes.
+pub fn calculate_signature_with_hashes<R: RollingHash + Default>(
+    basis: &mut (impl Read + ?Sized), options: &SignatureOptions, hash: &mut dyn StrongHash)
+    -> Result<Signature> {
+    let options = &check_options(options, hash)?;
+    let mut signature = Signature::new(options);
+    hash_blocks::<R>(basis, options, hash, &mut |len, weak, strong| {
+        signature.push_sums(len, weak, strong);
+        Ok(())
+    })?;
+    Ok(signature)
+}
+
+/// Check that options, already checked for any signature, suit one whose first blocks
+/// have the lengths in `head_lens`, and return the options for the signature, whose block
+/// length is the longest.
+fn variable_options(options: &SignatureOptions, head_lens: &[u32]) -> Result<SignatureOptions> {
+    if options.magic.is_content_defined() {
+        return Err(Error::InvalidOptions(
+            "content-defined chunks can't also have given lengths".to_owned()));
+    }
+    if head_lens.contains(&0) {
+        return Err(Error::InvalidOptions("a block length is zero".to_owned()));
+    }
+    Ok(SignatureOptions {
+        block_len: head_lens.iter().cloned().fold(options.block_len, u32::max),
+        .. *options
+    })
+}
+
+/// Generate a signature whose blocks vary in length, written in the v2 signature format,
+/// `magic::SIGNATURE_V2_MAGIC`.
+///
+/// The first blocks have the lengths in `head_lens`, in order, and the rest have
+/// `options.block_len`. For example, small blocks at the start of a file, where it's
+/// often edited, find small changes there, while bigger blocks keep the signature of the
+/// rest small. `Signature::read_from` reads either format, and deltas are generated
+/// from v2 signatures in the same way.
+///
+/// `Error::InvalidOptions` is returned, before anything is written, if any length is
+/// zero, if the options are invalid, or if the format is content-defined.
+pub fn generate_signature_variable<R: Read + ?Sized, W: Write + ?Sized>(
+    basis: &mut R, options: &SignatureOptions, head_lens: &[u32], sig: &mut W)
+    -> Result<Statistics> {
+    let options = &check_options(options, &*options.magic.strong_hash()?)?;
+    let v2_options = variable_options(options, head_lens)?;
+    let mut hash = options.magic.strong_hash()?;
+    let hash = &mut Timed::new(&mut *hash);
+    let basis = &mut Timed::new(basis);
+    let mut stats = write_signature(&v2_options, true, sig, &IoOptions::default(), &mut |f| {
+        if options.magic.is_rabinkarp() {
+            hash_variable_blocks::<RabinKarp>(basis, options, head_lens, hash, f)
+        } else {
+            hash_variable_blocks::<Rollsum1>(basis, options, head_lens, hash, f)
+        }
+    })?;
+    stats.set_phases(basis.time, hash.time);
+    Ok(stats)
+}
+
+/// Calculate a signature whose blocks vary in length into memory, as
+/// `generate_signature_variable` would write it.
+pub fn calculate_signature_variable<R: Read + ?Sized>(basis: &mut R, options: &SignatureOptions,
+                                                     head_lens: &[u32]) -> Result<Signature> {
+    let options = &check_options(options, &*options.magic.strong_hash()?)?;
+    let v2_options = variable_options(options, head_lens)?;
+    let mut signature = Signature::new_variable(&v2_options);
+    let hash = &mut *options.magic.strong_hash()?;
+    let f = &mut |len, weak, strong: &[u8]| {
+        signature.push_chunk(len, weak, strong);
+        Ok(())
+    };
+    if options.magic.is_rabinkarp() {
+        hash_variable_blocks::<RabinKarp>(basis, options, head_lens, hash, f)?;
+    } else {
+        hash_variable_blocks::<Rollsum1>(basis, options, head_lens, hash, f)?;
+    }
+    Ok(signature)
+}
+
+/// Calculate the identity of a basis, as it would be found from its signature, without
+/// holding the signature in memory.
+pub(crate) fn basis_id<R: Read + ?Sized>(basis: &mut R, options: &SignatureOptions)
+    -> Result<BasisId> {
+    let options = &check_options(options, &*options.magic.strong_hash()?)?;
+    let mut hash = Blake2Hash::default();
+    hash_blocks_standard(basis, options, &mut |_, weak, strong| {
+        hash_block(&mut hash, weak, strong);
+        Ok(())
+    })?;
+    Ok(BasisId { options: *options, hash: finish_basis_hash(&mut hash) })
+}
+
+/// Extend `signature` to cover a basis that has grown by having data appended, reading
+/// only the new data rather than the whole basis.
+///
+/// The old last block may have been short, so it's hashed again, starting from where it
+/// begins in `basis`, along with everything after it. For a content-defined format, the
+/// old last chunk ended only because the basis did, and is divided again the same way.
+/// In a v2 signature, the new blocks have the length of the longest block. The rest of
+/// the basis isn't read: it must be unchanged since the signature was made, or the
+/// signature won't match it.
+///
+/// `Error::InvalidOptions` is returned if `basis` is too short to hold all but the last
+/// block of the signature. The statistics count the bytes hashed, and all the blocks in
+/// the extended signature.
+pub fn extend_signature<B: Read + Seek>(signature: &mut Signature, basis: &mut B)
+    -> Result<Statistics> {
+    let start = Timer::start();
+    let options = signature.options();
+    let keep = signature.block_count().saturating_sub(1);
+    let tail_start = signature.block_offset(keep);
+    if basis.seek(SeekFrom::End(0))? < tail_start {
+        return Err(Error::InvalidOptions(
+            "the basis is shorter than the signature being extended".to_owned()));
+    }
+    basis.seek(SeekFrom::Start(tail_start))?;
+    signature.truncate(keep);
+    let in_bytes = hash_blocks_standard(basis, &options, &mut |len, weak, strong| {
+        signature.push_sums(len, weak, strong);
+        Ok(())
+    })?;
+    Ok(Statistics {
+        in_bytes,
+        block_count: signature.block_count() as u64,
+        block_len: options.block_len,
+        elapsed: start.elapsed(),
+        .. Statistics::new("signature")
+    })
+}
+
+/// Update `signature` for a basis in which the bytes in the ranges `changed` have been
+/// overwritten, hashing again only the blocks they touch rather than the whole basis.
+///
+/// This suits a backup agent that's told which parts of a file changed, by the
+/// filesystem or by a virtual machine's map of dirty blocks. The ranges are of offsets
+/// in `basis` as it is now, and may overlap or come in any order. The basis may also
+/// have grown or shrunk at its end, which is handled as by `extend_signature`: the old
+/// last block, or the block the basis now ends in, is hashed again along with everything
+/// after it. Anything else outside `changed` must be unchanged since the signature was
+/// made, or the signature won't match it.
+///
+/// For a content-defined format, a change can move where chunks end, so the basis is
+/// divided into chunks again from the start of the chunk each change begins in, until a
+/// chunk ends, after the change, where one ended before.
+///
+/// The statistics count the bytes read, and all the blocks in the updated signature.
+pub fn update_signature<B: Read + Seek>(signature: &mut Signature, basis: &mut B,
+                                        changed: &[Range<u64>])
+    -> Result<Statistics> {
+    let start = Timer::start();
+    let options = signature.options();
+    let basis_len = basis.seek(SeekFrom::End(0))?;
+    let lens = signature.block_lens().map(<[u32]>::to_vec);
+    // Where each block starts, and then where the last one ends.
+    let mut offsets = vec![0];
+    for i in 0..signature.block_count() {
+        let len = lens.as_ref().map_or(options.block_len, |lens| lens[i]);
+        offsets.push(offsets[i] + u64::from(len));
+    }
+    let keep = offsets[1..].partition_point(|&end| end < basis_len)
+        .min(signature.block_count().saturating_sub(1));
+    let tail_start = offsets[keep];
+    offsets.truncate(keep + 1);
+    signature.truncate(keep);
+    let mut ranges: Vec<Range<u64>> = changed.iter()
+        .map(|r| r.start..r.end.min(tail_start))
+        .filter(|r| r.start < r.end)
+        .collect();
+    ranges.sort_by_key(|r| r.start);
+    let mut hash = options.magic.strong_hash()?;
+    let rabinkarp = options.magic.is_rabinkarp();
+    let mut in_bytes = 0;
+    // Blocks before this have already been hashed again where they need to be.
+    let mut done = 0;
+    let mut tail_done = false;
+    for r in ranges {
+        if r.end <= done {
+            continue;
+        }
+        let first = offsets.partition_point(|&o| o <= r.start.max(done)) - 1;
+        basis.seek(SeekFrom::Start(offsets[first]))?;
+        let mut part = if lens.is_some() {
+            Signature::new_variable(&options)
+        } else {
+            Signature::new(&options)
+        };
+        let f = &mut |len, weak, strong: &[u8]| {
+            part.push_sums(len, weak, strong);
+            Ok(())
+        };
+        if options.magic.is_content_defined() {
+            let from = offsets[first];
+            let stop = &mut |chunked| {
+                let end = from + chunked;
+                end >= r.end && end <= tail_start && offsets.binary_search(&end).is_ok()
+            };
+            in_bytes += if rabinkarp {
+                hash_chunks_until::<RabinKarp>(basis, &options, &mut *hash, f, stop)
+            } else {
+                hash_chunks_until::<Rollsum1>(basis, &options, &mut *hash, f, stop)
+            }?;
+            let mut ends = Vec::with_capacity(part.block_count());
+            for &len in part.block_lens().unwrap() {
+                ends.push(ends.last().unwrap_or(&from) + u64::from(len));
+            }
+            let end = *ends.last().unwrap_or(&from);
+            if end > tail_start {
+                // No chunk ended where one did before, so the rest of the basis has
+                // been divided again.
+                signature.splice(first..signature.block_count(), part);
+                tail_done = true;
+                break;
+            }
+            let last = offsets.binary_search(&end).unwrap();
+            offsets.splice((first + 1)..=last, ends);
+            signature.splice(first..last, part);
+            done = end;
+        } else {
+            let last = offsets.partition_point(|&o| o < r.end);
+            let basis = &mut basis.take(offsets[last] - offsets[first]);
+            in_bytes += match &lens {
+                Some(lens) if rabinkarp => hash_variable_blocks::<RabinKarp>(
+                    basis, &options, &lens[first..last], &mut *hash, f),
+                Some(lens) => hash_variable_blocks::<Rollsum1>(
+                    basis, &options, &lens[first..last], &mut *hash, f),
+                None => hash_blocks_standard(basis, &options, f),
+            }?;
+            signature.splice(first..last, part);
+            done = offsets[last];
+        }
+    }
+    if !tail_done {
+        basis.seek(SeekFrom::Start(tail_start))?;
+        in_bytes += hash_blocks_standard(basis, &options, &mut |len, weak, strong| {
+            signature.push_sums(len, weak, strong);
+            Ok(())
+        })?;
+    }
+    Ok(Statistics {
+        in_bytes,
+        block_count: signature.block_count() as u64,
+        block_len: options.block_len,
+        elapsed: start.elapsed(),
+        .. Statistics::new("signature")
+    })
+}
+
+/// How far a signature has been calculated, from which it can be resumed.
+///
+/// This holds the signature of the blocks hashed so far and how much of the basis they
+/// cover. With the `serde` feature it can be serialized, so that signing a very large
+/// basis can carry on where it left off after the process is restarted.
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct SignatureCheckpoint {
+    signature: Signature,
+    basis_offset: u64,
+}
+
+impl SignatureCheckpoint {
+    /// Start a signature with `options`, before any of the basis is read.
+    ///
+    /// `Error::InvalidOptions` or `Error::StrongLenTooLong` is returned if the options
+    /// are invalid.
+    pub fn new(options: &SignatureOptions) -> Result<SignatureCheckpoint> {
+        let options = check_options(options, &*options.magic.strong_hash()?)?;
+        Ok(SignatureCheckpoint { signature: Signature::new(&options), basis_offset: 0 })
+    }
+
+    /// The signature of the blocks hashed so far.
+    pub fn signature(&self) -> &Signature {
+        &self.signature
+    }
+
+    /// How much of the basis has been hashed, and so where reading it resumes.
+    pub fn basis_offset(&self) -> u64 {
+        self.basis_offset
+    }
+}
+
+/// Calculate a signature into memory, starting or resuming from `checkpoint`, and passing
+/// the checkpoint to `save` each time another `interval` bytes of the basis are hashed.
+///
+/// `basis` is read from `checkpoint.basis_offset()` to its end; what comes before must be
+/// unchanged. If this is interrupted, resuming from the last checkpoint saved gives the
+/// same signature as if it had run straight through, including for a content-defined
+/// format. An error from `save` stops the signature and is returned.
+///
+/// `Error::InvalidOptions` is returned if `interval` is zero, or if the checkpoint's
+/// blocks vary in length other than as content-defined chunks.
+pub fn resume_signature<B: Read + Seek + ?Sized>(
+    mut checkpoint: SignatureCheckpoint, basis: &mut B, interval: u64,
+    save: &mut dyn FnMut(&SignatureCheckpoint) -> Result<()>) -> Result<Signature> {
+    let sig = &checkpoint.signature;
+    if interval == 0 {
+        return Err(Error::InvalidOptions("interval is zero".to_owned()));
+    }
+    if sig.block_lens().is_some() && !sig.format().is_content_defined() {
+        return Err(Error::InvalidOptions(
+            "a signature whose blocks vary in length can't be resumed".to_owned()));
+    }
+    let options = sig.options();
+    basis.seek(SeekFrom::Start(checkpoint.basis_offset))?;
+    let mut next_save = checkpoint.basis_offset.saturating_add(interval);
+    hash_blocks_standard(basis, &options, &mut |len, weak, strong| {
+        checkpoint.signature.push_sums(len, weak, strong);
+        checkpoint.basis_offset += u64::from(len);
+        if checkpoint.basis_offset >= next_save {
+            next_save = checkpoint.basis_offset.saturating_add(interval);
+            save(&checkpoint)?;
+        }
+        Ok(())
+    })?;
+    Ok(checkpoint.signature)
+}
+
+/// Passes writes through to another writer unchanged, and at the same time calculates
+/// the signature of everything written.
+///
+/// A backup tool that writes out a full copy of a file can make the signature for its
+/// next run in the same pass, rather than reading the copy again.
+pub struct SignatureSink<W: Write> {
+    inner: W,
+    weak: fn(&[u8]) -> u32,
+    strong: Box<dyn StrongHash + Send>,
+
+    /// For a content-defined format, finds where each chunk ends.
+    chunker: Option<Chunker>,
+
+    /// Data for the current, incomplete, block; or for a content-defined format, data
+    /// not yet divided into chunks.
+    block: Vec<u8>,
+
+    signature: Signature,
+}
+
+impl<W: Write> SignatureSink<W> {
+    /// Write through to `inner`, calculating a signature with `options`.
+    ///
+    /// `Error::InvalidOptions` is returned if the options are invalid.
+    pub fn new(inner: W, options: &SignatureOptions) -> Result<SignatureSink<W>> {
+        let strong = options.magic.strong_hash()?;
+        let options = &check_options(options, &*strong)?;
+        Ok(SignatureSink {
+            inner,
+            weak: if options.magic.is_rabinkarp() {
+                block_sum::<RabinKarp>
+            } else {
+                block_sum::<Rollsum1>
+            },
+            strong,
+            chunker: if options.magic.is_content_defined() {
+                Some(Chunker::new(options.block_len))
+            } else {
+                None
+            },
+            block: Vec::with_capacity(options.block_len as usize),
+            signature: Signature::new(options),
+        })
+    }
+
+    /// The signature of the blocks written so far, not including the last incomplete
+    /// block.
+    pub fn signature(&self) -> &Signature {
+        &self.signature
+    }
+
+    /// The writer data is passed through to.
+    pub fn get_ref(&self) -> &W {
+        &self.inner
+    }
+
+    /// Hash any incomplete last block, flush the inner writer, and return it along with
+    /// the signature of everything written.
+    pub fn finish(mut self) -> Result<(W, Signature)> {
+        if let Some(chunker) = self.chunker {
+            while let Some(len) = chunker.next_chunk(&self.block, true) {
+                if len == 0 {
+                    break;
+                }
+                self.push_block(len);
+            }
+        } else if !self.block.is_empty() {
+            self.push_block(self.block.len());
+        }
+        self.inner.flush()?;
+        Ok((self.inner, self.signature))
+    }
+
+    /// Add the sums of the first `len` bytes of `block` to the signature, and remove them.
+    fn push_block(&mut self, len: usize) {
+        let block = &self.block[..len];
+        let mut strong = [0; RS_MAX_STRONG_SUM_LENGTH];
+        let strong = &mut strong[..self.signature.strong_len() as usize];
+        let seed = self.signature.seed();
+        seeded_strong_sum(&mut *self.strong, seed, block, strong);
+        self.signature.push_sums(len as u32, seed_weak((self.weak)(block), seed), strong);
+        self.block.drain(..len);
+    }
+
+    /// Hash data that's been written.
+    fn absorb(&mut self, mut data: &[u8]) {
+        if let Some(chunker) = self.chunker {
+            self.block.extend_from_slice(data);
+            while let Some(len) = chunker.next_chunk(&self.block, false) {
+                self.push_block(len);
+            }
+            return;
+        }
+        let block_len = self.signature.block_len() as usize;
+        while !data.is_empty() {
+            let n = (block_len - self.block.len()).min(data.len());
+            self.block.extend_from_slice(&data[..n]);
+            data = &data[n..];
+            if self.block.len() == block_len {
+                self.push_block(block_len);
+            }
+        }
+    }
+}
+
+impl<W: Write> Write for SignatureSink<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let l = self.inner.write(buf)?;
+        self.absorb(&buf[..l]);
+        Ok(l)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+impl Signature {
+    /// Write the signature as a signature file, the same as `generate_signature` or
+    /// `generate_signature_variable` would for its basis, returning how many bytes were
+    /// written.
+    ///
+    /// This saves a signature calculated into memory, such as by `SignatureSink`, for
+    /// `Signature::read_from` or librsync to read.
+    pub fn write_to<W: Write + ?Sized>(&self, out: &mut W) -> Result<u64> {
+        let lens = self.block_lens();
+        let v2 = lens.is_some() && !self.format().is_content_defined();
+        let stats = write_signature(&self.options(), v2, out, &IoOptions::default(), &mut |f| {
+            for (i, weak, strong) in self.blocks() {
+                f(lens.map_or(self.block_len(), |lens| lens[i]), weak, strong)?;
+            }
+            Ok(0)
+        })?;
+        Ok(stats.out_bytes)
     }
-    Ok(())
 }
 
 #[cfg(test)]
 mod test {
     use std::vec::Vec;
-    use std::io::Cursor;
+    use std::io::{self, Cursor, ErrorKind};
     use super::*;
+    use super::super::magic::SignatureFormat;
+    use super::super::test_util::pattern;
 
     fn generate_signature_on_arrays(in_buf: &[u8]) -> Vec<u8> {
         let mut out_buf = Cursor::new(Vec::<u8>::new());
         let options = SignatureOptions::default();
         assert_eq!(options.block_len, 2 << 10);
 
-        generate_signature(&mut in_buf.as_ref(), &options, &mut out_buf).unwrap();
+        generate_signature(&mut &in_buf[..], &options, &mut out_buf).unwrap();
         out_buf.into_inner()
     }
 
@@ -157,14 +946,628 @@ mod test {
             ]);
     }
 
-    // TODO: Test for generation of a more interesting file, and examine the actual
-    // output.
     #[test]
     pub fn small_file() {
         let out_buf = generate_signature_on_arrays("Hello world\n".as_bytes());
         // Should have: 12-byte header, 1x(4-byte weak sum, 32-byte strong sum.)
         assert_eq!(out_buf.len(), 12 + 4 + 32);
+        // As generated by C librsync 2.2 `rdiff signature`.
+        assert_eq!(&out_buf[12..20],
+            &[0x26, 0x58, 0x05, 0xba, 0xd2, 0x59, 0xf1, 0x47]);
+    }
+
+    /// Three blocks, the last of them short, compared to C librsync output.
+    #[test]
+    pub fn multiple_blocks_match_librsync() {
+        let options = SignatureOptions {
+            block_len: 1024,
+            .. SignatureOptions::default()
+        }.with_strong_len(8);
+        let mut out_buf = Vec::new();
+        generate_signature(&mut pattern(3000).as_slice(), &options, &mut out_buf).unwrap();
+        assert_eq!(out_buf, vec![
+            0x72, 0x73, 0x01, 0x37, 0x00, 0x00, 0x04, 0x00, 0x00, 0x00, 0x00, 0x08,
+            0xe4, 0x21, 0x6e, 0x05, 0x9a, 0xf5, 0x86, 0x1f, 0x21, 0x29, 0x66, 0x71,
+            0xde, 0x42, 0x6e, 0x84, 0x7f, 0xd0, 0xf4, 0xf5, 0x02, 0xd0, 0xef, 0xab,
+            0x12, 0xce, 0x42, 0xf6, 0x2f, 0xf2, 0xc4, 0xc7, 0x96, 0x6a, 0x82, 0xa3,
+        ]);
+    }
+
+    #[test]
+    pub fn rabinkarp_matches_librsync() {
+        let options = SignatureOptions {
+            magic: SignatureFormat::RkBlake2Sig,
+            block_len: 1024,
+            .. SignatureOptions::default()
+        }.with_strong_len(8);
+        let mut out_buf = Vec::new();
+        generate_signature(&mut pattern(3000).as_slice(), &options, &mut out_buf).unwrap();
+        assert_eq!(out_buf, vec![
+            0x72, 0x73, 0x01, 0x47, 0x00, 0x00, 0x04, 0x00, 0x00, 0x00, 0x00, 0x08,
+            0xbd, 0x97, 0xf4, 0xd6, 0x9a, 0xf5, 0x86, 0x1f, 0x21, 0x29, 0x66, 0x71,
+            0xa8, 0xfc, 0x25, 0xfd, 0x7f, 0xd0, 0xf4, 0xf5, 0x02, 0xd0, 0xef, 0xab,
+            0x39, 0x71, 0x0a, 0x1f, 0x2f, 0xf2, 0xc4, 0xc7, 0x96, 0x6a, 0x82, 0xa3,
+        ]);
     }
 
-    // TODO: Test larger files against known hashes.
+    /// Trait objects can still be passed, and give the same signature.
+    #[test]
+    pub fn trait_objects() {
+        let data = pattern(3000);
+        let mut direct = Vec::new();
+        generate_signature(&mut data.as_slice(), &SignatureOptions::default(), &mut direct)
+            .unwrap();
+        let basis: &mut dyn Read = &mut data.as_slice();
+        let mut via_dyn = Vec::new();
+        let sig: &mut dyn Write = &mut via_dyn;
+        generate_signature(basis, &SignatureOptions::default(), sig).unwrap();
+        assert_eq!(via_dyn, direct);
+    }
+
+    #[test]
+    pub fn calculate_signature_in_memory() {
+        let options = SignatureOptions {
+            block_len: 1024,
+            .. SignatureOptions::default()
+        }.with_strong_len(8);
+        let signature = calculate_signature(&mut pattern(3000).as_slice(), &options).unwrap();
+        assert_eq!(signature.weak_sums, vec![0xe4216e05, 0xde426e84, 0x12ce42f6]);
+        assert_eq!(signature.strong_sum(2), &[0x2f, 0xf2, 0xc4, 0xc7, 0x96, 0x6a, 0x82, 0xa3]);
+    }
+
+    #[test]
+    pub fn md4_matches_librsync() {
+        let options = SignatureOptions {
+            magic: SignatureFormat::Md4Sig,
+            block_len: 1024,
+            .. SignatureOptions::default()
+        }.with_strong_len(16);
+        let mut out_buf = Vec::new();
+        generate_signature(&mut pattern(3000).as_slice(), &options, &mut out_buf).unwrap();
+        assert_eq!(out_buf, vec![
+            0x72, 0x73, 0x01, 0x36, 0x00, 0x00, 0x04, 0x00, 0x00, 0x00, 0x00, 0x10,
+            0xe4, 0x21, 0x6e, 0x05, 0xc3, 0xec, 0xc0, 0x15, 0x1f, 0x6e, 0x74, 0xa4,
+            0x5a, 0x93, 0x14, 0x6f, 0xcf, 0x2f, 0x59, 0x91, 0xde, 0x42, 0x6e, 0x84,
+            0xce, 0x13, 0x40, 0x00, 0x8f, 0xaf, 0x0b, 0xce, 0xbf, 0xc2, 0x39, 0x33,
+            0x4f, 0x52, 0x93, 0x37, 0x12, 0xce, 0x42, 0xf6, 0x61, 0x70, 0xfc, 0x60,
+            0xcd, 0x3f, 0xca, 0x31, 0x40, 0x9c, 0x92, 0xae, 0xb8, 0xca, 0x01, 0xa4,
+        ]);
+    }
+
+    #[test]
+    pub fn rabinkarp_md4_matches_librsync() {
+        let options = SignatureOptions {
+            magic: SignatureFormat::RkMd4Sig,
+            block_len: 1024,
+            .. SignatureOptions::default()
+        }.with_strong_len(16);
+        let mut out_buf = Vec::new();
+        generate_signature(&mut pattern(3000).as_slice(), &options, &mut out_buf).unwrap();
+        assert_eq!(out_buf, vec![
+            0x72, 0x73, 0x01, 0x46, 0x00, 0x00, 0x04, 0x00, 0x00, 0x00, 0x00, 0x10,
+            0xbd, 0x97, 0xf4, 0xd6, 0xc3, 0xec, 0xc0, 0x15, 0x1f, 0x6e, 0x74, 0xa4,
+            0x5a, 0x93, 0x14, 0x6f, 0xcf, 0x2f, 0x59, 0x91, 0xa8, 0xfc, 0x25, 0xfd,
+            0xce, 0x13, 0x40, 0x00, 0x8f, 0xaf, 0x0b, 0xce, 0xbf, 0xc2, 0x39, 0x33,
+            0x4f, 0x52, 0x93, 0x37, 0x39, 0x71, 0x0a, 0x1f, 0x61, 0x70, 0xfc, 0x60,
+            0xcd, 0x3f, 0xca, 0x31, 0x40, 0x9c, 0x92, 0xae, 0xb8, 0xca, 0x01, 0xa4,
+        ]);
+    }
+
+    #[test]
+    pub fn statistics() {
+        let options = SignatureOptions {
+            block_len: 1024,
+            .. SignatureOptions::default()
+        }.with_strong_len(8);
+        let mut out_buf = Vec::new();
+        let stats = generate_signature(&mut pattern(3000).as_slice(), &options, &mut out_buf)
+            .unwrap();
+        assert_eq!(stats.op, "signature");
+        assert_eq!(stats.in_bytes, 3000);
+        assert_eq!((stats.block_count, stats.block_len), (3, 1024));
+        assert_eq!(stats.out_bytes, out_buf.len() as u64);
+        assert_eq!(stats.literal_cmds + stats.copy_cmds, 0);
+        assert!(stats.strong_time > Duration::default(), "{:?}", stats);
+        assert!(stats.read_time + stats.rolling_time + stats.write_time <= stats.elapsed,
+                "{:?}", stats);
+    }
+
+    #[test]
+    pub fn builder() {
+        assert_eq!(SignatureOptions::new().build().unwrap(), SignatureOptions::default());
+        let options = SignatureOptions::new().block_len(4096).strong_len(16).build().unwrap();
+        assert_eq!(options, SignatureOptions {
+            block_len: 4096,
+            .. SignatureOptions::default()
+        }.with_strong_len(16));
+        // Without an explicit length, the strong sums are as long as the format's hash.
+        let options = SignatureOptions::new().magic(SignatureFormat::Md4Sig).build().unwrap();
+        assert_eq!(options.strong_len, 16);
+        // Nor with a length of zero.
+        let options = SignatureOptions::new().magic(SignatureFormat::Md4Sig).strong_len(0);
+        assert_eq!(options.build().unwrap().strong_len, 16);
+    }
+
+    #[test]
+    pub fn builder_rejects_nonsense() {
+        let err = SignatureOptions::new().block_len(0).build().unwrap_err();
+        assert!(matches!(err, Error::InvalidOptions(_)), "{:?}", err);
+        let err = SignatureOptions::new().strong_len(33).build().unwrap_err();
+        assert!(matches!(err, Error::StrongLenTooLong { strong_len: 33, max: 32 }), "{:?}", err);
+        let builder = SignatureOptions::new().magic(SignatureFormat::RkMd4Sig).strong_len(17);
+        let err = builder.build().unwrap_err();
+        assert!(matches!(err, Error::StrongLenTooLong { strong_len: 17, max: 16 }), "{:?}", err);
+        assert_eq!(err.kind(), ErrorKind::InvalidInput);
+    }
+
+    /// A reader that cancels a token once it's been read from.
+    struct CancelAfterRead<'a>(&'a [u8], CancelToken);
+
+    impl<'a> Read for CancelAfterRead<'a> {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            self.1.cancel();
+            self.0.read(buf)
+        }
+    }
+
+    #[test]
+    pub fn cancel() {
+        let token = CancelToken::new();
+        let basis = pattern(10_000);
+        let mut out_buf = Vec::new();
+        let err = generate_signature_cancellable(
+            &mut CancelAfterRead(&basis, token.clone()), &SignatureOptions::default(),
+            &mut out_buf, &token).unwrap_err();
+        assert!(matches!(err, Error::Cancelled), "{:?}", err);
+        // Only the first block was hashed; or, when blocks are read in chunks to hash in
+        // parallel, none.
+        let blocks = if cfg!(feature = "parallel") { 0 } else { 1 };
+        assert_eq!(out_buf.len(), 12 + blocks * (4 + 32));
+    }
+
+    /// Hashing in parallel gives the same signature as hashing one block at a time,
+    /// however many threads there are, including when the basis doesn't end on a chunk
+    /// or block boundary.
+    #[cfg(feature = "parallel")]
+    #[test]
+    pub fn parallel_matches_sequential() {
+        let pools: Vec<_> = [1, 3, 8].iter()
+            .map(|&n| rayon::ThreadPoolBuilder::new().num_threads(n).build().unwrap())
+            .collect();
+        for &magic in &[SignatureFormat::Blake2Sig, SignatureFormat::RkMd4Sig] {
+            let options = SignatureOptions::new().magic(magic).block_len(4096).strong_len(8)
+                .build().unwrap();
+            for &len in &[0, 4096, PARALLEL_CHUNK_LEN, 2 * PARALLEL_CHUNK_LEN + 3 * 4096 + 5] {
+                let basis = pattern(len);
+                let mut sequential = Vec::new();
+                generate_signature_with_hash(&mut basis.as_slice(), &options,
+                                             &mut *magic.strong_hash().unwrap(), &mut sequential)
+                    .unwrap();
+                for pool in &pools {
+                    let mut parallel = Vec::new();
+                    pool.install(|| generate_signature(&mut basis.as_slice(), &options,
+                                                       &mut parallel)).unwrap();
+                    assert_eq!(parallel, sequential, "{:?} {} {}", magic, len,
+                               pool.current_num_threads());
+                }
+                assert_eq!(calculate_signature(&mut basis.as_slice(), &options).unwrap(),
+                           Signature::read_from(&mut sequential.as_slice()).unwrap());
+            }
+        }
+    }
+
+    #[test]
+    pub fn strong_len_too_long_for_md4() {
+        let options = SignatureOptions {
+            magic: SignatureFormat::Md4Sig,
+            .. SignatureOptions::default()
+        };
+        let mut out_buf = Vec::new();
+        let err = generate_signature(&mut pattern(3000).as_slice(), &options, &mut out_buf)
+            .unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::InvalidInput);
+        assert!(matches!(err, Error::StrongLenTooLong { strong_len: 32, max: 16 }), "{:?}", err);
+        assert!(out_buf.is_empty());
+    }
+
+    /// A `strong_len` of zero writes the whole strong sum, rather than a header that
+    /// can't be read back.
+    #[test]
+    pub fn zero_strong_len_is_whole_hash() {
+        let data = pattern(3000);
+        for &magic in &[SignatureFormat::Md4Sig, SignatureFormat::RkBlake2Sig] {
+            let options = SignatureOptions { magic, block_len: 1024, strong_len: 0, seed: 0 };
+            let full = options.with_strong_len(magic.max_strong_len());
+            let (mut sig, mut full_sig) = (Vec::new(), Vec::new());
+            generate_signature(&mut data.as_slice(), &options, &mut sig).unwrap();
+            generate_signature(&mut data.as_slice(), &full, &mut full_sig).unwrap();
+            assert_eq!(sig, full_sig);
+            let signature = calculate_signature(&mut data.as_slice(), &options).unwrap();
+            assert_eq!(signature.strong_len(), magic.max_strong_len());
+            assert_eq!(Signature::read_from(&mut sig.as_slice()).unwrap(), signature);
+        }
+    }
+
+    #[cfg(feature = "blake3")]
+    #[test]
+    pub fn blake3_signature() {
+        use blake3;
+        let options = SignatureOptions {
+            magic: SignatureFormat::Blake3Sig,
+            block_len: 1024,
+            .. SignatureOptions::default()
+        }.with_strong_len(8);
+        let basis = pattern(3000);
+        let mut out_buf = Vec::new();
+        generate_signature(&mut basis.as_slice(), &options, &mut out_buf).unwrap();
+        assert_eq!(&out_buf[..12], &[
+            0x72, 0x73, 0x81, 0x47, 0x00, 0x00, 0x04, 0x00, 0x00, 0x00, 0x00, 0x08]);
+        // The weak sums are RabinKarp, as for RkBlake2Sig.
+        assert_eq!(&out_buf[12..16], &[0xbd, 0x97, 0xf4, 0xd6]);
+        assert_eq!(&out_buf[16..24], &blake3::hash(&basis[..1024]).as_bytes()[..8]);
+        assert_eq!(&out_buf[36..40], &[0x39, 0x71, 0x0a, 0x1f]);
+        assert_eq!(&out_buf[40..], &blake3::hash(&basis[2048..]).as_bytes()[..8]);
+    }
+
+    /// A hash that's not one of the standard formats.
+    struct XorHash(u8);
+
+    impl StrongHash for XorHash {
+        fn digest_len(&self) -> usize { 1 }
+        fn update(&mut self, buf: &[u8]) {
+            for b in buf { self.0 ^= *b; }
+        }
+        fn finalize_truncated(&mut self, out: &mut [u8]) {
+            out.copy_from_slice(&[self.0][..out.len()]);
+            self.0 = 0;
+        }
+    }
+
+    #[test]
+    pub fn custom_strong_hash() {
+        let options = SignatureOptions {
+            block_len: 2,
+            .. SignatureOptions::default()
+        }.with_strong_len(1);
+        let mut out_buf = Vec::new();
+        generate_signature_with_hash(&mut &b"abcde"[..], &options, &mut XorHash(0), &mut out_buf)
+            .unwrap();
+        // Each block has a 4-byte weak sum, then the one-byte strong sum.
+        assert_eq!(out_buf.len(), 12 + 3 * 5);
+        assert_eq!(&out_buf[12..16], &[0x01, 0x81, 0x01, 0x01]);
+        assert_eq!([out_buf[16], out_buf[21], out_buf[26]], [b'a' ^ b'b', b'c' ^ b'd', b'e']);
+        let sig = calculate_signature_with_hash(&mut &b"abcde"[..], &options, &mut XorHash(0))
+            .unwrap();
+        assert_eq!(sig.strong_sum(2), b"e");
+        let err = calculate_signature_with_hash(&mut &b"abcde"[..], &options.with_strong_len(2),
+                                                &mut XorHash(0)).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::InvalidInput);
+    }
+
+    /// Blocks longer than 64kB must not overflow the weak sum arithmetic.
+    #[test]
+    pub fn large_block() {
+        let options = SignatureOptions {
+            block_len: 100_000,
+            .. SignatureOptions::default()
+        }.with_strong_len(8);
+        let mut out_buf = Vec::new();
+        generate_signature(&mut pattern(100_000).as_slice(), &options, &mut out_buf).unwrap();
+        assert_eq!(&out_buf[12..], &[
+            0x28, 0x0c, 0x05, 0x73, 0xe5, 0xaa, 0x26, 0x9e, 0x0f, 0xa0, 0x37, 0xd6]);
+    }
+
+    #[test]
+    pub fn extend_appended() {
+        let options = SignatureOptions { block_len: 1000, .. SignatureOptions::default() };
+        let data = pattern(20_000);
+        for &(old_len, new_len) in &[(0, 2500), (3000, 7000), (3500, 7000), (3500, 3700),
+                                     (4000, 4000)] {
+            let mut sig = calculate_signature(&mut &data[..old_len], &options).unwrap();
+            let stats = extend_signature(&mut sig, &mut Cursor::new(&data[..new_len])).unwrap();
+            let expected = calculate_signature(&mut &data[..new_len], &options).unwrap();
+            assert_eq!(sig, expected, "{} to {}", old_len, new_len);
+            // Only the old last block and the new data are read.
+            assert!(stats.in_bytes <= (new_len - old_len) as u64 + 1000, "{:?}", stats);
+            assert_eq!(stats.block_count, expected.block_count() as u64);
+        }
+    }
+
+    #[test]
+    pub fn extend_shorter_basis() {
+        let options = SignatureOptions { block_len: 1000, .. SignatureOptions::default() };
+        let mut sig = calculate_signature(&mut &pattern(5000)[..], &options).unwrap();
+        let err = extend_signature(&mut sig, &mut Cursor::new(pattern(3000))).unwrap_err();
+        assert!(matches!(err, Error::InvalidOptions(_)), "{:?}", err);
+    }
+
+    /// Updating a signature for changed ranges gives the signature of the changed basis,
+    /// reading little more than the blocks they touch.
+    #[test]
+    #[allow(clippy::single_range_in_vec_init)]
+    pub fn update_changed_ranges() {
+        let fixed = SignatureOptions { block_len: 1000, .. SignatureOptions::default() };
+        let cdc = SignatureOptions {
+            magic: SignatureFormat::CdcBlake2Sig,
+            block_len: 256,
+            seed: 7,
+            .. SignatureOptions::default()
+        };
+        let seeded = SignatureOptions { seed: 3, .. fixed };
+        let old = pattern(20_000);
+        // Fixed blocks after a couple of shorter ones, seeded fixed blocks, and chunks.
+        let cases: &[(usize, &[Range<u64>])] = &[
+            (20_000, &[]),
+            (20_000, &[5000..5001]),
+            (20_000, &[12_345..12_400, 100..2100, 1500..1600, 7..7]),
+            (20_000, &[0..20_000]),
+            (23_500, &[9000..9999]),
+            (15_500, &[300..400, 15_000..15_500]),
+            (999, &[0..10]),
+            (0, &[]),
+        ];
+        for &(new_len, changed) in cases {
+            let mut new = pattern(new_len);
+            for r in changed {
+                for b in &mut new[(r.start as usize)..(r.end as usize)] {
+                    *b = b.wrapping_mul(31).wrapping_add(1);
+                }
+            }
+            let sign = |data: &[u8], options: &SignatureOptions| if options.seed == 0 {
+                calculate_signature_variable(&mut &data[..], options, &[10, 500]).unwrap()
+            } else {
+                calculate_signature(&mut &data[..], options).unwrap()
+            };
+            for options in &[fixed, seeded, cdc] {
+                let mut sig = sign(&old, options);
+                let stats = update_signature(&mut sig, &mut Cursor::new(&new), changed).unwrap();
+                let expected = sign(&new, options);
+                assert_eq!(sig, expected, "{:?} {} {:?}", options, new_len, changed);
+                assert_eq!(stats.block_count, expected.block_count() as u64);
+                let changed_len: u64 = changed.iter().map(|r| r.end - r.start).sum();
+                let tail_len = (new_len as u64).saturating_sub(20_000) + 5000;
+                assert!(stats.in_bytes <= changed_len + tail_len + 8 * 1024,
+                        "{:?} {:?}", stats, changed);
+            }
+        }
+    }
+
+    #[test]
+    pub fn content_defined_chunks() {
+        let options = SignatureOptions {
+            magic: SignatureFormat::CdcBlake2Sig,
+            block_len: 256,
+            .. SignatureOptions::default()
+        };
+        let data = pattern(20_000);
+        let sig = calculate_signature(&mut data.as_slice(), &options).unwrap();
+        let lens = sig.block_lens().unwrap();
+        assert_eq!(lens.len(), sig.block_count());
+        assert_eq!(lens.iter().map(|&l| l as usize).sum::<usize>(), data.len());
+        assert!(lens[..(lens.len() - 1)].iter().all(|&l| (64..=1024).contains(&l)), "{:?}", lens);
+        let mut out_buf = Vec::new();
+        let stats = generate_signature(&mut data.as_slice(), &options, &mut out_buf).unwrap();
+        assert_eq!(out_buf.len(), 12 + sig.block_count() * (4 + 4 + 32));
+        assert_eq!(stats.out_bytes, out_buf.len() as u64);
+        assert_eq!(&out_buf[12..16], &lens[0].to_be_bytes());
+        assert_eq!(&out_buf[16..20], &sig.weak_sum(0).to_be_bytes());
+
+        for &old_len in &[0, 5000, 9999] {
+            let mut old = calculate_signature(&mut &data[..old_len], &options).unwrap();
+            extend_signature(&mut old, &mut Cursor::new(&data)).unwrap();
+            assert_eq!(old, sig, "{}", old_len);
+        }
+
+        for &block_len in &[63, u32::MAX / 4 + 1] {
+            let options = SignatureOptions { block_len, .. options };
+            let err = calculate_signature(&mut data.as_slice(), &options).unwrap_err();
+            assert!(matches!(err, Error::InvalidOptions(_)), "{:?}", err);
+        }
+    }
+
+    /// A v2 signature is extended with blocks of its longest length.
+    #[test]
+    pub fn signature_sink() {
+        let data: Vec<u8> = (0..50_000).map(|i| ((i * 7 + i / 13) % 251) as u8).collect();
+        let cdc = SignatureOptions {
+            magic: SignatureFormat::CdcBlake2Sig,
+            block_len: 1024,
+            strong_len: 16,
+            seed: 0,
+        };
+        for options in &[SignatureOptions::default(), cdc] {
+            let mut sink = SignatureSink::new(Vec::new(), options).unwrap();
+            for piece in data.chunks(777) {
+                sink.write_all(piece).unwrap();
+            }
+            assert!(sink.signature().block_count() > 0);
+            assert_eq!(sink.get_ref().len(), data.len());
+            let (out, sig) = sink.finish().unwrap();
+            assert_eq!(out, data);
+            assert_eq!(sig, calculate_signature(&mut data.as_slice(), options).unwrap());
+        }
+        let (out, sig) = SignatureSink::new(Vec::new(), &SignatureOptions::default()).unwrap()
+            .finish().unwrap();
+        assert_eq!((out.len(), sig.block_count()), (0, 0));
+        let zero = SignatureOptions { block_len: 0, .. SignatureOptions::default() };
+        assert!(matches!(SignatureSink::new(Vec::new(), &zero), Err(Error::InvalidOptions(_))));
+    }
+
+    /// A signature in memory is written just as it would have been generated.
+    #[test]
+    pub fn write_in_memory_signature() {
+        let basis = pattern(20_000);
+        let fixed = SignatureOptions { block_len: 1000, .. SignatureOptions::default() };
+        let cdc = SignatureOptions {
+            magic: SignatureFormat::CdcBlake2Sig,
+            block_len: 256,
+            seed: 7,
+            .. SignatureOptions::default()
+        };
+        for options in &[fixed, fixed.with_strong_len(8), cdc] {
+            let mut expected = Vec::new();
+            generate_signature(&mut basis.as_slice(), options, &mut expected).unwrap();
+            let sig = calculate_signature(&mut basis.as_slice(), options).unwrap();
+            let mut out = Vec::new();
+            assert_eq!(sig.write_to(&mut out).unwrap(), out.len() as u64);
+            assert_eq!(out, expected);
+            assert_eq!(Signature::read_from(&mut out.as_slice()).unwrap(), sig);
+        }
+        let mut expected = Vec::new();
+        generate_signature_variable(&mut basis.as_slice(), &fixed, &[10, 20], &mut expected)
+            .unwrap();
+        let sig = calculate_signature_variable(&mut basis.as_slice(), &fixed, &[10, 20]).unwrap();
+        let mut out = Vec::new();
+        sig.write_to(&mut out).unwrap();
+        assert_eq!(out, expected);
+    }
+
+    #[test]
+    pub fn pipelined_signature() {
+        let basis = pattern(300_000);
+        for &magic in &[SignatureFormat::RkBlake2Sig, SignatureFormat::CdcBlake2Sig] {
+            let options = SignatureOptions { magic, block_len: 1024, .. Default::default() };
+            let mut expected = Vec::new();
+            generate_signature(&mut basis.as_slice(), &options, &mut expected).unwrap();
+            let mut sig = Vec::new();
+            let stats = generate_signature_pipelined(&mut basis.as_slice(), &options, &mut sig)
+                .unwrap();
+            assert_eq!(sig, expected);
+            assert_eq!(stats.out_bytes, sig.len() as u64);
+        }
+    }
+
+    /// A compressed signature is smaller, and read just like an uncompressed one.
+    #[cfg(feature = "zstd")]
+    #[test]
+    pub fn compressed_signature() {
+        use super::super::compress::ZSTD_MAGIC;
+        use super::super::signature::verify_signature;
+
+        let basis = pattern(1024).repeat(200);
+        let options = SignatureOptions { block_len: 256, .. SignatureOptions::default() };
+        let mut plain = Vec::new();
+        generate_signature(&mut basis.as_slice(), &options, &mut plain).unwrap();
+        let mut compressed = Vec::new();
+        let stats = generate_signature_compressed(&mut basis.as_slice(), &options,
+                                                  &mut compressed, 0).unwrap();
+        assert_eq!(&compressed[..4], &ZSTD_MAGIC.to_be_bytes());
+        assert_eq!(stats.out_bytes, compressed.len() as u64);
+        // The basis repeats, so its sums do too.
+        assert!(compressed.len() < plain.len() / 2, "{} {}", compressed.len(), plain.len());
+        assert_eq!(Signature::read_from(&mut compressed.as_slice()).unwrap(),
+                   Signature::read_from(&mut plain.as_slice()).unwrap());
+        assert_eq!(verify_signature(&mut compressed.as_slice()).unwrap().block_count, 800);
+    }
+
+    /// A seed changes every sum, and is carried in the signature file, so that deltas
+    /// still find the basis's blocks.
+    #[test]
+    pub fn seeded_signature() {
+        use super::super::magic::SIGNATURE_SEEDED_MAGIC;
+        use super::super::mkdelta::{generate_delta, generate_delta_with_options,
+                                    DeltaOptions};
+
+        let basis = pattern(20_000);
+        for &magic in &[SignatureFormat::Blake2Sig, SignatureFormat::RkMd4Sig,
+                        SignatureFormat::CdcBlake2Sig] {
+            let options = SignatureOptions::new().magic(magic).block_len(1024)
+                .seed(0x1234_5678).build().unwrap();
+            let sig = calculate_signature(&mut basis.as_slice(), &options).unwrap();
+            assert_eq!(sig.seed(), 0x1234_5678);
+            let mut buf = Vec::new();
+            generate_signature(&mut basis.as_slice(), &options, &mut buf).unwrap();
+            assert_eq!(&buf[..4], &SIGNATURE_SEEDED_MAGIC.to_be_bytes());
+            assert_eq!(&buf[4..8], &0x1234_5678u32.to_be_bytes());
+            assert_eq!(Signature::read_from(&mut buf.as_slice()).unwrap(), sig);
+            let mut sink = SignatureSink::new(Vec::new(), &options).unwrap();
+            sink.write_all(&basis).unwrap();
+            assert_eq!(sink.finish().unwrap().1, sig);
+
+            let plain = calculate_signature(&mut basis.as_slice(),
+                                            &SignatureOptions { seed: 0, .. options }).unwrap();
+            assert_eq!(plain.block_count(), sig.block_count());
+            for i in 0..sig.block_count() {
+                assert_ne!(sig.weak_sum(i), plain.weak_sum(i));
+                assert_ne!(sig.strong_sum(i), plain.strong_sum(i));
+            }
+
+            let stats = generate_delta(&sig, &mut basis.as_slice(), &mut io::sink()).unwrap();
+            assert_eq!((stats.copy_bytes, stats.literal_bytes), (basis.len() as u64, 0));
+            let basis_id = DeltaOptions { basis_id: true, .. DeltaOptions::default() };
+            let err = generate_delta_with_options(&sig, &mut basis.as_slice(), &mut io::sink(),
+                                                  &basis_id).unwrap_err();
+            assert!(matches!(err, Error::InvalidOptions(_)), "{:?}", err);
+        }
+    }
+
+    #[test]
+    pub fn extend_variable() {
+        let options = SignatureOptions { block_len: 1000, .. SignatureOptions::default() };
+        let data = pattern(10_000);
+        let mut sig = calculate_signature_variable(&mut &data[..2500], &options, &[100, 300])
+            .unwrap();
+        let stats = extend_signature(&mut sig, &mut Cursor::new(&data)).unwrap();
+        assert_eq!(sig, calculate_signature_variable(&mut data.as_slice(), &options, &[100, 300])
+                   .unwrap());
+        assert_eq!(stats.in_bytes, 10_000 - 2400);
+    }
+
+    /// Stop a checkpointed signature at its `stop_at`th checkpoint, and return that
+    /// checkpoint.
+    fn interrupted(data: &[u8], options: &SignatureOptions, stop_at: usize)
+        -> SignatureCheckpoint {
+        let mut saved = Vec::new();
+        let checkpoint = SignatureCheckpoint::new(options).unwrap();
+        let err = resume_signature(checkpoint, &mut Cursor::new(data), 10_000, &mut |c| {
+            saved.push(c.clone());
+            if saved.len() == stop_at { Err(Error::Cancelled) } else { Ok(()) }
+        }).unwrap_err();
+        assert!(matches!(err, Error::Cancelled), "{:?}", err);
+        saved.pop().unwrap()
+    }
+
+    #[test]
+    pub fn resume_from_checkpoint() {
+        let data = pattern(100_000);
+        for &magic in &[SignatureFormat::RkBlake2Sig, SignatureFormat::CdcBlake2Sig] {
+            let options = SignatureOptions { magic, block_len: 1024, strong_len: 0, seed: 0 };
+            let whole = calculate_signature(&mut data.as_slice(), &options).unwrap();
+            let checkpoint = interrupted(&data, &options, 3);
+            let offset = checkpoint.basis_offset();
+            assert!((30_000..40_000).contains(&offset), "{}", offset);
+            assert_eq!(checkpoint.signature().block_offset(checkpoint.signature().block_count()),
+                       offset);
+            let mut saves = 0;
+            let resumed = resume_signature(checkpoint, &mut Cursor::new(&data), 10_000,
+                                           &mut |_| { saves += 1; Ok(()) }).unwrap();
+            assert_eq!(resumed, whole);
+            assert!((5..=7).contains(&saves), "{}", saves);
+        }
+    }
+
+    #[test]
+    pub fn checkpoint_errors() {
+        let err = SignatureCheckpoint::new(&SignatureOptions::default().with_strong_len(40))
+            .unwrap_err();
+        assert!(matches!(err, Error::StrongLenTooLong { .. }), "{:?}", err);
+        let checkpoint = SignatureCheckpoint::new(&SignatureOptions::default()).unwrap();
+        let err = resume_signature(checkpoint, &mut Cursor::new(&[]), 0, &mut |_| Ok(()))
+            .unwrap_err();
+        assert!(matches!(err, Error::InvalidOptions(_)), "{:?}", err);
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    pub fn serialize_checkpoint() {
+        let data = pattern(50_000);
+        let options = SignatureOptions { block_len: 1000, .. SignatureOptions::default() };
+        let checkpoint = interrupted(&data, &options, 2);
+        let json = serde_json::to_string(&checkpoint).unwrap();
+        let checkpoint: SignatureCheckpoint = serde_json::from_str(&json).unwrap();
+        assert_eq!(checkpoint.basis_offset(), 20_000);
+        let resumed = resume_signature(checkpoint, &mut Cursor::new(&data), 10_000,
+                                       &mut |_| Ok(())).unwrap();
+        assert_eq!(resumed, calculate_signature(&mut data.as_slice(), &options).unwrap());
+    }
 }
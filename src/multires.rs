@@ -0,0 +1,315 @@
+// rdiff(rust) -- library for network deltas
+// Copyright 2018 Martin Pool.
+
+//! Signatures of one basis at several block lengths, made in one pass.
+//!
+//! Long blocks make a small signature and find long unchanged regions with few COPYs,
+//! but a change anywhere in a block loses the whole block. Short blocks find more of the
+//! data around a change, but make a big signature. A multi-resolution signature holds
+//! both, or three levels, and `mkdelta::generate_delta_multires` matches the coarse
+//! blocks where it can and fills in with the fine ones.
+//!
+//! The file format, `magic::SIGNATURE_MULTI_MAGIC`, is an extension of this library,
+//! not understood by librsync.
+
+use std::cmp::Reverse;
+use std::io;
+use std::io::{ErrorKind, Read, Write};
+
+use byteorder::{BigEndian, ReadBytesExt, WriteBytesExt};
+
+use super::compress::decompressed;
+use super::error::{Error, Result};
+use super::io_options::IoOptions;
+use super::magic::SIGNATURE_MULTI_MAGIC;
+use super::mksum::{write_signature, SignatureSink};
+use super::signature::{Signature, SignatureOptions};
+use super::stats::{Statistics, Timer};
+
+/// Most levels a multi-resolution signature can have.
+pub const MAX_LEVELS: usize = 8;
+
+/// Signatures of one basis at several block lengths.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MultiSignature {
+    basis_len: u64,
+    levels: Vec<Signature>,
+}
+
+impl MultiSignature {
+    /// Combine signatures of a basis of `basis_len` bytes, made with different block
+    /// lengths and otherwise the same options.
+    ///
+    /// `Error::InvalidOptions` is returned if there are none or more than `MAX_LEVELS`,
+    /// if their options differ other than in block length, if two have the same block
+    /// length, if any has blocks of varying length, or if they don't have the right
+    /// number of blocks for the basis.
+    pub fn new(basis_len: u64, mut levels: Vec<Signature>) -> Result<MultiSignature> {
+        levels.sort_by_key(|l| Reverse(l.block_len()));
+        if let Some(problem) = levels_problem(basis_len, &levels) {
+            return Err(Error::InvalidOptions(problem));
+        }
+        Ok(MultiSignature { basis_len, levels })
+    }
+
+    /// Read a multi-resolution signature file into memory, decompressing it if it's
+    /// compressed.
+    ///
+    /// `Error::BadMagic` is returned if it's not a multi-resolution signature, and
+    /// `Error::CorruptSignature` if its levels don't fit together, as well as the errors
+    /// from `Signature::read_from` for each level.
+    pub fn read_from<R: Read + ?Sized>(sig: &mut R) -> Result<MultiSignature> {
+        let sig = &mut decompressed(sig)?;
+        let magic = sig.read_u32::<BigEndian>()?;
+        if magic != SIGNATURE_MULTI_MAGIC {
+            return Err(Error::BadMagic(magic));
+        }
+        let basis_len = sig.read_u64::<BigEndian>()?;
+        let count = sig.read_u32::<BigEndian>()? as usize;
+        if count == 0 || count > MAX_LEVELS {
+            return Err(Error::CorruptSignature(format!("{} levels", count)));
+        }
+        let mut levels = Vec::with_capacity(count);
+        for _ in 0..count {
+            let len = sig.read_u64::<BigEndian>()?;
+            let mut level = (&mut *sig).take(len);
+            levels.push(Signature::read_from(&mut level)?);
+            if level.limit() > 0 {
+                return Err(io::Error::new(ErrorKind::UnexpectedEof,
+                                          "signature ended in the middle of a level").into());
+            }
+        }
+        if let Some(problem) = levels_problem(basis_len, &levels) {
+            return Err(Error::CorruptSignature(problem));
+        }
+        Ok(MultiSignature { basis_len, levels })
+    }
+
+    /// Write the signature in the multi-resolution format, returning how many bytes
+    /// were written.
+    pub fn write_to<W: Write + ?Sized>(&self, out: &mut W) -> Result<u64> {
+        out.write_u32::<BigEndian>(SIGNATURE_MULTI_MAGIC)?;
+        out.write_u64::<BigEndian>(self.basis_len)?;
+        out.write_u32::<BigEndian>(self.levels.len() as u32)?;
+        let mut written = 16;
+        for level in &self.levels {
+            let mut buf = Vec::new();
+            write_signature(&level.options(), false, &mut buf, &IoOptions::default(),
+                            &mut |f| {
+                                for (_, weak, strong) in level.blocks() {
+                                    f(level.block_len(), weak, strong)?;
+                                }
+                                Ok(self.basis_len)
+                            })?;
+            out.write_u64::<BigEndian>(buf.len() as u64)?;
+            out.write_all(&buf)?;
+            written += 8 + buf.len() as u64;
+        }
+        out.flush()?;
+        Ok(written)
+    }
+
+    /// Length of the basis.
+    pub fn basis_len(&self) -> u64 {
+        self.basis_len
+    }
+
+    /// The signature at each block length, longest first.
+    pub fn levels(&self) -> &[Signature] {
+        &self.levels
+    }
+}
+
+/// What's wrong with a set of levels, sorted by block length, for a basis of
+/// `basis_len` bytes, if anything.
+fn levels_problem(basis_len: u64, levels: &[Signature]) -> Option<String> {
+    let first = match levels.first() {
+        Some(first) => first,
+        None => return Some("there are no levels".to_owned()),
+    };
+    if levels.len() > MAX_LEVELS {
+        return Some(format!("{} levels is more than {}", levels.len(), MAX_LEVELS));
+    }
+    let (format, strong_len, seed) = (first.format(), first.strong_len(), first.seed());
+    for (i, level) in levels.iter().enumerate() {
+        if (level.format(), level.strong_len(), level.seed()) != (format, strong_len, seed) {
+            return Some("the levels have different formats".to_owned());
+        }
+        if level.block_lens().is_some() {
+            return Some("a level has blocks of varying length".to_owned());
+        }
+        if i > 0 && level.block_len() == levels[i - 1].block_len() {
+            return Some(format!("two levels have block length {}", level.block_len()));
+        }
+        let expected = basis_len.div_ceil(u64::from(level.block_len()));
+        if level.block_count() as u64 != expected {
+            return Some(format!("a level has {} blocks rather than {}",
+                                level.block_count(), expected));
+        }
+    }
+    None
+}
+
+/// Calculate signatures of a basis at each of `block_lens`, reading it once.
+///
+/// The other options, and the format, which can't be content-defined, are taken from
+/// `options`. `Error::InvalidOptions` is returned if any of the block lengths can't be
+/// used, or they can't make a `MultiSignature`.
+pub fn calculate_multi_signature<R: Read + ?Sized>(basis: &mut R, options: &SignatureOptions,
+                                                   block_lens: &[u32])
+    -> Result<MultiSignature> {
+    if options.magic.is_content_defined() {
+        return Err(Error::InvalidOptions(
+            "a multi-resolution signature can't be content-defined".to_owned()));
+    }
+    let mut sinks = block_lens.iter()
+        .map(|&block_len| SignatureSink::new(io::sink(), &SignatureOptions {
+            block_len,
+            .. *options
+        }))
+        .collect::<Result<Vec<_>>>()?;
+    let mut buf = vec![0; IoOptions::default().read_buf];
+    let mut basis_len = 0;
+    loop {
+        let l = match basis.read(&mut buf) {
+            Ok(0) => break,
+            Ok(l) => l,
+            Err(e) if e.kind() == ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e.into()),
+        };
+        basis_len += l as u64;
+        for sink in &mut sinks {
+            sink.write_all(&buf[..l])?;
+        }
+    }
+    let levels = sinks.into_iter()
+        .map(|sink| Ok(sink.finish()?.1))
+        .collect::<Result<Vec<_>>>()?;
+    MultiSignature::new(basis_len, levels)
+}
+
+/// Generate a multi-resolution signature file, reading the basis once.
+///
+/// The options are as for `calculate_multi_signature`. The statistics give the total
+/// number of blocks, and the shortest block length.
+pub fn generate_multi_signature<R: Read + ?Sized, W: Write + ?Sized>(
+    basis: &mut R, options: &SignatureOptions, block_lens: &[u32], sig: &mut W)
+    -> Result<Statistics> {
+    let start = Timer::start();
+    let multi = calculate_multi_signature(basis, options, block_lens)?;
+    let out_bytes = multi.write_to(sig)?;
+    let levels = multi.levels();
+    Ok(Statistics {
+        in_bytes: multi.basis_len,
+        out_bytes,
+        block_count: levels.iter().map(|l| l.block_count() as u64).sum(),
+        block_len: levels[levels.len() - 1].block_len(),
+        elapsed: start.elapsed(),
+        .. Statistics::new("signature")
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use std::io::Cursor;
+
+    use super::*;
+    use super::super::magic::SignatureFormat;
+    use super::super::mkdelta::{generate_delta, generate_delta_multires};
+    use super::super::mksum::calculate_signature;
+    use super::super::patch::apply_patch;
+    use super::super::test_util::random;
+
+    /// The levels are just the signatures at each block length, and survive being
+    /// written and read.
+    #[test]
+    pub fn one_pass_matches_separate_signatures() {
+        let basis = random(100_000);
+        let options = SignatureOptions::default();
+        let multi = calculate_multi_signature(&mut basis.as_slice(), &options,
+                                              &[1024, 8192]).unwrap();
+        assert_eq!(multi.basis_len(), 100_000);
+        assert_eq!(multi.levels().len(), 2);
+        for (level, &block_len) in multi.levels().iter().zip(&[8192, 1024]) {
+            let options = SignatureOptions { block_len, .. options };
+            assert_eq!(level, &calculate_signature(&mut basis.as_slice(), &options).unwrap());
+        }
+        let mut file = Vec::new();
+        let stats = generate_multi_signature(&mut basis.as_slice(), &options, &[8192, 1024],
+                                             &mut file).unwrap();
+        assert_eq!((stats.in_bytes, stats.out_bytes), (100_000, file.len() as u64));
+        assert_eq!((stats.block_count, stats.block_len), (13 + 98, 1024));
+        assert_eq!(MultiSignature::read_from(&mut file.as_slice()).unwrap(), multi);
+    }
+
+    /// Coarse blocks match the unchanged regions, and fine blocks most of the data
+    /// around each change, so the delta is smaller than from either level alone: even
+    /// the fine level alone can't match its short last block before the appended data.
+    #[test]
+    pub fn delta_uses_both_levels() {
+        let basis = random(300_000);
+        let mut new = basis.clone();
+        for &at in &[10_000, 100_000, 200_000, 290_000] {
+            new[at] ^= 0xff;
+        }
+        new.extend_from_slice(b"appended");
+        let options = SignatureOptions::default();
+        let multi = calculate_multi_signature(&mut basis.as_slice(), &options,
+                                              &[512, 32_768]).unwrap();
+        let mut delta = Vec::new();
+        let stats = generate_delta_multires(&multi, &mut new.as_slice(), &mut delta).unwrap();
+        let mut patched = Vec::new();
+        apply_patch(&mut Cursor::new(&basis), &mut delta.as_slice(), &mut patched).unwrap();
+        assert!(patched == new);
+        assert_eq!(stats.literal_bytes, 4 * 512 + 8);
+        for level in multi.levels() {
+            let mut single = Vec::new();
+            generate_delta(level, &mut new.as_slice(), &mut single).unwrap();
+            assert!(delta.len() < single.len(), "{} {}", level.block_len(), single.len());
+        }
+    }
+
+    #[test]
+    pub fn bad_levels() {
+        let basis = random(10_000);
+        let options = SignatureOptions::default();
+        for block_lens in &[&[][..], &[1024, 1024], &[0, 1024]] {
+            match calculate_multi_signature(&mut basis.as_slice(), &options, block_lens) {
+                Err(Error::InvalidOptions(_)) => (),
+                r => panic!("unexpected {:?}", r),
+            }
+        }
+        let cdc = SignatureOptions { magic: SignatureFormat::CdcBlake2Sig, .. options };
+        assert!(calculate_multi_signature(&mut basis.as_slice(), &cdc, &[1024]).is_err());
+        let level = calculate_signature(&mut basis.as_slice(), &options).unwrap();
+        assert!(MultiSignature::new(8_000, vec![level.clone()]).is_err());
+        let md4 = SignatureOptions { magic: SignatureFormat::Md4Sig, block_len: 512,
+                                     strong_len: 8, .. options };
+        let other = calculate_signature(&mut basis.as_slice(), &md4).unwrap();
+        assert!(MultiSignature::new(10_000, vec![level, other]).is_err());
+    }
+
+    #[test]
+    pub fn corrupt_files() {
+        let basis = random(10_000);
+        let multi = calculate_multi_signature(&mut basis.as_slice(), &SignatureOptions::default(),
+                                              &[1024, 4096]).unwrap();
+        let mut file = Vec::new();
+        multi.write_to(&mut file).unwrap();
+        match MultiSignature::read_from(&mut &file[..file.len() - 1]) {
+            Err(Error::Io(e)) => assert_eq!(e.kind(), ErrorKind::UnexpectedEof),
+            r => panic!("unexpected {:?}", r),
+        }
+        // A different basis length doesn't fit the number of blocks.
+        let mut wrong = file.clone();
+        wrong[10] ^= 0x40;
+        match MultiSignature::read_from(&mut wrong.as_slice()) {
+            Err(Error::CorruptSignature(_)) => (),
+            r => panic!("unexpected {:?}", r),
+        }
+        match MultiSignature::read_from(&mut &file[4..]) {
+            Err(Error::BadMagic(_)) => (),
+            r => panic!("unexpected {:?}", r),
+        }
+    }
+}
@@ -0,0 +1,1161 @@
+// rdiff(rust) -- library for network deltas
+// Copyright 2018 Martin Pool.
+
+//! Apply deltas to a basis file, to reconstruct the new file.
+
+use std::collections::HashMap;
+#[cfg(feature = "mmap")]
+use std::fs::File;
+use std::io;
+use std::io::{BufReader, BufWriter, Read, Seek, SeekFrom, Write};
+
+#[cfg(feature = "mmap")]
+use memmap2::Mmap;
+
+use super::basis::{copy_range, BasisProvider, BasisReader, CachedBasis};
+use super::cancel::CancelToken;
+use super::compress::decompressed;
+use super::delta::{check_copy, checksum_hash, finish_checksum, CommandHeader, DeltaReader};
+use super::error::{Error, Result};
+use super::io_options::IoOptions;
+use super::magic::DeltaFormat;
+use super::mksum::{basis_id, SignatureOptions, SignatureSink};
+use super::progress::{Meter, Progress};
+use super::signature::Signature;
+use super::stats::{Statistics, Timer};
+use super::strongsum::{Blake2Hash, StrongHash};
+
+/// Options for applying a delta.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct PatchOptions {
+    /// Fail with `Error::CorruptDelta` on any LITERAL command longer than this.
+    ///
+    /// Literals are streamed through to the output, so memory use is bounded in any case;
+    /// this stops a delta from an untrusted source carrying data other than as COPYs.
+    pub max_literal_len: u64,
+
+    /// Check the output against the whole-file checksum at the end of the delta, failing
+    /// with `Error::ChecksumMismatch` if they differ.
+    ///
+    /// The delta must then be a `DeltaFormat::ChecksummedDelta`; others give
+    /// `Error::InvalidOptions`. Without this, checksums are read but not checked.
+    pub checked: bool,
+
+    /// Before writing anything, check that the basis is the one the delta was made from,
+    /// by recalculating its signature, failing with `Error::BasisMismatch` if it's not.
+    /// This reads the whole basis an extra time.
+    ///
+    /// The delta must then be a `DeltaFormat::BasisCheckedDelta`; others give
+    /// `Error::InvalidOptions`.
+    pub check_basis: bool,
+
+    /// Keep up to this many of the most recently read blocks of the basis in memory, so
+    /// that COPYs from the same part of the basis don't all seek and read it again.
+    ///
+    /// This helps with deltas that copy the same regions many times, such as those of
+    /// shuffled or repetitive data. Zero, the default, turns the cache off.
+    pub cache_blocks: usize,
+
+    /// Length of the blocks held in the cache, which must not be zero if `cache_blocks`
+    /// isn't.
+    pub cache_block_len: usize,
+}
+
+/// Default length of cached basis blocks.
+pub const DEFAULT_CACHE_BLOCK_LEN: usize = 64 << 10;
+
+impl Default for PatchOptions {
+    /// No limit on literals, and no cache.
+    fn default() -> PatchOptions {
+        PatchOptions {
+            max_literal_len: u64::MAX,
+            checked: false,
+            check_basis: false,
+            cache_blocks: 0,
+            cache_block_len: DEFAULT_CACHE_BLOCK_LEN,
+        }
+    }
+}
+
+/// Passes reads through to a basis that's borrowed.
+struct BorrowedBasis<'a, B: ?Sized>(&'a mut B);
+
+impl<'a, B: BasisProvider + ?Sized> BasisProvider for BorrowedBasis<'a, B> {
+    fn read_at(&mut self, offset: u64, buf: &mut [u8]) -> io::Result<()> {
+        self.0.read_at(offset, buf)
+    }
+
+    fn size(&mut self) -> io::Result<u64> {
+        self.0.size()
+    }
+}
+
+/// Apply a delta to a basis file, writing out the new file.
+///
+/// The basis must allow random access, such as a `File`, because COPY commands can refer
+/// to any part of it, in any order: see `basis::BasisProvider`. The delta and the output
+/// are streamed.
+///
+/// A delta compressed with zstd, as by `mkdelta::generate_delta_compressed`, is
+/// decompressed as it's read; this and the other patch functions take either.
+///
+/// `Error::BadMagic` is returned if the delta has the wrong magic, and
+/// `Error::CorruptDelta` if it contains an unknown command or tries to copy from beyond
+/// the end of the basis. On success,
+/// the statistics count the commands read.
+pub fn apply_patch<B: BasisProvider + ?Sized, D: Read + ?Sized, W: Write + ?Sized>(
+    basis: &mut B, delta: &mut D, out: &mut W) -> Result<Statistics> {
+    apply_patch_with_io(basis, delta, out, &IoOptions::default())
+}
+
+/// Apply a delta, buffering the delta and output as set by `io`.
+pub fn apply_patch_with_io<B: BasisProvider + ?Sized, D: Read + ?Sized, W: Write + ?Sized>(
+    basis: &mut B, delta: &mut D, out: &mut W, io: &IoOptions)
+    -> Result<Statistics> {
+    patch_with(basis, delta, out, &PatchOptions::default(), io)
+}
+
+/// Apply a delta with non-default `options`.
+pub fn apply_patch_with_options<B: BasisProvider + ?Sized, D: Read + ?Sized, W: Write + ?Sized>(
+    basis: &mut B, delta: &mut D, out: &mut W, options: &PatchOptions)
+    -> Result<Statistics> {
+    patch_with(basis, delta, out, options, &IoOptions::default())
+}
+
+fn patch_with<B: BasisProvider + ?Sized, D: Read + ?Sized, W: Write + ?Sized>(
+    basis: &mut B, delta: &mut D, out: &mut W, options: &PatchOptions, io: &IoOptions)
+    -> Result<Statistics> {
+    let start = Timer::start();
+    let commands = read_delta(delta, options, io)?;
+    if let (true, Some(id)) = (options.check_basis, commands.basis_id()) {
+        if basis_id(&mut BasisReader::new(basis)?, &id.options)? != *id {
+            return Err(Error::BasisMismatch);
+        }
+    }
+    let basis_len = basis.size()?;
+    let mut buf = Vec::new();
+    if options.cache_blocks > 0 {
+        let mut cached = CachedBasis::new(BorrowedBasis(basis), options.cache_block_len,
+                                          options.cache_blocks)?;
+        return apply_commands(start, &[basis_len], commands, out, options, io,
+                              &mut |_, offset, len, out| {
+            copy_range(&mut cached, offset, len, &mut buf, out)
+        });
+    }
+    apply_commands(start, &[basis_len], commands, out, options, io, &mut |_, offset, len, out| {
+        copy_range(basis, offset, len, &mut buf, out)
+    })
+}
+
+/// Apply a delta, and calculate the signature of the new file, with `sig_options`, as
+/// it's written.
+///
+/// This makes the signature for the next delta against the new file without reading
+/// it again. `Error::InvalidOptions` is returned, before anything is written, if
+/// `sig_options` are invalid. The statistics are those of applying the delta; the
+/// signature can be saved as a signature file by `Signature::write_to`.
+pub fn apply_patch_and_sign<B: BasisProvider + ?Sized, D: Read + ?Sized, W: Write + ?Sized>(
+    basis: &mut B, delta: &mut D, out: &mut W, options: &PatchOptions,
+    sig_options: &SignatureOptions)
+    -> Result<(Statistics, Signature)> {
+    let mut sink = SignatureSink::new(out, sig_options)?;
+    let stats = patch_with(basis, delta, &mut sink, options, &IoOptions::default())?;
+    let (_, signature) = sink.finish()?;
+    Ok((stats, signature))
+}
+
+/// Check that a delta would apply to a basis, without writing the new file.
+///
+/// The whole delta is read, and every COPY is checked to lie within the basis, giving
+/// the same errors as `apply_patch`. If the delta is a `DeltaFormat::ChecksummedDelta`,
+/// the new file is also rebuilt, reading the basis, and checked against the checksum,
+/// giving `Error::ChecksumMismatch` if it's wrong; otherwise only the basis's length is
+/// needed. On success the statistics are those of applying the delta.
+pub fn check_patch<B: BasisProvider + ?Sized, D: Read + ?Sized>(basis: &mut B, delta: &mut D)
+    -> Result<Statistics> {
+    let start = Timer::start();
+    let io = &IoOptions::default();
+    let commands = read_delta(delta, &PatchOptions::default(), io)?;
+    let checked = commands.format() == DeltaFormat::ChecksummedDelta;
+    let options = &PatchOptions { checked, .. PatchOptions::default() };
+    let basis_len = basis.size()?;
+    let mut buf = Vec::new();
+    apply_commands(start, &[basis_len], commands, &mut io::sink(), options, io,
+                   &mut |_, offset, len, out| {
+        if checked {
+            copy_range(basis, offset, len, &mut buf, out)
+        } else {
+            Ok(())
+        }
+    })
+}
+
+/// Apply a `DeltaFormat::MultiBasisDelta`, from `mkdelta::generate_delta_multi`, to the
+/// bases whose signatures it was made from, in the same order.
+///
+/// This can also apply any other delta, which refers only to the first basis.
+/// `Error::CorruptDelta` is returned if the delta refers to a basis beyond those given,
+/// and `Error::InvalidOptions` if there are none.
+pub fn apply_patch_multi<B: BasisProvider>(bases: &mut [B], delta: &mut (impl Read + ?Sized),
+                                           out: &mut (impl Write + ?Sized))
+    -> Result<Statistics> {
+    if bases.is_empty() {
+        return Err(Error::InvalidOptions("no bases were given".to_owned()));
+    }
+    let basis_lens = bases.iter_mut()
+        .map(|b| b.size())
+        .collect::<io::Result<Vec<u64>>>()?;
+    let (options, io) = (&PatchOptions::default(), &IoOptions::default());
+    let commands = read_delta(delta, options, io)?;
+    let mut buf = Vec::new();
+    apply_commands(Timer::start(), &basis_lens, commands, out, options, io,
+                   &mut |basis, offset, len, out| {
+        copy_range(&mut bases[basis], offset, len, &mut buf, out)
+    })
+}
+
+/// Apply a delta to a basis that can only be read once, from start to end, such as a pipe
+/// or the body of a network response.
+///
+/// The delta is read twice: first to find which ranges of the basis are copied and in
+/// what order, and then, after seeking back to where it started, to write the output. As
+/// the basis is read, ranges that later COPYs need are kept in memory until their last
+/// use, and the rest is skipped. If more than `memory_limit` bytes of the basis would have
+/// to be kept at once, this fails with `Error::MemoryLimit`, leaving the output partly
+/// written. A delta whose COPYs read the basis forwards, as `analyze::DeltaSummary`
+/// reports with `needs_seek`, needs no memory for the basis at all.
+///
+/// The basis isn't read past the end of the last COPY. `Error::CorruptDelta` is returned
+/// if it ends before then.
+pub fn apply_patch_streamed<R: Read + ?Sized, D: Read + Seek + ?Sized, W: Write + ?Sized>(
+    basis: &mut R, delta: &mut D, out: &mut W, memory_limit: u64)
+    -> Result<Statistics> {
+    let start = Timer::start();
+    let delta_start = delta.stream_position()?;
+    let mut basis = StreamedBasis::plan(basis, &mut *delta, memory_limit)?;
+    delta.seek(SeekFrom::Start(delta_start))?;
+    let (options, io) = (&PatchOptions::default(), &IoOptions::default());
+    let commands = read_delta(delta, options, io)?;
+    apply_commands(start, &[u64::MAX], commands, out, options, io, &mut |_, offset, len, out| {
+        basis.copy(offset, len, out)
+    })
+}
+
+/// A basis read forwards only, keeping what later COPYs need.
+///
+/// The basis is divided into pieces at the start and end of each COPY, so that each
+/// piece is either wholly inside or wholly outside any COPY.
+struct StreamedBasis<'a, R: ?Sized> {
+    inner: &'a mut R,
+
+    /// How much of the basis has been read.
+    pos: u64,
+
+    /// Offsets of the boundaries between pieces, in order. Piece `i` runs from
+    /// `bounds[i]` to `bounds[i + 1]`.
+    bounds: Vec<u64>,
+
+    /// The index of the last non-empty COPY that reads each piece, if any does.
+    last_use: Vec<Option<usize>>,
+
+    /// The pieces whose last use is each COPY.
+    expiring: Vec<Vec<usize>>,
+
+    /// The next piece to be read.
+    next_piece: usize,
+
+    /// Pieces that have been read and are still needed.
+    cache: HashMap<usize, Vec<u8>>,
+
+    /// Total length of the pieces in `cache`.
+    cached: u64,
+
+    memory_limit: u64,
+
+    /// The number of non-empty COPYs done so far.
+    copies_done: usize,
+}
+
+impl<'a, R: Read + ?Sized> StreamedBasis<'a, R> {
+    /// Read through `delta` to find the ranges each COPY needs.
+    fn plan(inner: &'a mut R, delta: &mut (impl Read + ?Sized), memory_limit: u64)
+        -> Result<StreamedBasis<'a, R>> {
+        let mut commands = DeltaReader::new(BufReader::new(decompressed(delta)?))?;
+        let mut copies = Vec::new();
+        loop {
+            match commands.read_header()? {
+                CommandHeader::Literal { len } => commands.copy_literal(len, &mut io::sink())?,
+                CommandHeader::Copy { len: 0, .. } => (),
+                CommandHeader::Copy { offset, len } => {
+                    check_copy(offset, len, u64::MAX)?;
+                    copies.push((offset, offset + len));
+                }
+                CommandHeader::End => break,
+            }
+        }
+        let mut bounds: Vec<u64> = copies.iter().flat_map(|&(start, end)| [start, end]).collect();
+        bounds.sort_unstable();
+        bounds.dedup();
+        let pieces = bounds.len().saturating_sub(1);
+        let mut last_use = vec![None; pieces];
+        let mut expiring = vec![Vec::new(); copies.len()];
+        // Going backwards, the first COPY found to read a piece is its last use. Each
+        // piece is visited once, by skipping over runs that already have a last use:
+        // `skip[p]` leads towards the first piece at or after `p` that might not.
+        let mut skip: Vec<usize> = (0..=pieces).collect();
+        for (i, &(start, end)) in copies.iter().enumerate().rev() {
+            let last = bounds.binary_search(&end).unwrap();
+            let mut p = next_unused(&mut skip, bounds.binary_search(&start).unwrap());
+            while p < last {
+                last_use[p] = Some(i);
+                expiring[i].push(p);
+                skip[p] = p + 1;
+                p = next_unused(&mut skip, p + 1);
+            }
+        }
+        Ok(StreamedBasis {
+            inner,
+            pos: 0,
+            bounds,
+            last_use,
+            expiring,
+            next_piece: 0,
+            cache: HashMap::new(),
+            cached: 0,
+            memory_limit,
+            copies_done: 0,
+        })
+    }
+
+    /// Write the range for the next COPY, reading the basis as far as its end.
+    fn copy(&mut self, offset: u64, len: u64, out: &mut dyn Write) -> Result<()> {
+        if len == 0 {
+            return Ok(());
+        }
+        let i = self.copies_done;
+        let end = offset + len;
+        // First whatever was read earlier and kept...
+        let mut p = self.bounds.binary_search(&offset).map_err(|_| Self::changed())?;
+        while p < self.next_piece && self.bounds[p] < end {
+            out.write_all(self.cache.get(&p).ok_or_else(Self::changed)?)?;
+            p += 1;
+        }
+        // ...then the rest as it's read.
+        while self.pos < end {
+            let p = self.next_piece;
+            let (piece_start, piece_end) = match self.bounds.get(p..(p + 2)) {
+                Some(&[s, e]) => (s, e),
+                _ => return Err(Self::changed()),
+            };
+            self.read(piece_start - self.pos, &mut io::sink(), offset, len)?;
+            let in_copy = piece_start >= offset;
+            match self.last_use[p] {
+                Some(last) if last > i => {
+                    let n = piece_end - piece_start;
+                    if self.cached + n > self.memory_limit {
+                        return Err(Error::MemoryLimit(self.memory_limit));
+                    }
+                    let mut data = Vec::with_capacity(n as usize);
+                    self.read(n, &mut data, offset, len)?;
+                    if in_copy {
+                        out.write_all(&data)?;
+                    }
+                    self.cached += n;
+                    self.cache.insert(p, data);
+                }
+                Some(_) if in_copy => self.read(piece_end - piece_start, out, offset, len)?,
+                _ => self.read(piece_end - piece_start, &mut io::sink(), offset, len)?,
+            }
+            self.next_piece += 1;
+        }
+        for p in &self.expiring[i] {
+            if let Some(data) = self.cache.remove(p) {
+                self.cached -= data.len() as u64;
+            }
+        }
+        self.copies_done += 1;
+        Ok(())
+    }
+
+    /// Read `n` bytes of the basis to `to`, failing as if the COPY of `len` bytes from
+    /// `offset` was beyond the end of the basis if it ends first.
+    fn read(&mut self, n: u64, to: &mut dyn Write, offset: u64, len: u64) -> Result<()> {
+        let l = io::copy(&mut (&mut self.inner).take(n), to)?;
+        self.pos += l;
+        if l < n {
+            check_copy(offset, len, self.pos)?;
+        }
+        Ok(())
+    }
+
+    /// The error for a delta that differs the second time it's read.
+    fn changed() -> Error {
+        Error::CorruptDelta("the delta changed while it was read".to_owned())
+    }
+}
+
+/// Follow `skip` from `p` to a piece that leads to itself, and point everything on the
+/// way straight at it.
+fn next_unused(skip: &mut [usize], p: usize) -> usize {
+    let mut found = p;
+    while skip[found] != found {
+        found = skip[found];
+    }
+    let mut p = p;
+    while p != found {
+        p = std::mem::replace(&mut skip[p], found);
+    }
+    found
+}
+
+/// Apply a delta to a basis that's already in memory, such as a memory-mapped file.
+///
+/// COPY commands are written straight from `basis`, rather than seeking and reading.
+pub fn apply_patch_from_slice<D: Read + ?Sized, W: Write + ?Sized>(basis: &[u8], delta: &mut D,
+                                                                    out: &mut W)
+    -> Result<Statistics> {
+    patch_slice_with_io(basis, delta, out, &IoOptions::default())
+}
+
+fn patch_slice_with_io<D: Read + ?Sized, W: Write + ?Sized>(basis: &[u8], delta: &mut D,
+                                                             out: &mut W, io: &IoOptions)
+    -> Result<Statistics> {
+    let options = &PatchOptions::default();
+    let commands = read_delta(delta, options, io)?;
+    apply_commands(Timer::start(), &[basis.len() as u64], commands, out, options, io,
+                   &mut |_, offset, len, out| {
+        Ok(out.write_all(&basis[offset as usize..(offset + len) as usize])?)
+    })
+}
+
+/// Apply a delta to a basis file by mapping it into memory, so that each COPY command is
+/// a memory copy rather than a seek and a read.
+///
+/// # Safety
+///
+/// Nothing, in this process or any other, may change or truncate the basis file while
+/// the patch is applied. The mapped memory is read as an immutable slice, so a change
+/// is undefined behaviour, and if the file is truncated the process may be killed by
+/// `SIGBUS`.
+#[cfg(feature = "mmap")]
+pub unsafe fn apply_patch_mmap<D: Read + ?Sized, W: Write + ?Sized>(basis: &File, delta: &mut D,
+                                                                     out: &mut W)
+    -> Result<Statistics> {
+    // Safety: the caller keeps the file unchanged.
+    let map = unsafe { Mmap::map(basis)? };
+    apply_patch_from_slice(&map, delta, out)
+}
+
+/// Writes a COPY of `len` bytes from `offset` in the basis with the given index.
+type CopyFn<'a> = dyn FnMut(usize, u64, u64, &mut dyn Write) -> Result<()> + 'a;
+
+/// Start reading `delta`, decompressing it if it's compressed, and checking that it can
+/// be applied with `options`.
+fn read_delta<'d, D: Read + ?Sized>(delta: &'d mut D, options: &PatchOptions, io: &IoOptions)
+    -> Result<DeltaReader<BufReader<Box<dyn Read + 'd>>>> {
+    let delta = decompressed(delta)?;
+    let mut commands = DeltaReader::new(BufReader::with_capacity(io.read_buf, delta))?;
+    commands.set_max_literal_len(options.max_literal_len);
+    if options.checked && commands.format() != DeltaFormat::ChecksummedDelta {
+        return Err(Error::InvalidOptions("the delta has no checksum to check".to_owned()));
+    }
+    if options.check_basis && commands.basis_id().is_none() {
+        return Err(Error::InvalidOptions("the delta doesn't identify its basis".to_owned()));
+    }
+    if options.cache_blocks > 0 && options.cache_block_len == 0 {
+        return Err(Error::InvalidOptions("cache_block_len is zero".to_owned()));
+    }
+    Ok(commands)
+}
+
+/// Apply the commands from `commands`, passing each COPY to `copy`, with the index of
+/// its basis, once it's been checked to lie within that basis.
+fn apply_commands(start: Timer, basis_lens: &[u64], mut commands: DeltaReader<impl Read>,
+                  out: &mut (impl Write + ?Sized), options: &PatchOptions, io: &IoOptions,
+                  copy: &mut CopyFn)
+    -> Result<Statistics> {
+    commands.set_basis_count(basis_lens.len() as u64);
+    let out = &mut HashWrite {
+        inner: BufWriter::with_capacity(io.write_buf, out),
+        hash: if options.checked { Some(checksum_hash()) } else { None },
+    };
+    loop {
+        match commands.read_header()? {
+            CommandHeader::Literal { len } => commands.copy_literal(len, out)?,
+            CommandHeader::Copy { offset, len } => {
+                let basis = commands.basis() as usize;
+                check_copy(offset, len, basis_lens[basis])?;
+                copy(basis, offset, len, out)?;
+            }
+            CommandHeader::End => break,
+        }
+    }
+    out.flush()?;
+    if let Some(hash) = out.hash.as_mut() {
+        if Some(&finish_checksum(hash)) != commands.checksum() {
+            return Err(Error::ChecksumMismatch);
+        }
+    }
+    let stats = commands.statistics();
+    Ok(Statistics {
+        out_bytes: stats.literal_bytes + stats.copy_bytes,
+        elapsed: start.elapsed(),
+        .. stats.clone()
+    })
+}
+
+/// Passes writes through, and hashes them if `hash` is set.
+struct HashWrite<W: Write> {
+    inner: W,
+    hash: Option<Blake2Hash>,
+}
+
+impl<W: Write> Write for HashWrite<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let l = self.inner.write(buf)?;
+        if let Some(hash) = self.hash.as_mut() {
+            hash.update(&buf[..l]);
+        }
+        Ok(l)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+/// Apply a delta, calling `progress` as the delta is read.
+pub fn apply_patch_with_progress<B: BasisProvider + ?Sized, D: Read + ?Sized, W: Write + ?Sized>(
+    basis: &mut B, delta: &mut D, out: &mut W, progress: &mut dyn FnMut(Progress))
+    -> Result<Statistics> {
+    apply_patch_metered(basis, delta, out, &IoOptions::default(), &Meter::new(None, progress))
+}
+
+/// Apply a delta, stopping with `Error::Cancelled` soon after `cancel` is cancelled.
+pub fn apply_patch_cancellable<B: BasisProvider + ?Sized, D: Read + ?Sized, W: Write + ?Sized>(
+    basis: &mut B, delta: &mut D, out: &mut W, cancel: &CancelToken)
+    -> Result<Statistics> {
+    apply_patch(basis, &mut cancel.reader(delta), out)
+}
+
+pub(crate) fn apply_patch_metered<B: BasisProvider + ?Sized, D: Read + ?Sized, W: Write + ?Sized>(
+    basis: &mut B, delta: &mut D, out: &mut W, io: &IoOptions, meter: &Meter)
+    -> Result<Statistics> {
+    let stats = apply_patch_with_io(basis, &mut meter.reader(delta), &mut meter.writer(out),
+                                    io)?;
+    meter.finish();
+    Ok(stats)
+}
+
+/// # Safety
+///
+/// As for `apply_patch_mmap`, the basis file must not change while the patch is applied.
+#[cfg(feature = "mmap")]
+pub(crate) unsafe fn apply_patch_mmap_metered<D: Read + ?Sized, W: Write + ?Sized>(
+    basis: &File, delta: &mut D, out: &mut W, io: &IoOptions, meter: &Meter)
+    -> Result<Statistics> {
+    // Safety: the caller keeps the file unchanged.
+    let map = unsafe { Mmap::map(basis)? };
+    let stats = patch_slice_with_io(&map, &mut meter.reader(delta), &mut meter.writer(out), io)?;
+    meter.finish();
+    Ok(stats)
+}
+
+#[cfg(test)]
+mod test {
+    use std::io::{Cursor, ErrorKind};
+
+    use super::*;
+    use super::super::delta::{DeltaWriter, OP_COPY_N1_N1, OP_COPY_N8_N8, OP_LITERAL_N1};
+    use super::super::magic::SignatureFormat;
+    use super::super::mkdelta::{generate_delta, generate_delta_multi, generate_delta_with_options,
+                                DeltaOptions};
+    use super::super::mksum::{calculate_signature, SignatureOptions};
+    use super::super::test_util::pattern;
+
+    fn patch(basis: &[u8], delta: &[u8]) -> Result<Vec<u8>> {
+        let mut out = Vec::new();
+        apply_patch(&mut Cursor::new(basis), &mut &delta[..], &mut out)?;
+        Ok(out)
+    }
+
+    fn round_trip(basis: &[u8], new: &[u8]) {
+        let options = SignatureOptions {
+            block_len: 256,
+            .. SignatureOptions::default()
+        };
+        let sig = calculate_signature(&mut &basis[..], &options).unwrap();
+        let mut delta = Vec::new();
+        generate_delta(&sig, &mut &new[..], &mut delta).unwrap();
+        assert_eq!(patch(basis, &delta).unwrap(), new);
+        let mut out = Vec::new();
+        apply_patch_from_slice(basis, &mut &delta[..], &mut out).unwrap();
+        assert_eq!(out, new);
+    }
+
+    #[test]
+    pub fn empty() {
+        assert_eq!(patch(b"", &[b'r', b's', 0x02, 0x36, 0]).unwrap(), b"");
+    }
+
+    #[test]
+    pub fn literal_and_copies() {
+        let delta = [
+            b'r', b's', 0x02, 0x36,
+            3, b'a', b'b', b'c',
+            OP_LITERAL_N1, 2, b'd', b'e',
+            OP_COPY_N1_N1, 1, 3, // "ell"
+            OP_COPY_N8_N8, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 1, // "h"
+            0];
+        assert_eq!(patch(b"hello", &delta).unwrap(), b"abcdeellh");
+    }
+
+    #[test]
+    pub fn statistics() {
+        let delta = [
+            b'r', b's', 0x02, 0x36,
+            3, b'a', b'b', b'c',
+            OP_COPY_N1_N1, 1, 3,
+            0];
+        let stats = apply_patch(&mut Cursor::new(b"hello"), &mut &delta[..], &mut Vec::new())
+            .unwrap();
+        assert_eq!(stats.op, "patch");
+        assert_eq!((stats.in_bytes, stats.out_bytes), (delta.len() as u64, 6));
+        assert_eq!((stats.literal_cmds, stats.literal_bytes, stats.literal_cmd_bytes), (1, 3, 1));
+        assert_eq!((stats.copy_cmds, stats.copy_bytes, stats.copy_cmd_bytes), (1, 3, 3));
+    }
+
+    #[test]
+    pub fn copy_beyond_slice() {
+        let delta = [b'r', b's', 0x02, 0x36, OP_COPY_N1_N1, 3, 3, 0];
+        let err = apply_patch_from_slice(b"hello", &mut &delta[..], &mut Vec::new()).unwrap_err();
+        assert!(matches!(err, Error::CorruptDelta(_)), "{:?}", err);
+    }
+
+    #[test]
+    pub fn round_trips() {
+        let basis = pattern(10_000);
+        round_trip(&basis, &basis);
+        round_trip(&basis, b"");
+        round_trip(b"", &basis);
+        round_trip(&basis, &basis[5000..]);
+        let mut changed = basis.clone();
+        changed[77] = 0;
+        changed.extend_from_slice(&basis[..3000]);
+        changed.splice(4000..4000, b"an insertion".iter().cloned());
+        round_trip(&basis, &changed);
+    }
+
+    #[test]
+    pub fn all_signature_formats_round_trip() {
+        let basis = pattern(10_000);
+        let mut new = basis[3000..].to_vec();
+        new.extend_from_slice(&basis[..3000]);
+        let mut formats = vec![SignatureFormat::Md4Sig, SignatureFormat::RkMd4Sig,
+                               SignatureFormat::Blake2Sig, SignatureFormat::RkBlake2Sig];
+        if cfg!(feature = "blake3") {
+            formats.push(SignatureFormat::Blake3Sig);
+        }
+        for magic in &formats {
+            let options = SignatureOptions {
+                magic: *magic,
+                block_len: 256,
+                .. SignatureOptions::default()
+            }.with_strong_len(8);
+            let sig = calculate_signature(&mut basis.as_slice(), &options).unwrap();
+            let mut delta = Vec::new();
+            generate_delta(&sig, &mut new.as_slice(), &mut delta).unwrap();
+            assert_eq!(patch(&basis, &delta).unwrap(), new, "{:?}", magic);
+        }
+    }
+
+    #[test]
+    pub fn bad_magic() {
+        let err = patch(b"", &[b'r', b's', 0x01, 0x37, 0]).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::InvalidData);
+        assert!(matches!(err, Error::BadMagic(0x72730137)), "{:?}", err);
+    }
+
+    #[test]
+    pub fn copy_beyond_basis() {
+        let delta = [b'r', b's', 0x02, 0x36, OP_COPY_N1_N1, 3, 3, 0];
+        let err = patch(b"hello", &delta).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::InvalidData);
+        assert!(matches!(err, Error::CorruptDelta(_)), "{:?}", err);
+    }
+
+    #[test]
+    pub fn unknown_command() {
+        let err = patch(b"", &[b'r', b's', 0x02, 0x36, 0x55]).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::InvalidData);
+        assert!(matches!(err, Error::CorruptDelta(_)), "{:?}", err);
+    }
+
+    #[test]
+    pub fn truncated() {
+        let err = patch(b"", &[b'r', b's', 0x02, 0x36, 4, b'a']).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::UnexpectedEof);
+        let err = patch(b"", &[b'r', b's', 0x02, 0x36]).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::UnexpectedEof);
+    }
+
+    #[test]
+    pub fn max_literal_len() {
+        let delta = [b'r', b's', 0x02, 0x36, 3, b'a', b'b', b'c', 0];
+        let mut out = Vec::new();
+        let options = PatchOptions { max_literal_len: 3, .. PatchOptions::default() };
+        apply_patch_with_options(&mut Cursor::new(b""), &mut &delta[..], &mut out, &options)
+            .unwrap();
+        assert_eq!(out, b"abc");
+        let options = PatchOptions { max_literal_len: 2, .. PatchOptions::default() };
+        let err = apply_patch_with_options(&mut Cursor::new(b""), &mut &delta[..], &mut out,
+                                           &options).unwrap_err();
+        assert!(matches!(err, Error::CorruptDelta(_)), "{:?}", err);
+    }
+
+    /// A literal claiming to be enormous is streamed, not allocated up front.
+    #[test]
+    pub fn huge_literal_header() {
+        let mut delta = vec![b'r', b's', 0x02, 0x36, OP_LITERAL_N1 + 3];
+        delta.extend(&(1u64 << 40).to_be_bytes());
+        delta.extend(b"ab");
+        let mut out = Vec::new();
+        let err = apply_patch(&mut Cursor::new(b""), &mut delta.as_slice(), &mut out)
+            .unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::UnexpectedEof);
+        assert_eq!(out, b"ab");
+    }
+
+    #[test]
+    pub fn checked() {
+        let basis = pattern(10_000);
+        let mut new = basis[1000..].to_vec();
+        new.extend_from_slice(b"tail");
+        let sig = calculate_signature(&mut basis.as_slice(), &SignatureOptions::default())
+            .unwrap();
+        let mut delta = Vec::new();
+        let options = DeltaOptions { checksum: true, .. DeltaOptions::default() };
+        generate_delta_with_options(&sig, &mut new.as_slice(), &mut delta, &options).unwrap();
+        let checked = PatchOptions { checked: true, .. PatchOptions::default() };
+        let mut out = Vec::new();
+        apply_patch_with_options(&mut Cursor::new(&basis), &mut delta.as_slice(), &mut out,
+                                 &checked).unwrap();
+        assert_eq!(out, new);
+        assert_eq!(patch(&basis, &delta).unwrap(), new);
+
+        let mut other = basis.clone();
+        other[5000] ^= 1;
+        let err = apply_patch_with_options(&mut Cursor::new(&other), &mut delta.as_slice(),
+                                           &mut Vec::new(), &checked).unwrap_err();
+        assert!(matches!(err, Error::ChecksumMismatch), "{:?}", err);
+        assert_eq!(err.kind(), ErrorKind::InvalidData);
+
+        let mut plain = Vec::new();
+        generate_delta(&sig, &mut new.as_slice(), &mut plain).unwrap();
+        let err = apply_patch_with_options(&mut Cursor::new(&basis), &mut plain.as_slice(),
+                                           &mut Vec::new(), &checked).unwrap_err();
+        assert!(matches!(err, Error::InvalidOptions(_)), "{:?}", err);
+    }
+
+    #[test]
+    pub fn check_only() {
+        let basis = pattern(10_000);
+        let mut new = basis[1000..].to_vec();
+        new.extend_from_slice(b"tail");
+        let sig = calculate_signature(&mut basis.as_slice(), &SignatureOptions::default())
+            .unwrap();
+        let options = DeltaOptions { checksum: true, .. DeltaOptions::default() };
+        let mut checksummed = Vec::new();
+        generate_delta_with_options(&sig, &mut new.as_slice(), &mut checksummed, &options)
+            .unwrap();
+        let mut plain = Vec::new();
+        generate_delta(&sig, &mut new.as_slice(), &mut plain).unwrap();
+        for delta in &[&checksummed, &plain] {
+            let stats = check_patch(&mut Cursor::new(&basis), &mut delta.as_slice()).unwrap();
+            assert_eq!(stats.out_bytes, new.len() as u64);
+        }
+
+        let mut other = basis.clone();
+        other[5000] ^= 1;
+        let err = check_patch(&mut Cursor::new(&other), &mut checksummed.as_slice())
+            .unwrap_err();
+        assert!(matches!(err, Error::ChecksumMismatch), "{:?}", err);
+        // Without a checksum, only the length of the basis matters.
+        check_patch(&mut Cursor::new(&other), &mut plain.as_slice()).unwrap();
+        for delta in &[&checksummed, &plain] {
+            let err = check_patch(&mut Cursor::new(&basis[..5000]), &mut delta.as_slice())
+                .unwrap_err();
+            assert!(matches!(err, Error::CorruptDelta(_)), "{:?}", err);
+            let err = check_patch(&mut Cursor::new(&basis), &mut &delta[..delta.len() - 1])
+                .unwrap_err();
+            assert_eq!(err.kind(), ErrorKind::UnexpectedEof, "{:?}", err);
+        }
+    }
+
+    #[test]
+    pub fn check_basis() {
+        let basis = pattern(10_000);
+        let new = basis[3000..].to_vec();
+        let sig_options = SignatureOptions { block_len: 512, .. SignatureOptions::default() };
+        let sig = calculate_signature(&mut basis.as_slice(), &sig_options).unwrap();
+        let mut delta = Vec::new();
+        let options = DeltaOptions { basis_id: true, .. DeltaOptions::default() };
+        generate_delta_with_options(&sig, &mut new.as_slice(), &mut delta, &options).unwrap();
+        assert_eq!(&delta[..4], b"rs\x856");
+        let check = PatchOptions { check_basis: true, .. PatchOptions::default() };
+        let mut out = Vec::new();
+        let stats = apply_patch_with_options(&mut Cursor::new(&basis), &mut delta.as_slice(),
+                                             &mut out, &check).unwrap();
+        assert_eq!(out, new);
+        assert_eq!(stats.in_bytes, delta.len() as u64);
+        assert_eq!(patch(&basis, &delta).unwrap(), new);
+
+        // The wrong basis is caught before anything is written.
+        let mut other = basis.clone();
+        other[9000] ^= 1;
+        let mut out = Vec::new();
+        let err = apply_patch_with_options(&mut Cursor::new(&other), &mut delta.as_slice(),
+                                           &mut out, &check).unwrap_err();
+        assert!(matches!(err, Error::BasisMismatch), "{:?}", err);
+        assert!(out.is_empty());
+
+        let mut plain = Vec::new();
+        generate_delta(&sig, &mut new.as_slice(), &mut plain).unwrap();
+        let err = apply_patch_with_options(&mut Cursor::new(&basis), &mut plain.as_slice(),
+                                           &mut Vec::new(), &check).unwrap_err();
+        assert!(matches!(err, Error::InvalidOptions(_)), "{:?}", err);
+    }
+
+    /// A basis too big to hold in memory, in which each byte is its offset in GiB.
+    struct Gigabytes {
+        pos: u64,
+        len: u64,
+    }
+
+    impl Read for Gigabytes {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            let gib = self.pos >> 30;
+            let end = self.len.min((gib + 1) << 30);
+            let l = (buf.len() as u64).min(end.saturating_sub(self.pos)) as usize;
+            buf[..l].fill(gib as u8);
+            self.pos += l as u64;
+            Ok(l)
+        }
+    }
+
+    impl Seek for Gigabytes {
+        fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+            self.pos = match pos {
+                SeekFrom::Start(p) => p,
+                SeekFrom::End(d) => (self.len as i64 + d) as u64,
+                SeekFrom::Current(d) => (self.pos as i64 + d) as u64,
+            };
+            Ok(self.pos)
+        }
+    }
+
+    #[test]
+    pub fn copies_beyond_4gib() {
+        let mut basis = Gigabytes { pos: 0, len: 6 << 30 };
+        let mut delta = vec![b'r', b's', 0x02, 0x36, OP_COPY_N8_N8 - 3];
+        delta.extend(&((4u64 << 30) - 2).to_be_bytes());
+        delta.push(4);
+        delta.push(OP_COPY_N8_N8);
+        delta.extend(&(1u64 << 30).to_be_bytes());
+        delta.extend(&(5u64 << 30).to_be_bytes());
+        delta.push(0);
+        let mut out = Vec::new();
+        let stats = apply_patch(&mut basis, &mut delta.as_slice(), &mut Limit(&mut out, 16))
+            .unwrap();
+        assert_eq!(stats.copy_cmds, 2);
+        assert_eq!(stats.copy_bytes, 4 + (5 << 30));
+        assert_eq!(stats.out_bytes, 4 + (5 << 30));
+        assert_eq!(out, [3, 3, 4, 4, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1]);
+    }
+
+    /// A file made of parts of two bases is sent mostly as COPYs from each.
+    #[test]
+    pub fn multiple_bases() {
+        let options = SignatureOptions { block_len: 256, .. SignatureOptions::default() };
+        let a = pattern(20_000);
+        let b: Vec<u8> = (0..20_000).map(|i| ((i * 13 + i / 7) % 241) as u8).collect();
+        let mut new = a[..8000].to_vec();
+        new.extend_from_slice(&b[5000..15_000]);
+        new.extend_from_slice(b"something new");
+        new.extend_from_slice(&a[12_000..]);
+        let sig_a = calculate_signature(&mut a.as_slice(), &options).unwrap();
+        let sig_b = calculate_signature(&mut b.as_slice(), &options).unwrap();
+        let mut delta = Vec::new();
+        let stats = generate_delta_multi(&[&sig_a, &sig_b], &mut new.as_slice(), &mut delta)
+            .unwrap();
+        assert_eq!(&delta[..4], b"rs\x846");
+        assert!(stats.literal_bytes < 600, "{:?}", stats);
+        let mut out = Vec::new();
+        let mut bases = [Cursor::new(&a), Cursor::new(&b)];
+        let stats = apply_patch_multi(&mut bases, &mut delta.as_slice(), &mut out).unwrap();
+        assert_eq!(out, new);
+        assert_eq!(stats.in_bytes, delta.len() as u64);
+
+        // Given only the first basis, the delta is rejected rather than misapplied.
+        let err = patch(&a, &delta).unwrap_err();
+        assert!(matches!(err, Error::CorruptDelta(_)), "{:?}", err);
+        let err = apply_patch_multi::<Cursor<&[u8]>>(&mut [], &mut delta.as_slice(),
+                                                     &mut Vec::new()).unwrap_err();
+        assert!(matches!(err, Error::InvalidOptions(_)), "{:?}", err);
+    }
+
+    /// A basis fetched a range at a time, as from a remote store.
+    struct Ranges<'a> {
+        data: &'a [u8],
+        requests: Vec<(u64, usize)>,
+    }
+
+    impl<'a> BasisProvider for Ranges<'a> {
+        fn read_at(&mut self, offset: u64, buf: &mut [u8]) -> io::Result<()> {
+            self.requests.push((offset, buf.len()));
+            let offset = offset as usize;
+            buf.copy_from_slice(&self.data[offset..(offset + buf.len())]);
+            Ok(())
+        }
+
+        fn size(&mut self) -> io::Result<u64> {
+            Ok(self.data.len() as u64)
+        }
+    }
+
+    #[test]
+    pub fn basis_providers() {
+        let basis = pattern(10_000);
+        let mut w = DeltaWriter::new(Vec::new()).unwrap();
+        w.copy(5000, 100).unwrap();
+        w.literal(b"between").unwrap();
+        w.copy(200, 10).unwrap();
+        let delta = w.finish().unwrap();
+        let expected = patch(&basis, &delta).unwrap();
+
+        let mut ranges = Ranges { data: &basis, requests: Vec::new() };
+        let mut out = Vec::new();
+        apply_patch(&mut ranges, &mut delta.as_slice(), &mut out).unwrap();
+        assert_eq!(out, expected);
+        assert_eq!(ranges.requests, [(5000, 100), (200, 10)]);
+
+        let mut out = Vec::new();
+        apply_patch(&mut basis.clone()[..], &mut delta.as_slice(), &mut out).unwrap();
+        assert_eq!(out, expected);
+        let provider: &mut dyn BasisProvider = &mut Cursor::new(&basis);
+        let mut out = Vec::new();
+        apply_patch(provider, &mut delta.as_slice(), &mut out).unwrap();
+        assert_eq!(out, expected);
+    }
+
+    /// Counts the bytes read through it.
+    struct CountReads<'a>(Cursor<&'a [u8]>, u64);
+
+    impl<'a> Read for CountReads<'a> {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            let l = self.0.read(buf)?;
+            self.1 += l as u64;
+            Ok(l)
+        }
+    }
+
+    impl<'a> Seek for CountReads<'a> {
+        fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+            self.0.seek(pos)
+        }
+    }
+
+    /// Repeated COPYs from the same region read it from the basis only once.
+    #[test]
+    pub fn cached_blocks() {
+        let basis = pattern(100_000);
+        let mut w = DeltaWriter::new(Vec::new()).unwrap();
+        let mut expected = Vec::new();
+        for i in 0..200u64 {
+            let offset = (i * 7919) % 3000 + if i % 2 == 0 { 0 } else { 50_000 };
+            w.copy(offset, 500).unwrap();
+            w.literal(b"-").unwrap();
+            expected.extend_from_slice(&basis[offset as usize..(offset + 500) as usize]);
+            expected.push(b'-');
+        }
+        let delta = w.finish().unwrap();
+        let options = PatchOptions {
+            cache_blocks: 4,
+            cache_block_len: 4096,
+            .. PatchOptions::default()
+        };
+        let mut reader = CountReads(Cursor::new(&basis), 0);
+        let mut out = Vec::new();
+        apply_patch_with_options(&mut reader, &mut delta.as_slice(), &mut out, &options)
+            .unwrap();
+        assert_eq!(out, expected);
+        assert!(reader.1 <= 4 * 4096, "{}", reader.1);
+
+        // With too small a cache, blocks are evicted and read again, and the output is the
+        // same.
+        let small = PatchOptions { cache_blocks: 1, cache_block_len: 1000, .. options };
+        let mut reader = CountReads(Cursor::new(&basis), 0);
+        let mut out = Vec::new();
+        apply_patch_with_options(&mut reader, &mut delta.as_slice(), &mut out, &small)
+            .unwrap();
+        assert_eq!(out, expected);
+        assert!(reader.1 > 100_000, "{}", reader.1);
+    }
+
+    /// Blocks at the end of the basis are short, and COPYs span several blocks.
+    #[test]
+    pub fn cached_round_trip() {
+        let basis = pattern(10_007);
+        let mut new = basis[5000..].to_vec();
+        new.extend_from_slice(b"new");
+        new.extend_from_slice(&basis);
+        let sig = calculate_signature(&mut basis.as_slice(), &SignatureOptions::default())
+            .unwrap();
+        let mut delta = Vec::new();
+        generate_delta(&sig, &mut new.as_slice(), &mut delta).unwrap();
+        for &(cache_blocks, cache_block_len) in &[(1, 1), (2, 999), (3, 10_000), (8, 1 << 20)] {
+            let options = PatchOptions { cache_blocks, cache_block_len,
+                                         .. PatchOptions::default() };
+            let mut out = Vec::new();
+            apply_patch_with_options(&mut Cursor::new(&basis), &mut delta.as_slice(), &mut out,
+                                     &options).unwrap();
+            assert_eq!(out, new);
+        }
+        let zero = PatchOptions { cache_blocks: 1, cache_block_len: 0,
+                                  .. PatchOptions::default() };
+        let err = apply_patch_with_options(&mut Cursor::new(&basis), &mut delta.as_slice(),
+                                           &mut Vec::new(), &zero).unwrap_err();
+        assert!(matches!(err, Error::InvalidOptions(_)), "{:?}", err);
+    }
+
+    fn patch_streamed(basis: &[u8], delta: &[u8], memory_limit: u64) -> Result<Vec<u8>> {
+        let mut out = Vec::new();
+        apply_patch_streamed(&mut &basis[..], &mut Cursor::new(delta), &mut out, memory_limit)?;
+        Ok(out)
+    }
+
+    /// A delta that reads the basis forwards needs no memory for it.
+    #[test]
+    pub fn streamed_forwards() {
+        // Data that doesn't repeat, so that blocks aren't copied from earlier matches.
+        let mut x: u64 = 1;
+        let basis: Vec<u8> = (0..100_000).map(|_| {
+            x = x.wrapping_mul(6_364_136_223_846_793_005).wrapping_add(1_442_695_040_888_963_407);
+            (x >> 56) as u8
+        }).collect();
+        let mut new = basis[..30_000].to_vec();
+        new.extend_from_slice(b"an insertion");
+        new.extend_from_slice(&basis[50_000..90_112]);
+        let sig = calculate_signature(&mut basis.as_slice(), &SignatureOptions::default())
+            .unwrap();
+        let mut delta = Vec::new();
+        generate_delta(&sig, &mut new.as_slice(), &mut delta).unwrap();
+        assert_eq!(patch_streamed(&basis, &delta, 0).unwrap(), new);
+
+        // Nothing is read beyond the last COPY, which ends at the end of a block, and the
+        // delta can start part way through its file.
+        let mut reader = CountReads(Cursor::new(&basis), 0);
+        let mut in_file = Cursor::new([&b"header"[..], &delta].concat());
+        in_file.set_position(6);
+        let mut out = Vec::new();
+        let stats = apply_patch_streamed(&mut reader, &mut in_file, &mut out, 0).unwrap();
+        assert_eq!(out, new);
+        assert_eq!(reader.1, 90_112);
+        assert_eq!(stats.out_bytes, new.len() as u64);
+    }
+
+    /// Ranges that are copied again later, or out of order, are kept until they're used,
+    /// within the memory limit.
+    #[test]
+    pub fn streamed_out_of_order() {
+        let basis = pattern(20_000);
+        let mut w = DeltaWriter::new(Vec::new()).unwrap();
+        w.copy(15_000, 2000).unwrap();
+        w.copy(1000, 4000).unwrap();
+        w.literal(b"between").unwrap();
+        w.copy(3000, 500).unwrap();
+        w.copy(0, 0).unwrap();
+        w.copy(16_000, 3000).unwrap();
+        w.copy(2000, 100).unwrap();
+        let delta = w.finish().unwrap();
+        let expected = patch(&basis, &delta).unwrap();
+        // For the first COPY, 1000..5000 is read and kept for later, as is 16_000..17_000.
+        assert_eq!(patch_streamed(&basis, &delta, 5000).unwrap(), expected);
+        let err = patch_streamed(&basis, &delta, 4999).unwrap_err();
+        assert!(matches!(err, Error::MemoryLimit(4999)), "{:?}", err);
+        assert_eq!(err.kind(), ErrorKind::OutOfMemory);
+    }
+
+    #[test]
+    pub fn streamed_shuffled() {
+        let basis = pattern(50_000);
+        let mut new = Vec::new();
+        for i in 0..50 {
+            let at = (i * 7919) % 45_000;
+            new.extend_from_slice(&basis[at..(at + 3000)]);
+        }
+        let sig = calculate_signature(&mut basis.as_slice(), &SignatureOptions::default())
+            .unwrap();
+        let mut delta = Vec::new();
+        generate_delta(&sig, &mut new.as_slice(), &mut delta).unwrap();
+        assert_eq!(patch_streamed(&basis, &delta, 50_000).unwrap(), new);
+    }
+
+    #[test]
+    pub fn streamed_basis_too_short() {
+        let mut w = DeltaWriter::new(Vec::new()).unwrap();
+        w.copy(500, 1000).unwrap();
+        let err = patch_streamed(&pattern(1000), &w.finish().unwrap(), 0).unwrap_err();
+        assert!(matches!(err, Error::CorruptDelta(_)), "{:?}", err);
+        assert!(err.to_string().contains("1000 byte basis"), "{}", err);
+    }
+
+    /// Patching and signing gives the new file and the signature it would have had from
+    /// reading it again.
+    #[test]
+    pub fn patch_and_sign() {
+        let basis = pattern(20_000);
+        let mut new = pattern(25_000);
+        new[7000..7100].fill(9);
+        let sig = calculate_signature(&mut basis.as_slice(), &SignatureOptions::default())
+            .unwrap();
+        let mut delta = Vec::new();
+        generate_delta(&sig, &mut new.as_slice(), &mut delta).unwrap();
+        let cdc = SignatureOptions {
+            magic: SignatureFormat::CdcBlake2Sig,
+            block_len: 256,
+            .. SignatureOptions::default()
+        };
+        for sig_options in &[SignatureOptions::default(), cdc] {
+            let mut out = Vec::new();
+            let (stats, new_sig) = apply_patch_and_sign(
+                &mut Cursor::new(&basis), &mut delta.as_slice(), &mut out,
+                &PatchOptions::default(), sig_options).unwrap();
+            assert_eq!(out, new);
+            assert_eq!(stats.out_bytes, new.len() as u64);
+            assert_eq!(new_sig, calculate_signature(&mut new.as_slice(), sig_options).unwrap());
+        }
+
+        let mut out = Vec::new();
+        let zero = SignatureOptions { block_len: 0, .. SignatureOptions::default() };
+        let err = apply_patch_and_sign(&mut Cursor::new(&basis), &mut delta.as_slice(),
+                                       &mut out, &PatchOptions::default(), &zero).unwrap_err();
+        assert!(matches!(err, Error::InvalidOptions(_)), "{:?}", err);
+        assert!(out.is_empty());
+    }
+
+    /// Keeps the given number of bytes from the start of the output, and discards the rest.
+    struct Limit<'a>(&'a mut Vec<u8>, usize);
+
+    impl<'a> Write for Limit<'a> {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            let l = buf.len().min(self.1 - self.0.len().min(self.1));
+            self.0.extend_from_slice(&buf[..l]);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+}
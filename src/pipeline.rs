@@ -0,0 +1,201 @@
+// rdiff(rust) -- library for network deltas
+// Copyright 2018 Martin Pool.
+
+//! Run an operation with its reading and writing on their own threads.
+//!
+//! One thread reads the input, the caller's thread does the work, and another writes the
+//! output, connected by bounded channels. While the work is hashing or searching one
+//! buffer, the next is being read and the last written, so a fast disk and the CPU can
+//! both be kept busy.
+
+use std::io;
+use std::io::{Read, Write};
+use std::mem;
+use std::sync::mpsc::{sync_channel, Receiver, SyncSender};
+use std::thread;
+
+use super::error::Result;
+use super::io_options::IoOptions;
+
+/// How many buffers can wait in each channel.
+const DEPTH: usize = 4;
+
+/// Run `f`, reading from `input` and writing to `output` on other threads.
+///
+/// `f` is given a reader and writer that pass data through the channels, in pieces of
+/// `io.read_buf` and `io.write_buf` bytes. The output is flushed once `f` returns. An
+/// error from the reading or writing thread is returned in preference to the error it
+/// caused in `f`.
+pub(crate) fn pipelined<R, W, T, F>(input: &mut R, output: &mut W, io: &IoOptions, f: F)
+    -> Result<T>
+    where R: Read + Send + ?Sized,
+          W: Write + Send + ?Sized,
+          F: FnOnce(&mut ChannelReader, &mut ChannelWriter) -> Result<T> {
+    let read_len = io.read_buf.max(1);
+    let write_len = io.write_buf.max(1);
+    thread::scope(|scope| {
+        let (in_tx, in_rx) = sync_channel(DEPTH);
+        let reader = scope.spawn(move || read_into(input, read_len, &in_tx));
+        let (out_tx, out_rx) = sync_channel(DEPTH);
+        let writer = scope.spawn(move || write_from(output, &out_rx));
+        let mut r = ChannelReader { rx: in_rx, buf: Vec::new(), pos: 0, eof: false };
+        let mut w = ChannelWriter { tx: Some(out_tx), buf: Vec::new(), write_len };
+        let result = f(&mut r, &mut w).and_then(|t| {
+            w.flush()?;
+            Ok(t)
+        });
+        // Closing the channels stops both threads, even if `f` failed part way.
+        drop(r);
+        drop(w);
+        let read_result = reader.join().expect("reading thread panicked");
+        let write_result = writer.join().expect("writing thread panicked");
+        read_result?;
+        write_result?;
+        result
+    })
+}
+
+/// Read `input` in pieces of up to `read_len` bytes, sending each to `tx`, until its end
+/// or until the receiver is dropped.
+fn read_into<R: Read + ?Sized>(input: &mut R, read_len: usize,
+                               tx: &SyncSender<io::Result<Vec<u8>>>)
+    -> io::Result<()> {
+    loop {
+        let mut buf = vec![0; read_len];
+        let l = match input.read(&mut buf) {
+            Ok(l) => l,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+            Err(e) => {
+                let kind = e.kind();
+                let _ = tx.send(Err(io::Error::new(kind, "the input couldn't be read")));
+                return Err(e);
+            }
+        };
+        buf.truncate(l);
+        if tx.send(Ok(buf)).is_err() || l == 0 {
+            return Ok(());
+        }
+    }
+}
+
+/// Write each buffer received from `rx` to `output`, and then flush it.
+fn write_from<W: Write + ?Sized>(output: &mut W, rx: &Receiver<Vec<u8>>) -> io::Result<()> {
+    for buf in rx {
+        output.write_all(&buf)?;
+    }
+    output.flush()
+}
+
+/// Reads data sent by the reading thread.
+pub(crate) struct ChannelReader {
+    rx: Receiver<io::Result<Vec<u8>>>,
+    buf: Vec<u8>,
+    pos: usize,
+    eof: bool,
+}
+
+impl Read for ChannelReader {
+    fn read(&mut self, out: &mut [u8]) -> io::Result<usize> {
+        while self.pos == self.buf.len() && !self.eof && !out.is_empty() {
+            match self.rx.recv() {
+                Ok(Ok(buf)) => {
+                    self.eof = buf.is_empty();
+                    self.buf = buf;
+                    self.pos = 0;
+                }
+                Ok(Err(e)) => return Err(e),
+                Err(_) => return Err(io::Error::new(io::ErrorKind::BrokenPipe,
+                                                    "the reading thread stopped")),
+            }
+        }
+        let l = (self.buf.len() - self.pos).min(out.len());
+        out[..l].copy_from_slice(&self.buf[self.pos..self.pos + l]);
+        self.pos += l;
+        Ok(l)
+    }
+}
+
+/// Collects written data into pieces, and sends them to the writing thread.
+pub(crate) struct ChannelWriter {
+    tx: Option<SyncSender<Vec<u8>>>,
+    buf: Vec<u8>,
+    write_len: usize,
+}
+
+impl ChannelWriter {
+    fn send(&mut self) -> io::Result<()> {
+        let buf = mem::take(&mut self.buf);
+        match &self.tx {
+            Some(tx) if tx.send(buf).is_ok() => Ok(()),
+            _ => {
+                self.tx = None;
+                Err(io::Error::new(io::ErrorKind::BrokenPipe, "the writing thread stopped"))
+            }
+        }
+    }
+}
+
+impl Write for ChannelWriter {
+    fn write(&mut self, data: &[u8]) -> io::Result<usize> {
+        let l = (self.write_len - self.buf.len()).min(data.len());
+        self.buf.extend_from_slice(&data[..l]);
+        if self.buf.len() == self.write_len {
+            self.send()?;
+        }
+        Ok(l)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        if !self.buf.is_empty() {
+            self.send()?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use super::super::error::Error;
+
+    /// A writer that fails after some data.
+    struct Full(usize);
+
+    impl Write for Full {
+        fn write(&mut self, data: &[u8]) -> io::Result<usize> {
+            if self.0 == 0 {
+                return Err(io::Error::other("disk full"));
+            }
+            let l = self.0.min(data.len());
+            self.0 -= l;
+            Ok(l)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    pub fn copy_through() {
+        let data: Vec<u8> = (0..100_000u32).map(|i| (i % 251) as u8).collect();
+        let io = IoOptions { read_buf: 1000, write_buf: 777 };
+        let mut out = Vec::new();
+        let n = pipelined(&mut data.as_slice(), &mut out, &io, |r, w| {
+            Ok(io::copy(r, w)?)
+        }).unwrap();
+        assert_eq!(n, data.len() as u64);
+        assert_eq!(out, data);
+    }
+
+    #[test]
+    pub fn write_errors() {
+        let data = vec![7; 100_000];
+        let err = pipelined(&mut data.as_slice(), &mut Full(5000), &IoOptions::default(),
+                            |r, w| Ok(io::copy(r, w)?)).unwrap_err();
+        match err {
+            Error::Io(e) => assert_eq!(e.to_string(), "disk full"),
+            e => panic!("unexpected {:?}", e),
+        }
+    }
+}
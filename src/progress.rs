@@ -0,0 +1,142 @@
+// rdiff(rust) -- library for network deltas
+// Copyright 2018 Martin Pool.
+
+//! Progress reports from long-running operations.
+//!
+//! The `_with_progress` variants of the signature, delta and patch functions call back
+//! with a `Progress` as data passes through, roughly every megabyte, and once more at the
+//! end.
+
+use std::cell::{Cell, RefCell};
+use std::io::{self, Read, Write};
+
+/// Report progress after about this many more bytes have been read or written.
+const REPORT_INTERVAL: u64 = 1 << 20;
+
+/// How far an operation has got.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Progress {
+    /// Bytes read so far from the main input: the basis, new file or delta.
+    pub read: u64,
+
+    /// Bytes written so far to the output.
+    pub written: u64,
+
+    /// Total length of the main input, if it's known.
+    pub total: Option<u64>,
+}
+
+impl Progress {
+    /// Percentage of the input that's been read, if the total is known.
+    pub fn percent(&self) -> Option<f64> {
+        self.total.map(|t| if t == 0 { 100.0 } else { self.read as f64 * 100.0 / t as f64 })
+    }
+}
+
+/// Counts the bytes read and written through its readers and writers, and passes
+/// `Progress` to a callback.
+pub(crate) struct Meter<'a> {
+    progress: Cell<Progress>,
+    reported: Cell<u64>,
+    callback: RefCell<&'a mut dyn FnMut(Progress)>,
+}
+
+impl<'a> Meter<'a> {
+    pub(crate) fn new(total: Option<u64>, callback: &'a mut dyn FnMut(Progress)) -> Meter<'a> {
+        Meter {
+            progress: Cell::new(Progress { read: 0, written: 0, total }),
+            reported: Cell::new(0),
+            callback: RefCell::new(callback),
+        }
+    }
+
+    pub(crate) fn reader<R: Read>(&self, inner: R) -> MeterRead<'_, 'a, R> {
+        MeterRead { meter: self, inner }
+    }
+
+    pub(crate) fn writer<W: Write>(&self, inner: W) -> MeterWrite<'_, 'a, W> {
+        MeterWrite { meter: self, inner }
+    }
+
+    fn add(&self, read: usize, written: usize) {
+        let mut p = self.progress.get();
+        p.read += read as u64;
+        p.written += written as u64;
+        self.progress.set(p);
+        if p.read + p.written - self.reported.get() >= REPORT_INTERVAL {
+            self.report();
+        }
+    }
+
+    fn report(&self) {
+        let p = self.progress.get();
+        self.reported.set(p.read + p.written);
+        (self.callback.borrow_mut())(p);
+    }
+
+    /// Make the final report, once the operation has succeeded.
+    pub(crate) fn finish(&self) {
+        self.report();
+    }
+}
+
+pub(crate) struct MeterRead<'m, 'a, R> {
+    meter: &'m Meter<'a>,
+    inner: R,
+}
+
+impl<'m, 'a, R: Read> Read for MeterRead<'m, 'a, R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let l = self.inner.read(buf)?;
+        self.meter.add(l, 0);
+        Ok(l)
+    }
+}
+
+pub(crate) struct MeterWrite<'m, 'a, W> {
+    meter: &'m Meter<'a>,
+    inner: W,
+}
+
+impl<'m, 'a, W: Write> Write for MeterWrite<'m, 'a, W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let l = self.inner.write(buf)?;
+        self.meter.add(0, l);
+        Ok(l)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    pub fn reports_at_intervals_and_end() {
+        let mut reports = Vec::new();
+        {
+            let mut callback = |p| reports.push(p);
+            let meter = Meter::new(Some(3 << 20), &mut callback);
+            let input = vec![0u8; 3 << 20];
+            let mut out = Vec::new();
+            io::copy(&mut meter.reader(input.as_slice()), &mut meter.writer(&mut out)).unwrap();
+            meter.finish();
+        }
+        assert!(reports.len() >= 6, "{:?}", reports.len());
+        assert!(reports.windows(2).all(|w| w[0].read <= w[1].read));
+        let last = reports.last().unwrap();
+        assert_eq!((last.read, last.written), (3 << 20, 3 << 20));
+        assert_eq!(last.percent(), Some(100.0));
+    }
+
+    #[test]
+    pub fn percent() {
+        let p = Progress { read: 25, written: 0, total: Some(100) };
+        assert_eq!(p.percent(), Some(25.0));
+        assert_eq!(Progress { total: None, .. p }.percent(), None);
+        assert_eq!(Progress { read: 0, written: 0, total: Some(0) }.percent(), Some(100.0));
+    }
+}
@@ -0,0 +1,155 @@
+// rdiff(rust) -- library for network deltas
+// Copyright 2018 Martin Pool.
+
+//! The RabinKarp rolling hash, used as the weak checksum by librsync 2.2 and later.
+//!
+//! This is a polynomial hash modulo 2^32. It distributes better than the classic
+//! rollsum, and is selected by the `rs\x01G` and `rs\x01F` signature magics.
+
+use core::num::Wrapping;
+
+use super::rollsum::{block_sum, RollingHash};
+use super::simd::rabinkarp_sum;
+
+/// Initial hash value, which effectively encodes the length into the hash so that
+/// runs of zeros of different lengths hash differently.
+const SEED: Wrapping<u32> = Wrapping(1);
+
+/// The multiplier, from librsync's `RABINKARP_MULT`.
+pub(crate) const MULT: Wrapping<u32> = Wrapping(0x0810_4225);
+
+/// The inverse of `MULT` modulo 2^32: multiplying by it divides by `MULT`.
+const INVM: Wrapping<u32> = Wrapping(0x98f0_09ad);
+
+/// Adjustment for the seed when rolling values out: `(MULT - 1) * SEED`.
+const ADJ: Wrapping<u32> = Wrapping(0x0810_4224);
+
+/// Calculate the RabinKarp weak checksum of a whole block in one go.
+pub fn weak_sum(buf: &[u8]) -> u32 {
+    block_sum::<RabinKarp>(buf)
+}
+
+/// Raise `m` to the power `p`, modulo 2^32.
+pub(crate) fn pow(mut m: Wrapping<u32>, mut p: usize) -> Wrapping<u32> {
+    let mut ans = Wrapping(1);
+    while p != 0 {
+        if p & 1 != 0 {
+            ans *= m;
+        }
+        m *= m;
+        p >>= 1;
+    }
+    ans
+}
+
+/// RabinKarp rolling hash state, compatible with librsync's `rabinkarp_t`.
+#[derive(Debug, Copy, Clone)]
+pub struct RabinKarp {
+    /// Number of bytes included in the hash.
+    count: usize,
+
+    /// The accumulated hash value.
+    hash: Wrapping<u32>,
+
+    /// `MULT` raised to the power `count`.
+    mult: Wrapping<u32>,
+}
+
+impl RabinKarp {
+    pub fn new() -> RabinKarp {
+        RabinKarp {
+            count: 0,
+            hash: SEED,
+            mult: Wrapping(1),
+        }
+    }
+}
+
+impl Default for RabinKarp {
+    fn default() -> RabinKarp {
+        RabinKarp::new()
+    }
+}
+
+impl RollingHash for RabinKarp {
+    fn digest(&self) -> u32 {
+        self.hash.0
+    }
+
+    fn roll_in(&mut self, c_in: u8) {
+        self.hash = self.hash * MULT + Wrapping(u32::from(c_in));
+        self.count += 1;
+        self.mult *= MULT;
+    }
+
+    fn roll_out(&mut self, c_out: u8) {
+        self.count -= 1;
+        self.mult *= INVM;
+        self.hash -= self.mult * (Wrapping(u32::from(c_out)) + ADJ);
+    }
+
+    fn rotate(&mut self, c_out: u8, c_in: u8) {
+        self.hash = self.hash * MULT + Wrapping(u32::from(c_in))
+            - self.mult * (Wrapping(u32::from(c_out)) + ADJ);
+    }
+
+    fn update(&mut self, buf: &[u8]) {
+        let shift = pow(MULT, buf.len());
+        self.hash = self.hash * shift + Wrapping(rabinkarp_sum(buf));
+        self.count += buf.len();
+        self.mult *= shift;
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::super::rollsum::RollingHash;
+    use super::{RabinKarp, weak_sum, MULT, INVM};
+
+    #[test]
+    pub fn inverse_multiplier() {
+        assert_eq!((MULT * INVM).0, 1);
+    }
+
+    #[test]
+    pub fn empty() {
+        assert_eq!(RabinKarp::new().digest(), 1);
+        assert_eq!(weak_sum(&[]), 1);
+    }
+
+    /// Results checked against C librsync's rabinkarp.h.
+    #[test]
+    pub fn matches_librsync() {
+        let buf: Vec<u8> = (0..300usize).map(|i| (255 - (i % 7) * 3) as u8).collect();
+        assert_eq!(weak_sum(&buf), 0xe208032e);
+
+        let mut rolled = RabinKarp::new();
+        for &c in &buf {
+            rolled.roll_in(c);
+        }
+        assert_eq!(rolled.digest(), 0xe208032e);
+
+        let mut rk = RabinKarp::new();
+        rk.update(&buf);
+        for (i, &c) in buf.iter().enumerate() {
+            rk.rotate(c, (200 + i) as u8);
+        }
+        assert_eq!(rk.digest(), 0x374f0d03);
+        for i in 0..100 {
+            rk.roll_out((200 + i) as u8);
+        }
+        assert_eq!(rk.digest(), 0xc0eaf97d);
+    }
+
+    /// Rolling over a window gives the same result as hashing it directly.
+    #[test]
+    pub fn rotate_equals_update() {
+        let buf: Vec<u8> = (0..100u32).map(|i| (i * 37 % 256) as u8).collect();
+        let mut rk = RabinKarp::new();
+        rk.update(&buf[..16]);
+        for i in 0..(buf.len() - 16) {
+            rk.rotate(buf[i], buf[i + 16]);
+            assert_eq!(rk.digest(), weak_sum(&buf[i + 1..i + 17]));
+        }
+    }
+}
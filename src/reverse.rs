@@ -0,0 +1,175 @@
+// rdiff(rust) -- library for network deltas
+// Copyright 2018 Martin Pool.
+
+//! Reverse deltas, which turn the new file back into the old.
+//!
+//! A backup tool can keep only the newest version of a file whole, and store a reverse
+//! delta for each older version. The COPY commands of a forward delta show which parts of
+//! the old file are also in the new, and where: the reverse delta copies those parts back
+//! from the new file, and carries the rest of the old file as literal data.
+
+use std::io;
+use std::io::{BufReader, BufWriter, Read, Write};
+
+use super::basis::{copy_range, BasisProvider};
+use super::delta::{check_copy, CommandHeader, DeltaCommand, DeltaReader, DeltaWriter};
+use super::error::Result;
+use super::search::DEFAULT_MAX_LITERAL_LEN;
+use super::stats::{Statistics, Timer};
+
+/// Generate a delta from the new file back to `basis`, given the `delta` from `basis` to
+/// the new file.
+///
+/// The new file isn't needed. The forward delta is read once, and then the parts of the
+/// basis that aren't in the new file are read to go into the reverse delta as literals.
+///
+/// `Error::CorruptDelta` is returned if the forward delta copies from beyond the end of
+/// the basis. The statistics count the commands written to the reverse delta.
+pub fn reverse_delta<B: BasisProvider + ?Sized, D: Read + ?Sized, W: Write + ?Sized>(
+    basis: &mut B, delta: &mut D, reverse: &mut W) -> Result<Statistics> {
+    reverse_with(basis, delta, None, reverse)
+}
+
+/// Apply a delta to `basis`, writing the new file to `out`, and at the same time generate
+/// the reverse delta from the new file back to the basis.
+///
+/// This is what a backup tool keeping reverse deltas does with each new version: the delta
+/// is read only once.
+pub fn apply_patch_with_reverse<B, D, W, V>(basis: &mut B, delta: &mut D, out: &mut W,
+                                           reverse: &mut V) -> Result<Statistics>
+    where B: BasisProvider + ?Sized, D: Read + ?Sized, W: Write + ?Sized, V: Write + ?Sized {
+    reverse_with(basis, delta, Some(&mut BufWriter::new(out)), reverse)
+}
+
+fn reverse_with<B: BasisProvider + ?Sized, D: Read + ?Sized, W: Write + ?Sized>(
+    basis: &mut B, delta: &mut D, mut out: Option<&mut dyn Write>, reverse: &mut W)
+    -> Result<Statistics> {
+    let start = Timer::start();
+    let basis_len = basis.size()?;
+    let mut buf = Vec::new();
+    let mut commands = DeltaReader::new(BufReader::new(delta))?;
+    // The range of the basis copied by each COPY, and where it went in the new file.
+    let mut copies: Vec<(u64, u64, u64)> = Vec::new();
+    let mut new_pos = 0;
+    loop {
+        match commands.read_header()? {
+            CommandHeader::Literal { len } => {
+                match out.as_mut() {
+                    Some(out) => commands.copy_literal(len, out)?,
+                    None => commands.copy_literal(len, &mut io::sink())?,
+                }
+                new_pos += len;
+            }
+            CommandHeader::Copy { offset, len } => {
+                check_copy(offset, len, basis_len)?;
+                if let Some(out) = out.as_mut() {
+                    copy_range(basis, offset, len, &mut buf, out)?;
+                }
+                if len > 0 {
+                    copies.push((offset, offset + len, new_pos));
+                }
+                new_pos += len;
+            }
+            CommandHeader::End => break,
+        }
+    }
+    if let Some(out) = out.as_mut() {
+        out.flush()?;
+    }
+
+    // Walk through the basis in order, copying each part from the first place it's found
+    // in the new file, and sending the gaps as literals.
+    copies.sort_unstable();
+    let mut w = DeltaWriter::new(BufWriter::new(reverse))?;
+    let mut pos = 0;
+    for (old_start, old_end, new_start) in copies {
+        if old_start > pos {
+            literal_from_basis(basis, pos, old_start, &mut buf, &mut w)?;
+            pos = old_start;
+        }
+        if old_end > pos {
+            w.copy(new_start + (pos - old_start), old_end - pos)?;
+            pos = old_end;
+        }
+    }
+    literal_from_basis(basis, pos, basis_len, &mut buf, &mut w)?;
+    w.write_command(&DeltaCommand::End)?;
+    Ok(Statistics {
+        op: "reverse",
+        in_bytes: commands.statistics().in_bytes,
+        elapsed: start.elapsed(),
+        .. w.statistics().clone()
+    })
+}
+
+/// Write the basis from `from` to `to` as literals, in pieces of bounded size.
+fn literal_from_basis<B: BasisProvider + ?Sized, W: Write>(basis: &mut B, mut from: u64, to: u64,
+                                                           buf: &mut Vec<u8>,
+                                                           w: &mut DeltaWriter<W>)
+    -> Result<()> {
+    while from < to {
+        let l = (to - from).min(DEFAULT_MAX_LITERAL_LEN as u64) as usize;
+        buf.resize(l, 0);
+        basis.read_at(from, buf)?;
+        w.literal(buf)?;
+        from += l as u64;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use std::io::Cursor;
+
+    use super::*;
+    use super::super::error::Error;
+    use super::super::memory;
+    use super::super::test_util::pattern_seeded;
+
+    fn check_reverse(old: &[u8], new: &[u8]) -> Vec<u8> {
+        let delta = memory::delta_of(&memory::signature_of(old), new).unwrap();
+        let mut reverse = Vec::new();
+        reverse_delta(&mut Cursor::new(old), &mut &delta[..], &mut reverse).unwrap();
+        assert_eq!(memory::apply(new, &reverse).unwrap(), old);
+
+        let mut patched = Vec::new();
+        let mut reverse2 = Vec::new();
+        apply_patch_with_reverse(&mut Cursor::new(old), &mut &delta[..], &mut patched,
+                                 &mut reverse2).unwrap();
+        assert_eq!(patched, new);
+        assert_eq!(reverse2, reverse);
+        reverse
+    }
+
+    #[test]
+    pub fn reverse_edits() {
+        let old = pattern_seeded(200_000, 0);
+        let mut new = old[..50_000].to_vec();
+        new.extend(pattern_seeded(7000, 5));
+        new.extend_from_slice(&old[90_000..150_000]);
+        new.extend_from_slice(&old[60_000..120_000]);
+        let reverse = check_reverse(&old, &new);
+        // Only what was deleted, and the ends of blocks around it, is carried.
+        assert!(reverse.len() < 100_000, "{}", reverse.len());
+    }
+
+    #[test]
+    pub fn reverse_trivial() {
+        check_reverse(b"", b"");
+        check_reverse(b"", b"hello");
+        check_reverse(b"hello", b"");
+        let old = pattern_seeded(10_000, 0);
+        let reverse = check_reverse(&old, &old);
+        assert!(reverse.len() < 20, "{}", reverse.len());
+    }
+
+    #[test]
+    pub fn copy_beyond_basis() {
+        let mut w = DeltaWriter::new(Vec::new()).unwrap();
+        w.copy(10, 20).unwrap();
+        let delta = w.finish().unwrap();
+        let err = reverse_delta(&mut Cursor::new(vec![0; 25]), &mut &delta[..],
+                                &mut Vec::new()).unwrap_err();
+        assert!(matches!(err, Error::CorruptDelta(_)), "{:?}", err);
+    }
+}
@@ -6,14 +6,38 @@
 
 #![allow(dead_code)]
 
-use std::num::Wrapping;
+use core::num::Wrapping;
 
-/// Generic rollsum algorithm trait.
+use super::simd::rollsum_sums;
+
+/// Offset added to every byte before it's summed, as in librsync's `ROLLSUM_CHAR_OFFSET`.
+///
+/// Without it, runs of zero bytes of different lengths would all have the same sum.
+pub const RS_CHAR_OFFSET: u16 = 31;
+
+/// Calculate the classic rollsum weak checksum of a whole block in one go.
 ///
-/// Rollsums hold a checksum across a contiguous range of bytes, which can roll
+/// This is the value stored for each block in a signature.
+pub fn weak_sum(buf: &[u8]) -> u32 {
+    block_sum::<Rollsum1>(buf)
+}
+
+/// Calculate the digest of a whole block using rolling hash `R`.
+pub fn block_sum<R: RollingHash + Default>(buf: &[u8]) -> u32 {
+    let mut r = R::default();
+    r.update(buf);
+    r.digest()
+}
+
+/// Generic rolling hash trait.
+///
+/// Rolling hashes hold a checksum across a contiguous range of bytes, which can roll
 /// forward through a file, adding a new byte to the right hand side and, separately
 /// removing one from the end.
-pub trait Rollsum {
+///
+/// The classic rollsum and RabinKarp are implemented here, but delta generation is
+/// generic over this trait, so other hashes can be tried too.
+pub trait RollingHash {
     /// Return consolidated u32 rolling sum digest.
     fn digest(&self) -> u32;
 
@@ -43,14 +67,14 @@ pub struct Rollsum1 {
 }
 
 impl Rollsum1 {
-    const CHAR_OFFSET: Wrapping<u16> = Wrapping(31);
+    const CHAR_OFFSET: Wrapping<u16> = Wrapping(RS_CHAR_OFFSET);
 
     pub fn new() -> Rollsum1 {
         Rollsum1::default()
     }
 }
 
-impl Rollsum for Rollsum1 {
+impl RollingHash for Rollsum1 {
     fn digest(&self) -> u32 {
         (self.s2.0 as u32) << 16 | (self.s1.0 as u32)
     }
@@ -76,14 +100,13 @@ impl Rollsum for Rollsum1 {
     }
 
     fn update(&mut self, buf: &[u8]) {
-        let mut s1 = self.s1;
-        let mut s2 = self.s2;
-        for c in buf {
-            s1 += Wrapping(*c as u16);
-            s2 += s1;
-        }
-        let len = buf.len() as u32;
+        let (sum, weighted) = rollsum_sums(buf);
         let ll = Wrapping(buf.len() as u16);
+        let mut s2 = self.s2 + ll * self.s1 + Wrapping(weighted as u16);
+        let mut s1 = self.s1 + Wrapping(sum as u16);
+        // Only the low 16 bits matter, but the triangular number must be computed
+        // exactly before it's truncated, and it overflows u32 for blocks >64kB.
+        let len = buf.len() as u64;
         let trilen = Wrapping(((len * (len + 1)) / 2) as u16);
         // Now add the corresponding char offsets.
         s1 += ll * Rollsum1::CHAR_OFFSET;
@@ -98,7 +121,7 @@ impl Rollsum for Rollsum1 {
 
 #[cfg(test)]
 mod test {
-    use super::{Rollsum, Rollsum1};
+    use super::{RollingHash, Rollsum1, weak_sum};
 
     #[test]
     pub fn default_value() {
@@ -111,7 +134,6 @@ mod test {
 
     #[test]
     pub fn rollsum() {
-        // TODO: Check behavior on high u8 inputs, and check against C librsync.
         let mut rs = Rollsum1::new();
         rs.roll_in(0u8);
         assert_eq!(rs.count.0, 1);
@@ -154,10 +176,43 @@ mod test {
     pub fn update() {
         let mut rs = Rollsum1::new();
         let mut buf = [0u8; 256];
-        for i in 0..buf.len() {
-            buf[i] = i as u8;
+        for (i, b) in buf.iter_mut().enumerate() {
+            *b = i as u8;
         }
         rs.update(&buf);
         assert_eq!(rs.digest(), 0x3a009e80);
     }
-}
\ No newline at end of file
+
+    #[test]
+    pub fn weak_sum_matches_update() {
+        let buf = b"Hello world\n";
+        let mut rs = Rollsum1::new();
+        rs.update(buf);
+        assert_eq!(weak_sum(buf), rs.digest());
+        assert_eq!(weak_sum(&[]), 0);
+    }
+
+    /// Results for high byte values, checked against C librsync's rollsum.c.
+    #[test]
+    pub fn high_bytes_match_librsync() {
+        let buf: Vec<u8> = (0..300usize).map(|i| (255 - (i % 7) * 3) as u8).collect();
+        assert_eq!(weak_sum(&buf), 0xe3d244a5);
+
+        let mut rolled = Rollsum1::new();
+        for &c in &buf {
+            rolled.roll_in(c);
+        }
+        assert_eq!(rolled.digest(), 0xe3d244a5);
+
+        let mut rs = Rollsum1::new();
+        rs.update(&buf);
+        for (i, &c) in buf.iter().enumerate() {
+            rs.rotate(c, (200 + i) as u8);
+        }
+        assert_eq!(rs.digest(), 0x0cc0c9e6);
+        for i in 0..100 {
+            rs.roll_out((200 + i) as u8);
+        }
+        assert_eq!(rs.digest(), 0x58e08854);
+    }
+}
@@ -0,0 +1,352 @@
+// rdiff(rust) -- library for network deltas
+// Copyright 2018 Martin Pool.
+
+//! The search through a new file for blocks matching a signature, shared by the
+//! functions in `mkdelta` and by delta `Job`s.
+
+use core::cmp::min;
+
+use alloc::vec::Vec;
+
+use super::cdc::Chunker;
+use super::compat::Write;
+use super::delta::DeltaWriter;
+use super::error::Result;
+use super::index::{Lookup, SignatureIndex};
+use super::rollsum::{block_sum, RollingHash};
+use super::strongsum::StrongHash;
+
+/// Longest literal sent by default, 32kB as in librsync.
+pub(crate) const DEFAULT_MAX_LITERAL_LEN: usize = 32 << 10;
+
+/// Data before the current window is discarded from the search buffer once this much has
+/// accumulated.
+const DRAIN_LEN: usize = 64 << 10;
+
+/// How the blocks of a signature are divided among the signatures it was joined from.
+#[derive(Debug, Copy, Clone)]
+#[cfg_attr(not(feature = "std"), allow(dead_code))]
+pub(crate) enum Parts<'a> {
+    /// It wasn't joined: it's the signature of one basis.
+    Whole,
+    /// It's of several bases, and the first block of each is at these indexes.
+    Bases(&'a [usize]),
+    /// It's of one basis at several block lengths, and the first block of each level is
+    /// at these indexes.
+    Levels(&'a [usize]),
+}
+
+/// The state of a search through the new file, which is fed in a piece at a time.
+pub(crate) struct Search<'i, 's: 'i, R> {
+    index: &'i SignatureIndex<'s>,
+    /// For a joined signature, where each part's blocks start.
+    parts: Parts<'i>,
+    block_len: usize,
+    /// For a content-defined signature, finds the chunks of the new file to look up,
+    /// rather than rolling a window across it.
+    chunker: Option<Chunker>,
+    /// For other signatures whose blocks vary in length, each different length, longest
+    /// first, and a window of each length is rolled across the new file.
+    window_lens: Vec<usize>,
+    /// The rolling sum of the window of each length in `window_lens`, if calculated.
+    window_sums: Vec<Option<R>>,
+    max_literal_len: usize,
+    /// Data from the new file that's not yet been emitted, starting at `lit_start`.
+    ///
+    /// New data is appended by the caller.
+    pub(crate) buf: Vec<u8>,
+    /// Start of data not yet covered by any command.
+    lit_start: usize,
+    /// Start of the window we're currently trying to match.
+    pos: usize,
+    /// Rolling sum of the window, if it's been calculated.
+    sum: Option<R>,
+    /// The block matched by the data just before `pos`, if any.
+    prev: Option<usize>,
+    /// Number of windows whose weak sum matched a block but whose strong sum didn't.
+    pub(crate) false_matches: u64,
+    /// Number of windows looked up in the index, and how many matched some weak sum;
+    /// only reported through tracing.
+    #[cfg_attr(not(feature = "tracing"), allow(dead_code))]
+    pub(crate) windows: u64,
+    #[cfg_attr(not(feature = "tracing"), allow(dead_code))]
+    pub(crate) weak_hits: u64,
+}
+
+impl<'i, 's, R: RollingHash + Default> Search<'i, 's, R> {
+    pub(crate) fn new(index: &'i SignatureIndex<'s>, max_literal_len: usize)
+        -> Search<'i, 's, R> {
+        let sig = index.signature();
+        let mut window_lens: Vec<usize> = match sig.block_lens() {
+            Some(lens) if !sig.format().is_content_defined() => {
+                lens.iter().map(|&l| l as usize).collect()
+            }
+            _ => Vec::new(),
+        };
+        window_lens.sort_unstable_by(|a, b| b.cmp(a));
+        window_lens.dedup();
+        Search {
+            index,
+            parts: Parts::Whole,
+            block_len: sig.block_len() as usize,
+            chunker: if sig.format().is_content_defined() {
+                Some(Chunker::new(sig.block_len()))
+            } else {
+                None
+            },
+            window_sums: window_lens.iter().map(|_| None).collect(),
+            window_lens,
+            max_literal_len,
+            buf: Vec::new(),
+            lit_start: 0,
+            pos: 0,
+            sum: None,
+            prev: None,
+            false_matches: 0,
+            windows: 0,
+            weak_hits: 0,
+        }
+    }
+
+    /// Search a signature made by joining others as described by `parts`, writing COPYs
+    /// from each basis.
+    #[cfg(feature = "std")]
+    pub(crate) fn with_parts(self, parts: Parts<'i>) -> Search<'i, 's, R> {
+        Search { parts, .. self }
+    }
+
+    /// Carry on a search that had got `pos` bytes into `buf`, which holds the data not
+    /// yet covered by any command, as returned by `pending()`.
+    #[cfg(feature = "std")]
+    pub(crate) fn resumed(self, buf: Vec<u8>, pos: usize) -> Search<'i, 's, R> {
+        Search { buf, lit_start: 0, pos, sum: None, prev: None, .. self }
+    }
+
+    /// The data not yet covered by any command, and how far into it the search has got.
+    #[cfg(feature = "std")]
+    pub(crate) fn pending(&self) -> (&[u8], usize) {
+        (&self.buf[self.lit_start..], self.pos - self.lit_start)
+    }
+
+    /// Write commands for as much of `buf` as can be matched so far.
+    ///
+    /// If `eof` is true, `buf` holds the rest of the new file, and commands are written
+    /// for all of it.
+    pub(crate) fn process<W: Write>(&mut self, hash: &mut dyn StrongHash, eof: bool,
+                                    out: &mut DeltaWriter<W>) -> Result<()> {
+        if let Some(chunker) = self.chunker {
+            return self.process_chunks(&chunker, hash, eof, out);
+        }
+        if !self.window_lens.is_empty() {
+            return self.process_windows(hash, eof, out);
+        }
+        let block_len = self.block_len;
+        loop {
+            // Wait until we have a whole window, and the byte after it to roll in.
+            if !eof && self.buf.len() <= self.pos + block_len {
+                break;
+            }
+            let pos = self.pos;
+            let avail = self.buf.len() - pos;
+            if avail == 0 {
+                break;
+            }
+            let window_len = min(block_len, avail);
+            let window = &self.buf[pos..(pos + window_len)];
+            let weak = self.sum.get_or_insert_with(|| {
+                let mut r = R::default();
+                r.update(window);
+                r
+            });
+            let lookup = self.index.lookup_after(self.prev, weak.digest(), window, hash);
+            self.windows += 1;
+            if lookup != Lookup::Miss {
+                self.weak_hits += 1;
+            }
+            if lookup == Lookup::FalseMatch {
+                trace_event!(pos, "false weak match");
+                self.false_matches += 1;
+            }
+            if let Lookup::Match(block) = lookup {
+                trace_event!(pos, block, "matched block");
+                out.literal(&self.buf[self.lit_start..pos])?;
+                self.copy_block(block, window_len, out)?;
+                self.pos += window_len;
+                self.lit_start = self.pos;
+                self.sum = None;
+                self.prev = Some(block);
+            } else {
+                self.prev = None;
+                if avail > block_len {
+                    weak.rotate(self.buf[pos], self.buf[pos + block_len]);
+                } else {
+                    // Near the end of the file, the window shrinks until it's empty.
+                    weak.roll_out(self.buf[pos]);
+                }
+                self.pos += 1;
+                if self.pos - self.lit_start >= self.max_literal_len {
+                    out.literal(&self.buf[self.lit_start..self.pos])?;
+                    self.lit_start = self.pos;
+                }
+            }
+            self.drain();
+        }
+        if eof {
+            out.literal(&self.buf[self.lit_start..self.pos])?;
+            self.lit_start = self.pos;
+        }
+        Ok(())
+    }
+
+    /// Like `process`, but for a content-defined signature: divide the new file into
+    /// chunks the same way as the basis, and look up each whole chunk.
+    fn process_chunks<W: Write>(&mut self, chunker: &Chunker, hash: &mut dyn StrongHash,
+                                eof: bool, out: &mut DeltaWriter<W>) -> Result<()> {
+        loop {
+            let pos = self.pos;
+            let len = match chunker.next_chunk(&self.buf[pos..], eof) {
+                Some(0) | None => break,
+                Some(len) => len,
+            };
+            let chunk = &self.buf[pos..(pos + len)];
+            let lens = self.index.signature().block_lens().unwrap_or(&[]);
+            let lookup = match self.index.lookup_after(self.prev, block_sum::<R>(chunk), chunk,
+                                                       hash) {
+                // A truncated strong sum might match a chunk of another length.
+                Lookup::Match(block) if lens[block] as usize != len => Lookup::FalseMatch,
+                lookup => lookup,
+            };
+            self.windows += 1;
+            if lookup != Lookup::Miss {
+                self.weak_hits += 1;
+            }
+            if lookup == Lookup::FalseMatch {
+                trace_event!(pos, "false weak match");
+                self.false_matches += 1;
+            }
+            if let Lookup::Match(block) = lookup {
+                trace_event!(pos, block, "matched chunk");
+                out.literal(&self.buf[self.lit_start..pos])?;
+                self.copy_block(block, len, out)?;
+                self.lit_start = pos + len;
+                self.prev = Some(block);
+            } else {
+                self.prev = None;
+                while pos + len - self.lit_start >= self.max_literal_len {
+                    let end = self.lit_start + self.max_literal_len;
+                    out.literal(&self.buf[self.lit_start..end])?;
+                    self.lit_start = end;
+                }
+            }
+            self.pos = pos + len;
+            self.drain();
+        }
+        if eof {
+            out.literal(&self.buf[self.lit_start..self.pos])?;
+            self.lit_start = self.pos;
+        }
+        Ok(())
+    }
+
+    /// Like `process`, but for a signature whose blocks vary in length: roll a window of
+    /// each length, and at each position try the longest first.
+    fn process_windows<W: Write>(&mut self, hash: &mut dyn StrongHash, eof: bool,
+                                 out: &mut DeltaWriter<W>) -> Result<()> {
+        let index = self.index;
+        let lens = index.signature().block_lens().unwrap_or(&[]);
+        let longest = self.window_lens[0];
+        loop {
+            if !eof && self.buf.len() <= self.pos + longest {
+                break;
+            }
+            let pos = self.pos;
+            let avail = self.buf.len() - pos;
+            if avail == 0 {
+                break;
+            }
+            let mut matched = None;
+            for (&len, sum) in self.window_lens.iter().zip(self.window_sums.iter_mut()) {
+                // Only near the end of the file is a window too long.
+                if len > avail {
+                    continue;
+                }
+                let window = &self.buf[pos..(pos + len)];
+                let weak = sum.get_or_insert_with(|| {
+                    let mut r = R::default();
+                    r.update(window);
+                    r
+                });
+                let lookup = match index.lookup_after(self.prev, weak.digest(), window, hash) {
+                    Lookup::Match(block) if lens[block] as usize != len => Lookup::FalseMatch,
+                    lookup => lookup,
+                };
+                self.windows += 1;
+                if lookup != Lookup::Miss {
+                    self.weak_hits += 1;
+                }
+                if lookup == Lookup::FalseMatch {
+                    trace_event!(pos, len, "false weak match");
+                    self.false_matches += 1;
+                }
+                if let Lookup::Match(block) = lookup {
+                    matched = Some((block, len));
+                    break;
+                }
+            }
+            if let Some((block, len)) = matched {
+                trace_event!(pos, block, "matched block");
+                out.literal(&self.buf[self.lit_start..pos])?;
+                self.copy_block(block, len, out)?;
+                self.pos += len;
+                self.lit_start = self.pos;
+                self.window_sums.iter_mut().for_each(|s| *s = None);
+                self.prev = Some(block);
+            } else {
+                self.prev = None;
+                for (&len, sum) in self.window_lens.iter().zip(self.window_sums.iter_mut()) {
+                    if pos + len < self.buf.len() {
+                        if let Some(weak) = sum {
+                            weak.rotate(self.buf[pos], self.buf[pos + len]);
+                        }
+                    } else {
+                        *sum = None;
+                    }
+                }
+                self.pos += 1;
+                if self.pos - self.lit_start >= self.max_literal_len {
+                    out.literal(&self.buf[self.lit_start..self.pos])?;
+                    self.lit_start = self.pos;
+                }
+            }
+            self.drain();
+        }
+        if eof {
+            out.literal(&self.buf[self.lit_start..self.pos])?;
+            self.lit_start = self.pos;
+        }
+        Ok(())
+    }
+
+    /// Write a COPY of `len` bytes of `block`, from whichever basis it's in.
+    fn copy_block<W: Write>(&self, block: usize, len: usize, out: &mut DeltaWriter<W>)
+        -> Result<()> {
+        let (starts, several_bases): (&[usize], bool) = match self.parts {
+            Parts::Whole => (&[], false),
+            Parts::Bases(starts) => (starts, true),
+            Parts::Levels(starts) => (starts, false),
+        };
+        let part = starts.partition_point(|&s| s <= block).max(1) - 1;
+        let start = starts.get(part).map_or(0, |&s| self.index.block_offset(s));
+        let basis = if several_bases { part as u64 } else { 0 };
+        out.copy_from(basis, self.index.block_offset(block) - start, len as u64)
+    }
+
+    /// Discard data that's already been emitted.
+    fn drain(&mut self) {
+        if self.lit_start >= DRAIN_LEN {
+            self.buf.drain(..self.lit_start);
+            self.pos -= self.lit_start;
+            self.lit_start = 0;
+        }
+    }
+}
@@ -0,0 +1,1111 @@
+// rdiff(rust) -- library for network deltas
+// Copyright 2018 Martin Pool.
+
+//! Signatures held in memory, and the options for making them.
+//!
+//! A signature describes each block of a basis file by a weak and a strong checksum, and
+//! is the input to delta generation.
+
+#[cfg(feature = "std")]
+use std::io;
+#[cfg(feature = "std")]
+use std::io::{BufReader, ErrorKind, Read};
+
+use alloc::borrow::ToOwned;
+#[cfg(feature = "serde")]
+use core::convert::TryFrom;
+#[cfg(feature = "std")]
+use core::ops::Range;
+use alloc::format;
+use alloc::string::String;
+use alloc::vec::Vec;
+#[cfg(feature = "std")]
+use byteorder::{BigEndian, ReadBytesExt};
+
+use super::cdc::{MAX_AVG_LEN, MIN_AVG_LEN};
+#[cfg(feature = "std")]
+use super::compress::decompressed;
+use super::error::{Error, Result};
+#[cfg(feature = "std")]
+use super::io_options::IoOptions;
+#[cfg(feature = "std")]
+use super::magic::{SIGNATURE_SEEDED_MAGIC, SIGNATURE_V2_MAGIC};
+use super::magic::SignatureFormat;
+use super::strongsum::{strong_sum, StrongHash};
+
+// Must match that in rdiff.
+pub(crate) const RS_MAX_STRONG_SUM_LENGTH: usize = 32;
+
+/// Configuration options for a generated signature file.
+///
+/// The values from `SignatureOptions::default()` are usually good, but applications
+/// might want to set the `block_len`. `SignatureOptions::new()` starts a builder that
+/// checks the values are sensible.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct SignatureOptions {
+    /// Format of the signature, identified by its magic number.
+    pub magic: SignatureFormat,
+
+    /// Length of a block in bytes.
+    ///
+    /// Smaller blocks produce larger signatures because there are more blocks, but allow matching
+    /// smaller common regions between files.
+    ///
+    /// For a content-defined format, this is the average length of a chunk, and must be
+    /// at least 64.
+    pub block_len: u32,
+
+    /// Length of strong signatures.
+    ///
+    /// This is normally best left at the default, which is the strong hash, but
+    /// they may be truncated to get smaller signatures although with a risk of exploitable
+    /// collisions.
+    ///
+    /// As in librsync, zero means the whole length of the strong hash, whichever it is.
+    pub strong_len: u32,
+
+    /// A seed mixed into the weak and strong sums of every block, or zero for none.
+    ///
+    /// The seed is hashed before each block's data for its strong sum, so someone who can
+    /// choose the contents of files, but doesn't know the seed, can't make blocks whose
+    /// truncated strong sums collide. As in rsync, a new random seed can be used each
+    /// time. A seeded signature is written with `magic::SIGNATURE_SEEDED_MAGIC`, which
+    /// librsync can't read.
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub seed: u32,
+}
+
+impl Default for SignatureOptions {
+    fn default() -> SignatureOptions {
+        SignatureOptions {
+            magic: SignatureFormat::Blake2Sig,
+            block_len: super::DEFAULT_BLOCK_LEN,
+            strong_len: RS_MAX_STRONG_SUM_LENGTH as u32,
+            seed: 0,
+        }
+    }
+}
+
+impl SignatureOptions {
+    /// Start building options, from the defaults.
+    #[allow(clippy::new_ret_no_self)]
+    pub fn new() -> SignatureOptionsBuilder {
+        SignatureOptionsBuilder::default()
+    }
+
+    pub fn with_strong_len(self, s: u32) -> SignatureOptions {
+        SignatureOptions {
+            strong_len: s,
+            .. self
+        }
+    }
+
+    /// Default options, but with a block length suited to a basis of `len` bytes.
+    ///
+    /// The block length is roughly the square root of the length, so that both the number
+    /// of blocks and the data each one covers grow slowly: it's 2 to the power of half the
+    /// base-2 logarithm of `len`, each rounded down. So a 1 MB or a 2 MB file has 1 KB
+    /// blocks, and a 1 TB file 1 MB blocks. It is at least `MIN_SIZED_BLOCK_LEN`, and at most
+    /// `MAX_BLOCK_LEN`.
+    pub fn for_file_size(len: u64) -> SignatureOptions {
+        let log2 = 63u32.saturating_sub(len.leading_zeros());
+        SignatureOptions {
+            block_len: (1u32 << (log2 / 2)).clamp(MIN_SIZED_BLOCK_LEN, MAX_BLOCK_LEN),
+            .. SignatureOptions::default()
+        }
+    }
+}
+
+/// The shortest block length chosen by `SignatureOptions::for_file_size`, for files of up
+/// to 64 KB.
+pub const MIN_SIZED_BLOCK_LEN: u32 = 256;
+
+/// The longest block length allowed in options or a signature header.
+///
+/// Making a delta holds a block of the new file in memory at a time, so this bounds what
+/// a crafted signature can make it buffer and hash.
+pub const MAX_BLOCK_LEN: u32 = 1 << 24;
+
+/// Builds `SignatureOptions`, checking them before any IO happens.
+///
+/// ```
+/// use rdiff::signature::SignatureOptions;
+///
+/// let options = SignatureOptions::new().block_len(4096).strong_len(16).build().unwrap();
+/// assert_eq!(options.block_len, 4096);
+/// ```
+#[derive(Debug, Copy, Clone)]
+pub struct SignatureOptionsBuilder {
+    magic: SignatureFormat,
+    block_len: u32,
+    strong_len: Option<u32>,
+    seed: u32,
+}
+
+impl Default for SignatureOptionsBuilder {
+    fn default() -> SignatureOptionsBuilder {
+        let options = SignatureOptions::default();
+        SignatureOptionsBuilder {
+            magic: options.magic,
+            block_len: options.block_len,
+            strong_len: None,
+            seed: 0,
+        }
+    }
+}
+
+impl SignatureOptionsBuilder {
+    /// Set the signature format, and so the weak and strong hashes.
+    pub fn magic(self, magic: SignatureFormat) -> SignatureOptionsBuilder {
+        SignatureOptionsBuilder { magic, .. self }
+    }
+
+    /// Set the block length, which must not be zero or more than `MAX_BLOCK_LEN`.
+    pub fn block_len(self, block_len: u32) -> SignatureOptionsBuilder {
+        SignatureOptionsBuilder { block_len, .. self }
+    }
+
+    /// Truncate the strong sums to `strong_len` bytes, which must be no more than the
+    /// format's strong hash produces.
+    ///
+    /// If this isn't called, or `strong_len` is zero, the whole hash is kept.
+    pub fn strong_len(self, strong_len: u32) -> SignatureOptionsBuilder {
+        SignatureOptionsBuilder { strong_len: Some(strong_len), .. self }
+    }
+
+    /// Mix `seed` into the sums of every block.
+    pub fn seed(self, seed: u32) -> SignatureOptionsBuilder {
+        SignatureOptionsBuilder { seed, .. self }
+    }
+
+    /// Check the values and return the options, or `Error::InvalidOptions` for a bad
+    /// block length, `Error::StrongLenTooLong` for a bad strong sum length, and
+    /// `Error::UnsupportedFormat` for a format whose hash isn't built in.
+    pub fn build(self) -> Result<SignatureOptions> {
+        self.magic.check_built_in()?;
+        let max = self.magic.max_strong_len();
+        let strong_len = match self.strong_len {
+            None | Some(0) => max,
+            Some(l) => l,
+        };
+        if let Some(problem) = block_len_problem(self.magic, self.block_len) {
+            return Err(Error::InvalidOptions(problem));
+        }
+        if strong_len > max {
+            return Err(Error::StrongLenTooLong { strong_len, max });
+        }
+        Ok(SignatureOptions {
+            magic: self.magic,
+            block_len: self.block_len,
+            strong_len,
+            seed: self.seed,
+        })
+    }
+}
+
+/// Describe what's wrong with `block_len` for format `magic`, if anything.
+fn block_len_problem(magic: SignatureFormat, block_len: u32) -> Option<String> {
+    if block_len == 0 {
+        Some("block_len is zero".to_owned())
+    } else if !magic.is_content_defined() && block_len > MAX_BLOCK_LEN {
+        Some(format!("block_len {} is longer than {}", block_len, MAX_BLOCK_LEN))
+    } else if magic.is_content_defined() && !(MIN_AVG_LEN..=MAX_AVG_LEN).contains(&block_len) {
+        Some(format!("average chunk length {} is not between {} and {}",
+                     block_len, MIN_AVG_LEN, MAX_AVG_LEN))
+    } else {
+        None
+    }
+}
+
+/// Check that the options describe a signature that can be generated with `hash`, and
+/// return them with a `strong_len` of zero replaced by the length of `hash`.
+pub(crate) fn check_options(options: &SignatureOptions, hash: &dyn StrongHash)
+    -> Result<SignatureOptions> {
+    options.magic.check_built_in()?;
+    if let Some(problem) = block_len_problem(options.magic, options.block_len) {
+        return Err(Error::InvalidOptions(problem));
+    }
+    let max = hash.digest_len() as u32;
+    match options.strong_len {
+        0 => Ok(SignatureOptions { strong_len: max, .. *options }),
+        strong_len if strong_len > max => Err(Error::StrongLenTooLong { strong_len, max }),
+        _ => Ok(*options),
+    }
+}
+
+/// Mix `seed` into the weak sum of a block, unless it's zero.
+pub(crate) fn seed_weak(weak: u32, seed: u32) -> u32 {
+    weak ^ seed
+}
+
+/// Calculate the strong sum of a block, truncated to `out.len()`, hashing `seed` before
+/// the data unless it's zero.
+pub(crate) fn seeded_strong_sum(hash: &mut dyn StrongHash, seed: u32, buf: &[u8],
+                                out: &mut [u8]) {
+    if seed != 0 {
+        hash.update(&seed.to_be_bytes());
+    }
+    strong_sum(hash, buf, out);
+}
+
+/// Check the header values of a signature that's been read in.
+pub(crate) fn check_header(magic: SignatureFormat, block_len: u32, strong_len: u32) -> Result<()> {
+    if let Some(problem) = block_len_problem(magic, block_len) {
+        return Err(Error::CorruptSignature(problem));
+    }
+    if strong_len == 0 || strong_len > magic.max_strong_len() {
+        return Err(Error::CorruptSignature(format!(
+            "strong sum length {} is out of range", strong_len)));
+    }
+    Ok(())
+}
+
+/// Read the magic number and header of a signature file, returning its options and
+/// whether it's a v2 signature.
+#[cfg(feature = "std")]
+fn read_header(sig: &mut (impl Read + ?Sized)) -> Result<(SignatureOptions, bool)> {
+    let mut magic = sig.read_u32::<BigEndian>()?;
+    let mut seed = 0;
+    if magic == SIGNATURE_SEEDED_MAGIC {
+        seed = sig.read_u32::<BigEndian>()?;
+        magic = sig.read_u32::<BigEndian>()?;
+    }
+    let v2 = magic == SIGNATURE_V2_MAGIC;
+    if v2 {
+        magic = sig.read_u32::<BigEndian>()?;
+    }
+    let magic = SignatureFormat::check_magic(magic)?;
+    if v2 && magic.is_content_defined() {
+        return Err(Error::CorruptSignature(
+            "a v2 signature can't hold content-defined chunks".to_owned()));
+    }
+    let block_len = sig.read_u32::<BigEndian>()?;
+    let strong_len = sig.read_u32::<BigEndian>()?;
+    check_header(magic, block_len, strong_len)?;
+    Ok((SignatureOptions { magic, block_len, strong_len, seed }, v2))
+}
+
+/// Fill `entry` with the next block of a signature file, returning false if the file
+/// ends before it.
+#[cfg(feature = "std")]
+fn read_entry(sig: &mut (impl Read + ?Sized), entry: &mut [u8]) -> Result<bool> {
+    let mut l = 0;
+    while l < entry.len() {
+        match sig.read(&mut entry[l..])? {
+            0 => break,
+            n => l += n,
+        }
+    }
+    if l > 0 && l < entry.len() {
+        return Err(io::Error::new(ErrorKind::UnexpectedEof,
+                                  "signature ended in the middle of a block").into());
+    }
+    Ok(l > 0)
+}
+
+/// Decode the length of a block in a v2 signature, which must be no more than
+/// `max_len`.
+#[cfg(feature = "std")]
+fn check_entry_len(len: &[u8], max_len: u64) -> Result<u32> {
+    let len = (&len[..]).read_u32::<BigEndian>()?;
+    if len == 0 || u64::from(len) > max_len {
+        return Err(Error::CorruptSignature(format!("a block has length {}", len)));
+    }
+    Ok(len)
+}
+
+/// What a signature file holds, found by checking it without keeping its blocks.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct SignatureInfo {
+    /// Format of the signature.
+    pub format: SignatureFormat,
+
+    /// Block length from the header: for a content-defined format, the average.
+    pub block_len: u32,
+
+    /// Length of each strong sum.
+    pub strong_len: u32,
+
+    /// True if each block has its own length, as in a v2 signature file or a
+    /// content-defined format.
+    pub variable: bool,
+
+    /// The seed mixed into the sums, or zero for none.
+    pub seed: u32,
+
+    /// Number of blocks.
+    pub block_count: u64,
+}
+
+/// Check that a signature file is well formed, reading it through to its end without
+/// holding its blocks in memory or building an index.
+///
+/// This finds the same errors as `Signature::read_from`: `Error::BadMagic` or
+/// `Error::UnsupportedFormat` for the magic number, `Error::CorruptSignature` for
+/// nonsensical header values or block lengths, and an `Io` error of kind `UnexpectedEof`
+/// if the file ends in the middle of a block. A service can use this to screen a
+/// signature cheaply before accepting it.
+#[cfg(feature = "std")]
+pub fn verify_signature<R: Read + ?Sized>(sig: &mut R) -> Result<SignatureInfo> {
+    let sig = &mut BufReader::new(decompressed(sig)?);
+    let (options, v2) = read_header(sig)?;
+    let variable = v2 || options.magic.is_content_defined();
+    let len_bytes = if variable { 4 } else { 0 };
+    let max_len = max_block_len(options.magic, options.block_len);
+    let mut entry = vec![0u8; len_bytes + 4 + options.strong_len as usize];
+    let mut block_count = 0;
+    while read_entry(sig, &mut entry)? {
+        if variable {
+            check_entry_len(&entry[..4], max_len)?;
+        }
+        block_count += 1;
+    }
+    Ok(SignatureInfo {
+        format: options.magic,
+        block_len: options.block_len,
+        strong_len: options.strong_len,
+        variable,
+        seed: options.seed,
+        block_count,
+    })
+}
+
+/// A signature of a basis file, held in memory.
+///
+/// Usually every block but the last has the same length. In a signature of
+/// content-defined chunks, or one read from a v2 signature file, each block has its own
+/// length, given by `block_lens`.
+///
+/// With the `serde` feature, signatures can be serialized, for example to store them in
+/// JSON or CBOR metadata. Deserialized signatures are checked to be consistent, as they
+/// are when read from a signature file.
+///
+/// To store signatures without re-reading them from signature files, `to_bytes` and
+/// `from_bytes` convert them to and from a compact binary form: see `compact`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "serde", serde(try_from = "SignatureFields"))]
+pub struct Signature {
+    /// Format of the signature, which determines the weak and strong hashes.
+    pub(crate) magic: SignatureFormat,
+
+    /// Length of each block, except that the last may be shorter; or for a
+    /// content-defined format, the average length; or if the blocks vary in length,
+    /// the longest.
+    pub(crate) block_len: u32,
+
+    /// Length of each strong sum.
+    pub(crate) strong_len: u32,
+
+    /// Seed mixed into the sums, or zero for none.
+    #[cfg_attr(feature = "serde", serde(default, skip_serializing_if = "is_zero"))]
+    pub(crate) seed: u32,
+
+    /// True if each block has its own length, in `block_lens`: always the case for a
+    /// content-defined format.
+    #[cfg_attr(feature = "serde", serde(default, skip_serializing_if = "core::ops::Not::not"))]
+    pub(crate) variable: bool,
+
+    /// Length of each block, if they vary; otherwise empty.
+    #[cfg_attr(feature = "serde", serde(default, skip_serializing_if = "Vec::is_empty"))]
+    pub(crate) block_lens: Vec<u32>,
+
+    /// Weak sum for each block.
+    pub(crate) weak_sums: Vec<u32>,
+
+    /// Strong sums for all blocks, concatenated, each `strong_len` bytes.
+    #[cfg_attr(feature = "serde", serde(with = "serde_bytes"))]
+    pub(crate) strong_sums: Vec<u8>,
+}
+
+/// The fields of a deserialized `Signature`, before they're checked.
+#[cfg(feature = "serde")]
+#[derive(serde::Deserialize)]
+struct SignatureFields {
+    magic: SignatureFormat,
+    block_len: u32,
+    strong_len: u32,
+    #[serde(default)]
+    seed: u32,
+    #[serde(default)]
+    variable: bool,
+    #[serde(default)]
+    block_lens: Vec<u32>,
+    weak_sums: Vec<u32>,
+    #[serde(with = "serde_bytes")]
+    strong_sums: Vec<u8>,
+}
+
+#[cfg(feature = "serde")]
+impl TryFrom<SignatureFields> for Signature {
+    type Error = Error;
+
+    fn try_from(f: SignatureFields) -> Result<Signature> {
+        check_header(f.magic, f.block_len, f.strong_len)?;
+        if f.weak_sums.len() as u64 * u64::from(f.strong_len) != f.strong_sums.len() as u64 {
+            return Err(Error::CorruptSignature(format!(
+                "{} bytes of strong sums don't match {} blocks",
+                f.strong_sums.len(), f.weak_sums.len())));
+        }
+        let variable = f.variable || f.magic.is_content_defined();
+        let lens_expected = if variable { f.weak_sums.len() } else { 0 };
+        if f.block_lens.len() != lens_expected {
+            return Err(Error::CorruptSignature(format!(
+                "{} block lengths don't match {} blocks",
+                f.block_lens.len(), lens_expected)));
+        }
+        let max = max_block_len(f.magic, f.block_len);
+        if let Some(len) = f.block_lens.iter().find(|&&l| l == 0 || u64::from(l) > max) {
+            return Err(Error::CorruptSignature(format!("a block has length {}", len)));
+        }
+        Ok(Signature {
+            magic: f.magic,
+            block_len: f.block_len,
+            strong_len: f.strong_len,
+            seed: f.seed,
+            variable,
+            block_lens: f.block_lens,
+            weak_sums: f.weak_sums,
+            strong_sums: f.strong_sums,
+        })
+    }
+}
+
+#[cfg(feature = "serde")]
+fn is_zero(seed: &u32) -> bool {
+    *seed == 0
+}
+
+/// The longest a block can be in a signature whose blocks vary in length.
+pub(crate) fn max_block_len(magic: SignatureFormat, block_len: u32) -> u64 {
+    if magic.is_content_defined() {
+        u64::from(block_len) * 4
+    } else {
+        u64::from(block_len)
+    }
+}
+
+impl Signature {
+    /// Make a new signature containing no blocks.
+    pub fn new(options: &SignatureOptions) -> Signature {
+        Signature {
+            magic: options.magic,
+            block_len: options.block_len,
+            strong_len: options.strong_len,
+            seed: options.seed,
+            variable: options.magic.is_content_defined(),
+            block_lens: Vec::new(),
+            weak_sums: Vec::new(),
+            strong_sums: Vec::new(),
+        }
+    }
+
+    /// Make a new signature containing no blocks, to which blocks of any length up to
+    /// `options.block_len` can be added by `push_chunk`.
+    ///
+    /// Serialized, it's written in the v2 signature format, `magic::SIGNATURE_V2_MAGIC`.
+    pub fn new_variable(options: &SignatureOptions) -> Signature {
+        Signature { variable: true, .. Signature::new(options) }
+    }
+
+    /// Read a signature file into memory.
+    ///
+    /// The format, and so the weak and strong hashes, are chosen from the magic number
+    /// at the start of the file, so the caller doesn't need to know it in advance. Both
+    /// v1 signatures, as written by librsync, and v2 signatures with blocks of varying
+    /// length are read. The input is read through to its end without seeking, so it can
+    /// be a pipe. A signature compressed with zstd, as by
+    /// `mksum::generate_signature_compressed`, is decompressed as it's read.
+    ///
+    /// `Error::CorruptSignature` is returned for nonsensical header values or block
+    /// lengths, and an `Io` error of kind `UnexpectedEof` if the input ends in the middle
+    /// of the header or of a block. An unrecognized magic number gives
+    /// `Error::BadMagic`.
+    #[cfg(feature = "std")]
+    pub fn read_from<R: Read + ?Sized>(sig: &mut R) -> Result<Signature> {
+        Signature::read_from_with_io(sig, &IoOptions::default())
+    }
+
+    /// Read a signature file, with a read buffer of the size set by `io`.
+    #[cfg(feature = "std")]
+    pub fn read_from_with_io<R: Read + ?Sized>(sig: &mut R, io: &IoOptions)
+        -> Result<Signature> {
+        let sig = &mut BufReader::with_capacity(io.read_buf, decompressed(sig)?);
+        let (options, v2) = read_header(sig)?;
+        // Nothing is allocated from the header values: blocks are added only as they're
+        // read, so memory use is bounded by the length of the input.
+        let mut signature = if v2 {
+            Signature::new_variable(&options)
+        } else {
+            Signature::new(&options)
+        };
+        // Where blocks vary in length, each one's length comes before its weak sum.
+        let len_bytes = if signature.variable { 4 } else { 0 };
+        let max_len = max_block_len(options.magic, options.block_len);
+        let mut entry = vec![0u8; len_bytes + 4 + options.strong_len as usize];
+        while read_entry(sig, &mut entry)? {
+            let (len, sums) = entry.split_at(len_bytes);
+            let weak = (&sums[..4]).read_u32::<BigEndian>()?;
+            if len_bytes == 0 {
+                signature.push_block(weak, &sums[4..]);
+            } else {
+                let len = check_entry_len(len, max_len)?;
+                signature.push_chunk(len, weak, &sums[4..]);
+            }
+        }
+        Ok(signature)
+    }
+
+    /// Format of the signature, determining its weak and strong hashes.
+    pub fn format(&self) -> SignatureFormat {
+        self.magic
+    }
+
+    /// Length of the basis blocks; the last block may be shorter.
+    ///
+    /// For a content-defined format, this is the average length of a chunk, and for
+    /// other signatures whose blocks vary in length it's the longest. The real lengths
+    /// are given by `block_lens`.
+    pub fn block_len(&self) -> u32 {
+        self.block_len
+    }
+
+    /// Length of each block, if they vary in length.
+    pub fn block_lens(&self) -> Option<&[u32]> {
+        if self.variable { Some(&self.block_lens) } else { None }
+    }
+
+    /// Offset in the basis where block `i` starts.
+    ///
+    /// Where blocks vary in length this adds up the lengths of the blocks before it;
+    /// `SignatureIndex::block_offset` is quicker.
+    pub fn block_offset(&self, i: usize) -> u64 {
+        if self.variable {
+            self.block_lens[..i].iter().map(|&l| u64::from(l)).sum()
+        } else {
+            i as u64 * u64::from(self.block_len)
+        }
+    }
+
+    /// Length of the (possibly truncated) strong sums.
+    pub fn strong_len(&self) -> u32 {
+        self.strong_len
+    }
+
+    /// The seed mixed into the sums, or zero for none.
+    pub fn seed(&self) -> u32 {
+        self.seed
+    }
+
+    /// The options that describe the signature's format, block length, strong sum length
+    /// and seed.
+    pub fn options(&self) -> SignatureOptions {
+        SignatureOptions {
+            magic: self.magic,
+            block_len: self.block_len,
+            strong_len: self.strong_len,
+            seed: self.seed,
+        }
+    }
+
+    /// Number of blocks in the basis.
+    pub fn block_count(&self) -> usize {
+        self.weak_sums.len()
+    }
+
+    /// Return the weak sum for block `i`.
+    pub fn weak_sum(&self, i: usize) -> u32 {
+        self.weak_sums[i]
+    }
+
+    /// Add the sums for the next block of the basis.
+    ///
+    /// `strong` must be exactly `strong_len` bytes, and the blocks must not vary in
+    /// length.
+    pub fn push_block(&mut self, weak: u32, strong: &[u8]) {
+        assert!(!self.variable, "blocks of varying length need a length");
+        assert_eq!(strong.len(), self.strong_len as usize);
+        self.weak_sums.push(weak);
+        self.strong_sums.extend_from_slice(strong);
+    }
+
+    /// Add the length and sums for the next chunk of the basis, in a content-defined
+    /// format, or the next block of a signature made by `new_variable`.
+    ///
+    /// `len` must be between one and the longest length for the signature, and `strong`
+    /// must be exactly `strong_len` bytes.
+    pub fn push_chunk(&mut self, len: u32, weak: u32, strong: &[u8]) {
+        assert!(self.variable, "the blocks don't vary in length");
+        assert!(len > 0 && u64::from(len) <= max_block_len(self.magic, self.block_len));
+        assert_eq!(strong.len(), self.strong_len as usize);
+        self.block_lens.push(len);
+        self.weak_sums.push(weak);
+        self.strong_sums.extend_from_slice(strong);
+    }
+
+    /// Add the next block, with its length `len` if the blocks vary in length.
+    #[cfg(feature = "std")]
+    pub(crate) fn push_sums(&mut self, len: u32, weak: u32, strong: &[u8]) {
+        if self.variable {
+            self.push_chunk(len, weak, strong);
+        } else {
+            self.push_block(weak, strong);
+        }
+    }
+
+    /// Drop all but the first `blocks` blocks.
+    #[cfg(feature = "std")]
+    pub(crate) fn truncate(&mut self, blocks: usize) {
+        self.block_lens.truncate(blocks);
+        self.weak_sums.truncate(blocks);
+        self.strong_sums.truncate(blocks * self.strong_len as usize);
+    }
+
+    /// Replace the blocks in `blocks` with all those of `other`, which has the same options
+    /// and, like this, either does or doesn't have block lengths.
+    #[cfg(feature = "std")]
+    pub(crate) fn splice(&mut self, blocks: Range<usize>, other: Signature) {
+        let l = self.strong_len as usize;
+        if self.variable {
+            self.block_lens.splice(blocks.clone(), other.block_lens);
+        }
+        self.weak_sums.splice(blocks.clone(), other.weak_sums);
+        self.strong_sums.splice((blocks.start * l)..(blocks.end * l), other.strong_sums);
+    }
+
+    /// Return the strong sum for block `i`.
+    pub fn strong_sum(&self, i: usize) -> &[u8] {
+        let l = self.strong_len as usize;
+        &self.strong_sums[i * l..(i + 1) * l]
+    }
+
+    /// Iterate the blocks in order, as `(index, weak sum, strong sum)`.
+    pub fn blocks(&self) -> impl Iterator<Item = (usize, u32, &[u8])> + '_ {
+        (0..self.block_count()).map(move |i| (i, self.weak_sum(i), self.strong_sum(i)))
+    }
+
+    /// Indexes, in ascending order, of the blocks whose weak sum is `weak`.
+    ///
+    /// This scans the whole signature; to look up many sums, build a `SignatureIndex`.
+    pub fn lookup_weak(&self, weak: u32) -> Vec<usize> {
+        self.weak_sums.iter().enumerate()
+            .filter(|&(_, &w)| w == weak)
+            .map(|(i, _)| i)
+            .collect()
+    }
+
+    /// Add the blocks of `other`, the signature of `other_len` bytes, as if they followed
+    /// the `len` bytes this is the signature of, without reading either again.
+    ///
+    /// Delta generation then treats the parts as one basis, the two joined together,
+    /// with the blocks of `other` at offsets after `len`. The signatures must have the
+    /// same options. Content-defined chunks near the join may differ from those of the
+    /// joined data, which only makes deltas a little larger. If this signature's blocks
+    /// are of a fixed length and `len` isn't a multiple of it, the blocks are given
+    /// lengths, as by `new_variable`, so that the short block at the end of this part is
+    /// kept.
+    ///
+    /// `Error::InvalidOptions` is returned, and nothing is changed, if the options differ
+    /// or either length doesn't match its signature's blocks.
+    pub fn append(&mut self, len: u64, other: &Signature, other_len: u64) -> Result<()> {
+        if other.options() != self.options() {
+            return Err(Error::InvalidOptions(
+                "the signatures have different formats or block lengths".to_owned()));
+        }
+        self.check_len(len)?;
+        other.check_len(other_len)?;
+        let whole_blocks = len == self.block_count() as u64 * u64::from(self.block_len);
+        if !self.variable && !other.variable && whole_blocks {
+            self.weak_sums.extend_from_slice(&other.weak_sums);
+            self.strong_sums.extend_from_slice(&other.strong_sums);
+            return Ok(());
+        }
+        if !self.variable {
+            self.block_lens = self.lens_of(len).collect();
+            self.variable = true;
+        }
+        self.block_lens.extend(other.lens_of(other_len));
+        self.weak_sums.extend_from_slice(&other.weak_sums);
+        self.strong_sums.extend_from_slice(&other.strong_sums);
+        Ok(())
+    }
+
+    /// Join the signatures of several parts, each given with the length of its data, into
+    /// one signature of the parts one after the other, as `append` does.
+    ///
+    /// `Error::InvalidOptions` is returned if there are no parts.
+    pub fn concat(parts: &[(&Signature, u64)]) -> Result<Signature> {
+        let (&(first, mut len), rest) = parts.split_first().ok_or_else(|| {
+            Error::InvalidOptions("no signatures were given".to_owned())
+        })?;
+        let mut joined = first.clone();
+        joined.check_len(len)?;
+        for &(sig, sig_len) in rest {
+            joined.append(len, sig, sig_len)?;
+            len += sig_len;
+        }
+        Ok(joined)
+    }
+
+    /// Check that the blocks could be those of `len` bytes.
+    fn check_len(&self, len: u64) -> Result<()> {
+        let ok = if self.variable {
+            self.block_lens.iter().map(|&l| u64::from(l)).sum::<u64>() == len
+        } else {
+            len.div_ceil(u64::from(self.block_len)) == self.block_count() as u64
+        };
+        if ok {
+            Ok(())
+        } else {
+            Err(Error::InvalidOptions(format!(
+                "{} blocks of up to {} bytes can't be the signature of {} bytes",
+                self.block_count(), self.block_len, len)))
+        }
+    }
+
+    /// The length of each block, given the signature is of `len` bytes.
+    fn lens_of(&self, len: u64) -> impl Iterator<Item = u32> + '_ {
+        let block_len = u64::from(self.block_len);
+        (0..self.block_count()).map(move |i| match self.block_lens.get(i) {
+            Some(&l) => l,
+            None => block_len.min(len - i as u64 * block_len) as u32,
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::io::ErrorKind;
+
+    use super::*;
+    use super::super::mksum::{calculate_signature, generate_signature};
+    use super::super::test_util::pattern;
+
+    fn options() -> SignatureOptions {
+        SignatureOptions {
+            block_len: 1000,
+            .. SignatureOptions::default()
+        }.with_strong_len(12)
+    }
+
+    fn sig_bytes(basis: &[u8], options: &SignatureOptions) -> Vec<u8> {
+        let mut buf = Vec::new();
+        generate_signature(&mut &basis[..], options, &mut buf).unwrap();
+        buf
+    }
+
+    #[test]
+    pub fn read_generated_signature() {
+        let basis = pattern(4500);
+        for magic in &[SignatureFormat::Md4Sig, SignatureFormat::RkMd4Sig,
+                       SignatureFormat::Blake2Sig, SignatureFormat::RkBlake2Sig] {
+            let options = SignatureOptions { magic: *magic, .. options() };
+            let buf = sig_bytes(&basis, &options);
+            let sig = Signature::read_from(&mut buf.as_slice()).unwrap();
+            assert_eq!(sig.format(), *magic);
+            assert_eq!(sig.block_len(), 1000);
+            assert_eq!(sig.strong_len(), 12);
+            assert_eq!(sig.block_count(), 5);
+            assert_eq!(sig.weak_sum(0), (&buf[12..16]).read_u32::<BigEndian>().unwrap());
+            assert_eq!(sig.strong_sum(0), &buf[16..28]);
+            assert_eq!(sig, calculate_signature(&mut basis.as_slice(), &options).unwrap());
+        }
+    }
+
+    #[cfg(feature = "blake3")]
+    #[test]
+    pub fn read_blake3_signature() {
+        let options = SignatureOptions { magic: SignatureFormat::Blake3Sig, .. options() };
+        let basis = pattern(4500);
+        let buf = sig_bytes(&basis, &options);
+        let sig = Signature::read_from(&mut buf.as_slice()).unwrap();
+        assert_eq!(sig.format(), SignatureFormat::Blake3Sig);
+        assert_eq!(sig, calculate_signature(&mut basis.as_slice(), &options).unwrap());
+    }
+
+    #[test]
+    pub fn read_content_defined_signature() {
+        let cdc = SignatureOptions {
+            magic: SignatureFormat::CdcBlake2Sig,
+            block_len: 256,
+            .. options()
+        };
+        let basis = pattern(5000);
+        let mut buf = sig_bytes(&basis, &cdc);
+        let sig = Signature::read_from(&mut buf.as_slice()).unwrap();
+        assert_eq!(sig, calculate_signature(&mut basis.as_slice(), &cdc).unwrap());
+        let lens = sig.block_lens().unwrap();
+        assert_eq!(lens[0], (&buf[12..16]).read_u32::<BigEndian>().unwrap());
+        assert_eq!(sig.block_offset(2), u64::from(lens[0] + lens[1]));
+        assert_eq!(sig.weak_sum(0), (&buf[16..20]).read_u32::<BigEndian>().unwrap());
+        assert_eq!(sig.strong_sum(0), &buf[20..32]);
+        assert_eq!(calculate_signature(&mut &basis[..], &options()).unwrap().block_lens(), None);
+
+        buf[12..16].copy_from_slice(&[0; 4]);
+        let err = Signature::read_from(&mut buf.as_slice()).unwrap_err();
+        assert!(matches!(err, Error::CorruptSignature(_)), "{:?}", err);
+        let small_chunks = [b'r', b's', 0x82, b'G', 0, 0, 0, 32, 0, 0, 0, 32];
+        let err = Signature::read_from(&mut &small_chunks[..]).unwrap_err();
+        assert!(matches!(err, Error::CorruptSignature(_)), "{:?}", err);
+    }
+
+    #[test]
+    pub fn read_v2_signature() {
+        use super::super::magic::SIGNATURE_V2_MAGIC;
+        use super::super::mksum::{calculate_signature_variable, generate_signature_variable};
+
+        let basis = pattern(5000);
+        let mut buf = Vec::new();
+        let stats = generate_signature_variable(&mut basis.as_slice(), &options(), &[100, 2000],
+                                                &mut buf).unwrap();
+        assert_eq!(stats.out_bytes, buf.len() as u64);
+        assert_eq!(&buf[..4], &SIGNATURE_V2_MAGIC.to_be_bytes());
+        assert_eq!(&buf[4..8], &(SignatureFormat::Blake2Sig as u32).to_be_bytes());
+        let sig = Signature::read_from(&mut buf.as_slice()).unwrap();
+        assert_eq!((sig.format(), sig.block_len(), sig.strong_len()),
+                   (SignatureFormat::Blake2Sig, 2000, 12));
+        assert_eq!(sig.block_lens(), Some(&[100, 2000, 1000, 1000, 900][..]));
+        assert_eq!(sig.block_offset(3), 3100);
+        assert_eq!(sig, calculate_signature_variable(&mut basis.as_slice(), &options(),
+                                                     &[100, 2000]).unwrap());
+        // The blocks are hashed at their own lengths.
+        let first = calculate_signature(&mut &basis[..100], &options()).unwrap();
+        assert_eq!((sig.weak_sum(0), sig.strong_sum(0)), (first.weak_sum(0), first.strong_sum(0)));
+
+        // No block can be longer than the header says.
+        buf[16..20].copy_from_slice(&2001u32.to_be_bytes());
+        let err = Signature::read_from(&mut buf.as_slice()).unwrap_err();
+        assert!(matches!(err, Error::CorruptSignature(_)), "{:?}", err);
+        buf[4..8].copy_from_slice(&(SignatureFormat::CdcBlake2Sig as u32).to_be_bytes());
+        let err = Signature::read_from(&mut buf.as_slice()).unwrap_err();
+        assert!(matches!(err, Error::CorruptSignature(_)), "{:?}", err);
+
+        let cdc = SignatureOptions { magic: SignatureFormat::CdcBlake2Sig, .. options() };
+        let err = calculate_signature_variable(&mut basis.as_slice(), &cdc, &[100]).unwrap_err();
+        assert!(matches!(err, Error::InvalidOptions(_)), "{:?}", err);
+        let err = calculate_signature_variable(&mut basis.as_slice(), &options(), &[0])
+            .unwrap_err();
+        assert!(matches!(err, Error::InvalidOptions(_)), "{:?}", err);
+    }
+
+    #[test]
+    pub fn inspect_blocks() {
+        let mut basis = pattern(3000);
+        basis.extend(pattern(1000));
+        let sig = calculate_signature(&mut basis.as_slice(), &options()).unwrap();
+        let blocks: Vec<_> = sig.blocks().collect();
+        assert_eq!(blocks.len(), 4);
+        for &(i, weak, strong) in &blocks {
+            assert_eq!((weak, strong), (sig.weak_sum(i), sig.strong_sum(i)));
+        }
+        assert_eq!(sig.lookup_weak(sig.weak_sum(0)), [0, 3]);
+        assert_eq!(sig.lookup_weak(sig.weak_sum(1)), [1]);
+        assert!(sig.lookup_weak(!sig.weak_sum(1)).is_empty());
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    pub fn serde_round_trip() {
+        let sig = calculate_signature(&mut pattern(3500).as_slice(), &options()).unwrap();
+        let json = serde_json::to_string(&sig).unwrap();
+        assert!(!json.contains("block_lens"), "{}", json);
+        assert_eq!(serde_json::from_str::<Signature>(&json).unwrap(), sig);
+
+        let cdc = SignatureOptions { magic: SignatureFormat::CdcBlake2Sig, .. options() };
+        let sig = calculate_signature(&mut pattern(3500).as_slice(), &cdc).unwrap();
+        let json = serde_json::to_string(&sig).unwrap();
+        assert_eq!(serde_json::from_str::<Signature>(&json).unwrap(), sig);
+
+        let sig = super::super::mksum::calculate_signature_variable(
+            &mut pattern(3500).as_slice(), &options(), &[10, 20]).unwrap();
+        let json = serde_json::to_string(&sig).unwrap();
+        assert_eq!(serde_json::from_str::<Signature>(&json).unwrap(), sig);
+
+        let options = options();
+        let json = serde_json::to_string(&options).unwrap();
+        assert_eq!(serde_json::from_str::<SignatureOptions>(&json).unwrap(), options);
+    }
+
+    /// Deserialized signatures are checked like those read from signature files.
+    #[cfg(feature = "serde")]
+    #[test]
+    pub fn serde_rejects_inconsistent_signatures() {
+        let bad = [
+            r#"{"magic":"Blake2Sig","block_len":0,"strong_len":8,"weak_sums":[],"strong_sums":[]}"#,
+            r#"{"magic":"Md4Sig","block_len":8,"strong_len":17,"weak_sums":[],"strong_sums":[]}"#,
+            r#"{"magic":"Blake2Sig","block_len":8,"strong_len":2,"weak_sums":[1],"strong_sums":[1]}"#,
+            r#"{"magic":"CdcBlake2Sig","block_len":64,"strong_len":1,"weak_sums":[1],"strong_sums":[1]}"#,
+        ];
+        for json in &bad {
+            let err = serde_json::from_str::<Signature>(json).unwrap_err();
+            assert!(err.to_string().contains("corrupt signature"), "{}", err);
+        }
+    }
+
+    #[test]
+    pub fn concat() {
+        use std::io::Cursor;
+        use super::super::mkdelta::generate_delta;
+        use super::super::patch::apply_patch;
+
+        let basis = pattern(4500);
+        let cdc = SignatureOptions {
+            magic: SignatureFormat::CdcBlake2Sig,
+            block_len: 256,
+            .. options()
+        };
+        for (options, split) in &[(options(), 2000), (options(), 1500), (cdc, 1500)] {
+            let (a, b) = basis.split_at(*split);
+            let sig_a = calculate_signature(&mut &a[..], options).unwrap();
+            let sig_b = calculate_signature(&mut &b[..], options).unwrap();
+            let joined = Signature::concat(&[(&sig_a, a.len() as u64), (&sig_b, b.len() as u64)])
+                .unwrap();
+            assert_eq!(joined.block_count(), sig_a.block_count() + sig_b.block_count());
+            assert_eq!(joined.block_offset(sig_a.block_count()), *split as u64);
+            if options.magic != SignatureFormat::CdcBlake2Sig && split % 1000 == 0 {
+                assert_eq!(joined, calculate_signature(&mut basis.as_slice(), options).unwrap());
+            }
+            let mut new = basis[500..].to_vec();
+            new.extend_from_slice(&basis[..500]);
+            let mut delta = Vec::new();
+            let stats = generate_delta(&joined, &mut new.as_slice(), &mut delta).unwrap();
+            assert!(stats.copy_bytes >= 2500, "{:?}", stats);
+            let mut out = Vec::new();
+            apply_patch(&mut Cursor::new(&basis), &mut delta.as_slice(), &mut out).unwrap();
+            assert_eq!(out, new);
+        }
+    }
+
+    #[test]
+    pub fn concat_errors() {
+        let sig = calculate_signature(&mut pattern(1500).as_slice(), &options()).unwrap();
+        let other = Signature::new(&options().with_strong_len(8));
+        for parts in &[&[][..], &[(&sig, 1500), (&other, 0)], &[(&sig, 2001)],
+                       &[(&sig, 1500), (&sig, 999)]] {
+            let err = Signature::concat(parts).unwrap_err();
+            assert!(matches!(err, Error::InvalidOptions(_)), "{:?}", err);
+        }
+        let mut unchanged = sig.clone();
+        assert!(unchanged.append(1000, &sig, 1500).is_err());
+        assert_eq!(unchanged, sig);
+    }
+
+    #[test]
+    pub fn empty_signature() {
+        let buf = sig_bytes(b"", &options());
+        let sig = Signature::read_from(&mut buf.as_slice()).unwrap();
+        assert_eq!(sig.block_count(), 0);
+    }
+
+    #[test]
+    pub fn bad_magic() {
+        let buf = [b'r', b's', 0x02, 0x36, 0, 0, 8, 0, 0, 0, 0, 32];
+        let err = Signature::read_from(&mut &buf[..]).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::InvalidData);
+        assert!(matches!(err, Error::BadMagic(0x72730236)), "{:?}", err);
+    }
+
+    #[test]
+    pub fn bad_header_values() {
+        let zero_block = [b'r', b's', 0x01, 0x37, 0, 0, 0, 0, 0, 0, 0, 32];
+        let err = Signature::read_from(&mut &zero_block[..]).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::InvalidData);
+        let long_strong = [b'r', b's', 0x01, 0x37, 0, 0, 8, 0, 0, 0, 0, 33];
+        let err = Signature::read_from(&mut &long_strong[..]).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::InvalidData);
+        let long_md4 = [b'r', b's', 0x01, 0x36, 0, 0, 8, 0, 0, 0, 0, 17];
+        let err = Signature::read_from(&mut &long_md4[..]).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::InvalidData);
+        assert!(matches!(err, Error::CorruptSignature(_)), "{:?}", err);
+        let zero_strong = [b'r', b's', 0x01, 0x37, 0, 0, 8, 0, 0, 0, 0, 0];
+        let err = Signature::read_from(&mut &zero_strong[..]).unwrap_err();
+        assert!(matches!(err, Error::CorruptSignature(_)), "{:?}", err);
+    }
+
+    /// A header with the largest allowed values, and nothing after it, is read cheaply.
+    #[test]
+    pub fn huge_header_values() {
+        let buf = [b'r', b's', 0x01, 0x37, 0x01, 0, 0, 0, 0, 0, 0, 32];
+        let sig = Signature::read_from(&mut &buf[..]).unwrap();
+        assert_eq!((sig.block_len(), sig.block_count()), (MAX_BLOCK_LEN, 0));
+        assert!(sig.weak_sums.capacity() == 0 && sig.strong_sums.capacity() == 0);
+
+        for block_len in &[MAX_BLOCK_LEN + 1, u32::MAX] {
+            let mut buf = buf.to_vec();
+            buf[4..8].copy_from_slice(&block_len.to_be_bytes());
+            buf.extend_from_slice(&[0; 36]);
+            let err = Signature::read_from(&mut &buf[..]).unwrap_err();
+            assert!(matches!(err, Error::CorruptSignature(_)), "{}: {:?}", block_len, err);
+        }
+        let options = SignatureOptions { block_len: MAX_BLOCK_LEN + 1, .. options() };
+        let err = calculate_signature(&mut &b""[..], &options).unwrap_err();
+        assert!(matches!(err, Error::InvalidOptions(_)), "{:?}", err);
+    }
+
+    #[cfg(not(feature = "blake3"))]
+    #[test]
+    pub fn blake3_unsupported() {
+        let buf = [b'r', b's', 0x81, b'G', 0, 0, 8, 0, 0, 0, 0, 32];
+        let err = Signature::read_from(&mut &buf[..]).unwrap_err();
+        assert!(matches!(err, Error::UnsupportedFormat(0x72738147)), "{:?}", err);
+
+        let options = SignatureOptions { magic: SignatureFormat::Blake3Sig, .. options() };
+        let err = calculate_signature(&mut &b"hello"[..], &options).unwrap_err();
+        assert!(matches!(err, Error::UnsupportedFormat(0x72738147)), "{:?}", err);
+        let err = SignatureOptions::new().magic(SignatureFormat::Blake3Sig).build().unwrap_err();
+        assert!(matches!(err, Error::UnsupportedFormat(0x72738147)), "{:?}", err);
+    }
+
+    #[test]
+    pub fn truncated() {
+        let buf = sig_bytes(&pattern(4500), &options());
+        for l in &[0, 3, 11, 12 + 7, buf.len() - 1] {
+            let err = Signature::read_from(&mut &buf[..*l]).unwrap_err();
+            assert_eq!(err.kind(), ErrorKind::UnexpectedEof, "length {}", l);
+        }
+    }
+
+    /// Verifying a signature finds the same errors as reading it, and describes it.
+    #[test]
+    pub fn verify() {
+        let basis = pattern(4500);
+        let buf = sig_bytes(&basis, &options());
+        assert_eq!(verify_signature(&mut buf.as_slice()).unwrap(), SignatureInfo {
+            format: SignatureFormat::Blake2Sig,
+            block_len: 1000,
+            strong_len: 12,
+            variable: false,
+            seed: 0,
+            block_count: 5,
+        });
+        let cdc = SignatureOptions { magic: SignatureFormat::CdcBlake2Sig, .. options() };
+        let mut cdc_buf = sig_bytes(&basis, &cdc);
+        let info = verify_signature(&mut cdc_buf.as_slice()).unwrap();
+        let sig = Signature::read_from(&mut cdc_buf.as_slice()).unwrap();
+        assert!(info.variable);
+        assert_eq!(info.block_count, sig.block_count() as u64);
+        cdc_buf[12..16].copy_from_slice(&[0; 4]);
+        let err = verify_signature(&mut cdc_buf.as_slice()).unwrap_err();
+        assert!(matches!(err, Error::CorruptSignature(_)), "{:?}", err);
+        for l in &[0, 11, buf.len() - 1] {
+            let err = verify_signature(&mut &buf[..*l]).unwrap_err();
+            assert_eq!(err.kind(), ErrorKind::UnexpectedEof, "length {}", l);
+        }
+        let err = verify_signature(&mut &b"not a signature"[..]).unwrap_err();
+        assert!(matches!(err, Error::BadMagic(_)), "{:?}", err);
+    }
+
+    #[test]
+    pub fn block_len_for_file_size() {
+        let block_len = |len| SignatureOptions::for_file_size(len).block_len;
+        assert_eq!(block_len(0), MIN_SIZED_BLOCK_LEN);
+        assert_eq!(block_len(1000), MIN_SIZED_BLOCK_LEN);
+        assert_eq!(block_len(1 << 20), 1 << 10);
+        assert_eq!(block_len((1 << 22) - 1), 1 << 10);
+        assert_eq!(block_len(1 << 40), 1 << 20);
+        assert_eq!(block_len(u64::MAX), MAX_BLOCK_LEN);
+        assert_eq!(SignatureOptions::for_file_size(1 << 30),
+                   SignatureOptions { block_len: 1 << 15, .. SignatureOptions::default() });
+    }
+}
@@ -0,0 +1,331 @@
+// rdiff(rust) -- library for network deltas
+// Copyright 2018 Martin Pool.
+
+//! Vectorized bulk updates of the rolling hashes.
+//!
+//! Summing whole blocks is the inner loop of signature generation, and of delta
+//! generation each time a match moves the window on by a block. Both hashes reduce to
+//! sums of the bytes weighted by their position, which are computed here many bytes at a
+//! time: with AVX2 or SSE2 on x86, chosen at runtime, or NEON on aarch64. Other
+//! platforms, and the bytes left over after the last whole vector, use scalar loops.
+//! Everything wraps modulo 2^32, so all the paths give exactly the same results.
+
+use core::num::Wrapping;
+
+#[cfg(any(target_arch = "x86", target_arch = "x86_64", target_arch = "aarch64"))]
+use super::rabinkarp::pow;
+use super::rabinkarp::MULT;
+
+/// Check for an x86 CPU feature: at runtime with `std`, and otherwise only if it's
+/// enabled at compile time.
+#[cfg(all(feature = "std", any(target_arch = "x86", target_arch = "x86_64")))]
+macro_rules! has_x86_feature {
+    ($f:tt) => { is_x86_feature_detected!($f) }
+}
+
+#[cfg(all(not(feature = "std"), any(target_arch = "x86", target_arch = "x86_64")))]
+macro_rules! has_x86_feature {
+    ($f:tt) => { cfg!(target_feature = $f) }
+}
+
+/// Sum the bytes of `buf`, and also sum them weighted by their distance from the end, so
+/// that the last byte counts once and the first `buf.len()` times.
+///
+/// These are what `buf` adds to the rollsum's `s1`, and to its `s2` beyond `buf.len()`
+/// times the old `s1`, not counting the character offsets.
+pub(crate) fn rollsum_sums(buf: &[u8]) -> (u32, u32) {
+    #[cfg(target_arch = "aarch64")]
+    {
+        unsafe { neon::rollsum_sums(buf) }
+    }
+    #[cfg(not(target_arch = "aarch64"))]
+    {
+        #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+        {
+            if has_x86_feature!("avx2") {
+                return unsafe { x86::rollsum_sums_avx2(buf) };
+            }
+            if has_x86_feature!("sse2") {
+                return unsafe { x86::rollsum_sums_sse2(buf) };
+            }
+        }
+        rollsum_sums_scalar(buf)
+    }
+}
+
+/// Hash `buf` as a polynomial in `MULT`, starting from zero: the first byte is multiplied
+/// by `MULT` to the power `buf.len() - 1`, and the last byte by one.
+pub(crate) fn rabinkarp_sum(buf: &[u8]) -> u32 {
+    #[cfg(target_arch = "aarch64")]
+    {
+        unsafe { neon::rabinkarp_sum(buf) }
+    }
+    #[cfg(not(target_arch = "aarch64"))]
+    {
+        #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+        {
+            if has_x86_feature!("avx2") {
+                return unsafe { x86::rabinkarp_sum_avx2(buf) };
+            }
+            if has_x86_feature!("sse2") {
+                return unsafe { x86::rabinkarp_sum_sse2(buf) };
+            }
+        }
+        rabinkarp_sum_scalar(buf)
+    }
+}
+
+fn rollsum_sums_scalar(buf: &[u8]) -> (u32, u32) {
+    let mut s1 = Wrapping(0u32);
+    let mut s2 = Wrapping(0u32);
+    for &c in buf {
+        s1 += Wrapping(u32::from(c));
+        s2 += s1;
+    }
+    (s1.0, s2.0)
+}
+
+fn rabinkarp_sum_scalar(buf: &[u8]) -> u32 {
+    horner(buf.iter().map(|&c| u32::from(c)))
+}
+
+/// Evaluate the polynomial in `MULT` whose coefficients are `coeffs`, highest power first.
+///
+/// A vector of lanes that have each hashed every `n`th byte, with multiplier `MULT^n`,
+/// combines this way into the hash of all the bytes.
+fn horner<I: IntoIterator<Item = u32>>(coeffs: I) -> u32 {
+    coeffs.into_iter().fold(Wrapping(0), |h, c| h * MULT + Wrapping(c)).0
+}
+
+/// Combine the rollsum sums of two adjacent pieces, given the length of the second.
+#[cfg(any(target_arch = "x86", target_arch = "x86_64", target_arch = "aarch64"))]
+fn rollsum_join(a: (u32, u32), b: (u32, u32), b_len: usize) -> (u32, u32) {
+    (a.0.wrapping_add(b.0),
+     a.1.wrapping_add((b_len as u32).wrapping_mul(a.0)).wrapping_add(b.1))
+}
+
+/// Combine the RabinKarp sums of two adjacent pieces, given the length of the second.
+#[cfg(any(target_arch = "x86", target_arch = "x86_64", target_arch = "aarch64"))]
+fn rabinkarp_join(a: u32, b: u32, b_len: usize) -> u32 {
+    (Wrapping(a) * pow(MULT, b_len) + Wrapping(b)).0
+}
+
+/// Add up 32-bit lanes.
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+fn lane_sum(lanes: &[u32]) -> u32 {
+    lanes.iter().fold(0u32, |a, &b| a.wrapping_add(b))
+}
+
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+mod x86 {
+    #[cfg(target_arch = "x86")]
+    use core::arch::x86::*;
+    #[cfg(target_arch = "x86_64")]
+    use core::arch::x86_64::*;
+    use core::mem::transmute;
+
+    use super::super::rabinkarp::{pow, MULT};
+    use super::{horner, lane_sum, rabinkarp_join, rabinkarp_sum_scalar, rollsum_join,
+                rollsum_sums_scalar};
+
+    /// 32 bytes at a time: the plain sums come from `sad`, the weighted ones from
+    /// multiplying by 32..1. The weighted sums of the earlier vectors are then topped up
+    /// by 32 times the plain sum of each vector, for each vector after it.
+    #[target_feature(enable = "avx2")]
+    pub(super) unsafe fn rollsum_sums_avx2(buf: &[u8]) -> (u32, u32) {
+        let chunks = buf.chunks_exact(32);
+        let tail = chunks.remainder();
+        let zero = _mm256_setzero_si256();
+        let weights = _mm256_setr_epi8(32, 31, 30, 29, 28, 27, 26, 25, 24, 23, 22, 21, 20, 19,
+                                       18, 17, 16, 15, 14, 13, 12, 11, 10, 9, 8, 7, 6, 5, 4, 3,
+                                       2, 1);
+        let ones = _mm256_set1_epi16(1);
+        let (mut s1, mut earlier, mut s2) = (zero, zero, zero);
+        for chunk in chunks {
+            let v = _mm256_loadu_si256(chunk.as_ptr() as *const __m256i);
+            earlier = _mm256_add_epi32(earlier, s1);
+            s1 = _mm256_add_epi32(s1, _mm256_sad_epu8(v, zero));
+            s2 = _mm256_add_epi32(s2, _mm256_madd_epi16(_mm256_maddubs_epi16(v, weights), ones));
+        }
+        let s1 = lane_sum(&transmute::<__m256i, [u32; 8]>(s1));
+        let s2 = lane_sum(&transmute::<__m256i, [u32; 8]>(earlier)).wrapping_mul(32)
+            .wrapping_add(lane_sum(&transmute::<__m256i, [u32; 8]>(s2)));
+        rollsum_join((s1, s2), rollsum_sums_scalar(tail), tail.len())
+    }
+
+    /// As for AVX2, but 16 bytes at a time, widened to 16 bits to be multiplied.
+    #[target_feature(enable = "sse2")]
+    pub(super) unsafe fn rollsum_sums_sse2(buf: &[u8]) -> (u32, u32) {
+        let chunks = buf.chunks_exact(16);
+        let tail = chunks.remainder();
+        let zero = _mm_setzero_si128();
+        let weights_lo = _mm_setr_epi16(16, 15, 14, 13, 12, 11, 10, 9);
+        let weights_hi = _mm_setr_epi16(8, 7, 6, 5, 4, 3, 2, 1);
+        let (mut s1, mut earlier, mut s2) = (zero, zero, zero);
+        for chunk in chunks {
+            let v = _mm_loadu_si128(chunk.as_ptr() as *const __m128i);
+            earlier = _mm_add_epi32(earlier, s1);
+            s1 = _mm_add_epi32(s1, _mm_sad_epu8(v, zero));
+            let lo = _mm_madd_epi16(_mm_unpacklo_epi8(v, zero), weights_lo);
+            let hi = _mm_madd_epi16(_mm_unpackhi_epi8(v, zero), weights_hi);
+            s2 = _mm_add_epi32(s2, _mm_add_epi32(lo, hi));
+        }
+        let s1 = lane_sum(&transmute::<__m128i, [u32; 4]>(s1));
+        let s2 = lane_sum(&transmute::<__m128i, [u32; 4]>(earlier)).wrapping_mul(16)
+            .wrapping_add(lane_sum(&transmute::<__m128i, [u32; 4]>(s2)));
+        rollsum_join((s1, s2), rollsum_sums_scalar(tail), tail.len())
+    }
+
+    /// 32 lanes, in four vectors, each hash every 32nd byte with multiplier `MULT^32`.
+    #[target_feature(enable = "avx2")]
+    pub(super) unsafe fn rabinkarp_sum_avx2(buf: &[u8]) -> u32 {
+        let chunks = buf.chunks_exact(32);
+        let tail = chunks.remainder();
+        let mult = _mm256_set1_epi32(pow(MULT, 32).0 as i32);
+        let mut acc = [_mm256_setzero_si256(); 4];
+        for chunk in chunks {
+            for (k, a) in acc.iter_mut().enumerate() {
+                let bytes = _mm_loadl_epi64(chunk[8 * k..].as_ptr() as *const __m128i);
+                *a = _mm256_add_epi32(_mm256_mullo_epi32(*a, mult), _mm256_cvtepu8_epi32(bytes));
+            }
+        }
+        let lanes = transmute::<[__m256i; 4], [u32; 32]>(acc);
+        rabinkarp_join(horner(lanes.iter().cloned()), rabinkarp_sum_scalar(tail), tail.len())
+    }
+
+    /// 16 lanes, in four vectors, each hash every 16th byte with multiplier `MULT^16`.
+    #[target_feature(enable = "sse2")]
+    pub(super) unsafe fn rabinkarp_sum_sse2(buf: &[u8]) -> u32 {
+        let chunks = buf.chunks_exact(16);
+        let tail = chunks.remainder();
+        let zero = _mm_setzero_si128();
+        let mult = _mm_set1_epi32(pow(MULT, 16).0 as i32);
+        let mut acc = [zero; 4];
+        for chunk in chunks {
+            let v = _mm_loadu_si128(chunk.as_ptr() as *const __m128i);
+            let lo = _mm_unpacklo_epi8(v, zero);
+            let hi = _mm_unpackhi_epi8(v, zero);
+            let words = [_mm_unpacklo_epi16(lo, zero), _mm_unpackhi_epi16(lo, zero),
+                         _mm_unpacklo_epi16(hi, zero), _mm_unpackhi_epi16(hi, zero)];
+            for (a, w) in acc.iter_mut().zip(&words) {
+                *a = _mm_add_epi32(mullo_epi32(*a, mult), *w);
+            }
+        }
+        let lanes = transmute::<[__m128i; 4], [u32; 16]>(acc);
+        rabinkarp_join(horner(lanes.iter().cloned()), rabinkarp_sum_scalar(tail), tail.len())
+    }
+
+    /// Multiply 32-bit lanes, keeping the low halves, which SSE2 can only do for the even
+    /// and odd lanes separately.
+    #[target_feature(enable = "sse2")]
+    unsafe fn mullo_epi32(a: __m128i, b: __m128i) -> __m128i {
+        let even = _mm_mul_epu32(a, b);
+        let odd = _mm_mul_epu32(_mm_srli_epi64(a, 32), _mm_srli_epi64(b, 32));
+        _mm_unpacklo_epi32(_mm_shuffle_epi32(even, 0b00_00_10_00),
+                           _mm_shuffle_epi32(odd, 0b00_00_10_00))
+    }
+}
+
+#[cfg(target_arch = "aarch64")]
+mod neon {
+    use core::arch::aarch64::*;
+
+    use super::super::rabinkarp::{pow, MULT};
+    use super::{horner, rabinkarp_join, rabinkarp_sum_scalar, rollsum_join,
+                rollsum_sums_scalar};
+
+    /// 16 bytes at a time, in the same way as for SSE2.
+    pub(super) unsafe fn rollsum_sums(buf: &[u8]) -> (u32, u32) {
+        let chunks = buf.chunks_exact(16);
+        let tail = chunks.remainder();
+        let weights: [u8; 16] = [16, 15, 14, 13, 12, 11, 10, 9, 8, 7, 6, 5, 4, 3, 2, 1];
+        let w = vld1q_u8(weights.as_ptr());
+        let (mut s1, mut earlier, mut s2) = (vdupq_n_u32(0), vdupq_n_u32(0), vdupq_n_u32(0));
+        for chunk in chunks {
+            let v = vld1q_u8(chunk.as_ptr());
+            earlier = vaddq_u32(earlier, s1);
+            s1 = vpadalq_u16(s1, vpaddlq_u8(v));
+            s2 = vpadalq_u16(s2, vmull_u8(vget_low_u8(v), vget_low_u8(w)));
+            s2 = vpadalq_u16(s2, vmull_u8(vget_high_u8(v), vget_high_u8(w)));
+        }
+        let s1 = vaddvq_u32(s1);
+        let s2 = vaddvq_u32(earlier).wrapping_mul(16).wrapping_add(vaddvq_u32(s2));
+        rollsum_join((s1, s2), rollsum_sums_scalar(tail), tail.len())
+    }
+
+    /// 16 lanes, in four vectors, each hash every 16th byte with multiplier `MULT^16`.
+    pub(super) unsafe fn rabinkarp_sum(buf: &[u8]) -> u32 {
+        let chunks = buf.chunks_exact(16);
+        let tail = chunks.remainder();
+        let mult = vdupq_n_u32(pow(MULT, 16).0);
+        let mut acc = [vdupq_n_u32(0); 4];
+        for chunk in chunks {
+            let v = vld1q_u8(chunk.as_ptr());
+            let lo = vmovl_u8(vget_low_u8(v));
+            let hi = vmovl_u8(vget_high_u8(v));
+            let words = [vmovl_u16(vget_low_u16(lo)), vmovl_u16(vget_high_u16(lo)),
+                         vmovl_u16(vget_low_u16(hi)), vmovl_u16(vget_high_u16(hi))];
+            for (a, w) in acc.iter_mut().zip(&words) {
+                *a = vmlaq_u32(*w, *a, mult);
+            }
+        }
+        let mut lanes = [0u32; 16];
+        for (a, l) in acc.iter().zip(lanes.chunks_exact_mut(4)) {
+            vst1q_u32(l.as_mut_ptr(), *a);
+        }
+        rabinkarp_join(horner(lanes.iter().cloned()), rabinkarp_sum_scalar(tail), tail.len())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    /// Buffers of every length up to a few vectors, and some longer ones, with all byte
+    /// values.
+    fn buffers() -> Vec<Vec<u8>> {
+        let mut lens: Vec<usize> = (0..200).collect();
+        lens.extend(&[1023, 2048, 4099, 70_000]);
+        lens.into_iter()
+            .map(|len| (0..len).map(|i| ((i * 7 + i / 13) % 256) as u8).collect())
+            .chain(vec![vec![0xff; 5000], vec![0; 5000]])
+            .collect()
+    }
+
+    #[test]
+    pub fn vectorized_matches_scalar() {
+        for buf in buffers() {
+            assert_eq!(rollsum_sums(&buf), rollsum_sums_scalar(&buf), "{}", buf.len());
+            assert_eq!(rabinkarp_sum(&buf), rabinkarp_sum_scalar(&buf), "{}", buf.len());
+        }
+    }
+
+    /// Each x86 implementation that this machine supports matches, not just the one
+    /// that's chosen.
+    #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+    #[test]
+    pub fn every_x86_implementation_matches_scalar() {
+        for buf in buffers() {
+            let rollsum = rollsum_sums_scalar(&buf);
+            let rabinkarp = rabinkarp_sum_scalar(&buf);
+            if is_x86_feature_detected!("sse2") {
+                assert_eq!(unsafe { x86::rollsum_sums_sse2(&buf) }, rollsum);
+                assert_eq!(unsafe { x86::rabinkarp_sum_sse2(&buf) }, rabinkarp);
+            }
+            if is_x86_feature_detected!("avx2") {
+                assert_eq!(unsafe { x86::rollsum_sums_avx2(&buf) }, rollsum);
+                assert_eq!(unsafe { x86::rabinkarp_sum_avx2(&buf) }, rabinkarp);
+            }
+        }
+    }
+
+    #[test]
+    pub fn join() {
+        let buf: Vec<u8> = (0..1000u32).map(|i| (i * 37 % 256) as u8).collect();
+        let (a, b) = buf.split_at(377);
+        assert_eq!(rollsum_join(rollsum_sums_scalar(a), rollsum_sums_scalar(b), b.len()),
+                   rollsum_sums_scalar(&buf));
+        assert_eq!(rabinkarp_join(rabinkarp_sum_scalar(a), rabinkarp_sum_scalar(b), b.len()),
+                   rabinkarp_sum_scalar(&buf));
+    }
+}
@@ -0,0 +1,188 @@
+// rdiff(rust) -- library for network deltas
+// Copyright 2018 Martin Pool.
+
+//! Write files with holes where the data is zero.
+//!
+//! Patching a sparse file, such as a virtual machine's disk image, would otherwise fill
+//! in all its holes: they read as zeros, so COPYs from them, like LITERALs of zeros,
+//! write zeros.
+
+use std::convert::TryFrom;
+use std::io::{self, Seek, SeekFrom, Write};
+
+/// Blocks of this many zeros, aligned to the start of the output, are skipped over.
+pub const SPARSE_BLOCK_LEN: usize = 4096;
+
+/// Default size of the buffer of a `SparseWriter`.
+const DEFAULT_BUF_LEN: usize = 64 << 10;
+
+/// Writes to a file, or anything else that can seek, seeking over blocks of zeros
+/// rather than writing them, so that a filesystem that supports it leaves holes.
+///
+/// The data is buffered. Flushing writes it out, and then if the output ends in a hole,
+/// writes its last byte, so that the file has its whole length. The output should start
+/// empty, or with zeros where the holes will be.
+pub struct SparseWriter<W: Write + Seek> {
+    inner: W,
+    buf: Vec<u8>,
+    buf_len: usize,
+    /// Offset in the output of the start of `buf`.
+    pos: u64,
+    /// Length of the zeros skipped since the last write to `inner`.
+    hole: u64,
+}
+
+impl<W: Write + Seek> SparseWriter<W> {
+    /// Write to `inner`, from its current position.
+    pub fn new(inner: W) -> SparseWriter<W> {
+        SparseWriter::with_capacity(DEFAULT_BUF_LEN, inner)
+    }
+
+    /// Write to `inner`, with a buffer of at least `capacity` bytes, and of at least one
+    /// block.
+    pub fn with_capacity(capacity: usize, inner: W) -> SparseWriter<W> {
+        let buf_len = capacity.max(SPARSE_BLOCK_LEN);
+        SparseWriter { inner, buf: Vec::with_capacity(buf_len), buf_len, pos: 0, hole: 0 }
+    }
+
+    /// Flush, and return the underlying writer.
+    pub fn into_inner(mut self) -> io::Result<W> {
+        self.flush()?;
+        Ok(self.inner)
+    }
+
+    /// Write out the buffered blocks, skipping those that are zero, and keep any
+    /// incomplete block at the end unless `all` is set.
+    fn write_blocks(&mut self, all: bool) -> io::Result<()> {
+        let mut done = 0;
+        let mut run_start = 0;
+        while done < self.buf.len() {
+            let to_boundary = SPARSE_BLOCK_LEN - ((self.pos + done as u64)
+                                                 % SPARSE_BLOCK_LEN as u64) as usize;
+            let end = self.buf.len().min(done + to_boundary);
+            if end - done < to_boundary && !all {
+                break;
+            }
+            if self.buf[done..end].iter().all(|&b| b == 0) {
+                self.write_run(run_start, done)?;
+                self.hole += (end - done) as u64;
+                run_start = end;
+            }
+            done = end;
+        }
+        self.write_run(run_start, done)?;
+        self.buf.drain(..done);
+        self.pos += done as u64;
+        Ok(())
+    }
+
+    /// Write `buf[start..end]`, after seeking over any hole before it.
+    fn write_run(&mut self, start: usize, end: usize) -> io::Result<()> {
+        if start == end {
+            return Ok(());
+        }
+        self.skip_hole(0)?;
+        self.inner.write_all(&self.buf[start..end])
+    }
+
+    /// Seek over the hole, except for its last `keep` bytes.
+    fn skip_hole(&mut self, keep: u64) -> io::Result<()> {
+        let skip = self.hole - keep;
+        if skip > 0 {
+            let skip = i64::try_from(skip)
+                .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "hole too long"))?;
+            self.inner.seek(SeekFrom::Current(skip))?;
+        }
+        self.hole = keep;
+        Ok(())
+    }
+}
+
+impl<W: Write + Seek> Write for SparseWriter<W> {
+    fn write(&mut self, data: &[u8]) -> io::Result<usize> {
+        if self.buf.len() == self.buf_len {
+            self.write_blocks(false)?;
+        }
+        let l = (self.buf_len - self.buf.len()).min(data.len());
+        self.buf.extend_from_slice(&data[..l]);
+        Ok(l)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.write_blocks(true)?;
+        if self.hole > 0 {
+            self.skip_hole(1)?;
+            self.inner.write_all(&[0])?;
+            self.hole = 0;
+        }
+        self.inner.flush()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::io::Cursor;
+
+    use super::*;
+
+    /// Data with long runs of zeros, at odd offsets.
+    fn holey(len: usize) -> Vec<u8> {
+        (0..len).map(|i| if (i / 10_000) % 3 == 1 { 7 } else { 0 }).collect()
+    }
+
+    #[test]
+    pub fn writes_the_same_data() {
+        for &len in &[0, 1, 4096, 4097, 30_000, 100_000] {
+            let data = holey(len);
+            for &piece in &[1, 1000, 5000, 1 << 20] {
+                let mut w = SparseWriter::with_capacity(10_000, Cursor::new(Vec::new()));
+                for chunk in data.chunks(piece) {
+                    w.write_all(chunk).unwrap();
+                }
+                // Flushing in the middle of a block doesn't change the result.
+                w.flush().unwrap();
+                w.write_all(b"end").unwrap();
+                let out = w.into_inner().unwrap().into_inner();
+                assert_eq!(out.len(), len + 3);
+                assert!(out[..len] == data[..] && &out[len..] == b"end", "{} {}", len, piece);
+            }
+        }
+        let mut w = SparseWriter::new(Cursor::new(Vec::new()));
+        w.write_all(&[0; 10_000]).unwrap();
+        assert_eq!(w.into_inner().unwrap().into_inner(), vec![0; 10_000]);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    pub fn leaves_holes() {
+        use std::fs::{self, File};
+        use std::os::unix::fs::MetadataExt;
+
+        use tempfile::TempDir;
+
+        let tmp = TempDir::new().unwrap();
+        let dir = tmp.path();
+        // A file that's only a gap shows whether this filesystem makes holes at all.
+        let probe = File::create(dir.join("probe")).unwrap();
+        probe.set_len(10 << 20).unwrap();
+        let makes_holes = probe.metadata().unwrap().blocks() * 512 < 1 << 20;
+        let path = dir.join("sparse");
+        let mut w = SparseWriter::new(File::create(&path).unwrap());
+        w.write_all(b"start").unwrap();
+        w.write_all(&vec![0; 10 << 20]).unwrap();
+        w.write_all(b"end").unwrap();
+        w.write_all(&vec![0; 1 << 20]).unwrap();
+        w.flush().unwrap();
+        drop(w);
+        let data = fs::read(&path).unwrap();
+        assert_eq!(data.len(), 5 + (10 << 20) + 3 + (1 << 20));
+        assert!(data.starts_with(b"start") && &data[5 + (10 << 20)..][..3] == b"end");
+        assert!(data[5..][..10 << 20].iter().all(|&b| b == 0));
+        assert!(data[5 + (10 << 20) + 3..].iter().all(|&b| b == 0));
+        if makes_holes {
+            // Only the blocks with data are allocated.
+            let allocated = fs::metadata(&path).unwrap().blocks() * 512;
+            assert!(allocated < 1 << 20, "{} bytes allocated", allocated);
+        }
+    }
+}
@@ -0,0 +1,256 @@
+// rdiff(rust) -- library for network deltas
+// Copyright 2018 Martin Pool.
+
+//! Statistics about what an operation read, wrote and found.
+
+use core::fmt;
+use core::time::Duration;
+#[cfg(feature = "std")]
+use std::io::{self, Read, Write};
+#[cfg(all(feature = "std", not(all(target_arch = "wasm32", target_os = "unknown"))))]
+use std::time::Instant;
+
+#[cfg(feature = "std")]
+use super::strongsum::StrongHash;
+
+/// Counts of the work done by one signature, delta or patch operation.
+///
+/// These correspond to librsync's `rs_stats_t`. The command counts are filled in for
+/// deltas, from the commands written, and for patches, from the commands read. The
+/// block counts are filled in for signatures and deltas.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Statistics {
+    /// Name of the operation: "signature", "delta", "patch", "compose" or "reverse".
+    pub op: &'static str,
+
+    /// Number of LITERAL commands.
+    pub literal_cmds: u64,
+
+    /// Bytes of data carried by LITERAL commands.
+    pub literal_bytes: u64,
+
+    /// Bytes taken by the opcodes and parameters of LITERAL commands.
+    pub literal_cmd_bytes: u64,
+
+    /// Number of COPY commands.
+    pub copy_cmds: u64,
+
+    /// Bytes of the basis referred to by COPY commands.
+    pub copy_bytes: u64,
+
+    /// Bytes taken by the opcodes and parameters of COPY commands.
+    pub copy_cmd_bytes: u64,
+
+    /// Number of times a weak sum matched some block but the strong sum didn't.
+    pub false_matches: u64,
+
+    /// Number of blocks in the signature.
+    pub block_count: u64,
+
+    /// Length of the signature's blocks.
+    pub block_len: u32,
+
+    /// Bytes read from the main input: the basis, new file or delta.
+    pub in_bytes: u64,
+
+    /// Bytes written to the output.
+    pub out_bytes: u64,
+
+    /// Wall-clock time the operation took.
+    pub elapsed: Duration,
+
+    /// Part of `elapsed` spent waiting to read the main input.
+    ///
+    /// This and the other phase times are measured when generating signatures and
+    /// deltas, and are otherwise zero. Where the work is spread over several threads,
+    /// they're summed over the threads, so may add up to more than `elapsed`.
+    pub read_time: Duration,
+
+    /// Part of `elapsed` not spent in any other phase, which is mostly rolling weak sums
+    /// over the input and looking them up in the signature.
+    pub rolling_time: Duration,
+
+    /// Part of `elapsed` spent calculating strong sums.
+    pub strong_time: Duration,
+
+    /// Part of `elapsed` spent waiting to write the output.
+    pub write_time: Duration,
+}
+
+impl Statistics {
+    pub(crate) fn new(op: &'static str) -> Statistics {
+        Statistics {
+            op,
+            .. Statistics::default()
+        }
+    }
+
+    /// Add the command and byte counts from `other`, which covers another part of the
+    /// same operation.
+    #[cfg(any(feature = "parallel",
+              all(feature = "std", not(all(target_arch = "wasm32", target_os = "unknown")))))]
+    pub(crate) fn add(&mut self, other: &Statistics) {
+        self.literal_cmds += other.literal_cmds;
+        self.literal_bytes += other.literal_bytes;
+        self.literal_cmd_bytes += other.literal_cmd_bytes;
+        self.copy_cmds += other.copy_cmds;
+        self.copy_bytes += other.copy_bytes;
+        self.copy_cmd_bytes += other.copy_cmd_bytes;
+        self.false_matches += other.false_matches;
+        self.in_bytes += other.in_bytes;
+        self.out_bytes += other.out_bytes;
+        self.read_time += other.read_time;
+        self.rolling_time += other.rolling_time;
+        self.strong_time += other.strong_time;
+        self.write_time += other.write_time;
+    }
+
+    /// Set the phase times, given the time spent reading and calculating strong sums,
+    /// once `elapsed` and `write_time` are set.
+    #[cfg(feature = "std")]
+    pub(crate) fn set_phases(&mut self, read_time: Duration, strong_time: Duration) {
+        self.read_time = read_time;
+        self.strong_time = strong_time;
+        self.rolling_time = self.elapsed.saturating_sub(read_time + strong_time + self.write_time);
+    }
+}
+
+/// Passes calls through to `inner`, a reader, writer or strong hash, and adds the time
+/// they take to `time`.
+#[cfg(feature = "std")]
+pub(crate) struct Timed<T> {
+    pub(crate) inner: T,
+    pub(crate) time: Duration,
+}
+
+#[cfg(feature = "std")]
+impl<T> Timed<T> {
+    pub(crate) fn new(inner: T) -> Timed<T> {
+        Timed { inner, time: Duration::default() }
+    }
+
+    fn time<U>(&mut self, f: impl FnOnce(&mut T) -> U) -> U {
+        let start = Timer::start();
+        let result = f(&mut self.inner);
+        self.time += start.elapsed();
+        result
+    }
+}
+
+#[cfg(feature = "std")]
+impl<R: Read> Read for Timed<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.time(|r| r.read(buf))
+    }
+}
+
+#[cfg(feature = "std")]
+impl<W: Write> Write for Timed<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.time(|w| w.write(buf))
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.time(|w| w.flush())
+    }
+}
+
+#[cfg(feature = "std")]
+impl<H: StrongHash + ?Sized> StrongHash for Timed<&mut H> {
+    fn digest_len(&self) -> usize {
+        self.inner.digest_len()
+    }
+
+    fn update(&mut self, buf: &[u8]) {
+        self.time(|h| h.update(buf))
+    }
+
+    fn finalize_truncated(&mut self, out: &mut [u8]) {
+        self.time(|h| h.finalize_truncated(out))
+    }
+}
+
+/// Measures `Statistics::elapsed`.
+///
+/// `wasm32-unknown-unknown` has no clock, and `Instant::now()` panics there, so on that
+/// target every operation takes no time.
+#[cfg(feature = "std")]
+pub(crate) struct Timer {
+    #[cfg(not(all(target_arch = "wasm32", target_os = "unknown")))]
+    start: Instant,
+}
+
+#[cfg(feature = "std")]
+impl Timer {
+    pub(crate) fn start() -> Timer {
+        Timer {
+            #[cfg(not(all(target_arch = "wasm32", target_os = "unknown")))]
+            start: Instant::now(),
+        }
+    }
+
+    pub(crate) fn elapsed(&self) -> Duration {
+        #[cfg(not(all(target_arch = "wasm32", target_os = "unknown")))]
+        return self.start.elapsed();
+        #[cfg(all(target_arch = "wasm32", target_os = "unknown"))]
+        return Duration::default();
+    }
+}
+
+impl fmt::Display for Statistics {
+    /// Format in the same way as librsync's `rs_format_stats`.
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} statistics: ", if self.op.is_empty() { "noop" } else { self.op })?;
+        if self.literal_cmds > 0 {
+            write!(f, "literal[{} cmds, {} bytes, {} cmdbytes] ",
+                   self.literal_cmds, self.literal_bytes, self.literal_cmd_bytes)?;
+        }
+        if self.copy_cmds > 0 || self.false_matches > 0 {
+            write!(f, "copy[{} cmds, {} bytes, {} cmdbytes, {} false]",
+                   self.copy_cmds, self.copy_bytes, self.copy_cmd_bytes, self.false_matches)?;
+        }
+        if self.block_count > 0 {
+            write!(f, " signature[{} blocks, {} bytes per block]", self.block_count, self.block_len)?;
+        }
+        let secs = self.elapsed.as_secs_f64().max(0.001);
+        write!(f, " speed[{:.1} MB ({:.1} MB/s) in, {:.1} MB ({:.1} MB/s) out, {:.3} sec]",
+               self.in_bytes as f64 / 1e6, self.in_bytes as f64 / 1e6 / secs,
+               self.out_bytes as f64 / 1e6, self.out_bytes as f64 / 1e6 / secs,
+               self.elapsed.as_secs_f64())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    pub fn format() {
+        let stats = Statistics {
+            literal_cmds: 2,
+            literal_bytes: 300,
+            literal_cmd_bytes: 3,
+            copy_cmds: 1,
+            copy_bytes: 2_000_000,
+            copy_cmd_bytes: 5,
+            in_bytes: 1_000_000,
+            out_bytes: 3_000_000,
+            elapsed: Duration::from_millis(500),
+            .. Statistics::new("patch")
+        };
+        assert_eq!(stats.to_string(),
+                   "patch statistics: literal[2 cmds, 300 bytes, 3 cmdbytes] \
+                    copy[1 cmds, 2000000 bytes, 5 cmdbytes, 0 false] \
+                    speed[1.0 MB (2.0 MB/s) in, 3.0 MB (6.0 MB/s) out, 0.500 sec]");
+        assert!(Statistics::default().to_string().starts_with("noop statistics:  speed["));
+        let stats = Statistics {
+            false_matches: 3,
+            block_count: 10,
+            block_len: 2048,
+            .. Statistics::new("delta")
+        };
+        assert!(stats.to_string().starts_with(
+            "delta statistics: copy[0 cmds, 0 bytes, 0 cmdbytes, 3 false] \
+             signature[10 blocks, 2048 bytes per block] speed["), "{}", stats);
+    }
+}
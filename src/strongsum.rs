@@ -0,0 +1,178 @@
+// rdiff(rust) -- library for network deltas
+// Copyright 2018 Martin Pool.
+
+//! Strong checksums: once a weak sum suggests two blocks might be the same, the strong
+//! sum confirms it.
+//!
+//! The hashes used by the standard signature formats are provided here, and applications
+//! can implement `StrongHash` for their own, for example to key the sums with a secret.
+
+use core::mem;
+
+use blake2::Blake2b;
+use blake2::digest::{FixedOutput, Input, VariableOutput};
+#[cfg(feature = "blake3")]
+use blake3;
+use md4::Md4;
+
+/// Generic strong hash algorithm trait.
+///
+/// One hasher is used for many blocks in turn: `finalize_truncated` produces the digest
+/// of everything passed to `update` since the last finalize, and then resets the state.
+pub trait StrongHash {
+    /// Length of the untruncated digest, in bytes.
+    fn digest_len(&self) -> usize;
+
+    /// Add a slice of bytes to the hash state.
+    fn update(&mut self, buf: &[u8]);
+
+    /// Write the first `out.len()` bytes of the digest into `out`, and reset the state.
+    ///
+    /// `out` must be no longer than `digest_len()`.
+    fn finalize_truncated(&mut self, out: &mut [u8]);
+}
+
+/// Calculate the strong sum of a whole block in one go, truncated to `out.len()`.
+pub fn strong_sum(hash: &mut dyn StrongHash, buf: &[u8], out: &mut [u8]) {
+    hash.update(buf);
+    hash.finalize_truncated(out);
+}
+
+/// MD4, as used by librsync before 1.0.
+#[derive(Clone, Default)]
+pub struct Md4Hash {
+    inner: Md4,
+}
+
+impl StrongHash for Md4Hash {
+    fn digest_len(&self) -> usize {
+        16
+    }
+
+    fn update(&mut self, buf: &[u8]) {
+        self.inner.process(buf);
+    }
+
+    fn finalize_truncated(&mut self, out: &mut [u8]) {
+        let d = mem::take(&mut self.inner).fixed_result();
+        out.copy_from_slice(&d[..out.len()]);
+    }
+}
+
+/// BLAKE2b with a 32-byte output, as used by librsync 1.0 and later.
+#[derive(Clone)]
+pub struct Blake2Hash {
+    inner: Blake2b,
+}
+
+impl Blake2Hash {
+    const DIGEST_LEN: usize = 32;
+
+    fn new_inner() -> Blake2b {
+        Blake2b::new(Blake2Hash::DIGEST_LEN).unwrap()
+    }
+}
+
+impl Default for Blake2Hash {
+    fn default() -> Blake2Hash {
+        Blake2Hash { inner: Blake2Hash::new_inner() }
+    }
+}
+
+impl StrongHash for Blake2Hash {
+    fn digest_len(&self) -> usize {
+        Blake2Hash::DIGEST_LEN
+    }
+
+    fn update(&mut self, buf: &[u8]) {
+        self.inner.process(buf);
+    }
+
+    fn finalize_truncated(&mut self, out: &mut [u8]) {
+        // Like librsync, always calculate the full-length hash and then truncate it: a
+        // shorter BLAKE2 output length would give different bytes.
+        let mut d = [0u8; Blake2Hash::DIGEST_LEN];
+        mem::replace(&mut self.inner, Blake2Hash::new_inner())
+            .variable_result(&mut d).unwrap();
+        out.copy_from_slice(&d[..out.len()]);
+    }
+}
+
+/// BLAKE3, for this library's own `Blake3Sig` format.
+#[cfg(feature = "blake3")]
+#[derive(Clone, Default)]
+pub struct Blake3Hash {
+    inner: blake3::Hasher,
+}
+
+#[cfg(feature = "blake3")]
+impl StrongHash for Blake3Hash {
+    fn digest_len(&self) -> usize {
+        blake3::OUT_LEN
+    }
+
+    fn update(&mut self, buf: &[u8]) {
+        self.inner.update(buf);
+    }
+
+    fn finalize_truncated(&mut self, out: &mut [u8]) {
+        let l = out.len();
+        out.copy_from_slice(&self.inner.finalize().as_bytes()[..l]);
+        self.inner.reset();
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn hash_abc(hash: &mut dyn StrongHash) -> Vec<u8> {
+        let mut out = vec![0; hash.digest_len()];
+        hash.update(b"a");
+        hash.update(b"bc");
+        hash.finalize_truncated(&mut out);
+        out
+    }
+
+    #[test]
+    pub fn md4() {
+        let mut hash = Md4Hash::default();
+        assert_eq!(hash_abc(&mut hash), &[
+            0xa4, 0x48, 0x01, 0x7a, 0xaf, 0x21, 0xd8, 0x52,
+            0x5f, 0xc1, 0x0a, 0xe8, 0x7a, 0xa6, 0x72, 0x9d]);
+    }
+
+    #[test]
+    pub fn blake2() {
+        let mut hash = Blake2Hash::default();
+        assert_eq!(hash_abc(&mut hash), &[
+            0xbd, 0xdd, 0x81, 0x3c, 0x63, 0x42, 0x39, 0x72,
+            0x31, 0x71, 0xef, 0x3f, 0xee, 0x98, 0x57, 0x9b,
+            0x94, 0x96, 0x4e, 0x3b, 0xb1, 0xcb, 0x3e, 0x42,
+            0x72, 0x62, 0xc8, 0xc0, 0x68, 0xd5, 0x23, 0x19]);
+    }
+
+    #[cfg(feature = "blake3")]
+    #[test]
+    pub fn blake3() {
+        let mut hash = Blake3Hash::default();
+        assert_eq!(hash_abc(&mut hash), blake3::hash(b"abc").as_bytes());
+    }
+
+    /// Finalizing resets the state, and truncation takes a prefix of the full digest.
+    #[test]
+    pub fn reset_and_truncate() {
+        let mut hash = Blake2Hash::default();
+        let full = hash_abc(&mut hash);
+        let mut short = [0u8; 8];
+        strong_sum(&mut hash, b"abc", &mut short);
+        assert_eq!(short, &full[..8]);
+        assert_eq!(hash_abc(&mut Md4Hash::default())[..5], {
+            let mut md4 = Md4Hash::default();
+            let mut out = [0u8; 5];
+            strong_sum(&mut md4, b"xyz", &mut out);
+            strong_sum(&mut md4, b"abc", &mut out);
+            out
+        });
+    }
+}
@@ -0,0 +1,32 @@
+// rdiff(rust) -- library for network deltas
+// Copyright 2018 Martin Pool.
+
+//! Test data shared by the unit tests.
+
+use alloc::vec::Vec;
+
+/// A deterministic, not-very-repetitive test pattern.
+pub fn pattern(len: usize) -> Vec<u8> {
+    pattern_seeded(len, 0)
+}
+
+/// The same pattern as `pattern`, shifted by `seed`, so that different seeds give data
+/// that mostly doesn't match.
+pub fn pattern_seeded(len: usize, seed: usize) -> Vec<u8> {
+    (0..len).map(|i| ((i * 7 + i / 13 + seed) % 251) as u8).collect()
+}
+
+/// Data that doesn't repeat, so that each block matches only itself.
+pub fn random(len: usize) -> Vec<u8> {
+    random_seeded(len, 1)
+}
+
+/// Pseudo-random data with no repeating structure, from a linear congruential
+/// generator started at `seed`.
+pub fn random_seeded(len: usize, seed: u64) -> Vec<u8> {
+    let mut x = seed;
+    (0..len).map(|_| {
+        x = x.wrapping_mul(6_364_136_223_846_793_005).wrapping_add(1_442_695_040_888_963_407);
+        (x >> 56) as u8
+    }).collect()
+}
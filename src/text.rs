@@ -0,0 +1,378 @@
+// rdiff(rust) -- library for network deltas
+// Copyright 2018 Martin Pool.
+
+//! Convert deltas to and from text, with one command on each line.
+//!
+//! The text can be read and edited by hand, and turned back into a delta, to make test
+//! cases or to reproduce a problem with a delta from elsewhere. For example:
+//!
+//! ```text
+//! format Delta
+//! COPY 0 2048
+//! LITERAL "hello\n"
+//! COPY 4096 1024
+//! END
+//! ```
+//!
+//! The first line names the `DeltaFormat`. A `DeltaFormat::BasisCheckedDelta` then has
+//! a `basis-id` line, with the signature format, block length, strong sum length, and
+//! hash in hex. In a `DeltaFormat::MultiBasisDelta`, `BASIS n` selects the basis that
+//! the following COPYs refer to. The whole-file checksum of a
+//! `DeltaFormat::ChecksummedDelta` follows END, as `checksum` and then its hex.
+//!
+//! Literal data is quoted, with printable ASCII as it is, and escapes `\"`, `\\`, `\n`,
+//! `\r`, `\t`, and `\xNN` for anything else. Blank lines, and lines starting with `#`,
+//! are ignored.
+
+use std::io::{BufRead, BufReader, Read, Write};
+
+use super::delta::{BasisId, CommandHeader, DeltaReader, DeltaWriter, BASIS_HASH_LEN,
+                   CHECKSUM_LEN};
+use super::error::{Error, Result};
+use super::magic::{DeltaFormat, SignatureFormat};
+use super::mksum::SignatureOptions;
+
+/// Magic numbers of the delta formats that can be named in text.
+const DELTA_MAGICS: &[u32] = &[0x72730236, 0x72738236, 0x72738336, 0x72738436, 0x72738536];
+
+/// Magic numbers of the signature formats that can be named in a `basis-id`.
+const SIGNATURE_MAGICS: &[u32] =
+    &[0x72730136, 0x72730146, 0x72730137, 0x72730147, 0x72738147, 0x72738247];
+
+/// Write the commands of `delta` as text.
+///
+/// Deltas in any format are read. The literal data is held in memory a command at a
+/// time.
+pub fn delta_to_text<D: Read + ?Sized, W: Write + ?Sized>(delta: &mut D, text: &mut W)
+    -> Result<()> {
+    let mut commands = DeltaReader::new(BufReader::new(delta))?;
+    commands.set_basis_count(u64::MAX);
+    writeln!(text, "format {:?}", commands.format())?;
+    if let Some(id) = commands.basis_id() {
+        writeln!(text, "basis-id {:?} {} {} {}", id.options.magic, id.options.block_len,
+                 id.options.strong_len, hex(&id.hash))?;
+    }
+    let mut basis = 0;
+    loop {
+        match commands.read_header()? {
+            CommandHeader::Copy { offset, len } => {
+                if commands.basis() != basis {
+                    basis = commands.basis();
+                    writeln!(text, "BASIS {}", basis)?;
+                }
+                writeln!(text, "COPY {} {}", offset, len)?;
+            }
+            CommandHeader::Literal { len } => {
+                let mut data = Vec::new();
+                commands.copy_literal(len, &mut data)?;
+                writeln!(text, "LITERAL {}", quote(&data))?;
+            }
+            CommandHeader::End => break,
+        }
+    }
+    writeln!(text, "END")?;
+    if let Some(checksum) = commands.checksum() {
+        writeln!(text, "checksum {}", hex(checksum))?;
+    }
+    text.flush()?;
+    Ok(())
+}
+
+/// Read the text of a delta, as written by `delta_to_text`, and write the delta.
+///
+/// The commands are encoded as `DeltaWriter` writes them: adjacent COPYs are merged, and
+/// numbers take as few bytes as they can, so the delta may not be byte for byte the
+/// one the text came from. `Error::CorruptDelta` is returned, giving the line number, if
+/// the text can't be understood.
+pub fn delta_from_text<T: Read + ?Sized, W: Write + ?Sized>(text: &mut T, delta: &mut W)
+    -> Result<()> {
+    let mut lines = Lines { lines: BufReader::new(text).lines(), number: 0 };
+    let line = lines.next()?.ok_or_else(|| lines.error("the text is empty"))?;
+    let format = match words(&line)[..] {
+        ["format", name] => DELTA_MAGICS.iter()
+            .filter_map(|&m| DeltaFormat::from_magic(m))
+            .find(|f| format!("{:?}", f) == name)
+            .ok_or_else(|| lines.error(&format!("unknown delta format {:?}", name)))?,
+        _ => return Err(lines.error("the text doesn't start with the delta format")),
+    };
+    let mut w = if format == DeltaFormat::BasisCheckedDelta {
+        let line = lines.next()?.unwrap_or_default();
+        let id = match words(&line)[..] {
+            ["basis-id", magic, block_len, strong_len, hash] => BasisId {
+                options: SignatureOptions {
+                    magic: SIGNATURE_MAGICS.iter()
+                        .filter_map(|&m| SignatureFormat::from_magic(m))
+                        .find(|f| format!("{:?}", f) == magic)
+                        .ok_or_else(|| lines.error(
+                            &format!("unknown signature format {:?}", magic)))?,
+                    block_len: lines.number(block_len)?,
+                    strong_len: lines.number(strong_len)?,
+                    seed: 0,
+                },
+                hash: lines.hex::<BASIS_HASH_LEN>(hash)?,
+            },
+            _ => return Err(lines.error("a BasisCheckedDelta needs a basis-id")),
+        };
+        DeltaWriter::with_basis_id(delta, &id)?
+    } else {
+        DeltaWriter::with_format(delta, format)?
+    };
+    let mut basis = 0;
+    // Length of the new file so far, which must be countable, as when reading a delta.
+    let mut new_len: u64 = 0;
+    loop {
+        let line = lines.next()?.ok_or_else(|| lines.error("the text ends before END"))?;
+        let too_long = || lines.error("the new file is too long to count");
+        match words(&line)[..] {
+            ["COPY", offset, len] => {
+                let (offset, len): (u64, u64) = (lines.number(offset)?, lines.number(len)?);
+                if offset.checked_add(len).is_none() {
+                    return Err(lines.error("the COPY ends beyond the largest offset"));
+                }
+                new_len = new_len.checked_add(len).ok_or_else(too_long)?;
+                w.copy_from(basis, offset, len)?;
+            }
+            ["BASIS", b] => basis = lines.number(b)?,
+            ["LITERAL", ..] => {
+                let quoted = line.trim().strip_prefix("LITERAL").unwrap_or_default().trim();
+                let data = unquote(quoted).map_err(|e| lines.error(e))?;
+                new_len = new_len.checked_add(data.len() as u64).ok_or_else(too_long)?;
+                w.literal(&data)?;
+            }
+            ["END"] => break,
+            _ => return Err(lines.error(&format!("unknown command {:?}", line.trim()))),
+        }
+    }
+    match lines.next()? {
+        None => (),
+        Some(line) => match words(&line)[..] {
+            ["checksum", checksum] => w.set_checksum(lines.hex::<CHECKSUM_LEN>(checksum)?),
+            _ => return Err(lines.error("only a checksum can follow END")),
+        },
+    }
+    if lines.next()?.is_some() {
+        return Err(lines.error("only a checksum can follow END"));
+    }
+    w.finish()?;
+    Ok(())
+}
+
+/// The lines of the text that aren't blank or comments, counted for error messages.
+struct Lines<B: BufRead> {
+    lines: std::io::Lines<B>,
+    number: usize,
+}
+
+impl<B: BufRead> Lines<B> {
+    fn next(&mut self) -> Result<Option<String>> {
+        for line in self.lines.by_ref() {
+            let line = line?;
+            self.number += 1;
+            let trimmed = line.trim();
+            if !trimmed.is_empty() && !trimmed.starts_with('#') {
+                return Ok(Some(line));
+            }
+        }
+        Ok(None)
+    }
+
+    fn error(&self, message: &str) -> Error {
+        Error::CorruptDelta(format!("line {} of the text: {}", self.number, message))
+    }
+
+    fn number<N: std::str::FromStr>(&self, word: &str) -> Result<N> {
+        word.parse().map_err(|_| self.error(&format!("bad number {:?}", word)))
+    }
+
+    fn hex<const N: usize>(&self, word: &str) -> Result<[u8; N]> {
+        let bad = || self.error(&format!("expected {} bytes of hex, not {:?}", N, word));
+        if word.len() != 2 * N {
+            return Err(bad());
+        }
+        let mut bytes = [0; N];
+        for (i, b) in bytes.iter_mut().enumerate() {
+            *b = u8::from_str_radix(word.get(2 * i..2 * i + 2).ok_or_else(bad)?, 16)
+                .map_err(|_| bad())?;
+        }
+        Ok(bytes)
+    }
+}
+
+fn words(line: &str) -> Vec<&str> {
+    line.split_whitespace().collect()
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+/// Quote literal data, escaping anything but printable ASCII.
+fn quote(data: &[u8]) -> String {
+    let mut s = String::with_capacity(data.len() + 2);
+    s.push('"');
+    for &b in data {
+        match b {
+            b'"' => s.push_str("\\\""),
+            b'\\' => s.push_str("\\\\"),
+            b'\n' => s.push_str("\\n"),
+            b'\r' => s.push_str("\\r"),
+            b'\t' => s.push_str("\\t"),
+            0x20..=0x7e => s.push(b as char),
+            _ => s.push_str(&format!("\\x{:02x}", b)),
+        }
+    }
+    s.push('"');
+    s
+}
+
+/// Read literal data quoted by `quote`.
+fn unquote(quoted: &str) -> std::result::Result<Vec<u8>, &'static str> {
+    let inner = quoted.strip_prefix('"').and_then(|q| q.strip_suffix('"'))
+        .filter(|_| quoted.len() >= 2)
+        .ok_or("literal data must be in double quotes")?;
+    let mut data = Vec::with_capacity(inner.len());
+    let mut bytes = inner.bytes();
+    while let Some(b) = bytes.next() {
+        let b = match b {
+            b'\\' => match bytes.next() {
+                Some(b'"') => b'"',
+                Some(b'\\') => b'\\',
+                Some(b'n') => b'\n',
+                Some(b'r') => b'\r',
+                Some(b't') => b'\t',
+                Some(b'x') => {
+                    let digits = [bytes.next().unwrap_or(0), bytes.next().unwrap_or(0)];
+                    std::str::from_utf8(&digits).ok()
+                        .and_then(|d| u8::from_str_radix(d, 16).ok())
+                        .ok_or("bad \\x escape in literal data")?
+                }
+                _ => return Err("bad escape in literal data"),
+            },
+            b'"' => return Err("unescaped quote in literal data"),
+            b => b,
+        };
+        data.push(b);
+    }
+    Ok(data)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use super::super::delta::{OP_COPY_N8_N8, OP_END};
+    use super::super::memory;
+    use super::super::test_util::random;
+
+    fn to_text(delta: &[u8]) -> String {
+        let mut text = Vec::new();
+        delta_to_text(&mut &delta[..], &mut text).unwrap();
+        String::from_utf8(text).unwrap()
+    }
+
+    fn from_text(text: &str) -> Result<Vec<u8>> {
+        let mut delta = Vec::new();
+        delta_from_text(&mut text.as_bytes(), &mut delta)?;
+        Ok(delta)
+    }
+
+    #[test]
+    pub fn round_trip() {
+        let old = random(100_000);
+        let mut new = old[..30_720].to_vec();
+        new.extend_from_slice(b"\"quoted\" \\ \n\t\x00\xff text");
+        new.extend_from_slice(&old[51_200..]);
+        let delta = memory::delta_of(&memory::signature_of(&old), &new).unwrap();
+        let text = to_text(&delta);
+        assert_eq!(text, "format Delta\nCOPY 0 30720\n\
+                          LITERAL \"\\\"quoted\\\" \\\\ \\n\\t\\x00\\xff text\"\n\
+                          COPY 51200 48800\nEND\n");
+        assert_eq!(from_text(&text).unwrap(), delta);
+    }
+
+    #[test]
+    pub fn other_formats() {
+        let mut w = DeltaWriter::with_format(Vec::new(), DeltaFormat::MultiBasisDelta).unwrap();
+        w.copy_from(0, 500, 100).unwrap();
+        w.copy_from(2, 0, 100).unwrap();
+        w.literal(b"x").unwrap();
+        let multi = w.finish().unwrap();
+        let text = to_text(&multi);
+        assert_eq!(text, "format MultiBasisDelta\nCOPY 500 100\nBASIS 2\nCOPY 0 100\n\
+                          LITERAL \"x\"\nEND\n");
+        assert_eq!(from_text(&text).unwrap(), multi);
+
+        let mut w = DeltaWriter::with_format(Vec::new(), DeltaFormat::ChecksummedDelta).unwrap();
+        w.set_checksum([7; CHECKSUM_LEN]);
+        let checksummed = w.finish().unwrap();
+        let text = to_text(&checksummed);
+        assert!(text.ends_with(&format!("END\nchecksum {}\n", "07".repeat(32))), "{}", text);
+        assert_eq!(from_text(&text).unwrap(), checksummed);
+
+        let sig = memory::signature_of(b"some basis");
+        let id = BasisId::of(&super::super::signature::Signature::read_from(
+            &mut sig.as_slice()).unwrap());
+        let checked = DeltaWriter::with_basis_id(Vec::new(), &id).unwrap().finish().unwrap();
+        let text = to_text(&checked);
+        assert!(text.starts_with("format BasisCheckedDelta\nbasis-id Blake2Sig 2048 32 "), "{}",
+                text);
+        assert_eq!(from_text(&text).unwrap(), checked);
+    }
+
+    /// Text written by hand, with comments, is encoded in the shortest form.
+    #[test]
+    pub fn hand_written() {
+        let delta = from_text("# a test case\nformat Delta\n\n  COPY 0 10\nCOPY 10 20\n\
+                               LITERAL \"a\\x62c\"\nEND\n").unwrap();
+        let mut w = DeltaWriter::new(Vec::new()).unwrap();
+        w.copy(0, 30).unwrap();
+        w.literal(b"abc").unwrap();
+        assert_eq!(delta, w.finish().unwrap());
+    }
+
+    /// A delta whose lengths add up to more than fit in a u64 is corrupt, rather than
+    /// overflowing.
+    #[test]
+    pub fn overflowing_delta() {
+        let mut delta = b"rs\x026".to_vec();
+        for _ in 0..2 {
+            delta.push(OP_COPY_N8_N8);
+            delta.extend_from_slice(&0u64.to_be_bytes());
+            delta.extend_from_slice(&(1u64 << 63).to_be_bytes());
+        }
+        delta.push(OP_END);
+        let err = delta_to_text(&mut delta.as_slice(), &mut Vec::new()).unwrap_err();
+        assert!(matches!(err, Error::CorruptDelta(_)), "{:?}", err);
+    }
+
+    #[test]
+    pub fn errors() {
+        for (text, message) in &[
+            ("", "line 0 of the text: the text is empty"),
+            ("format Nonsense\n", "line 1 of the text: unknown delta format \"Nonsense\""),
+            ("COPY 1 2\n", "line 1 of the text: the text doesn't start with the delta format"),
+            ("format Delta\nCOPY 1\n", "line 2 of the text: unknown command \"COPY 1\""),
+            ("format Delta\nCOPY x 2\n", "line 2 of the text: bad number \"x\""),
+            ("format Delta\nLITERAL abc\n",
+             "line 2 of the text: literal data must be in double quotes"),
+            ("format Delta\nLITERAL \"\\q\"\n", "line 2 of the text: bad escape in literal data"),
+            ("format Delta\nCOPY 1 2\n", "line 2 of the text: the text ends before END"),
+            ("format Delta\nEND\nCOPY 1 2\n", "line 3 of the text: only a checksum can follow END"),
+            ("format BasisCheckedDelta\nEND\n",
+             "line 2 of the text: a BasisCheckedDelta needs a basis-id"),
+            ("format Delta\nCOPY 18446744073709551615 1\n",
+             "line 2 of the text: the COPY ends beyond the largest offset"),
+            ("format Delta\nCOPY 0 9223372036854775808\nCOPY 0 9223372036854775808\n",
+             "line 3 of the text: the new file is too long to count"),
+        ] {
+            match from_text(text) {
+                Err(Error::CorruptDelta(m)) => assert_eq!(&m, message, "{:?}", text),
+                r => panic!("unexpected {:?} from {:?}", r, text),
+            }
+        }
+        // A checksum must be given for a checksummed delta.
+        assert!(matches!(from_text("format ChecksummedDelta\nEND\n"),
+                         Err(Error::InvalidOptions(_))));
+        assert!(matches!(from_text("format Delta\nBASIS 1\nCOPY 0 1\nEND\n"),
+                         Err(Error::InvalidOptions(_))));
+    }
+}
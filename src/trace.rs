@@ -0,0 +1,30 @@
+// rdiff(rust) -- library for network deltas
+// Copyright 2018 Martin Pool.
+
+//! Optional instrumentation.
+//!
+//! With the `tracing` feature, signature generation and delta search run in `debug`
+//! spans, and report what they found in events: block counts, weak sum hit rates, and
+//! each command written at `trace` level. Without it, these macros expand to nothing.
+
+#[cfg(feature = "tracing")]
+macro_rules! debug_event {
+    ($($arg:tt)*) => { tracing::debug!($($arg)*) }
+}
+
+// Without `std` there's nothing that reports events at `debug` level.
+#[cfg(not(feature = "tracing"))]
+#[allow(unused_macros)]
+macro_rules! debug_event {
+    ($($arg:tt)*) => {}
+}
+
+#[cfg(feature = "tracing")]
+macro_rules! trace_event {
+    ($($arg:tt)*) => { tracing::trace!($($arg)*) }
+}
+
+#[cfg(not(feature = "tracing"))]
+macro_rules! trace_event {
+    ($($arg:tt)*) => {}
+}
@@ -0,0 +1,435 @@
+// rdiff(rust) -- library for network deltas
+// Copyright 2018 Martin Pool.
+
+//! Signatures, deltas and patches of whole directory trees.
+//!
+//! `signature_dir` walks the old tree and writes a manifest, holding the relative path
+//! and size of each file along with its signature. `delta_dir` walks the new tree and,
+//! using the manifest, writes a delta archive with a delta for each file. `patch_dir`
+//! applies the archive to the old tree to rebuild the new one.
+//!
+//! Only directories and regular files are included: symlinks and special files are
+//! skipped. Paths are stored relative to the top of the tree, with `/` separators, and
+//! must be UTF-8. Entries are in sorted order, so the same tree gives the same manifest.
+//!
+//! The manifest starts with `TREE_MANIFEST_MAGIC`, and the archive with
+//! `TREE_DELTA_MAGIC`. Each then holds a series of entries: a one-byte kind, a 4-byte
+//! path length, and the path. For a file in a manifest, that's followed by its 8-byte
+//! size, and the 8-byte length and contents of its signature; for a file in an archive,
+//! by the 8-byte length and contents of its delta. A zero byte ends the series. All the
+//! integers are big-endian.
+
+use std::collections::BTreeMap;
+use std::fs::{self, File};
+use std::io::{self, BufReader, BufWriter, ErrorKind, Read, Write};
+use std::path::{Component, Path, PathBuf};
+
+use byteorder::{BigEndian, ReadBytesExt, WriteBytesExt};
+
+use super::error::{Error, Result};
+use super::files::{default_io, open_input, write_atomically};
+use super::magic::{TREE_DELTA_MAGIC, TREE_MANIFEST_MAGIC};
+use super::mkdelta::generate_delta;
+use super::mksum::{generate_signature, SignatureOptions};
+use super::patch::apply_patch;
+use super::signature::Signature;
+use super::stats::{Statistics, Timer};
+
+const END: u8 = 0;
+const DIR: u8 = 1;
+const FILE: u8 = 2;
+
+/// Paths longer than this are taken to be corruption.
+const MAX_PATH_LEN: u32 = 64 << 10;
+
+/// One entry from a manifest.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ManifestEntry {
+    /// A directory, which may be empty.
+    Dir { path: String },
+
+    /// A regular file, with its length and signature.
+    File { path: String, size: u64, signature: Signature },
+}
+
+/// A directory or file found in a tree, by its relative path.
+enum Found {
+    Dir(String),
+    File(String, PathBuf),
+}
+
+/// List the directories and regular files under `top`, in sorted order.
+fn walk(top: &Path) -> Result<Vec<Found>> {
+    let mut found = Vec::new();
+    walk_into(top, "", &mut found)?;
+    Ok(found)
+}
+
+fn walk_into(dir: &Path, prefix: &str, found: &mut Vec<Found>) -> Result<()> {
+    let mut children = Vec::new();
+    for entry in fs::read_dir(dir)? {
+        let entry = entry?;
+        let name = entry.file_name().into_string().map_err(|name| io::Error::new(
+            ErrorKind::InvalidData, format!("{:?} in {:?} isn't UTF-8", name, dir)))?;
+        children.push((name, entry.path(), entry.file_type()?));
+    }
+    children.sort_by(|a, b| a.0.cmp(&b.0));
+    for (name, path, file_type) in children {
+        let rel = format!("{}{}", prefix, name);
+        if file_type.is_dir() {
+            found.push(Found::Dir(rel.clone()));
+            walk_into(&path, &format!("{}/", rel), found)?;
+        } else if file_type.is_file() {
+            found.push(Found::File(rel, path));
+        }
+    }
+    Ok(())
+}
+
+fn write_entry_header(out: &mut dyn Write, kind: u8, path: &str) -> Result<()> {
+    out.write_u8(kind)?;
+    out.write_u32::<BigEndian>(path.len() as u32)?;
+    out.write_all(path.as_bytes())?;
+    Ok(())
+}
+
+/// Read an entry's kind and path, returning `None` at the end of the entries.
+///
+/// `corrupt` makes the error for an impossible entry.
+fn read_entry_header(r: &mut dyn Read, corrupt: fn(String) -> Error)
+    -> Result<Option<(u8, String)>> {
+    let kind = r.read_u8()?;
+    if kind == END {
+        return Ok(None);
+    } else if kind != DIR && kind != FILE {
+        return Err(corrupt(format!("unknown entry kind {}", kind)));
+    }
+    let len = r.read_u32::<BigEndian>()?;
+    if len > MAX_PATH_LEN {
+        return Err(corrupt(format!("path length {} is too long", len)));
+    }
+    let mut path = vec![0; len as usize];
+    r.read_exact(&mut path)?;
+    let path = String::from_utf8(path)
+        .map_err(|_| corrupt("a path isn't UTF-8".to_owned()))?;
+    Ok(Some((kind, path)))
+}
+
+/// Check that a path from an archive stays within the tree, returning it as a relative
+/// path.
+fn safe_path(path: &str) -> Result<PathBuf> {
+    let rel = PathBuf::from(path);
+    if path.is_empty() || !rel.components().all(|c| matches!(c, Component::Normal(_))) {
+        return Err(Error::CorruptDelta(format!("unsafe path {:?}", path)));
+    }
+    Ok(rel)
+}
+
+/// Check that neither `rel` under `top` nor any directory on the way to it is a symlink,
+/// which could lead outside the tree.
+///
+/// `path` is the path from the archive, for the error.
+fn check_no_symlinks(top: &Path, rel: &Path, path: &str) -> Result<()> {
+    let mut p = top.to_path_buf();
+    for component in rel.components() {
+        p.push(component);
+        match fs::symlink_metadata(&p) {
+            Ok(m) if m.file_type().is_symlink() => {
+                return Err(Error::CorruptDelta(format!("path {:?} goes through a symlink",
+                                                       path)));
+            }
+            Ok(_) => (),
+            Err(ref e) if e.kind() == ErrorKind::NotFound => break,
+            Err(e) => return Err(e.into()),
+        }
+    }
+    Ok(())
+}
+
+/// Add up the counts from the signature, delta or patch of each file.
+fn add_file_stats(total: &mut Statistics, file: &Statistics) {
+    total.add(file);
+    total.block_count += file.block_count;
+    total.block_len = total.block_len.max(file.block_len);
+}
+
+/// Walk the tree under `dir`, writing a manifest with the signature of each file.
+///
+/// The statistics add up the signatures of all the files.
+pub fn signature_dir<W: Write + ?Sized>(dir: &Path, options: &SignatureOptions,
+                                        manifest: &mut W) -> Result<Statistics> {
+    let start = Timer::start();
+    let mut stats = Statistics::new("signature");
+    let io = default_io();
+    let out = &mut BufWriter::new(manifest);
+    out.write_u32::<BigEndian>(TREE_MANIFEST_MAGIC)?;
+    for found in walk(dir)? {
+        match found {
+            Found::Dir(rel) => write_entry_header(out, DIR, &rel)?,
+            Found::File(rel, path) => {
+                let mut basis = open_input(&path, &io)?;
+                let mut sig = Vec::new();
+                let file_stats = generate_signature(&mut basis, options, &mut sig)?;
+                write_entry_header(out, FILE, &rel)?;
+                out.write_u64::<BigEndian>(file_stats.in_bytes)?;
+                out.write_u64::<BigEndian>(sig.len() as u64)?;
+                out.write_all(&sig)?;
+                add_file_stats(&mut stats, &file_stats);
+            }
+        }
+    }
+    out.write_u8(END)?;
+    out.flush()?;
+    stats.elapsed = start.elapsed();
+    Ok(stats)
+}
+
+/// Read a manifest written by `signature_dir`.
+///
+/// `Error::BadMagic` is returned if it's not a manifest, and `Error::CorruptSignature`
+/// if it holds a nonsensical entry.
+pub fn read_manifest<R: Read + ?Sized>(manifest: &mut R) -> Result<Vec<ManifestEntry>> {
+    let r = &mut BufReader::new(manifest);
+    let magic = r.read_u32::<BigEndian>()?;
+    if magic != TREE_MANIFEST_MAGIC {
+        return Err(Error::BadMagic(magic));
+    }
+    let mut entries = Vec::new();
+    while let Some((kind, path)) = read_entry_header(r, Error::CorruptSignature)? {
+        if kind == DIR {
+            entries.push(ManifestEntry::Dir { path });
+            continue;
+        }
+        let size = r.read_u64::<BigEndian>()?;
+        let sig_len = r.read_u64::<BigEndian>()?;
+        let mut sig = r.take(sig_len);
+        let signature = Signature::read_from(&mut sig)?;
+        if sig.limit() > 0 {
+            return Err(io::Error::new(ErrorKind::UnexpectedEof,
+                                      "manifest ended within a signature").into());
+        }
+        entries.push(ManifestEntry::File { path, size, signature });
+    }
+    Ok(entries)
+}
+
+/// Walk the new tree under `dir`, writing an archive with a delta for each file from the
+/// file at the same path in the manifest.
+///
+/// Files that aren't in the manifest are sent whole, as deltas from an empty file. Each
+/// delta is held in memory while it's generated. The statistics add up the deltas of all
+/// the files.
+pub fn delta_dir<R: Read + ?Sized, W: Write + ?Sized>(manifest: &mut R, dir: &Path,
+                                                      archive: &mut W) -> Result<Statistics> {
+    let start = Timer::start();
+    let mut stats = Statistics::new("delta");
+    let io = default_io();
+    let mut signatures = BTreeMap::new();
+    for entry in read_manifest(manifest)? {
+        if let ManifestEntry::File { path, signature, .. } = entry {
+            signatures.insert(path, signature);
+        }
+    }
+    let empty = Signature::new(&SignatureOptions::default());
+    let out = &mut BufWriter::new(archive);
+    out.write_u32::<BigEndian>(TREE_DELTA_MAGIC)?;
+    for found in walk(dir)? {
+        match found {
+            Found::Dir(rel) => write_entry_header(out, DIR, &rel)?,
+            Found::File(rel, path) => {
+                let sig = signatures.get(&rel).unwrap_or(&empty);
+                let mut new = open_input(&path, &io)?;
+                let mut delta = Vec::new();
+                add_file_stats(&mut stats, &generate_delta(sig, &mut new, &mut delta)?);
+                write_entry_header(out, FILE, &rel)?;
+                out.write_u64::<BigEndian>(delta.len() as u64)?;
+                out.write_all(&delta)?;
+            }
+        }
+    }
+    out.write_u8(END)?;
+    out.flush()?;
+    stats.elapsed = start.elapsed();
+    Ok(stats)
+}
+
+/// Apply an archive written by `delta_dir` to the old tree under `basis_dir`, writing
+/// the new tree under `out_dir`.
+///
+/// `out_dir` is created if need be. Each file is written atomically, so `out_dir` may be
+/// `basis_dir` itself, to update it in place; but in that case files that are no longer
+/// in the new tree are left as they are.
+///
+/// `Error::BadMagic` is returned if it's not a delta archive, and `Error::CorruptDelta`
+/// if an entry is nonsensical, or has a path that would lead outside the tree, including
+/// through a symlink already in either tree. The
+/// statistics add up the patches of all the files. Blocks of zeros in the new files are
+/// left as holes.
+pub fn patch_dir<R: Read + ?Sized>(basis_dir: &Path, archive: &mut R, out_dir: &Path)
+    -> Result<Statistics> {
+    let start = Timer::start();
+    let mut stats = Statistics::new("patch");
+    let io = default_io();
+    let r = &mut BufReader::new(archive);
+    let magic = r.read_u32::<BigEndian>()?;
+    if magic != TREE_DELTA_MAGIC {
+        return Err(Error::BadMagic(magic));
+    }
+    fs::create_dir_all(out_dir)?;
+    while let Some((kind, path)) = read_entry_header(r, Error::CorruptDelta)? {
+        let rel = safe_path(&path)?;
+        check_no_symlinks(out_dir, &rel, &path)?;
+        if kind == DIR {
+            fs::create_dir_all(out_dir.join(&rel))?;
+            continue;
+        }
+        let delta_len = r.read_u64::<BigEndian>()?;
+        check_no_symlinks(basis_dir, &rel, &path)?;
+        let basis_path = basis_dir.join(&rel);
+        let out_path = out_dir.join(&rel);
+        if let Some(parent) = out_path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        let mut delta = r.take(delta_len);
+        let file_stats = write_atomically(&out_path, &io, true, |out| {
+            let file_stats = match File::open(&basis_path) {
+                Ok(basis) => apply_patch(&mut BufReader::new(basis), &mut delta, out)?,
+                Err(ref e) if e.kind() == ErrorKind::NotFound => {
+                    apply_patch(&mut io::Cursor::new(&[]), &mut delta, out)?
+                }
+                Err(e) => return Err(e.into()),
+            };
+            if file_stats.in_bytes != delta_len {
+                return Err(Error::CorruptDelta(format!(
+                    "the delta for {:?} ends before its {} byte entry", path, delta_len)));
+            }
+            Ok(file_stats)
+        })?;
+        add_file_stats(&mut stats, &file_stats);
+    }
+    stats.elapsed = start.elapsed();
+    Ok(stats)
+}
+
+#[cfg(test)]
+mod test {
+    use tempfile::TempDir;
+
+    use super::*;
+    use super::super::test_util::pattern_seeded;
+
+    fn write(dir: &Path, rel: &str, data: &[u8]) {
+        let path = dir.join(rel);
+        fs::create_dir_all(path.parent().unwrap()).unwrap();
+        fs::write(path, data).unwrap();
+    }
+
+    /// The contents of a tree, with `None` for directories.
+    fn contents(top: &Path) -> Vec<(String, Option<Vec<u8>>)> {
+        walk(top).unwrap().into_iter().map(|f| match f {
+            Found::Dir(rel) => (rel, None),
+            Found::File(rel, path) => (rel, Some(fs::read(path).unwrap())),
+        }).collect()
+    }
+
+    #[test]
+    pub fn round_trip() {
+        let old = TempDir::new().unwrap();
+        write(old.path(), "a", &pattern_seeded(50_000, 0));
+        write(old.path(), "sub/b", &pattern_seeded(20_000, 1));
+        write(old.path(), "sub/gone", b"deleted");
+        fs::create_dir(old.path().join("empty")).unwrap();
+        let new = TempDir::new().unwrap();
+        let mut a = pattern_seeded(50_000, 0);
+        a[20_000..20_100].copy_from_slice(&pattern_seeded(100, 5));
+        write(new.path(), "a", &a);
+        write(new.path(), "sub/b", &pattern_seeded(20_000, 1));
+        write(new.path(), "sub/deeper/c", b"a new file");
+        fs::create_dir(new.path().join("also-empty")).unwrap();
+
+        let mut manifest = Vec::new();
+        let sig_stats = signature_dir(old.path(), &SignatureOptions::default(), &mut manifest)
+            .unwrap();
+        assert_eq!(sig_stats.in_bytes, 70_007);
+        let entries = read_manifest(&mut &manifest[..]).unwrap();
+        let paths: Vec<_> = entries.iter().map(|e| match e {
+            ManifestEntry::Dir { path } => path.clone(),
+            ManifestEntry::File { path, .. } => path.clone(),
+        }).collect();
+        assert_eq!(paths, ["a", "empty", "sub", "sub/b", "sub/gone"]);
+
+        let mut archive = Vec::new();
+        delta_dir(&mut &manifest[..], new.path(), &mut archive).unwrap();
+        assert!(archive.len() < 3000, "{}", archive.len());
+
+        let out = TempDir::new().unwrap();
+        patch_dir(old.path(), &mut &archive[..], out.path()).unwrap();
+        assert_eq!(contents(out.path()), contents(new.path()));
+
+        // Patching the old tree in place updates the files, but leaves the deleted one.
+        patch_dir(old.path(), &mut &archive[..], old.path()).unwrap();
+        assert_eq!(fs::read(old.path().join("a")).unwrap(), a);
+        assert!(old.path().join("sub/gone").exists());
+    }
+
+    #[test]
+    pub fn unsafe_paths() {
+        for path in &["../escape", "/abs", "a/../../b", ""] {
+            let mut archive = TREE_DELTA_MAGIC.to_be_bytes().to_vec();
+            write_entry_header(&mut archive, DIR, path).unwrap();
+            archive.push(END);
+            let out = TempDir::new().unwrap();
+            let err = patch_dir(out.path(), &mut &archive[..], out.path()).unwrap_err();
+            assert!(matches!(err, Error::CorruptDelta(_)), "{:?}: {:?}", path, err);
+        }
+    }
+
+    /// Paths through symlinks in either tree are refused, rather than followed outside it.
+    #[cfg(unix)]
+    #[test]
+    pub fn symlinked_directories() {
+        use std::os::unix::fs::symlink;
+
+        let outside = TempDir::new().unwrap();
+        write(outside.path(), "x", b"outside");
+        let tree = TempDir::new().unwrap();
+        symlink(outside.path(), tree.path().join("link")).unwrap();
+        let empty = TempDir::new().unwrap();
+        for (kind, path) in &[(FILE, "link/x"), (FILE, "link/new"), (DIR, "link/sub")] {
+            let mut archive = TREE_DELTA_MAGIC.to_be_bytes().to_vec();
+            write_entry_header(&mut archive, *kind, path).unwrap();
+            if *kind == FILE {
+                archive.extend_from_slice(&5u64.to_be_bytes());
+                archive.extend_from_slice(b"rs\x026\0");
+            }
+            archive.push(END);
+            // The output tree, and then for a file the basis tree, has the link.
+            let trees: &[_] = if *kind == FILE {
+                &[(&empty, &tree), (&tree, &empty)]
+            } else {
+                &[(&empty, &tree)]
+            };
+            for (basis, out) in trees {
+                let err = patch_dir(basis.path(), &mut &archive[..], out.path()).unwrap_err();
+                assert!(matches!(err, Error::CorruptDelta(_)), "{:?}: {:?}", path, err);
+            }
+        }
+        assert_eq!(contents(outside.path()), [("x".to_owned(), Some(b"outside".to_vec()))]);
+    }
+
+    #[test]
+    pub fn bad_magic_and_trailing_data() {
+        let err = read_manifest(&mut &b"rs\x026\0"[..]).unwrap_err();
+        assert!(matches!(err, Error::BadMagic(0x72730236)), "{:?}", err);
+
+        let mut archive = TREE_DELTA_MAGIC.to_be_bytes().to_vec();
+        write_entry_header(&mut archive, FILE, "f").unwrap();
+        archive.extend_from_slice(&6u64.to_be_bytes());
+        archive.extend_from_slice(b"rs\x026\0\0");
+        archive.push(END);
+        let out = TempDir::new().unwrap();
+        let err = patch_dir(out.path(), &mut &archive[..], out.path()).unwrap_err();
+        assert!(matches!(err, Error::CorruptDelta(_)), "{:?}", err);
+        assert!(!out.path().join("f").exists());
+    }
+}
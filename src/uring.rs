@@ -0,0 +1,609 @@
+// rdiff(rust) -- library for network deltas
+// Copyright 2018 Martin Pool.
+
+//! Basis reads and output writes through Linux's io_uring.
+//!
+//! The whole-file operations in `files` use these when the `io-uring` feature is on and
+//! the kernel allows it. The basis reads of a patch are found ahead of time from the
+//! delta's COPY commands, and submitted together, so that storage with high latency
+//! works on several at once rather than waiting for each seek in turn. Output is written
+//! back behind the caller, with several writes in flight.
+//!
+//! The ring is driven with the raw system calls, through `libc`, using only reads and
+//! writes at an offset, which need Linux 5.6.
+
+use std::collections::VecDeque;
+use std::convert::TryFrom;
+use std::fs::File;
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::mem;
+use std::os::unix::fs::FileExt;
+use std::os::unix::io::{AsRawFd, FromRawFd, OwnedFd, RawFd};
+use std::ptr;
+use std::sync::atomic::{AtomicU32, Ordering};
+
+use super::basis::BasisProvider;
+use super::delta::{CommandHeader, DeltaReader};
+
+/// How many operations each ring has in flight.
+pub(crate) const DEPTH: u32 = 8;
+
+/// The largest single read or write submitted.
+const MAX_LEN: usize = 1 << 30;
+
+const IORING_OFF_SQ_RING: libc::off_t = 0;
+const IORING_OFF_CQ_RING: libc::off_t = 0x800_0000;
+const IORING_OFF_SQES: libc::off_t = 0x1000_0000;
+const IORING_ENTER_GETEVENTS: u32 = 1;
+const IORING_OP_READ: u8 = 22;
+const IORING_OP_WRITE: u8 = 23;
+
+/// Laid out as the kernel's `struct io_sqring_offsets`.
+#[repr(C)]
+#[derive(Default)]
+#[allow(dead_code)]
+struct SqRingOffsets {
+    head: u32,
+    tail: u32,
+    ring_mask: u32,
+    ring_entries: u32,
+    flags: u32,
+    dropped: u32,
+    array: u32,
+    resv1: u32,
+    user_addr: u64,
+}
+
+/// Laid out as the kernel's `struct io_cqring_offsets`.
+#[repr(C)]
+#[derive(Default)]
+#[allow(dead_code)]
+struct CqRingOffsets {
+    head: u32,
+    tail: u32,
+    ring_mask: u32,
+    ring_entries: u32,
+    overflow: u32,
+    cqes: u32,
+    flags: u32,
+    resv1: u32,
+    user_addr: u64,
+}
+
+/// Laid out as the kernel's `struct io_uring_params`.
+#[repr(C)]
+#[derive(Default)]
+#[allow(dead_code)]
+struct Params {
+    sq_entries: u32,
+    cq_entries: u32,
+    flags: u32,
+    sq_thread_cpu: u32,
+    sq_thread_idle: u32,
+    features: u32,
+    wq_fd: u32,
+    resv: [u32; 3],
+    sq_off: SqRingOffsets,
+    cq_off: CqRingOffsets,
+}
+
+/// A submission queue entry, laid out as the kernel's `struct io_uring_sqe`, with the
+/// fields that reads and writes don't use left as padding.
+#[repr(C)]
+struct Sqe {
+    opcode: u8,
+    flags: u8,
+    ioprio: u16,
+    fd: i32,
+    off: u64,
+    addr: u64,
+    len: u32,
+    rw_flags: u32,
+    user_data: u64,
+    pad: [u64; 3],
+}
+
+/// A completion queue entry, laid out as the kernel's `struct io_uring_cqe`.
+#[repr(C)]
+struct Cqe {
+    user_data: u64,
+    res: i32,
+    flags: u32,
+}
+
+/// A region of a ring, mapped from its file descriptor.
+struct Map {
+    ptr: *mut u8,
+    len: usize,
+}
+
+impl Map {
+    fn new(fd: RawFd, len: usize, offset: libc::off_t) -> io::Result<Map> {
+        // Safety: a new shared mapping, whose length the kernel checks against the ring.
+        let ptr = unsafe {
+            libc::mmap(ptr::null_mut(), len, libc::PROT_READ | libc::PROT_WRITE,
+                       libc::MAP_SHARED | libc::MAP_POPULATE, fd, offset)
+        };
+        if ptr == libc::MAP_FAILED {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(Map { ptr: ptr as *mut u8, len })
+    }
+
+    /// The shared counter at `offset` in the map.
+    ///
+    /// Safety: `offset` must be one the kernel gave for a `u32` in this map.
+    unsafe fn u32_at(&self, offset: u32) -> &AtomicU32 {
+        &*(self.ptr.add(offset as usize) as *const AtomicU32)
+    }
+}
+
+impl Drop for Map {
+    fn drop(&mut self) {
+        // Safety: nothing refers to the map once its ring is dropped.
+        unsafe {
+            libc::munmap(self.ptr as *mut libc::c_void, self.len);
+        }
+    }
+}
+
+/// An io_uring, with at most `DEPTH` operations in flight, so that its completion queue
+/// can't overflow.
+pub(crate) struct Ring {
+    sq: Map,
+    cq: Map,
+    sqes: Map,
+    params: Params,
+    /// Operations queued and not yet submitted to the kernel.
+    unsubmitted: u32,
+    /// Operations queued whose completions haven't been taken.
+    in_flight: u32,
+    fd: OwnedFd,
+}
+
+impl Ring {
+    /// Set up a ring, failing if the kernel doesn't support io_uring or doesn't allow it.
+    pub(crate) fn new() -> io::Result<Ring> {
+        let mut params = Params::default();
+        // Safety: the kernel fills in `params`, which is laid out as it expects.
+        let fd = unsafe {
+            libc::syscall(libc::SYS_io_uring_setup, DEPTH, &mut params as *mut Params)
+        };
+        if fd < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        // Safety: the ring's descriptor is new, and owned only here.
+        let fd = unsafe { OwnedFd::from_raw_fd(fd as RawFd) };
+        let raw = fd.as_raw_fd();
+        let sq = Map::new(raw, params.sq_off.array as usize + params.sq_entries as usize * 4,
+                          IORING_OFF_SQ_RING)?;
+        let cq = Map::new(raw,
+                          params.cq_off.cqes as usize
+                              + params.cq_entries as usize * mem::size_of::<Cqe>(),
+                          IORING_OFF_CQ_RING)?;
+        let sqes = Map::new(raw, params.sq_entries as usize * mem::size_of::<Sqe>(),
+                            IORING_OFF_SQES)?;
+        Ok(Ring { sq, cq, sqes, params, unsubmitted: 0, in_flight: 0, fd })
+    }
+
+    fn is_full(&self) -> bool {
+        self.in_flight >= DEPTH.min(self.params.sq_entries)
+    }
+
+    /// Queue a read or write of `len` bytes at `addr`, from or to `offset` in `fd`,
+    /// tagged with `user_data`. The ring must not be full.
+    ///
+    /// Safety: the memory must stay valid, and not be otherwise used, until the
+    /// completion tagged `user_data` is taken, or the ring is dropped.
+    unsafe fn push(&mut self, opcode: u8, fd: RawFd, offset: u64, addr: *const u8, len: usize,
+                   user_data: u64) {
+        debug_assert!(!self.is_full() && len <= MAX_LEN);
+        let off = &self.params.sq_off;
+        let tail = self.sq.u32_at(off.tail).load(Ordering::Relaxed);
+        let index = tail & self.sq.u32_at(off.ring_mask).load(Ordering::Relaxed);
+        (self.sqes.ptr as *mut Sqe).add(index as usize).write(Sqe {
+            opcode,
+            flags: 0,
+            ioprio: 0,
+            fd,
+            off: offset,
+            addr: addr as u64,
+            len: len as u32,
+            rw_flags: 0,
+            user_data,
+            pad: [0; 3],
+        });
+        self.sq.u32_at(off.array + index * 4).store(index, Ordering::Relaxed);
+        self.sq.u32_at(off.tail).store(tail.wrapping_add(1), Ordering::Release);
+        self.unsubmitted += 1;
+        self.in_flight += 1;
+    }
+
+    /// Submit what's queued, and wait for at least `wait` completions.
+    fn enter(&mut self, wait: u32) -> io::Result<()> {
+        let flags = if wait > 0 { IORING_ENTER_GETEVENTS } else { 0 };
+        loop {
+            // Safety: no signal mask is passed.
+            let r = unsafe {
+                libc::syscall(libc::SYS_io_uring_enter, self.fd.as_raw_fd(), self.unsubmitted,
+                              wait, flags, ptr::null::<libc::sigset_t>(), 0usize)
+            };
+            if r >= 0 {
+                self.unsubmitted -= (r as u32).min(self.unsubmitted);
+                return Ok(());
+            }
+            let e = io::Error::last_os_error();
+            if e.kind() != io::ErrorKind::Interrupted {
+                return Err(e);
+            }
+        }
+    }
+
+    /// Take a completion, if there's one ready: its tag, and the result of the operation.
+    fn pop(&mut self) -> Option<(u64, i32)> {
+        let off = &self.params.cq_off;
+        // Safety: the offsets are the kernel's, and it's finished with entries before the
+        // tail that it publishes.
+        unsafe {
+            let head = self.cq.u32_at(off.head).load(Ordering::Relaxed);
+            if head == self.cq.u32_at(off.tail).load(Ordering::Acquire) {
+                return None;
+            }
+            let index = head & self.cq.u32_at(off.ring_mask).load(Ordering::Relaxed);
+            let cqe = &*(self.cq.ptr.add(off.cqes as usize) as *const Cqe).add(index as usize);
+            let completion = (cqe.user_data, cqe.res);
+            self.cq.u32_at(off.head).store(head.wrapping_add(1), Ordering::Release);
+            self.in_flight -= 1;
+            Some(completion)
+        }
+    }
+
+    /// Wait for a completion. Something must be in flight.
+    fn next(&mut self) -> io::Result<(u64, i32)> {
+        loop {
+            if let Some(completion) = self.pop() {
+                return Ok(completion);
+            }
+            self.enter(1)?;
+        }
+    }
+}
+
+impl Drop for Ring {
+    /// Wait for the operations in flight, so that the memory they use can be freed.
+    fn drop(&mut self) {
+        while self.in_flight > 0 {
+            if self.next().is_err() {
+                break;
+            }
+        }
+    }
+}
+
+/// The COPY commands of a delta, read ahead of the patch from another handle on it.
+///
+/// The commands end early if the delta is malformed: the patch itself reports that.
+pub(crate) struct Copies<R: Read> {
+    commands: Option<DeltaReader<R>>,
+}
+
+impl<R: Read> Copies<R> {
+    pub(crate) fn new(delta: R) -> Copies<R> {
+        Copies { commands: DeltaReader::new(delta).ok() }
+    }
+}
+
+impl<R: Read> Iterator for Copies<R> {
+    /// The offset and length of each COPY that isn't empty.
+    type Item = (u64, u64);
+
+    fn next(&mut self) -> Option<(u64, u64)> {
+        let commands = self.commands.as_mut()?;
+        loop {
+            match commands.read_header() {
+                Ok(CommandHeader::Literal { len }) => {
+                    if commands.copy_literal(len, &mut io::sink()).is_err() {
+                        break;
+                    }
+                }
+                Ok(CommandHeader::Copy { offset, len }) if len > 0 => return Some((offset, len)),
+                Ok(CommandHeader::Copy { .. }) => {}
+                Ok(CommandHeader::End) | Err(_) => break,
+            }
+        }
+        self.commands = None;
+        None
+    }
+}
+
+/// A read submitted ahead.
+struct Chunk {
+    offset: u64,
+    buf: Vec<u8>,
+    /// How much of `buf` has been used.
+    pos: usize,
+    /// How much of `buf` was read, once the read is complete: nothing if it failed.
+    filled: Option<usize>,
+}
+
+/// A basis file whose reads are submitted ahead, in the order that `plan` gives.
+///
+/// Reads that don't follow the plan are made directly.
+pub(crate) struct UringBasis<I> {
+    // First, so that it's dropped, waiting for the reads in flight, before their buffers.
+    ring: Ring,
+    file: File,
+    plan: I,
+    /// The rest of a planned read too long for one chunk.
+    rest: Option<(u64, u64)>,
+    chunk_len: usize,
+    /// Reads in flight or not used up, tagged with their sequence numbers from `first`.
+    chunks: VecDeque<Chunk>,
+    first: u64,
+}
+
+impl<I: Iterator<Item = (u64, u64)>> UringBasis<I> {
+    /// Read `file` through `ring`, in the order `plan` gives, in chunks of up to
+    /// `chunk_len` bytes.
+    pub(crate) fn new(ring: Ring, file: File, plan: I, chunk_len: usize) -> UringBasis<I> {
+        UringBasis { ring, file, plan, rest: None, chunk_len: chunk_len.clamp(1, MAX_LEN),
+                     chunks: VecDeque::new(), first: 0 }
+    }
+
+    /// Submit the next planned reads, until the ring is full.
+    fn read_ahead(&mut self) -> io::Result<()> {
+        let mut queued = false;
+        while !self.ring.is_full() {
+            let (offset, len) = match self.rest.take().or_else(|| self.plan.next()) {
+                Some(range) => range,
+                None => break,
+            };
+            let l = len.min(self.chunk_len as u64);
+            if l < len {
+                self.rest = Some((offset + l, len - l));
+            }
+            let mut buf = vec![0; l as usize];
+            let tag = self.first + self.chunks.len() as u64;
+            // Safety: the buffer is kept in `chunks`, untouched, until the read completes.
+            unsafe {
+                self.ring.push(IORING_OP_READ, self.file.as_raw_fd(), offset, buf.as_mut_ptr(),
+                               buf.len(), tag);
+            }
+            self.chunks.push_back(Chunk { offset, buf, pos: 0, filled: None });
+            queued = true;
+        }
+        if queued {
+            self.ring.enter(0)?;
+        }
+        Ok(())
+    }
+
+    /// Wait until the first chunk is read, and return how much of it was.
+    fn wait_first(&mut self) -> io::Result<usize> {
+        loop {
+            if let Some(filled) = self.chunks[0].filled {
+                return Ok(filled);
+            }
+            let (tag, res) = self.ring.next()?;
+            if let Some(chunk) = self.chunks.get_mut((tag - self.first) as usize) {
+                chunk.filled = Some(usize::try_from(res).unwrap_or(0));
+            }
+        }
+    }
+
+    fn pop_first(&mut self) {
+        self.chunks.pop_front();
+        self.first += 1;
+    }
+}
+
+impl<I: Iterator<Item = (u64, u64)>> BasisProvider for UringBasis<I> {
+    fn read_at(&mut self, offset: u64, buf: &mut [u8]) -> io::Result<()> {
+        let mut done = 0;
+        while done < buf.len() {
+            self.read_ahead()?;
+            let pos = offset + done as u64;
+            match self.chunks.front() {
+                Some(chunk) if chunk.offset + chunk.pos as u64 == pos => {}
+                _ => return self.file.read_exact_at(&mut buf[done..], pos),
+            }
+            let filled = self.wait_first()?;
+            let chunk = &mut self.chunks[0];
+            let l = filled.saturating_sub(chunk.pos).min(buf.len() - done);
+            if l == 0 {
+                // The read failed or was short: make it again directly, for its error.
+                self.pop_first();
+                return self.file.read_exact_at(&mut buf[done..], pos);
+            }
+            buf[done..done + l].copy_from_slice(&chunk.buf[chunk.pos..chunk.pos + l]);
+            chunk.pos += l;
+            done += l;
+            if chunk.pos == chunk.buf.len() {
+                self.pop_first();
+            }
+        }
+        Ok(())
+    }
+
+    fn size(&mut self) -> io::Result<u64> {
+        Ok(self.file.metadata()?.len())
+    }
+}
+
+/// A write submitted and not yet known to be complete.
+struct Pending {
+    offset: u64,
+    buf: Vec<u8>,
+    res: Option<i32>,
+}
+
+/// Writes a file from its start through a ring, in pieces of `write_len`, with several
+/// writes in flight behind the caller. Seeking moves where the next writes go. Errors
+/// from earlier writes are returned by later calls, and by `flush`, which waits for them
+/// all.
+pub(crate) struct UringWriter<'f> {
+    // First, so that it's dropped, waiting for the writes in flight, before their buffers.
+    ring: Ring,
+    file: &'f File,
+    buf: Vec<u8>,
+    write_len: usize,
+    /// Where the next write goes.
+    offset: u64,
+    /// Writes in flight, tagged with their sequence numbers from `first`.
+    pending: VecDeque<Pending>,
+    first: u64,
+}
+
+impl<'f> UringWriter<'f> {
+    /// Write `file` through a new ring, failing if one can't be set up.
+    pub(crate) fn new(file: &'f File, write_len: usize) -> io::Result<UringWriter<'f>> {
+        let write_len = write_len.clamp(1, MAX_LEN);
+        Ok(UringWriter { ring: Ring::new()?, file, buf: Vec::with_capacity(write_len), write_len,
+                         offset: 0, pending: VecDeque::new(), first: 0 })
+    }
+
+    /// Submit the buffered data.
+    fn send(&mut self) -> io::Result<()> {
+        if self.ring.is_full() {
+            self.finish_first()?;
+        }
+        let buf = mem::replace(&mut self.buf, Vec::with_capacity(self.write_len));
+        let tag = self.first + self.pending.len() as u64;
+        // Safety: the buffer is kept in `pending`, untouched, until the write completes.
+        unsafe {
+            self.ring.push(IORING_OP_WRITE, self.file.as_raw_fd(), self.offset, buf.as_ptr(),
+                           buf.len(), tag);
+        }
+        let offset = self.offset;
+        self.offset += buf.len() as u64;
+        self.pending.push_back(Pending { offset, buf, res: None });
+        self.ring.enter(0)
+    }
+
+    /// Wait for the first write in flight, and finish it directly if it was short.
+    fn finish_first(&mut self) -> io::Result<()> {
+        let res = loop {
+            if let Some(res) = self.pending[0].res {
+                break res;
+            }
+            let (tag, res) = self.ring.next()?;
+            if let Some(w) = self.pending.get_mut((tag - self.first) as usize) {
+                w.res = Some(res);
+            }
+        };
+        let w = self.pending.pop_front().expect("a write is pending");
+        self.first += 1;
+        let written = usize::try_from(res).map_err(|_| io::Error::from_raw_os_error(-res))?;
+        self.file.write_all_at(&w.buf[written..], w.offset + written as u64)
+    }
+}
+
+impl<'f> Write for UringWriter<'f> {
+    fn write(&mut self, data: &[u8]) -> io::Result<usize> {
+        let l = (self.write_len - self.buf.len()).min(data.len());
+        self.buf.extend_from_slice(&data[..l]);
+        if self.buf.len() == self.write_len {
+            self.send()?;
+        }
+        Ok(l)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        if !self.buf.is_empty() {
+            self.send()?;
+        }
+        while !self.pending.is_empty() {
+            self.finish_first()?;
+        }
+        Ok(())
+    }
+}
+
+impl<'f> Seek for UringWriter<'f> {
+    /// Move where the next write goes, after submitting what's buffered. Seeking from the
+    /// end isn't supported.
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        if !self.buf.is_empty() {
+            self.send()?;
+        }
+        self.offset = match pos {
+            SeekFrom::Start(offset) => Some(offset),
+            SeekFrom::Current(delta) => self.offset.checked_add_signed(delta),
+            SeekFrom::End(_) => return Err(io::Error::new(io::ErrorKind::Unsupported,
+                                                          "can't seek from the end")),
+        }.ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "seek out of range"))?;
+        Ok(self.offset)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::fs;
+
+    use tempfile::TempDir;
+
+    use super::*;
+    use super::super::memory;
+    use super::super::patch::apply_patch;
+    use super::super::test_util::random;
+
+    /// Whether this kernel lets us use io_uring: if not, the tests have nothing to check.
+    fn have_uring() -> bool {
+        Ring::new().is_ok()
+    }
+
+    #[test]
+    pub fn patch_reads_ahead() {
+        if !have_uring() {
+            return;
+        }
+        let old = random(1_000_000);
+        let mut new = old[600_000..].to_vec();
+        new.extend_from_slice(b"something inserted");
+        new.extend_from_slice(&old[..500_000]);
+        let delta = memory::delta_of(&memory::signature_of(&old), &new).unwrap();
+        let tmp = TempDir::new().unwrap();
+        let path = tmp.path().join("basis");
+        fs::write(&path, &old).unwrap();
+        for &chunk_len in &[1000, 4096, 1 << 20] {
+            let mut basis = UringBasis::new(Ring::new().unwrap(), File::open(&path).unwrap(),
+                                            Copies::new(delta.as_slice()), chunk_len);
+            let mut out = Vec::new();
+            apply_patch(&mut basis, &mut delta.as_slice(), &mut out).unwrap();
+            assert!(out == new, "chunk_len {}", chunk_len);
+        }
+        // Reads off the plan are made directly, and errors are still reported.
+        let mut basis = UringBasis::new(Ring::new().unwrap(), File::open(&path).unwrap(),
+                                        vec![(0, 10), (999_995, 10)].into_iter(), 4);
+        let mut buf = [0; 8];
+        basis.read_at(100, &mut buf).unwrap();
+        assert_eq!(buf, old[100..108]);
+        basis.read_at(0, &mut buf).unwrap();
+        assert_eq!(buf, old[..8]);
+        let err = basis.read_at(999_995, &mut buf).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::UnexpectedEof);
+    }
+
+    #[test]
+    pub fn writes_behind() {
+        if !have_uring() {
+            return;
+        }
+        let data = random(100_000);
+        let tmp = TempDir::new().unwrap();
+        let path = tmp.path().join("out");
+        let file = File::create(&path).unwrap();
+        let mut w = UringWriter::new(&file, 1000).unwrap();
+        for piece in data.chunks(777) {
+            w.write_all(piece).unwrap();
+        }
+        w.flush().unwrap();
+        w.seek(SeekFrom::Current(-50_000)).unwrap();
+        w.write_all(b"overwritten").unwrap();
+        w.flush().unwrap();
+        drop(w);
+        let mut expected = data;
+        expected[50_000..50_011].copy_from_slice(b"overwritten");
+        assert!(fs::read(&path).unwrap() == expected);
+    }
+}
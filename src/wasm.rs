@@ -0,0 +1,50 @@
+// rdiff(rust) -- library for network deltas
+// Copyright 2018 Martin Pool.
+
+//! JavaScript bindings, for use from WebAssembly built with the `wasm` feature.
+//!
+//! The `rdiff-wasm` crate, in `wasm/` in the repository, builds them into a module.
+//!
+//! These work on whole files held in `Uint8Array`s, like the `memory` module they call,
+//! and return the result as a new `Uint8Array`. Errors are thrown as JavaScript `Error`s.
+//!
+//! ```js
+//! import { signature, delta, patch } from "rdiff-wasm";
+//!
+//! const sig = signature(basis);
+//! const d = delta(sig, newFile);
+//! const out = patch(basis, d);
+//! ```
+
+use wasm_bindgen::prelude::*;
+
+use super::memory;
+use super::mksum::SignatureOptions;
+
+/// Return the signature of `basis`.
+///
+/// `block_len` and `strong_len` default to the library's defaults if they're omitted.
+#[wasm_bindgen]
+pub fn signature(basis: &[u8], block_len: Option<u32>, strong_len: Option<u32>)
+                 -> Result<Vec<u8>, JsError> {
+    let mut builder = SignatureOptions::new();
+    if let Some(block_len) = block_len {
+        builder = builder.block_len(block_len);
+    }
+    if let Some(strong_len) = strong_len {
+        builder = builder.strong_len(strong_len);
+    }
+    Ok(memory::signature_with_options(basis, &builder.build()?)?)
+}
+
+/// Return a delta from the basis described by the signature `sig`, to `new_file`.
+#[wasm_bindgen]
+pub fn delta(sig: &[u8], new_file: &[u8]) -> Result<Vec<u8>, JsError> {
+    Ok(memory::delta_of(sig, new_file)?)
+}
+
+/// Apply `delta` to `basis`, returning the new file.
+#[wasm_bindgen]
+pub fn patch(basis: &[u8], delta: &[u8]) -> Result<Vec<u8>, JsError> {
+    Ok(memory::apply(basis, delta)?)
+}
@@ -0,0 +1,519 @@
+//! Black-box tests for the rdiff command-line tool.
+
+// Copyright 2018 Martin Pool.
+
+extern crate rdiff;
+extern crate tempfile;
+
+use std::env;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::io::Write;
+use std::process::{Command, Output, Stdio};
+use std::time::{Duration, SystemTime};
+
+use rdiff::magic::SignatureFormat;
+use rdiff::memory::signature_with_options;
+use rdiff::mksum::SignatureOptions;
+use tempfile::TempDir;
+
+fn pattern(len: usize) -> Vec<u8> {
+    (0..len).map(|i| ((i * 7 + i / 13) % 251) as u8).collect()
+}
+
+
+fn rdiff(dir: &Path, args: &[&str]) -> Output {
+    Command::new(env!("CARGO_BIN_EXE_rdiff"))
+        .current_dir(dir)
+        .args(args)
+        .output()
+        .unwrap()
+}
+
+#[test]
+fn signature_delta_patch() {
+    let tmp = TempDir::new().unwrap();
+    let dir = tmp.path();
+    let basis = pattern(100 << 10);
+    let mut new = basis[10_240..].to_vec();
+    new.extend_from_slice(b"some new data");
+    fs::write(dir.join("basis"), &basis).unwrap();
+    fs::write(dir.join("new"), &new).unwrap();
+
+    assert!(rdiff(dir, &["signature", "basis", "sig"]).status.success());
+    // The default format is the same as C rdiff's.
+    let options = SignatureOptions {
+        magic: SignatureFormat::RkBlake2Sig,
+        .. SignatureOptions::default()
+    };
+    assert_eq!(fs::read(dir.join("sig")).unwrap(),
+               signature_with_options(&basis, &options).unwrap());
+
+    assert!(rdiff(dir, &["delta", "sig", "new", "delta"]).status.success());
+    assert!(fs::metadata(dir.join("delta")).unwrap().len() < 2000);
+    assert!(rdiff(dir, &["patch", "basis", "delta", "out"]).status.success());
+    assert_eq!(fs::read(dir.join("out")).unwrap(), new);
+}
+
+/// `diff` makes the same delta as `signature` and then `delta`.
+#[test]
+fn diff() {
+    let tmp = TempDir::new().unwrap();
+    let dir = tmp.path();
+    let basis = pattern(100 << 10);
+    let mut new = basis[10_240..].to_vec();
+    new.extend_from_slice(b"some new data");
+    fs::write(dir.join("basis"), &basis).unwrap();
+    fs::write(dir.join("new"), &new).unwrap();
+    for args in &[&[][..], &["-b", "512", "-H", "md4"]] {
+        let sig_args: Vec<&str> = ["signature", "-f"].iter().chain(*args)
+            .chain(&["basis", "sig"]).cloned().collect();
+        assert!(rdiff(dir, &sig_args).status.success());
+        assert!(rdiff(dir, &["delta", "-f", "sig", "new", "delta"]).status.success());
+        let diff_args: Vec<&str> = ["diff", "-f"].iter().chain(*args)
+            .chain(&["basis", "new", "diff"]).cloned().collect();
+        assert!(rdiff(dir, &diff_args).status.success());
+        assert_eq!(fs::read(dir.join("diff")).unwrap(), fs::read(dir.join("delta")).unwrap());
+    }
+    assert!(rdiff(dir, &["patch", "basis", "diff", "out"]).status.success());
+    assert_eq!(fs::read(dir.join("out")).unwrap(), new);
+    assert_eq!(rdiff(dir, &["diff", "-", "-"]).status.code(), Some(1));
+    assert!(!rdiff(dir, &["diff", "basis"]).status.success());
+}
+
+/// `diff --sig-cache` keeps the old file's signature, and reuses it while the file is
+/// unchanged.
+#[test]
+fn diff_sig_cache() {
+    let tmp = TempDir::new().unwrap();
+    let dir = tmp.path();
+    let basis = pattern(100 << 10);
+    let mut new = basis[10_240..].to_vec();
+    new.extend_from_slice(b"some new data");
+    fs::write(dir.join("basis"), &basis).unwrap();
+    fs::write(dir.join("new"), &new).unwrap();
+    // Files modified just now aren't cached.
+    fs::File::options().write(true).open(dir.join("basis")).unwrap()
+        .set_modified(SystemTime::now() - Duration::from_secs(60)).unwrap();
+    assert!(rdiff(dir, &["diff", "basis", "new", "plain"]).status.success());
+    let cached = ["diff", "-f", "--sig-cache", "cache", "basis", "new", "delta"];
+    assert!(rdiff(dir, &cached).status.success());
+    assert_eq!(fs::read(dir.join("delta")).unwrap(), fs::read(dir.join("plain")).unwrap());
+    let entries: Vec<PathBuf> = fs::read_dir(dir.join("cache")).unwrap()
+        .map(|e| e.unwrap().path())
+        .collect();
+    assert_eq!(entries.len(), 1);
+
+    // What's cached is used in place of hashing the old file.
+    let options = SignatureOptions {
+        magic: SignatureFormat::RkBlake2Sig,
+        .. SignatureOptions::default()
+    };
+    let empty = rdiff::mksum::calculate_signature(&mut &b""[..], &options).unwrap();
+    fs::write(&entries[0], empty.to_bytes()).unwrap();
+    assert!(rdiff(dir, &cached).status.success());
+    assert!(fs::metadata(dir.join("delta")).unwrap().len() > new.len() as u64);
+    assert_eq!(rdiff(dir, &["diff", "--sig-cache", "cache", "-", "new"]).status.code(),
+               Some(101));
+}
+
+/// Run rdiff with `input` on stdin, returning what it wrote to stdout.
+fn rdiff_piped(dir: &Path, args: &[&str], input: &[u8]) -> Vec<u8> {
+    let mut child = Command::new(env!("CARGO_BIN_EXE_rdiff"))
+        .current_dir(dir)
+        .args(args)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .spawn()
+        .unwrap();
+    let mut stdin = child.stdin.take().unwrap();
+    let input = input.to_vec();
+    let writer = std::thread::spawn(move || stdin.write_all(&input).unwrap());
+    let output = child.wait_with_output().unwrap();
+    writer.join().unwrap();
+    assert!(output.status.success(), "{:?}", args);
+    output.stdout
+}
+
+#[test]
+fn pipes() {
+    let tmp = TempDir::new().unwrap();
+    let dir = tmp.path();
+    let basis = pattern(100 << 10);
+    let mut new = basis[10_240..].to_vec();
+    new.extend_from_slice(b"some new data");
+    fs::write(dir.join("basis"), &basis).unwrap();
+    fs::write(dir.join("new"), &new).unwrap();
+
+    // Omitted names and `-` both mean stdin or stdout.
+    let sig = rdiff_piped(dir, &["signature"], &basis);
+    assert_eq!(rdiff_piped(dir, &["signature", "-", "-"], &basis), sig);
+    let delta = rdiff_piped(dir, &["delta", "-", "new"], &sig);
+    fs::write(dir.join("sig"), &sig).unwrap();
+    assert_eq!(rdiff_piped(dir, &["delta", "sig"], &new), delta);
+    assert_eq!(rdiff_piped(dir, &["patch", "basis"], &delta), new);
+    assert_eq!(rdiff_piped(dir, &["patch", "basis", "-", "-"], &delta), new);
+}
+
+#[test]
+fn signature_and_new_both_from_stdin() {
+    let tmp = TempDir::new().unwrap();
+    let dir = tmp.path();
+    let output = rdiff(dir, &["delta", "-"]);
+    assert_eq!(output.status.code(), Some(1));
+}
+
+#[test]
+fn signature_options() {
+    let tmp = TempDir::new().unwrap();
+    let dir = tmp.path();
+    let basis = pattern(10_000);
+    fs::write(dir.join("basis"), &basis).unwrap();
+    for &(args, magic, block_len, strong_len) in &[
+        (&["-H", "md4"][..], SignatureFormat::RkMd4Sig, 2048, 8),
+        (&["--hash=md4", "--rollsum=rollsum", "-S", "16"][..], SignatureFormat::Md4Sig, 2048, 16),
+        (&["-R", "rollsum", "-b", "1000"][..], SignatureFormat::Blake2Sig, 1000, 32),
+        (&["--block-size", "512", "--sum-size", "12"][..], SignatureFormat::RkBlake2Sig, 512, 12),
+        (&["-H", "md4", "-S", "0"][..], SignatureFormat::RkMd4Sig, 2048, 16),
+        (&["--cdc", "-b", "512"][..], SignatureFormat::CdcBlake2Sig, 512, 32),
+    ] {
+        let mut cmd = vec!["signature", "--force", "basis", "sig"];
+        cmd.extend_from_slice(args);
+        assert!(rdiff(dir, &cmd).status.success(), "{:?}", args);
+        let options = SignatureOptions { magic, block_len, strong_len, seed: 0 };
+        assert_eq!(fs::read(dir.join("sig")).unwrap(),
+                   signature_with_options(&basis, &options).unwrap(), "{:?}", args);
+    }
+    assert!(rdiff(dir, &["signature", "-f", "--seed", "1234", "basis", "sig"])
+            .status.success());
+    let options = SignatureOptions {
+        magic: SignatureFormat::RkBlake2Sig,
+        block_len: 2048,
+        strong_len: 32,
+        seed: 1234,
+    };
+    assert_eq!(fs::read(dir.join("sig")).unwrap(),
+               signature_with_options(&basis, &options).unwrap());
+}
+
+#[test]
+fn bad_signature_options() {
+    let tmp = TempDir::new().unwrap();
+    let dir = tmp.path();
+    fs::write(dir.join("basis"), b"hello").unwrap();
+    for args in &[&["-H", "sha1"][..], &["-R", "adler"][..], &["-b", "big"][..],
+                  &["--sum-size", "8x"][..], &["--cdc", "-H", "md4"][..]] {
+        let mut cmd = vec!["signature", "basis", "sig"];
+        cmd.extend_from_slice(args);
+        let output = rdiff(dir, &cmd);
+        assert_eq!(output.status.code(), Some(101), "{:?}", args);
+        assert!(String::from_utf8_lossy(&output.stderr).contains("rdiff --help"));
+    }
+    // Sizes that parse but that the library rejects.
+    for args in &[&["-b", "0"][..], &["-S", "33"][..], &["-H", "md4", "-S", "17"][..]] {
+        let mut cmd = vec!["signature", "basis", "sig"];
+        cmd.extend_from_slice(args);
+        assert_eq!(rdiff(dir, &cmd).status.code(), Some(1), "{:?}", args);
+        // The options are checked before the output is created.
+        assert!(!dir.join("sig").exists());
+    }
+}
+
+#[test]
+fn statistics() {
+    let tmp = TempDir::new().unwrap();
+    let dir = tmp.path();
+    fs::write(dir.join("basis"), pattern(10_000)).unwrap();
+    for &(op, args) in &[("signature", &["-s", "signature", "basis", "sig"][..]),
+                         ("delta", &["delta", "--statistics", "sig", "basis", "delta"][..]),
+                         ("patch", &["patch", "-s", "basis", "delta", "out"][..])] {
+        let output = rdiff(dir, args);
+        assert!(output.status.success(), "{:?}", args);
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        assert!(stderr.starts_with(&format!("rdiff: {} statistics: ", op)), "{}", stderr);
+        assert!(stderr.contains("speed["), "{}", stderr);
+    }
+    assert!(rdiff(dir, &["signature", "-f", "basis", "sig"]).stderr.is_empty());
+}
+
+#[test]
+fn dump_sig() {
+    let tmp = TempDir::new().unwrap();
+    let dir = tmp.path();
+    fs::write(dir.join("basis"), pattern(5000)).unwrap();
+    assert!(rdiff(dir, &["signature", "-H", "md4", "-b", "2000", "basis", "sig"])
+            .status.success());
+    let output = rdiff(dir, &["dump-sig", "sig"]);
+    assert!(output.status.success());
+    assert_eq!(String::from_utf8_lossy(&output.stdout),
+               "format: RkMd4Sig (0x72730146)\n\
+                block length: 2000\n\
+                strong sum length: 8\n\
+                blocks: 3\n");
+
+    let output = rdiff(dir, &["dump-sig", "--blocks", "sig"]);
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    let blocks: Vec<&str> = stdout.lines().skip(4).collect();
+    assert_eq!(blocks.len(), 3);
+    let sig = fs::read(dir.join("sig")).unwrap();
+    let fields: Vec<&str> = blocks[0].split_whitespace().collect();
+    assert_eq!(fields[0], "0");
+    assert_eq!(fields[1], format!("{:02x}{:02x}{:02x}{:02x}", sig[12], sig[13], sig[14], sig[15]));
+    assert_eq!(fields[2].len(), 16);
+
+    assert_eq!(rdiff(dir, &["dump-sig", "basis"]).status.code(), Some(1));
+
+    // Content-defined chunks are listed with their lengths.
+    assert!(rdiff(dir, &["signature", "-f", "--cdc", "-b", "256", "basis", "sig"])
+            .status.success());
+    let output = rdiff(dir, &["dump-sig", "--blocks", "sig"]);
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    let lens: Vec<usize> = stdout.lines().skip(4)
+        .map(|l| l.split_whitespace().nth(1).unwrap().parse().unwrap())
+        .collect();
+    assert_eq!(lens.iter().sum::<usize>(), 5000);
+}
+
+/// Several block sizes make a multi-resolution signature, which delta reads the same way.
+#[test]
+fn multi_resolution_signature() {
+    let tmp = TempDir::new().unwrap();
+    let dir = tmp.path();
+    let basis = pattern(100 << 10);
+    let mut new = basis.clone();
+    new[50_000] ^= 1;
+    fs::write(dir.join("basis"), &basis).unwrap();
+    fs::write(dir.join("new"), &new).unwrap();
+    assert!(rdiff(dir, &["signature", "-b", "16384,512", "basis", "sig"]).status.success());
+    let output = rdiff(dir, &["dump-sig", "sig"]);
+    assert!(output.status.success());
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert!(stdout.starts_with("multi-resolution signature of 102400 bytes\n\
+                                level 0:\n\
+                                format: RkBlake2Sig (0x72730147)\n\
+                                block length: 16384\n"), "{}", stdout);
+    assert!(stdout.contains("level 1:\nformat: RkBlake2Sig (0x72730147)\nblock length: 512\n"));
+
+    assert!(rdiff(dir, &["delta", "sig", "new", "delta"]).status.success());
+    assert!(fs::metadata(dir.join("delta")).unwrap().len() < 1000);
+    assert!(rdiff(dir, &["patch", "basis", "delta", "out"]).status.success());
+    assert_eq!(fs::read(dir.join("out")).unwrap(), new);
+
+    assert_eq!(rdiff(dir, &["signature", "-b", "1024,x", "basis", "sig"]).status.code(),
+               Some(101));
+    assert_eq!(rdiff(dir, &["signature", "-f", "-b", "1024,1024", "basis", "sig"])
+                   .status.code(),
+               Some(1));
+}
+
+/// A compressed signature is read like any other.
+#[test]
+fn compressed_signature() {
+    let tmp = TempDir::new().unwrap();
+    let dir = tmp.path();
+    let basis = pattern(100 << 10);
+    let mut new = basis.clone();
+    new.extend_from_slice(b"more");
+    fs::write(dir.join("basis"), &basis).unwrap();
+    fs::write(dir.join("new"), &new).unwrap();
+    assert_eq!(rdiff(dir, &["signature", "--compress", "gzip", "basis", "sig"]).status.code(),
+               Some(101));
+    if cfg!(not(feature = "zstd")) {
+        return;
+    }
+    for args in &[&["signature", "-f", "--compress", "zstd", "basis", "sig.zst"][..],
+                  &["signature", "-f", "--compress", "zstd", "-b", "8192,512", "basis",
+                    "sig.zst"]] {
+        assert!(rdiff(dir, args).status.success());
+        assert_eq!(&fs::read(dir.join("sig.zst")).unwrap()[..4], b"\x28\xb5\x2f\xfd");
+        assert!(rdiff(dir, &["dump-sig", "sig.zst"]).status.success());
+        assert!(rdiff(dir, &["delta", "-f", "sig.zst", "new", "delta"]).status.success());
+        assert!(fs::metadata(dir.join("delta")).unwrap().len() < 1000);
+        assert!(rdiff(dir, &["patch", "-f", "basis", "delta", "out"]).status.success());
+        assert_eq!(fs::read(dir.join("out")).unwrap(), new);
+    }
+}
+
+/// A compressed delta is recognized by `patch` and `dump-delta`.
+#[test]
+fn compressed_delta() {
+    let tmp = TempDir::new().unwrap();
+    let dir = tmp.path();
+    let basis = pattern(100 << 10);
+    let mut new = b"new start ".repeat(2000);
+    new.extend_from_slice(&basis);
+    fs::write(dir.join("basis"), &basis).unwrap();
+    fs::write(dir.join("new"), &new).unwrap();
+    assert!(rdiff(dir, &["signature", "basis", "sig"]).status.success());
+    assert_eq!(rdiff(dir, &["delta", "--compress", "gzip", "sig", "new", "delta"])
+                   .status.code(),
+               Some(101));
+    if cfg!(not(feature = "zstd")) {
+        return;
+    }
+    assert!(rdiff(dir, &["delta", "sig", "new", "delta"]).status.success());
+    assert!(rdiff(dir, &["delta", "--compress", "zstd", "sig", "new", "delta.zst"])
+                .status.success());
+    let compressed = fs::read(dir.join("delta.zst")).unwrap();
+    assert_eq!(&compressed[..4], b"\x28\xb5\x2f\xfd");
+    assert!(compressed.len() * 10 < fs::metadata(dir.join("delta")).unwrap().len() as usize);
+    assert!(rdiff(dir, &["patch", "basis", "delta.zst", "out"]).status.success());
+    assert_eq!(fs::read(dir.join("out")).unwrap(), new);
+    let plain = rdiff(dir, &["dump-delta", "delta"]);
+    let output = rdiff(dir, &["dump-delta", "delta.zst"]);
+    assert!(output.status.success());
+    assert_eq!(output.stdout, plain.stdout);
+}
+
+#[test]
+fn dump_delta() {
+    let tmp = TempDir::new().unwrap();
+    let dir = tmp.path();
+    fs::write(dir.join("delta"), [b'r', b's', 0x02, 0x36,
+                                  3, b'a', b'b', b'c',
+                                  0x45, 1, 9,
+                                  0]).unwrap();
+    let output = rdiff(dir, &["dump-delta", "delta"]);
+    assert!(output.status.success());
+    assert_eq!(String::from_utf8_lossy(&output.stdout),
+               concat!("           0 LITERAL len=3\n",
+                       "           3 COPY offset=1 len=9\n",
+                       "          12 END\n",
+                       "copy: 1 cmds, 9 bytes (75.0%)\n",
+                       "literal: 1 cmds, 3 bytes (25.0%)\n"));
+
+    // Commands before a corrupt one are still listed.
+    fs::write(dir.join("bad"), [b'r', b's', 0x02, 0x36, 1, b'a', 0x55]).unwrap();
+    let output = rdiff(dir, &["dump-delta", "bad"]);
+    assert_eq!(output.status.code(), Some(1));
+    assert_eq!(String::from_utf8_lossy(&output.stdout), "           0 LITERAL len=1\n");
+}
+
+#[test]
+fn missing_arguments() {
+    let tmp = TempDir::new().unwrap();
+    let dir = tmp.path();
+    for args in &[&[][..], &["delta"][..], &["patch"][..]] {
+        assert!(!rdiff(dir, args).status.success(), "{:?}", args);
+    }
+}
+
+#[test]
+fn bad_delta() {
+    let tmp = TempDir::new().unwrap();
+    let dir = tmp.path();
+    fs::write(dir.join("basis"), b"hello").unwrap();
+    fs::write(dir.join("delta"), b"not a delta").unwrap();
+    let output = rdiff(dir, &["patch", "basis", "delta", "out"]);
+    assert_eq!(output.status.code(), Some(1));
+    assert!(String::from_utf8_lossy(&output.stderr).contains("rdiff error"));
+}
+
+/// `patch --check` reports whether the delta applies, and writes nothing.
+#[test]
+fn patch_check() {
+    let tmp = TempDir::new().unwrap();
+    let dir = tmp.path();
+    let basis = pattern(100 << 10);
+    let mut new = basis[1000..].to_vec();
+    new.extend_from_slice(b"more");
+    fs::write(dir.join("basis"), &basis).unwrap();
+    fs::write(dir.join("new"), &new).unwrap();
+    fs::write(dir.join("short"), &basis[..50 << 10]).unwrap();
+    assert!(rdiff(dir, &["signature", "basis", "sig"]).status.success());
+    assert!(rdiff(dir, &["delta", "sig", "new", "delta"]).status.success());
+    let output = rdiff(dir, &["patch", "--check", "basis", "delta"]);
+    assert!(output.status.success());
+    assert!(output.stdout.is_empty());
+    let output = rdiff(dir, &["patch", "--check", "short", "delta"]);
+    assert_eq!(output.status.code(), Some(1));
+    assert!(String::from_utf8_lossy(&output.stderr).contains("rdiff error"));
+    assert_eq!(rdiff(dir, &["patch", "--check", "basis", "delta", "out"]).status.code(),
+               Some(101));
+    assert!(!dir.join("out").exists());
+}
+
+/// `patch --signature` writes the new file's signature as `signature` would.
+#[test]
+fn patch_signature() {
+    let tmp = TempDir::new().unwrap();
+    let dir = tmp.path();
+    let basis = pattern(100 << 10);
+    let mut new = basis[1000..].to_vec();
+    new.extend_from_slice(b"more");
+    fs::write(dir.join("basis"), &basis).unwrap();
+    fs::write(dir.join("new"), &new).unwrap();
+    assert!(rdiff(dir, &["signature", "basis", "sig"]).status.success());
+    assert!(rdiff(dir, &["delta", "sig", "new", "delta"]).status.success());
+    for args in &[&[][..], &["-b", "512", "-H", "md4"]] {
+        let sig_args: Vec<&str> = ["signature", "-f"].iter().chain(*args)
+            .chain(&["new", "new.sig"]).cloned().collect();
+        assert!(rdiff(dir, &sig_args).status.success());
+        let patch_args: Vec<&str> = ["patch", "-f", "--signature", "out.sig"].iter()
+            .chain(*args).chain(&["basis", "delta", "out"]).cloned().collect();
+        assert!(rdiff(dir, &patch_args).status.success());
+        assert_eq!(fs::read(dir.join("out")).unwrap(), new);
+        assert_eq!(fs::read(dir.join("out.sig")).unwrap(), fs::read(dir.join("new.sig")).unwrap());
+    }
+    let output = rdiff(dir, &["patch", "--signature", "-", "basis", "delta", "out2"]);
+    assert!(output.status.success());
+    let options = SignatureOptions {
+        magic: SignatureFormat::RkBlake2Sig,
+        .. SignatureOptions::default()
+    };
+    assert_eq!(output.stdout, signature_with_options(&new, &options).unwrap());
+    assert_eq!(fs::read(dir.join("out2")).unwrap(), new);
+    assert_eq!(rdiff(dir, &["patch", "--signature", "out.sig", "basis", "delta", "out3"])
+               .status.code(), Some(1));
+    assert!(!dir.join("out3").exists());
+    assert_eq!(rdiff(dir, &["patch", "--signature", "-", "basis", "delta"]).status.code(),
+               Some(101));
+    assert_eq!(rdiff(dir, &["patch", "--check", "--signature", "s", "basis", "delta"])
+               .status.code(), Some(101));
+}
+
+/// An existing output is only replaced with `--force`, and only once the new one is
+/// complete.
+#[test]
+fn force() {
+    let tmp = TempDir::new().unwrap();
+    let dir = tmp.path();
+    let basis = pattern(10_000);
+    fs::write(dir.join("basis"), &basis).unwrap();
+    fs::write(dir.join("sig"), b"precious").unwrap();
+    let output = rdiff(dir, &["signature", "basis", "sig"]);
+    assert_eq!(output.status.code(), Some(1));
+    assert!(String::from_utf8_lossy(&output.stderr).contains("--force"));
+    assert_eq!(fs::read(dir.join("sig")).unwrap(), b"precious");
+    assert_eq!(rdiff(dir, &["delta", "sig", "basis", "sig"]).status.code(), Some(1));
+    assert_eq!(fs::read(dir.join("sig")).unwrap(), b"precious");
+
+    assert!(rdiff(dir, &["signature", "--force", "basis", "sig"]).status.success());
+    assert!(rdiff(dir, &["delta", "sig", "basis", "delta"]).status.success());
+    // A failed patch leaves neither a truncated output nor its temporary file.
+    fs::write(dir.join("out"), b"old output").unwrap();
+    fs::write(dir.join("short"), &basis[..5000]).unwrap();
+    assert_eq!(rdiff(dir, &["patch", "-f", "short", "delta", "out"]).status.code(), Some(1));
+    assert_eq!(fs::read(dir.join("out")).unwrap(), b"old output");
+    assert!(rdiff(dir, &["patch", "-f", "basis", "delta", "out"]).status.success());
+    assert_eq!(fs::read(dir.join("out")).unwrap(), basis);
+    let mut names: Vec<_> = fs::read_dir(dir).unwrap()
+        .map(|e| e.unwrap().file_name().into_string().unwrap())
+        .collect();
+    names.sort();
+    assert_eq!(names, ["basis", "delta", "out", "short", "sig"]);
+}
+
+#[test]
+fn bench() {
+    let tmp = TempDir::new().unwrap();
+    let dir = tmp.path();
+    let output = rdiff(dir, &["bench", "--size", "300000", "--block-size", "512"]);
+    assert!(output.status.success());
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert!(stdout.starts_with("basis: 300000 bytes, RkBlake2Sig, block length 512"), "{}", stdout);
+    for name in &["signature", "unchanged", "edited", "inserted", "appended", "moved", "new"] {
+        assert!(stdout.contains(&format!("{}: ", name)), "{}", stdout);
+    }
+    assert_eq!(rdiff(dir, &["bench", "--size", "lots"]).status.code(), Some(101));
+}
@@ -0,0 +1,42 @@
+#!/bin/sh
+# Build rdiff from C librsync releases and make golden files with each of them, in
+# `librsync-<version>/` alongside this script.
+#
+# Needs network access, a C compiler, cmake and libpopt.
+
+set -eu
+
+cd "$(dirname "$0")"
+work=$(mktemp -d)
+trap 'rm -rf "$work"' EXIT
+
+for version in 2.0.2 2.3.4; do
+    curl -sSfL "https://github.com/librsync/librsync/archive/refs/tags/v$version.tar.gz" \
+        | tar -xz -C "$work"
+    src="$work/librsync-$version"
+    cmake -S "$src" -B "$src/build" -DCMAKE_BUILD_TYPE=Release
+    cmake --build "$src/build" --target rdiff
+    rdiff="$src/build/rdiff"
+
+    out="librsync-$version"
+    rm -rf "$out"
+    mkdir "$out"
+    case $version in
+        2.0.*) weak_sums="" ;;  # Only the rollsum, and no -R option.
+        *) weak_sums="rollsum rabinkarp" ;;
+    esac
+    for hash in md4 blake2; do
+        case $hash in md4) full=16 ;; blake2) full=32 ;; esac
+        for weak in ${weak_sums:-rollsum}; do
+            prefix=""
+            weak_opt=""
+            if [ -n "$weak_sums" ]; then
+                weak_opt="-R $weak"
+                [ "$weak" = rabinkarp ] && prefix="rk-"
+            fi
+            "$rdiff" -b 256 -S $full -H $hash $weak_opt signature basis "$out/$prefix$hash.sig"
+            "$rdiff" -b 256 -S 8 -H $hash $weak_opt signature basis "$out/$prefix$hash-s8.sig"
+        done
+    done
+    "$rdiff" delta "$out/md4.sig" new "$out/new.delta"
+done
@@ -0,0 +1,135 @@
+//! Tests against fixed signature and delta files, and, where they're present, the
+//! same files made by releases of C librsync.
+//!
+//! See `tests/data/golden/README.md` for how they were made.
+
+// Copyright 2018 Martin Pool.
+
+extern crate rdiff;
+
+use std::fs;
+use std::path::PathBuf;
+
+use rdiff::io_options::IoOptions;
+use rdiff::magic::SignatureFormat;
+use rdiff::memory::{apply, delta_of, signature_with_options};
+use rdiff::mkdelta::{generate_delta_pipelined, generate_delta_with_io, DeltaOptions};
+use rdiff::mksum::{generate_signature_with_io, SignatureOptions};
+use rdiff::signature::Signature;
+
+/// The signature files, with the format and strong sum length they were made with. All
+/// have 256-byte blocks.
+const SIGNATURES: &[(&str, SignatureFormat, u32)] = &[
+    ("md4.sig", SignatureFormat::Md4Sig, 16),
+    ("md4-s8.sig", SignatureFormat::Md4Sig, 8),
+    ("blake2.sig", SignatureFormat::Blake2Sig, 32),
+    ("blake2-s8.sig", SignatureFormat::Blake2Sig, 8),
+    ("rk-md4.sig", SignatureFormat::RkMd4Sig, 16),
+    ("rk-md4-s8.sig", SignatureFormat::RkMd4Sig, 8),
+    ("rk-blake2.sig", SignatureFormat::RkBlake2Sig, 32),
+    ("rk-blake2-s8.sig", SignatureFormat::RkBlake2Sig, 8),
+];
+
+fn golden_dir() -> PathBuf {
+    [env!("CARGO_MANIFEST_DIR"), "tests", "data", "golden"].iter().collect()
+}
+
+fn golden(name: &str) -> Vec<u8> {
+    let path = golden_dir().join(name);
+    fs::read(&path).unwrap_or_else(|e| panic!("read {}: {}", path.display(), e))
+}
+
+#[test]
+fn signatures_are_identical() {
+    let basis = golden("basis");
+    for &(name, magic, strong_len) in SIGNATURES {
+        let options = SignatureOptions { magic, block_len: 256, strong_len, seed: 0 };
+        assert!(signature_with_options(&basis, &options).unwrap() == golden(name), "{}", name);
+    }
+}
+
+#[test]
+fn signatures_parse() {
+    for &(name, magic, strong_len) in SIGNATURES {
+        let sig = Signature::read_from(&mut golden(name).as_slice()).unwrap();
+        assert_eq!(sig.format(), magic, "{}", name);
+        assert_eq!((sig.block_len(), sig.strong_len()), (256, strong_len), "{}", name);
+        assert_eq!(sig.block_count(), 36, "{}", name);
+    }
+}
+
+/// The same delta is made from each of the signatures.
+#[test]
+fn deltas_are_identical() {
+    let new = golden("new");
+    let expected = golden("new.delta");
+    for &(name, _, _) in SIGNATURES {
+        assert!(delta_of(&golden(name), &new).unwrap() == expected, "{}", name);
+    }
+}
+
+#[test]
+fn patch_golden_delta() {
+    assert!(apply(&golden("basis"), &golden("new.delta")).unwrap() == golden("new"));
+}
+
+/// Each `librsync-<version>` directory made by `make-librsync.sh` has the signatures
+/// that release supports, which are the same bytes as this crate makes, and a delta
+/// that patches `basis` to `new`.
+#[test]
+fn librsync_releases() {
+    let basis = golden("basis");
+    let new = golden("new");
+    for entry in fs::read_dir(golden_dir()).unwrap() {
+        let dir = entry.unwrap().path();
+        if !dir.file_name().unwrap().to_str().unwrap().starts_with("librsync-") {
+            continue;
+        }
+        for &(name, magic, strong_len) in SIGNATURES {
+            if let Ok(sig) = fs::read(dir.join(name)) {
+                let options = SignatureOptions { magic, block_len: 256, strong_len, seed: 0 };
+                assert!(signature_with_options(&basis, &options).unwrap() == sig,
+                        "{} {}", dir.display(), name);
+            }
+        }
+        let delta = fs::read(dir.join("new.delta")).unwrap();
+        assert!(apply(&basis, &delta).unwrap() == new, "{}", dir.display());
+    }
+}
+
+/// The signatures and deltas are the same bytes however they're made: whatever the
+/// buffer sizes, on any number of threads, and on a platform of either endianness,
+/// since the files compared against don't change.
+#[test]
+fn output_is_deterministic() {
+    let basis = golden("basis");
+    let new = golden("new");
+    let expected = golden("new.delta");
+    let buffers = [IoOptions { read_buf: 0, write_buf: 0 },
+                   IoOptions { read_buf: 7, write_buf: 1000 }];
+    for &(name, magic, strong_len) in SIGNATURES {
+        let options = SignatureOptions { magic, block_len: 256, strong_len, seed: 0 };
+        let sig = Signature::read_from(&mut golden(name).as_slice()).unwrap();
+        for io in &buffers {
+            let mut sig_file = Vec::new();
+            generate_signature_with_io(&mut basis.as_slice(), &options, &mut sig_file, io)
+                .unwrap();
+            assert!(sig_file == golden(name), "{} {:?}", name, io);
+            let mut delta = Vec::new();
+            generate_delta_with_io(&sig, &mut new.as_slice(), &mut delta, io).unwrap();
+            assert!(delta == expected, "{} {:?}", name, io);
+        }
+        let mut delta = Vec::new();
+        generate_delta_pipelined(&sig, &mut new.as_slice(), &mut delta,
+                                 &DeltaOptions::default()).unwrap();
+        assert!(delta == expected, "{}", name);
+        #[cfg(feature = "parallel")]
+        {
+            let options = DeltaOptions { deterministic: true, .. DeltaOptions::default() };
+            let mut delta = Vec::new();
+            rdiff::mkdelta::generate_delta_parallel_with_options(
+                &sig, &mut new.as_slice(), &mut delta, 1000, &options).unwrap();
+            assert!(delta == expected, "{}", name);
+        }
+    }
+}
@@ -0,0 +1,98 @@
+//! Property tests that patching with a delta always gives back the new file.
+
+// Copyright 2018 Martin Pool.
+
+extern crate proptest;
+extern crate rdiff;
+
+use proptest::prelude::*;
+
+use rdiff::magic::SignatureFormat;
+use rdiff::memory::{apply, delta_of, signature_with_options};
+use rdiff::mksum::SignatureOptions;
+
+/// A change made to the basis to get the new file.
+#[derive(Debug, Clone)]
+enum Edit {
+    Insert(usize, Vec<u8>),
+    Delete(usize, usize),
+    Replace(usize, Vec<u8>),
+    Truncate(usize),
+    Append(Vec<u8>),
+}
+
+impl Edit {
+    /// Apply the edit, with positions taken modulo the length of `data`.
+    fn apply(&self, data: &mut Vec<u8>) {
+        let at = |pos: usize| if data.is_empty() { 0 } else { pos % (data.len() + 1) };
+        match self {
+            Edit::Insert(pos, bytes) => {
+                let pos = at(*pos);
+                data.splice(pos..pos, bytes.iter().cloned());
+            }
+            Edit::Delete(pos, len) => {
+                let pos = at(*pos);
+                let end = (pos + len).min(data.len());
+                data.drain(pos..end);
+            }
+            Edit::Replace(pos, bytes) => {
+                let pos = at(*pos);
+                let end = (pos + bytes.len()).min(data.len());
+                data.splice(pos..end, bytes.iter().cloned());
+            }
+            Edit::Truncate(len) => {
+                let len = at(*len);
+                data.truncate(len);
+            }
+            Edit::Append(bytes) => data.extend_from_slice(bytes),
+        }
+    }
+}
+
+fn edit() -> impl Strategy<Value = Edit> {
+    let bytes = || prop::collection::vec(any::<u8>(), 0..300);
+    prop_oneof![
+        (any::<usize>(), bytes()).prop_map(|(p, b)| Edit::Insert(p, b)),
+        (any::<usize>(), 0..1000usize).prop_map(|(p, l)| Edit::Delete(p, l)),
+        (any::<usize>(), bytes()).prop_map(|(p, b)| Edit::Replace(p, b)),
+        any::<usize>().prop_map(Edit::Truncate),
+        bytes().prop_map(Edit::Append),
+    ]
+}
+
+/// Basis files that are either random, or repetitive so that blocks recur.
+fn basis() -> impl Strategy<Value = Vec<u8>> {
+    prop_oneof![
+        prop::collection::vec(any::<u8>(), 0..5000),
+        (prop::collection::vec(any::<u8>(), 1..20), 0..5000usize)
+            .prop_map(|(unit, len)| unit.iter().cloned().cycle().take(len).collect()),
+    ]
+}
+
+fn format() -> impl Strategy<Value = SignatureFormat> {
+    prop_oneof![
+        Just(SignatureFormat::Md4Sig),
+        Just(SignatureFormat::Blake2Sig),
+        Just(SignatureFormat::RkMd4Sig),
+        Just(SignatureFormat::RkBlake2Sig),
+    ]
+}
+
+proptest! {
+    #[test]
+    fn patch_gives_new_file(basis in basis(),
+                            edits in prop::collection::vec(edit(), 0..5),
+                            block_len in prop_oneof![1..8u32, 8..300u32, Just(2048u32)],
+                            magic in format(),
+                            strong_len in 8..=16u32) {
+        let mut new = basis.clone();
+        for e in &edits {
+            e.apply(&mut new);
+        }
+        let options = SignatureOptions { magic, block_len, strong_len, seed: 0 };
+        let sig = signature_with_options(&basis, &options).unwrap();
+        let delta = delta_of(&sig, &new).unwrap();
+        // Shorter strong sums could, rarely, match the wrong block.
+        prop_assert!(apply(&basis, &delta).unwrap() == new);
+    }
+}
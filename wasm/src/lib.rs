@@ -0,0 +1,8 @@
+// rdiff-rs wasm -- WebAssembly module of a Rust library for network deltas.
+// Copyright 2018 Martin Pool.
+
+//! A WebAssembly module exporting the JavaScript bindings in `rdiff::wasm`.
+//!
+//! Build an npm package from the top of the repository with `wasm-pack build wasm`.
+
+pub use rdiff::wasm::*;